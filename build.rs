@@ -1,5 +1,3 @@
-use minijinja_embed;
-
 fn main() {
     minijinja_embed::embed_templates!("templates");
 }
@@ -0,0 +1,76 @@
+use crate::Stencil;
+use crate::json::{self, Json};
+
+// What a runtime built against the generated header depends on: the stencils and the names,
+// order and types of their patch arguments. --abi writes it as JSON for `check-abi`.
+pub struct AbiStencil {
+    name: String,
+    args: Vec<AbiArg>,
+}
+
+#[derive(PartialEq, Eq)]
+struct AbiArg {
+    name: String,
+    // The argument's type in cnp_patch_<name> and cnp_emit_<name>.
+    patch_type: String,
+    emit_type: String,
+}
+
+pub fn from_stencils(stencils: &[Stencil]) -> Vec<AbiStencil> {
+    stencils.iter().map(|stencil| AbiStencil {
+        name: stencil.name.to_string(),
+        args: stencil.holes.iter().filter(|h| h.is_argument()).map(|hole| AbiArg {
+            name: hole.name.to_string(),
+            patch_type: hole.datatype.to_string(),
+            emit_type: hole.value_datatype.to_string(),
+        }).collect(),
+    }).collect()
+}
+
+// Describes every change from `baseline` to `current` that breaks callers compiled against the
+// baseline. New stencils don't break anything.
+pub fn breaking_changes(baseline: &[AbiStencil], current: &[AbiStencil]) -> Vec<String> {
+    let mut changes = Vec::new();
+    for old in baseline {
+        let Some(new) = current.iter().find(|s| s.name == old.name) else {
+            changes.push(format!("stencil {} was removed", old.name));
+            continue;
+        };
+        if old.args.len() != new.args.len() {
+            changes.push(format!("{} takes {} arguments, was {}", old.name, new.args.len(), old.args.len()));
+            continue;
+        }
+        for (i, (old_arg, new_arg)) in old.args.iter().zip(&new.args).enumerate() {
+            if old_arg.name != new_arg.name {
+                let moved = new.args.iter().any(|a| a.name == old_arg.name);
+                let what = if moved { "moved" } else { "renamed" };
+                changes.push(format!("{} argument {} is {}, was {} ({})", old.name, i, new_arg.name, old_arg.name, what));
+            } else if old_arg != new_arg {
+                changes.push(format!("{} argument {} is {} / {}, was {} / {}", old.name, new_arg.name,
+                    new_arg.patch_type, new_arg.emit_type, old_arg.patch_type, old_arg.emit_type));
+            }
+        }
+    }
+    changes
+}
+
+// Reads the JSON --abi writes.
+pub fn parse(text: &str) -> Result<Vec<AbiStencil>, String> {
+    let value = json::parse(text)?;
+    let string = |value: &Json, name: &str| match value.field(name) {
+        Some(Json::String(s)) => Ok(s.clone()),
+        _ => Err(format!("expected string field {}", name)),
+    };
+    let array = |value: &Json, name: &str| match value.field(name) {
+        Some(Json::Array(items)) => Ok(items.clone()),
+        _ => Err(format!("expected array field {}", name)),
+    };
+    array(&value, "stencils")?.iter().map(|stencil| Ok(AbiStencil {
+        name: string(stencil, "name")?,
+        args: array(stencil, "args")?.iter().map(|arg| Ok(AbiArg {
+            name: string(arg, "name")?,
+            patch_type: string(arg, "patch_type")?,
+            emit_type: string(arg, "emit_type")?,
+        })).collect::<Result<_, String>>()?,
+    })).collect()
+}
@@ -0,0 +1,478 @@
+use std::ops::Range;
+
+use goblin::elf::header;
+
+use crate::x86::{self, BranchKind};
+
+// Instruction set specifics needed when cutting stencils apart. Everything else works on
+// relocations and is architecture neutral.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Arch {
+    X86_64,
+    AArch64,
+    RiscV,
+    Arm,
+}
+
+#[derive(Default, Clone, Copy)]
+pub struct InsnStats {
+    pub insns: usize,
+    // Calls and branches include the indirect ones, branches include the jump to the next stencil.
+    pub calls: usize,
+    pub branches: usize,
+    pub indirect: usize,
+    pub returns: usize,
+    pub vector: usize,
+}
+
+// Multi-byte NOPs compilers use for alignment padding, plus int3 fill.
+const X86_NOPS: &[&[u8]] = &[
+    &[0x90],
+    &[0xcc],
+    &[0x66, 0x90],
+    &[0x0f, 0x1f, 0x00],
+    &[0x0f, 0x1f, 0x40, 0x00],
+    &[0x0f, 0x1f, 0x44, 0x00, 0x00],
+    &[0x66, 0x0f, 0x1f, 0x44, 0x00, 0x00],
+    &[0x0f, 0x1f, 0x80, 0x00, 0x00, 0x00, 0x00],
+    &[0x0f, 0x1f, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00],
+    &[0x66, 0x0f, 0x1f, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00],
+    &[0x2e, 0x0f, 0x1f, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00],
+    &[0x66, 0x2e, 0x0f, 0x1f, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00],
+];
+
+impl Arch {
+    pub fn from_machine(machine: u16) -> Result<Arch, String> {
+        match machine {
+            header::EM_X86_64 => Ok(Arch::X86_64),
+            header::EM_AARCH64 => Ok(Arch::AArch64),
+            header::EM_RISCV => Ok(Arch::RiscV),
+            header::EM_ARM => Ok(Arch::Arm),
+            _ => Err(format!("unsupported machine {}", header::machine_to_str(machine))),
+        }
+    }
+
+    // Names of the architectures in `arch=object` inputs.
+    pub fn from_name(name: &str) -> Option<Arch> {
+        match name {
+            "x86_64" => Some(Arch::X86_64),
+            "aarch64" => Some(Arch::AArch64),
+            "riscv" => Some(Arch::RiscV),
+            "arm" => Some(Arch::Arm),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Arch::X86_64 => "x86_64",
+            Arch::AArch64 => "aarch64",
+            Arch::RiscV => "riscv",
+            Arch::Arm => "arm",
+        }
+    }
+
+    // Preprocessor condition that holds when compiling for this architecture.
+    pub fn c_condition(self) -> &'static str {
+        match self {
+            Arch::X86_64 => "defined(__x86_64__)",
+            Arch::AArch64 => "defined(__aarch64__)",
+            Arch::RiscV => "defined(__riscv)",
+            Arch::Arm => "defined(__arm__)",
+        }
+    }
+
+    // The return instruction cnp_emit_callable puts where a stencil continues.
+    pub fn return_thunk(self) -> &'static [u8] {
+        match self {
+            Arch::X86_64 => &[0xc3],
+            // ret
+            Arch::AArch64 => &[0xc0, 0x03, 0x5f, 0xd6],
+            // jalr zero, 0(ra)
+            Arch::RiscV => &[0x67, 0x80, 0x00, 0x00],
+            // bx lr
+            Arch::Arm => &[0x1e, 0xff, 0x2f, 0xe1],
+        }
+    }
+
+    // Where the alignment padding at the end of `code` starts, code.len() if there is none.
+    pub fn padding_start(self, code: &[u8]) -> usize {
+        let nops = match self {
+            Arch::X86_64 => X86_NOPS,
+            // Padding on fixed width ISAs is plain nops, but it is never part of st_size.
+            _ => return code.len(),
+        };
+        let mut end = code.len();
+        while let Some(nop) = nops.iter().filter(|nop| code[..end].ends_with(nop)).max_by_key(|nop| nop.len()) {
+            end -= nop.len();
+            if nop[0] == 0x66 || nop[0] == 0x2e {
+                // Long NOPs are extended with redundant operand size prefixes.
+                while end > 0 && code[end - 1] == 0x66 {
+                    end -= 1;
+                }
+            }
+        }
+        end
+    }
+
+    // Whether `code` ends in an instruction that never falls through, `reloc_at_end` being whether
+    // a relocation patches its last bytes.
+    pub fn ends_in_terminator(self, code: &[u8], reloc_at_end: bool) -> bool {
+        let end = code.len();
+        match self {
+            // jmp rel32 to a hole or through its GOT entry, ret, ud2 or jmp rel8
+            Arch::X86_64 => (end >= 5 && code[end - 5] == 0xe9 && reloc_at_end) ||
+                (end >= 6 && code[end - 6..end - 4] == [0xff, 0x25] && reloc_at_end) ||
+                code.ends_with(&[0xc3]) ||
+                code.ends_with(&[0x0f, 0x0b]) ||
+                (end >= 2 && code[end - 2] == 0xeb),
+            // There's no padding to strip, see padding_start.
+            _ => false,
+        }
+    }
+
+    // The offsets the direct branches in `code` jump to, relative to its start, leaving out the
+    // ones patched by relocations starting at `relocs`. None if they can't be worked out.
+    pub fn branch_targets(self, code: &[u8], relocs: &[usize]) -> Option<Vec<i64>> {
+        match self {
+            Arch::X86_64 => {
+                let insns = x86::decode_all(code)?;
+                Some(x86::starts(&insns).into_iter().zip(&insns)
+                    .filter(|(start, insn)| insn.branch.is_some() && insn.rel.is_some_and(|(offset, _)| !relocs.contains(&(start + offset))))
+                    .filter_map(|(start, insn)| x86::rel_target(code, start, insn))
+                    .collect())
+            }
+            _ => None,
+        }
+    }
+
+    // Returns `end` extended over any literal pool the instructions in text[start..end] load from.
+    // Compilers place pools after the function's st_size, so they are otherwise cut off.
+    pub fn literal_pool_end(self, text: &[u8], start: usize, end: usize) -> usize {
+        if self != Arch::AArch64 {
+            return end;
+        }
+        let mut pool_end = end;
+        for pc in (start..end).step_by(4) {
+            let Some(word) = text.get(pc..pc + 4).map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]])) else {
+                break;
+            };
+            // LDR (literal), LDRSW (literal) and PRFM (literal): opc 011 V 00 imm19 Rt
+            if word & 0x3b00_0000 != 0x1800_0000 {
+                continue;
+            }
+            let size = match (word >> 26 & 1, word >> 30) {
+                (0, 0) | (0, 2) | (1, 0) => 4,
+                (0, 1) | (1, 1) => 8,
+                (1, 2) => 16,
+                _ => continue,
+            };
+            let imm19 = ((word << 8) as i32) >> 13;
+            let target = pc as i64 + imm19 as i64 * 4;
+            if target >= end as i64 && target as usize + size <= text.len() {
+                pool_end = pool_end.max(target as usize + size);
+            }
+        }
+        pool_end
+    }
+
+    // The byte ranges of the instructions in `code`. Whatever x86-64 code doesn't decode is
+    // left as one range at the end.
+    pub fn instructions(self, code: &[u8]) -> Vec<Range<usize>> {
+        let mut insns = Vec::new();
+        let mut offset = 0;
+        while offset < code.len() {
+            let len = match self {
+                Arch::X86_64 => x86::decode(&code[offset..]).map_or(code.len() - offset, |insn| insn.len),
+                // Compressed instructions have anything but 0b11 in their low bits.
+                Arch::RiscV if code[offset] & 3 != 3 => 2,
+                _ => 4,
+            };
+            let end = (offset + len).min(code.len());
+            insns.push(offset..end);
+            offset = end;
+        }
+        insns
+    }
+
+    // Counts the kinds of instructions in `code` that matter for estimating its cost.
+    pub fn insn_stats(self, code: &[u8]) -> InsnStats {
+        let mut stats = InsnStats::default();
+        for insn in self.instructions(code) {
+            let bytes = &code[insn];
+            stats.insns += 1;
+            let word = || bytes.iter().rev().fold(0u32, |word, &b| word << 8 | b as u32);
+            match self {
+                Arch::X86_64 => {
+                    let Some(insn) = x86::decode(bytes) else { continue };
+                    match (insn.branch, insn.indirect) {
+                        (Some(BranchKind::Call), _) => stats.calls += 1,
+                        (Some(_), _) => stats.branches += 1,
+                        (_, Some(BranchKind::Call)) => { stats.calls += 1; stats.indirect += 1; }
+                        (_, Some(_)) => { stats.branches += 1; stats.indirect += 1; }
+                        _ => {}
+                    }
+                    stats.returns += insn.ret as usize;
+                    stats.vector += insn.vector as usize;
+                }
+                Arch::AArch64 => {
+                    let word = word();
+                    match word {
+                        _ if word & 0xfc00_0000 == 0x9400_0000 => stats.calls += 1,
+                        // b, b.cond, cbz/cbnz, tbz/tbnz
+                        _ if word & 0xfc00_0000 == 0x1400_0000 || word & 0xff00_0010 == 0x5400_0000 ||
+                             word & 0x7c00_0000 == 0x3400_0000 => stats.branches += 1,
+                        _ if word & 0xffff_fc1f == 0xd63f_0000 => { stats.calls += 1; stats.indirect += 1; }
+                        _ if word & 0xffff_fc1f == 0xd61f_0000 => { stats.branches += 1; stats.indirect += 1; }
+                        _ if word & 0xffff_fc1f == 0xd65f_0000 => stats.returns += 1,
+                        // SIMD and FP data processing, and loads/stores of SIMD registers.
+                        _ if word >> 25 & 7 == 7 || (word >> 25 & 5 == 4 && word >> 26 & 1 == 1) => stats.vector += 1,
+                        _ => {}
+                    }
+                }
+                Arch::RiscV if bytes.len() == 2 => {
+                    let half = word();
+                    let rs1 = half >> 7 & 0x1f;
+                    match (half & 3, half >> 13) {
+                        // c.j, c.beqz, c.bnez
+                        (1, 5..=7) => stats.branches += 1,
+                        // c.jr and c.jalr, c.jr ra being a return.
+                        (2, 4) if half >> 2 & 0x1f == 0 && rs1 != 0 => match half >> 12 & 1 {
+                            0 if rs1 == 1 => stats.returns += 1,
+                            0 => { stats.branches += 1; stats.indirect += 1; }
+                            _ => { stats.calls += 1; stats.indirect += 1; }
+                        },
+                        _ => {}
+                    }
+                }
+                Arch::RiscV => {
+                    let word = word();
+                    let (rd, rs1) = (word >> 7 & 0x1f, word >> 15 & 0x1f);
+                    match word & 0x7f {
+                        0x6f if rd == 0 => stats.branches += 1,
+                        0x6f => stats.calls += 1,
+                        0x67 if rd == 0 && rs1 == 1 => stats.returns += 1,
+                        // auipc+jalr pairs are how calls and tail calls reach far targets.
+                        0x67 if rd == 0 => { stats.branches += 1; stats.indirect += 1; }
+                        0x67 => { stats.calls += 1; stats.indirect += 1; }
+                        0x63 => stats.branches += 1,
+                        0x57 => stats.vector += 1,
+                        // Vector loads and stores share their opcodes with scalar FP ones.
+                        0x07 | 0x27 if matches!(word >> 12 & 7, 0 | 5..=7) => stats.vector += 1,
+                        _ => {}
+                    }
+                }
+                Arch::Arm => {
+                    let word = word();
+                    match word {
+                        _ if word >> 28 == 0xf && word >> 25 & 7 == 5 => stats.calls += 1,
+                        _ if word >> 25 & 7 == 5 && word >> 24 & 1 == 1 => stats.calls += 1,
+                        _ if word >> 25 & 7 == 5 => stats.branches += 1,
+                        _ if word & 0x0fff_fff0 == 0x012f_ff10 => match word & 0xf {
+                            14 => stats.returns += 1,
+                            _ => { stats.branches += 1; stats.indirect += 1; }
+                        },
+                        _ if word & 0x0fff_fff0 == 0x012f_ff30 => { stats.calls += 1; stats.indirect += 1; }
+                        // pop {..., pc}
+                        _ if word & 0x0fff_8000 == 0x08bd_8000 => stats.returns += 1,
+                        // NEON, and VFP on coprocessors 10 and 11.
+                        _ if word & 0xfe00_0000 == 0xf200_0000 || word & 0xff10_0000 == 0xf400_0000 => stats.vector += 1,
+                        _ if matches!(word >> 25 & 7, 6 | 7) && word >> 9 & 7 == 5 && word >> 28 != 0xf => stats.vector += 1,
+                        _ => {}
+                    }
+                }
+            }
+        }
+        stats
+    }
+
+    // The most bytes `code` moves the stack pointer below where it was on entry, from the pushes
+    // and constant adjustments in its prologue and the return addresses of its calls. What the
+    // callees use on top is not included. Instructions are scanned in order and the deepest point
+    // is kept, so epilogues on early exits don't hide the frame. None if the stack pointer is
+    // moved by a variable amount, like for alloca, or the code doesn't decode.
+    pub fn stack_usage(self, code: &[u8]) -> Option<u64> {
+        let mut depth = 0i64;
+        let mut peak = 0i64;
+        // The depth saved in the frame pointer, which epilogues restore the stack pointer from.
+        let mut frame = None;
+        for insn in self.instructions(code) {
+            let bytes = &code[insn];
+            let word = || bytes.iter().rev().fold(0u32, |word, &b| word << 8 | b as u32);
+            match self {
+                Arch::X86_64 => {
+                    let insn = x86::decode(bytes)?;
+                    let rex = bytes[..insn.prefixes].last().copied().filter(|b| b & 0xf0 == 0x40).unwrap_or(0);
+                    let op = &bytes[insn.prefixes..];
+                    let modrm = op.get(1).copied().unwrap_or(0);
+                    // ModRM operands naming rsp itself rather than r12.
+                    let rm_rsp = modrm >> 6 == 3 && modrm & 7 == 4 && rex & 1 == 0;
+                    let reg_rsp = modrm >> 3 & 7 == 4 && rex & 4 == 0;
+                    let imm = |at: usize| match op[at..] {
+                        [b] => b as i8 as i64,
+                        [a, b, c, d] => i32::from_le_bytes([a, b, c, d]) as i64,
+                        _ => 0,
+                    };
+                    match op[0] {
+                        0x50..=0x57 | 0x68 | 0x6a | 0x9c => depth += 8,
+                        0x58..=0x5f | 0x9d => depth -= 8,
+                        0xff if modrm >> 3 & 7 == 6 => depth += 8,
+                        0x8f if modrm >> 3 & 7 == 0 => depth -= 8,
+                        0xe8 => peak = peak.max(depth + 8),
+                        0xff if matches!(modrm >> 3 & 7, 2 | 3) => peak = peak.max(depth + 8),
+                        // sub/add rsp, imm
+                        0x81 | 0x83 if rm_rsp && modrm >> 3 & 7 == 5 => depth += imm(2),
+                        0x81 | 0x83 if rm_rsp && modrm >> 3 & 7 == 0 => depth -= imm(2),
+                        // Realigning with `and rsp, -align` drops by up to align - 1 bytes.
+                        0x81 | 0x83 if rm_rsp && modrm >> 3 & 7 == 4 => depth += !imm(2),
+                        // lea rsp, [rsp + disp] and lea rsp, [rbp + disp]
+                        0x8d if reg_rsp && matches!(modrm, 0x64 | 0xa4) && op.get(2) == Some(&0x24) => depth -= imm(3),
+                        0x8d if reg_rsp && rex & 1 == 0 && matches!(modrm, 0x65 | 0xa5) => depth = frame? - imm(2),
+                        // mov rbp, rsp and mov rsp, rbp
+                        0x89 if rex & 5 == 0 && modrm == 0xe5 => frame = Some(depth),
+                        0x8b if rex & 5 == 0 && modrm == 0xec => frame = Some(depth),
+                        0x89 if rex & 5 == 0 && modrm == 0xec => depth = frame?,
+                        0x8b if rex & 5 == 0 && modrm == 0xe5 => depth = frame?,
+                        // leave
+                        0xc9 => depth = frame? - 8,
+                        0x01 | 0x29 | 0x81 | 0x83 | 0x89 | 0xc7 if rm_rsp => return None,
+                        0x03 | 0x2b | 0x8b | 0x8d if reg_rsp => return None,
+                        _ => {}
+                    }
+                }
+                Arch::AArch64 => {
+                    let word = word();
+                    let imm12 = || ((word >> 10 & 0xfff) << if word >> 22 & 1 == 1 { 12 } else { 0 }) as i64;
+                    let imm7 = || ((word << 10) as i32 >> 25) as i64;
+                    let imm9 = || ((word << 11) as i32 >> 23) as i64;
+                    match word {
+                        // sub/add sp, sp, #imm
+                        _ if word & 0xff80_03ff == 0xd100_03ff => depth += imm12(),
+                        _ if word & 0xff80_03ff == 0x9100_03ff => depth -= imm12(),
+                        // stp/ldp of x or d registers writing back to sp, pre-index then post-index.
+                        _ if word & 0xffc0_03e0 == 0xa980_03e0 || word & 0xffc0_03e0 == 0x6d80_03e0 => depth -= imm7() * 8,
+                        _ if word & 0xffc0_03e0 == 0xa8c0_03e0 || word & 0xffc0_03e0 == 0x6cc0_03e0 => depth -= imm7() * 8,
+                        // str/ldr x, pre-index then post-index.
+                        _ if word & 0xffe0_0fe0 == 0xf800_0fe0 => depth -= imm9(),
+                        _ if word & 0xffe0_0fe0 == 0xf840_07e0 => depth -= imm9(),
+                        // mov x29, sp and mov sp, x29
+                        0x9100_03fd => frame = Some(depth),
+                        0x9100_03bf => depth = frame?,
+                        // sub sp, sp, x (extended register)
+                        _ if word & 0xffe0_001f == 0xcb20_001f => return None,
+                        _ => {}
+                    }
+                }
+                Arch::RiscV if bytes.len() == 2 => {
+                    let half = word();
+                    // c.addi16sp
+                    if half & 0xef83 == 0x6101 {
+                        let imm = (half >> 12 & 1) << 9 | (half >> 3 & 3) << 7 | (half >> 5 & 1) << 6 |
+                            (half >> 2 & 1) << 5 | (half >> 6 & 1) << 4;
+                        depth -= ((imm << 22) as i32 >> 22) as i64;
+                    }
+                }
+                Arch::RiscV => {
+                    let word = word();
+                    let imm = (word as i32 >> 20) as i64;
+                    match word & 0xfffff {
+                        // addi sp, sp, imm
+                        0x10113 => depth -= imm,
+                        // addi s0, sp, imm and addi sp, s0, imm
+                        0x10413 => frame = Some(depth - imm),
+                        0x40113 => depth = frame? - imm,
+                        // sub sp, sp, reg
+                        0x10133 if word >> 25 == 0x20 => return None,
+                        _ => {}
+                    }
+                }
+                Arch::Arm => {
+                    let word = word();
+                    let imm = || ((word & 0xff).rotate_right((word >> 8 & 0xf) * 2)) as i64;
+                    let regs = (word & 0xffff).count_ones() as i64 * 4;
+                    match word & 0x0fff_0000 {
+                        // push and pop, as stmdb sp! and ldmia sp!
+                        0x092d_0000 => depth += regs,
+                        0x08bd_0000 => depth -= regs,
+                        // str/ldr of a single register
+                        0x052d_0000 if word & 0xfff == 4 => depth += 4,
+                        0x049d_0000 if word & 0xfff == 4 => depth -= 4,
+                        // sub/add sp, sp, #imm
+                        0x024d_0000 if word >> 12 & 0xf == 13 => depth += imm(),
+                        0x028d_0000 if word >> 12 & 0xf == 13 => depth -= imm(),
+                        _ if word & 0x0fbf_0e00 == 0x0d2d_0a00 => depth += (word & 0xff) as i64 * 4,
+                        _ if word & 0x0fbf_0e00 == 0x0cbd_0a00 => depth -= (word & 0xff) as i64 * 4,
+                        _ => {}
+                    }
+                }
+            }
+            peak = peak.max(depth);
+        }
+        u64::try_from(peak).ok()
+    }
+
+    // If `code` ends with an unconditional branch whose target is patched by a relocation of kind
+    // `relocation` at `reloc_offset`, returns the length of that branch.
+    pub fn trailing_branch_len(self, code: &[u8], reloc_offset: usize, relocation: &str) -> Option<usize> {
+        let end = code.len();
+        let last_word = || code.get(end.checked_sub(4)?..).map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]));
+        match self {
+            // jmp rel32
+            Arch::X86_64 => (end >= 5 && reloc_offset + 4 == end && code[end - 5..] == [0xe9, 0, 0, 0, 0]).then_some(5),
+            // b imm26
+            Arch::AArch64 => (relocation == "AARCH64_JUMP26" && reloc_offset + 4 == end &&
+                last_word()? & 0xfc00_0000 == 0x1400_0000).then_some(4),
+            Arch::RiscV => {
+                // jal x0, imm or the `tail` pair auipc t1, 0; jalr x0, t1
+                if relocation == "R_RISCV_JAL" && reloc_offset + 4 == end && last_word()? & 0xfff == 0x06f {
+                    Some(4)
+                } else if matches!(relocation, "R_RISCV_CALL" | "R_RISCV_CALL_PLT") && reloc_offset + 8 == end &&
+                          last_word()? & 0x7fff == 0x0067 {
+                    Some(8)
+                } else {
+                    None
+                }
+            }
+            // b imm24 with the always condition, ARM relocations are REL so the addend is in the imm.
+            Arch::Arm => (relocation == "ARM_JUMP24" && reloc_offset + 4 == end &&
+                last_word()? & 0xff00_0000 == 0xea00_0000).then_some(4),
+        }
+    }
+
+    // Whether the instruction patched by a relocation of kind `relocation` at `reloc_offset` is a
+    // direct jump, conditional or not, rather than a call or a data reference.
+    pub fn is_jump(self, code: &[u8], reloc_offset: usize, relocation: &str) -> bool {
+        let word = |at: usize| code.get(at..at + 4).map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]));
+        match self {
+            // jmp rel32 or jcc rel32
+            Arch::X86_64 => match reloc_offset.checked_sub(2).and_then(|at| code.get(at..reloc_offset)) {
+                Some([_, 0xe9]) => true,
+                Some([0x0f, opcode]) => opcode & 0xf0 == 0x80,
+                _ => false,
+            },
+            Arch::AArch64 => matches!(relocation, "AARCH64_JUMP26" | "AARCH64_CONDBR19" | "AARCH64_TSTBR14"),
+            // Jumps link to x0, the `tail` pair's jalr is the second word.
+            Arch::RiscV => match relocation {
+                "R_RISCV_BRANCH" | "R_RISCV_RVC_BRANCH" | "R_RISCV_RVC_JUMP" => true,
+                "R_RISCV_JAL" => word(reloc_offset).is_some_and(|w| w & 0xf80 == 0),
+                "R_RISCV_CALL" | "R_RISCV_CALL_PLT" => word(reloc_offset + 4).is_some_and(|w| w & 0xf80 == 0),
+                _ => false,
+            },
+            Arch::Arm => relocation == "ARM_JUMP24",
+        }
+    }
+}
+
+// Whether the relocation patches in the value itself rather than a distance or an encoded branch.
+pub fn is_absolute(relocation: &str) -> bool {
+    matches!(relocation, "X86_64_64" | "X86_64_32" | "X86_64_32S" | "AARCH64_ABS64" | "AARCH64_ABS32" | "R_RISCV_64" | "R_RISCV_32" | "ARM_ABS32")
+}
+
+// Number of bytes a relocation of kind `relocation` patches.
+pub fn reloc_width(relocation: &str) -> usize {
+    match relocation {
+        "X86_64_64" | "X86_64_PC64" | "AARCH64_ABS64" | "AARCH64_PREL64" | "R_RISCV_64" => 8,
+        "R_RISCV_CALL" | "R_RISCV_CALL_PLT" => 8,
+        _ => 4,
+    }
+}
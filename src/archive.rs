@@ -0,0 +1,45 @@
+use goblin::elf::Elf;
+
+// Builds a GNU ar archive from object files, with the symbol index linkers need to pick members.
+// Timestamps and owners are zero so identical inputs give an identical archive.
+pub fn write(members: &[(&str, &[u8])]) -> Result<Vec<u8>, String> {
+    let mut symbols = Vec::new();
+    for (i, (name, data)) in members.iter().enumerate() {
+        let elf = Elf::parse(data).map_err(|e| format!("{}: {}", name, e))?;
+        for sym in elf.syms.iter().filter(|sym| sym.st_bind() != goblin::elf::sym::STB_LOCAL && sym.st_shndx != 0) {
+            let name = elf.strtab.get_at(sym.st_name).ok_or_else(|| format!("{}: bad symbol name", name))?;
+            symbols.push((i, name));
+        }
+    }
+
+    let index_size = 4 + 4 * symbols.len() + symbols.iter().map(|(_, name)| name.len() + 1).sum::<usize>();
+    let mut offsets = Vec::with_capacity(members.len());
+    let mut offset = 8 + 60 + index_size.next_multiple_of(2);
+    for (_, data) in members {
+        offsets.push(offset as u32);
+        offset += 60 + data.len().next_multiple_of(2);
+    }
+
+    let mut out = b"!<arch>\n".to_vec();
+    let mut index = (symbols.len() as u32).to_be_bytes().to_vec();
+    for (member, _) in &symbols {
+        index.extend_from_slice(&offsets[*member].to_be_bytes());
+    }
+    for (_, name) in &symbols {
+        index.extend_from_slice(name.as_bytes());
+        index.push(0);
+    }
+    add_member(&mut out, "/", &index);
+    for (name, data) in members {
+        add_member(&mut out, &format!("{}/", name), data);
+    }
+    Ok(out)
+}
+
+fn add_member(out: &mut Vec<u8>, name: &str, data: &[u8]) {
+    out.extend_from_slice(format!("{:<16}{:<12}{:<6}{:<6}{:<8}{:<10}`\n", name, 0, 0, 0, 644, data.len()).as_bytes());
+    out.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        out.push(b'\n');
+    }
+}
@@ -0,0 +1,93 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::sha256::{self, Sha256};
+
+// Remembers the hash of everything that went into the last successful run for a given set of
+// outputs, so a run with identical inputs can skip regeneration entirely. The sources and .dwo
+// files the objects point at are only found while reading them, so the cache records their
+// hashes after the key and checks them again, along with the outputs' so that one edited or
+// deleted since is regenerated.
+pub struct Cache {
+    path: PathBuf,
+    key: String,
+}
+
+pub struct KeyBuilder {
+    hasher: Sha256,
+}
+
+impl KeyBuilder {
+    pub fn new() -> KeyBuilder {
+        let mut builder = KeyBuilder { hasher: Sha256::new() };
+        builder.add(env!("CARGO_PKG_VERSION").as_bytes());
+        builder
+    }
+
+    pub fn add(&mut self, data: &[u8]) {
+        // Length prefix each field so that moving bytes between fields changes the key.
+        self.hasher.update(&(data.len() as u64).to_le_bytes());
+        self.hasher.update(data);
+    }
+
+    pub fn finish(self) -> String {
+        hex::encode(self.hasher.finalize())
+    }
+}
+
+impl Cache {
+    pub fn new(dir: &str, outputs: &[&str], key: String) -> Cache {
+        let name = sha256::digest(outputs.join("\0").as_bytes());
+        Cache {
+            path: Path::new(dir).join(name),
+            key,
+        }
+    }
+
+    pub fn is_fresh(&self) -> bool {
+        let stored = fs::read_to_string(&self.path).unwrap_or_default();
+        let mut lines = stored.lines();
+        lines.next() == Some(self.key.as_str())
+            && lines.all(|line| line.split_once(' ').is_some_and(|(hash, path)| file_digest(Path::new(path)).as_deref() == Some(hash)))
+    }
+
+    // `inputs` are the files found while reading the objects, `outputs` the files written.
+    pub fn store(&self, inputs: &[PathBuf], outputs: &[&str]) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut contents = self.key.clone();
+        for path in inputs.iter().map(PathBuf::as_path).chain(outputs.iter().map(Path::new)) {
+            contents.push_str(&format!("\n{} {}", sha256::digest(&fs::read(path)?), path.display()));
+        }
+        fs::write(&self.path, contents)
+    }
+}
+
+fn file_digest(path: &Path) -> Option<String> {
+    fs::read(path).ok().map(|data| sha256::digest(&data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn edited_outputs_are_stale() {
+        let dir = std::env::temp_dir().join(format!("stenciltool-cache-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let output = dir.join("stencils.h");
+        let output = output.to_str().unwrap();
+        fs::write(output, "generated").unwrap();
+        let cache = Cache::new(dir.join("cache").to_str().unwrap(), &[output], "key".to_string());
+        assert!(!cache.is_fresh());
+        cache.store(&[], &[output]).unwrap();
+        assert!(cache.is_fresh());
+        fs::write(output, "edited").unwrap();
+        assert!(!cache.is_fresh());
+        fs::remove_file(output).unwrap();
+        assert!(!cache.is_fresh());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
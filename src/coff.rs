@@ -0,0 +1,367 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+
+use goblin::pe::{header, relocation, section_table, symbol, Coff};
+
+use crate::arch::{self, Arch};
+use crate::diagnostics::Category;
+use crate::{fuse, hole_datatypes, Hole, ReadArgs, Reloc, Stencil, Visibility};
+
+// Objects from MSVC (and clang-cl) are COFF. Functions have no size, so each one runs to the next
+// function in its section, addends are stored in the patched field rather than the relocation,
+// and names carry decorations that ELF doesn't have. They're read into the same stencils, holes
+// and relocation names as ELF objects, so everything after reading treats them alike.
+
+// The name a decorated symbol was declared with, and whether it's the `__imp_` import table entry
+// holding the address of that symbol, which dllimport code loads it through like ELF's GOT.
+// `foo@8` (stdcall), `@foo@8` (fastcall) and `foo@@8` (vectorcall) count the bytes of arguments.
+// C++ names start with `?` and are left mangled.
+pub fn undecorate(name: &str) -> (&str, bool) {
+    let (name, import) = match name.strip_prefix("__imp_") {
+        Some(name) => (name, true),
+        None => (name, false),
+    };
+    if name.starts_with('?') {
+        return (name, import);
+    }
+    let name = match name.rsplit_once('@') {
+        Some((base, bytes)) if !bytes.is_empty() && bytes.bytes().all(|b| b.is_ascii_digit()) => {
+            let base = base.strip_suffix('@').unwrap_or(base);
+            base.strip_prefix('@').unwrap_or(base)
+        }
+        _ => name,
+    };
+    (name, import)
+}
+
+#[derive(Clone, Copy)]
+struct Symbol<'a> {
+    // Without its decorations.
+    name: &'a str,
+    import: bool,
+    // One based, 0 for undefined symbols and negative for absolute and debug ones.
+    section: i16,
+    value: u32,
+    class: u8,
+    function: bool,
+}
+
+impl Symbol<'_> {
+    fn is_defined(&self) -> bool {
+        self.section > 0
+    }
+
+    // Section symbols and labels, which relocations use to point at an offset into the section
+    // rather than at a symbol of their own, like ELF's STT_SECTION.
+    fn is_section_relative(&self) -> bool {
+        self.is_defined() && !self.function && matches!(self.class, symbol::IMAGE_SYM_CLASS_STATIC | symbol::IMAGE_SYM_CLASS_LABEL)
+    }
+}
+
+// The symbol table, indexed like relocations index it, with None for auxiliary records.
+fn read_symbols<'a>(coff: &Coff<'a>, data: &'a [u8]) -> Result<Vec<Option<Symbol<'a>>>, Box<dyn Error>> {
+    let Some(table) = &coff.symbols else {
+        return Ok(Vec::new());
+    };
+    let count = coff.header.number_of_symbol_table as usize;
+    let strings = coff.header.pointer_to_symbol_table as usize + symbol::SymbolTable::size(count);
+    let mut symbols = Vec::with_capacity(count);
+    while symbols.len() < count {
+        let index = symbols.len();
+        let (inline, sym) = table.get(index).ok_or_else(|| Category::Malformed.error(format!("symbol {} is past the end of the file", index)))?;
+        let name = match (inline, sym.name_offset()) {
+            (Some(name), _) => name,
+            (None, Some(offset)) => data.get(strings + 4 + offset as usize..)
+                .and_then(|rest| std::str::from_utf8(&rest[..rest.iter().position(|&b| b == 0)?]).ok())
+                .ok_or_else(|| Category::Malformed.error(format!("symbol {} has no name in the string table", index)))?,
+            (None, None) => "",
+        };
+        let (name, import) = undecorate(name);
+        symbols.push(Some(Symbol {
+            name,
+            import,
+            section: sym.section_number,
+            value: sym.value,
+            class: sym.storage_class,
+            function: sym.derived_type() == symbol::IMAGE_SYM_DTYPE_FUNCTION,
+        }));
+        symbols.extend((0..sym.number_of_aux_symbols).map(|_| None));
+    }
+    Ok(symbols)
+}
+
+fn section_name<'c>(coff: &'c Coff, section: i16) -> &'c str {
+    (section as usize).checked_sub(1).and_then(|i| coff.sections.get(i)).and_then(|shdr| shdr.name().ok()).unwrap_or("?")
+}
+
+// The contents of a section, which a truncated file may not have.
+fn section_data<'a>(coff: &Coff, data: &'a [u8], section: i16) -> Result<&'a [u8], Box<dyn Error>> {
+    let shdr = &coff.sections[section as usize - 1];
+    let (start, size) = (shdr.pointer_to_raw_data as usize, shdr.size_of_raw_data as usize);
+    data.get(start..start + size)
+        .ok_or_else(|| Category::Malformed.error(format!("{} ({:#x} bytes at {:#x}) is past the end of the file", section_name(coff, section), size, start)))
+}
+
+fn is_stencil_symbol(symbol: &Symbol, args: &ReadArgs, only: Option<&HashSet<&str>>) -> bool {
+    if only.is_some_and(|only| !only.contains(symbol.name)) {
+        return false;
+    }
+    // COFF has no visibility, everything external is default.
+    let visible = !matches!(args.visibility, Visibility::Hidden);
+    symbol.is_defined() && symbol.function && !symbol.import && match symbol.class {
+        symbol::IMAGE_SYM_CLASS_EXTERNAL => visible,
+        symbol::IMAGE_SYM_CLASS_STATIC => args.include_local_functions.as_deref().is_some_and(|prefix| symbol.name.starts_with(prefix)),
+        _ => false,
+    }
+}
+
+// The MSVC CRT runs the function pointers in the .CRT$XC* and .CRT$XI* sections at startup and
+// the .CRT$XP* and .CRT$XT* ones at exit, and guards function-local statics with
+// _Init_thread_header.
+fn check_static_init(coff: &Coff, symbols: &[Option<Symbol>]) -> Result<(), Box<dyn Error>> {
+    for shdr in &coff.sections {
+        let name = shdr.name().unwrap_or("?");
+        let when = match name.get(..7) {
+            Some(".CRT$XC" | ".CRT$XI") => "at load time",
+            Some(".CRT$XP" | ".CRT$XT") => "at exit",
+            _ => continue,
+        };
+        return Err(format!("{} runs code {}, stencils can't have static constructors or destructors", name, when).into());
+    }
+    if let Some(symbol) = symbols.iter().flatten().find(|s| s.name == "_Init_thread_header") {
+        return Err(format!("{}: stencils can't have function-local statics with dynamic initializers", symbol.name).into());
+    }
+    Ok(())
+}
+
+pub fn read<'a>(coff: &Coff<'a>, data: &'a [u8], args: &ReadArgs, only: Option<&HashSet<&str>>) -> Result<Vec<Stencil<'a>>, Box<dyn Error>> {
+    if coff.header.machine != header::COFF_MACHINE_X86_64 {
+        return Err(format!("unsupported COFF machine {:#x}, only AMD64 objects can be read", coff.header.machine).into());
+    }
+    let arch = Arch::X86_64;
+    let symbols = read_symbols(coff, data)?;
+    check_static_init(coff, &symbols)?;
+    // Where every function starts in each section, which is where the one before it ends.
+    let mut starts = HashMap::<i16, Vec<u32>>::new();
+    for symbol in symbols.iter().flatten().filter(|s| s.is_defined() && s.function) {
+        starts.entry(symbol.section).or_default().push(symbol.value);
+    }
+    starts.values_mut().for_each(|starts| starts.sort_unstable());
+
+    let mut stencils = Vec::new();
+    for (index, symbol) in symbols.iter().enumerate() {
+        let Some(symbol) = symbol.filter(|s| is_stencil_symbol(s, args, only)) else {
+            continue;
+        };
+        let section_name = section_name(coff, symbol.section);
+        let shdr = (symbol.section as usize).checked_sub(1).and_then(|i| coff.sections.get(i))
+            .ok_or_else(|| Category::Malformed.error(format!("{}: no section {}", symbol.name, symbol.section)))?;
+        if shdr.characteristics & section_table::IMAGE_SCN_CNT_UNINITIALIZED_DATA != 0 {
+            return Err(format!("{}: {} has no contents", symbol.name, section_name).into());
+        }
+        if shdr.characteristics & (section_table::IMAGE_SCN_CNT_CODE | section_table::IMAGE_SCN_MEM_EXECUTE) == 0 {
+            return Err(Category::Malformed.error(format!("{}: {} isn't an executable section", symbol.name, section_name)));
+        }
+        let text_data = section_data(coff, data, symbol.section)?;
+        let start = symbol.value as usize;
+        let end = starts[&symbol.section].iter().find(|&&next| next > symbol.value).map_or(text_data.len(), |&next| next as usize);
+        if start > text_data.len() || end > text_data.len() {
+            return Err(Category::Malformed.error(format!("{}: {:#x} is outside {} ({:#x} bytes)", symbol.name, start, section_name, text_data.len())));
+        }
+        let pool_end = arch.literal_pool_end(text_data, start, end);
+        stencils.push(Stencil {
+            name: symbol.name,
+            display: None,
+            index,
+            id: 0,
+            section: symbol.section as usize,
+            address: symbol.value as u64,
+            size: (pool_end - start) as u64,
+            code: Cow::Borrowed(&text_data[start..pool_end]),
+            relocs: Vec::new(),
+            holes: Vec::new(),
+            terminates: false,
+            stack_size: arch.stack_usage(&text_data[start..end]),
+            literal_pool: (pool_end - end) as u64,
+            fallthrough: false,
+            taken_hole: None,
+            imm32_variant: None,
+            cold: false,
+            alias_of: None,
+            signature: None,
+            lines: Vec::new(),
+            source_range: None,
+            doc: Vec::new(),
+            op: None,
+            types: Vec::new(),
+            unit: None,
+            arch,
+        });
+    }
+    stencils.sort_by_key(|s| (s.section, s.address));
+    read_relocs(coff, data, &symbols, &mut stencils)?;
+    Ok(stencils)
+}
+
+// The ELF name of a COFF relocation, its width, and what to add to the field to get the ELF
+// addend. REL32_n is relative to n bytes past the end of the field, where an immediate follows it.
+fn relocation_kind(typ: u16) -> Option<(&'static str, usize, i64)> {
+    match typ {
+        relocation::IMAGE_REL_AMD64_ADDR64 => Some(("X86_64_64", 8, 0)),
+        relocation::IMAGE_REL_AMD64_ADDR32 => Some(("X86_64_32", 4, 0)),
+        relocation::IMAGE_REL_AMD64_REL32..=relocation::IMAGE_REL_AMD64_REL32_5 => Some(("X86_64_PC32", 4, -4 - (typ - relocation::IMAGE_REL_AMD64_REL32) as i64)),
+        _ => None,
+    }
+}
+
+fn relocation_name(typ: u16) -> String {
+    let name = match typ {
+        relocation::IMAGE_REL_AMD64_ADDR32NB => "ADDR32NB",
+        relocation::IMAGE_REL_AMD64_SECTION => "SECTION",
+        relocation::IMAGE_REL_AMD64_SECREL => "SECREL",
+        relocation::IMAGE_REL_AMD64_SECREL7 => "SECREL7",
+        relocation::IMAGE_REL_AMD64_TOKEN => "TOKEN",
+        relocation::IMAGE_REL_AMD64_SREL32 => "SREL32",
+        relocation::IMAGE_REL_AMD64_PAIR => "PAIR",
+        relocation::IMAGE_REL_AMD64_SSPAN32 => "SSPAN32",
+        _ => return format!("relocation type {:#x}", typ),
+    };
+    format!("IMAGE_REL_AMD64_{}", name)
+}
+
+fn read_relocs<'a>(coff: &Coff<'a>, data: &'a [u8], symbols: &[Option<Symbol<'a>>], stencils: &mut [Stencil<'a>]) -> Result<(), Box<dyn Error>> {
+    let callees = stencils.iter().map(|s| ((s.section, s.address), (s.index, s.name))).collect::<HashMap<_, _>>();
+    for (section, shdr) in coff.sections.iter().enumerate().map(|(i, shdr)| (i + 1, shdr)) {
+        if !stencils.iter().any(|s| s.section == section) {
+            continue;
+        }
+        for reloc in shdr.relocations(data)? {
+            if reloc.typ == relocation::IMAGE_REL_AMD64_ABSOLUTE {
+                continue;
+            }
+            let offset = reloc.virtual_address as u64;
+            let index = reloc.symbol_table_index as usize;
+            let symbol = symbols.get(index).copied().flatten()
+                .ok_or_else(|| Category::Malformed.error(format!("relocation at {:#x} in {} against unknown symbol {}", offset, section_name(coff, section as i16), index)))?;
+            // Functions without a stencil in the same section are left alone, like their relocations.
+            let next = stencils.partition_point(|s| (s.section, s.address) <= (section, offset));
+            let start = stencils[..next].last().map_or(next, |last| stencils.partition_point(|s| (s.section, s.address) < (last.section, last.address)));
+            for stencil in stencils[start..next].iter_mut().filter(|s| s.section == section && offset < s.address + s.size) {
+                let at = offset - stencil.address;
+                let Some((relocation, width, bias)) = relocation_kind(reloc.typ) else {
+                    return Err(Category::Malformed.error(format!("{}: {} relocation at {:#x} can't be patched in a stencil", stencil.name, relocation_name(reloc.typ), at)));
+                };
+                if offset + width as u64 > stencil.address + stencil.size {
+                    let next = symbols.iter().flatten().find(|s| s.section as usize == section && s.function && s.value as u64 == stencil.address + stencil.size);
+                    return Err(Category::Malformed.error(format!("{}: {} relocation at {:#x} runs past its end into {}'s code", stencil.name, relocation, at, next.map_or("?", |s| s.name))));
+                }
+                // The addend is in the field, which is left zero as in ELF objects.
+                let field = &mut stencil.code.to_mut()[at as usize..at as usize + width];
+                let value = match width {
+                    8 => i64::from_le_bytes(field.try_into()?),
+                    _ => i32::from_le_bytes(field.try_into()?) as i64,
+                };
+                field.fill(0);
+                let mut addend = value + bias;
+                let mut target = symbol;
+                let mut target_index = index;
+                if symbol.is_section_relative() {
+                    // Where in the section the field points, from the end of it for pc-relative ones.
+                    let location = (symbol.value as u64).wrapping_add(value as u64);
+                    if symbol.section as usize == stencil.section && location >= stencil.address && location < stencil.address + stencil.size {
+                        if arch::is_absolute(relocation) {
+                            return Err(Category::Malformed.error(format!("{}: {} relocation at {:#x} takes the address of its own code at {:#x}, which isn't known until it's emitted",
+                                stencil.name, relocation, at, location - stencil.address)));
+                        }
+                        let internal = (symbol.value as u64).wrapping_add(addend as u64).wrapping_sub(stencil.address);
+                        fuse::bake_internal_branch(stencil.code.to_mut(), at, internal, relocation)
+                            .ok_or_else(|| Category::Malformed.error(format!("{}: can't resolve {} relocation at {:#x} to its own code", stencil.name, relocation, at)))?;
+                        continue;
+                    }
+                    // Otherwise it's a reference to whatever symbol is defined there.
+                    let defined = symbols.iter().enumerate()
+                        .filter_map(|(i, s)| Some((i, (*s)?)))
+                        .filter(|(_, s)| s.section == symbol.section && s.value as u64 == location && !s.is_section_relative())
+                        .max_by_key(|(_, s)| s.function);
+                    let Some((i, defined)) = defined else {
+                        return Err(format!("{}: references {}{:+}, which isn't copied with the stencil", stencil.name, section_name(coff, symbol.section), location as i64).into());
+                    };
+                    addend -= location as i64 - symbol.value as i64;
+                    (target, target_index) = (defined, i);
+                }
+                let hole = if let Some(&(index, name)) = callees.get(&(target.section as usize, target.value as u64)).filter(|_| target.function && target.is_defined()) {
+                    Hole {
+                        name,
+                        index,
+                        datatype: "uint32_t",
+                        value_datatype: "void*",
+                        internal: true,
+                        stencil_ref: true,
+                        payload: None,
+                        transform: None,
+                        version: None,
+                    }
+                } else if target.is_defined() && target.class != symbol::IMAGE_SYM_CLASS_EXTERNAL {
+                    return Err(format!("{}: references {} in {}, which isn't copied with the stencil", stencil.name, target.name, section_name(coff, target.section)).into());
+                } else {
+                    let (datatype, value_datatype) = hole_datatypes(target.name).unwrap_or(("void*", "void*"));
+                    Hole {
+                        name: target.name,
+                        index: target_index,
+                        datatype,
+                        value_datatype,
+                        internal: hole_datatypes(target.name).is_some(),
+                        stencil_ref: false,
+                        payload: None,
+                        transform: None,
+                        version: None,
+                    }
+                };
+                // dllimport references load the address out of the import table, which
+                // relax_got_loads deals with like ELF's GOT.
+                let relocation = match (target.import, relocation) {
+                    (false, _) => relocation,
+                    (true, "X86_64_PC32") => "X86_64_GOTPCREL",
+                    (true, _) => return Err(Category::Malformed.error(format!("{}: {} relocation at {:#x} takes the address of __imp_{}, stencils have no import table",
+                        stencil.name, relocation, at, target.name))),
+                };
+                stencil.relocs.push(Reloc {
+                    offset: at,
+                    addend,
+                    hole,
+                    arg: None,
+                    relocation,
+                    far_call: false,
+                    continuation: false,
+                });
+            }
+        }
+    }
+    for stencil in stencils.iter_mut() {
+        stencil.relocs.sort_by_key(|r| r.offset);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::undecorate;
+
+    #[test]
+    fn decorations() {
+        assert_eq!(undecorate("add"), ("add", false));
+        assert_eq!(undecorate("add@8"), ("add", false));
+        assert_eq!(undecorate("@add@8"), ("add", false));
+        assert_eq!(undecorate("add@@16"), ("add", false));
+        assert_eq!(undecorate("__imp_memcpy"), ("memcpy", true));
+        assert_eq!(undecorate("__imp_ext@4"), ("ext", true));
+    }
+
+    #[test]
+    fn not_decorations() {
+        assert_eq!(undecorate("?add@@YAXPEA_J@Z"), ("?add@@YAXPEA_J@Z", false));
+        assert_eq!(undecorate("memcpy@GLIBC_2.14"), ("memcpy@GLIBC_2.14", false));
+        assert_eq!(undecorate("add@"), ("add@", false));
+    }
+}
@@ -0,0 +1,166 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::json::Json;
+
+// What stencils should be compiled with. Unwind tables and stack protectors add sections and
+// calls that aren't part of the stencil, and debug info gives the header signatures and docs.
+// preserve_none is an attribute the stencil functions carry themselves.
+pub const STENCIL_CFLAGS: &[&str] = &[
+    "-O3",
+    "-g",
+    "-ffunction-sections",
+    "-fno-asynchronous-unwind-tables",
+    "-fno-unwind-tables",
+    "-fno-stack-protector",
+    "-fcf-protection=none",
+];
+
+// Runs `command` (a compiler and its arguments) in `dir` with `-c` and `-o object` on the end.
+pub fn compile(command: &[String], dir: Option<&Path>, object: &Path) -> Result<(), String> {
+    let (program, args) = command.split_first().ok_or("empty compile command")?;
+    let mut process = Command::new(program);
+    if let Some(dir) = dir {
+        process.current_dir(dir);
+    }
+    let status = process
+        .args(args)
+        .arg("-c")
+        .arg("-o")
+        .arg(object)
+        .status()
+        .map_err(|e| format!("{}: {}", program, e))?;
+    if !status.success() {
+        return Err(format!("{} failed with {}", program, status));
+    }
+    Ok(())
+}
+
+// Runs `command` (a compiler and the sources) with `-o program` on the end.
+pub fn link(command: &[&str], program: &Path) -> Result<(), String> {
+    let (name, args) = command.split_first().ok_or("empty link command")?;
+    let status = Command::new(name)
+        .args(args)
+        .arg("-o")
+        .arg(program)
+        .status()
+        .map_err(|e| format!("{}: {}", name, e))?;
+    if !status.success() {
+        return Err(format!("{} failed with {}", name, status));
+    }
+    Ok(())
+}
+
+// The command and directory compile_commands.json has for `source`, without the options that
+// name the output or write dependency files, so it can be pointed at a temporary object.
+pub fn database_command(db: &Json, source: &Path) -> Result<(Vec<String>, PathBuf), String> {
+    let Json::Array(entries) = db else {
+        return Err("expected an array of compile commands".to_string());
+    };
+    let wanted = fs::canonicalize(source).map_err(|e| format!("{}: {}", source.display(), e))?;
+    let string = |entry: &Json, name: &str| match entry.field(name) {
+        Some(Json::String(s)) => Some(s.clone()),
+        _ => None,
+    };
+    for entry in entries {
+        let (Some(dir), Some(file)) = (string(entry, "directory"), string(entry, "file")) else {
+            continue;
+        };
+        let dir = PathBuf::from(dir);
+        if fs::canonicalize(dir.join(&file)).ok().as_ref() != Some(&wanted) {
+            continue;
+        }
+        let command = match (entry.field("arguments"), string(entry, "command")) {
+            (Some(Json::Array(arguments)), _) => arguments.iter().map(|arg| match arg {
+                Json::String(arg) => Ok(arg.clone()),
+                _ => Err("arguments must be strings".to_string()),
+            }).collect::<Result<Vec<_>, _>>()?,
+            (_, Some(command)) => split_command(&command)?,
+            _ => return Err(format!("the entry for {} has no command", file)),
+        };
+        return Ok((without_output_options(command), dir));
+    }
+    Err("not in the compile commands".to_string())
+}
+
+fn without_output_options(command: Vec<String>) -> Vec<String> {
+    let mut kept = Vec::with_capacity(command.len());
+    let mut args = command.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-c" | "-M" | "-MM" | "-MD" | "-MMD" | "-MP" => {}
+            "-o" | "-MF" | "-MT" | "-MQ" => {
+                args.next();
+            }
+            _ if ["-o", "-MF", "-MT", "-MQ"].iter().any(|option| arg.starts_with(option)) => {}
+            _ => kept.push(arg),
+        }
+    }
+    kept
+}
+
+// Splits a command line the way a POSIX shell would, for the quoting compile_commands.json uses.
+pub fn split_command(command: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word = None::<String>;
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' | '\t' | '\n' => words.extend(word.take()),
+            '\'' => {
+                let word = word.get_or_insert_default();
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => return Err("unterminated ' in command".to_string()),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_default();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => word.extend(chars.next()),
+                        Some(c) => word.push(c),
+                        None => return Err("unterminated \" in command".to_string()),
+                    }
+                }
+            }
+            '\\' => word.get_or_insert_default().extend(chars.next()),
+            c => word.get_or_insert_default().push(c),
+        }
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+// A directory for compiled objects, removed with everything in it when dropped.
+pub struct TempDir {
+    pub path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new() -> Result<TempDir, String> {
+        // `build` and --format staticlib can both need one in the same run.
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!("stenciltool-{}-{}", std::process::id(), COUNT.fetch_add(1, Ordering::Relaxed)));
+        fs::create_dir_all(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Ok(TempDir { path })
+    }
+
+    // A path for the object compiled from the `index`th source, named after it for errors.
+    pub fn object(&self, index: usize, source: &str) -> PathBuf {
+        let stem = Path::new(source).file_stem().map_or("stencils".into(), |stem| stem.to_string_lossy());
+        self.path.join(format!("{}-{}.o", index, stem))
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
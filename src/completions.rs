@@ -0,0 +1,123 @@
+use clap::{Command, ValueEnum};
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+// What completing an option needs to know, taken from clap's model of a command so the scripts
+// follow the options as they are added.
+struct Flag {
+    long: Option<String>,
+    short: Option<char>,
+    help: String,
+    // None for switches and options whose value is optional, Some(empty) for paths.
+    values: Option<Vec<String>>,
+}
+
+fn flags(command: &mut Command) -> Vec<Flag> {
+    command.build();
+    command.get_arguments().filter(|arg| !arg.is_positional() && !arg.is_hide_set()).map(|arg| {
+        let takes_value = arg.get_num_args().is_some_and(|range| range.min_values() > 0);
+        let values = arg.get_possible_values().iter().filter(|value| !value.is_hide_set()).map(|value| value.get_name().to_string()).collect();
+        Flag {
+            long: arg.get_long().map(str::to_string),
+            short: arg.get_short(),
+            help: arg.get_help().map(|help| help.to_string().lines().next().unwrap_or("").to_string()).unwrap_or_default(),
+            values: takes_value.then_some(values),
+        }
+    }).collect()
+}
+
+// `commands` are the subcommands by name, with the one taking objects under "".
+pub fn generate(shell: Shell, commands: Vec<(&str, Command)>) -> String {
+    let commands = commands.into_iter().map(|(name, mut command)| (name, flags(&mut command))).collect::<Vec<_>>();
+    let subcommands = commands.iter().map(|(name, _)| *name).filter(|name| !name.is_empty()).collect::<Vec<_>>().join(" ");
+    match shell {
+        Shell::Bash => bash(&commands, &subcommands),
+        Shell::Zsh => zsh(&commands, &subcommands),
+        Shell::Fish => fish(&commands, &subcommands),
+    }
+}
+
+fn bash(commands: &[(&str, Vec<Flag>)], subcommands: &str) -> String {
+    let mut out = String::from("_stenciltool() {\n");
+    out.push_str("    local cur=\"${COMP_WORDS[COMP_CWORD]}\" prev=\"${COMP_WORDS[COMP_CWORD-1]}\" command=\"\" opts=\"\"\n");
+    out.push_str(&format!("    case \"${{COMP_WORDS[1]}}\" in\n        {}) [[ $COMP_CWORD -gt 1 ]] && command=\"${{COMP_WORDS[1]}}\" ;;\n    esac\n", subcommands.replace(' ', "|")));
+    out.push_str("    case \"$command\" in\n");
+    for (name, flags) in commands {
+        out.push_str(&format!("        \"{}\")\n", name));
+        let names = flags.iter().flat_map(|flag| flag.long.iter().map(|long| format!("--{}", long)).chain(flag.short.map(|short| format!("-{}", short)))).collect::<Vec<_>>();
+        out.push_str(&format!("            opts=\"{}\"\n", names.join(" ")));
+        out.push_str("            case \"$prev\" in\n");
+        for flag in flags {
+            if let (Some(long), Some(values)) = (&flag.long, &flag.values) && !values.is_empty() {
+                out.push_str(&format!("                --{}) COMPREPLY=($(compgen -W \"{}\" -- \"$cur\")); return ;;\n", long, values.join(" ")));
+            }
+        }
+        out.push_str("            esac\n            ;;\n");
+    }
+    out.push_str("    esac\n");
+    out.push_str("    if [[ $cur == -* ]]; then\n        COMPREPLY=($(compgen -W \"$opts\" -- \"$cur\"))\n    else\n        COMPREPLY=($(compgen -f -- \"$cur\"))\n");
+    out.push_str(&format!("        [[ $COMP_CWORD -eq 1 ]] && COMPREPLY+=($(compgen -W \"{}\" -- \"$cur\"))\n    fi\n}}\n", subcommands));
+    out.push_str("complete -o filenames -F _stenciltool stenciltool\n");
+    out
+}
+
+fn zsh(commands: &[(&str, Vec<Flag>)], subcommands: &str) -> String {
+    let escape = |help: &str| help.replace('\\', "\\\\").replace('[', "\\[").replace(']', "\\]").replace(':', "\\:").replace('\'', "'\\''");
+    let mut out = format!("#compdef stenciltool\n\n_stenciltool() {{\n    local -a subcommands=({})\n", subcommands);
+    out.push_str("    local command=\"\"\n    (( CURRENT > 2 && ${subcommands[(Ie)$words[2]]} )) && command=$words[2]\n");
+    out.push_str("    if [[ -n $command ]]; then\n        shift words\n        (( CURRENT-- ))\n    elif (( CURRENT == 2 )) && [[ $words[2] != -* ]]; then\n        compadd -a subcommands\n    fi\n");
+    out.push_str("    case $command in\n");
+    for (name, flags) in commands {
+        out.push_str(&format!("        \"{}\")\n            _arguments -s \\\n", name));
+        for flag in flags {
+            let action = match &flag.values {
+                None => String::new(),
+                Some(values) if values.is_empty() => ":value:_files".to_string(),
+                Some(values) => format!(":value:({})", values.join(" ")),
+            };
+            let names = flag.long.iter().map(|long| format!("--{}", long)).chain(flag.short.map(|short| format!("-{}", short)));
+            for name in names {
+                out.push_str(&format!("                '{}[{}]{}' \\\n", name, escape(&flag.help), action));
+            }
+        }
+        out.push_str("                '*:file:_files'\n            ;;\n");
+    }
+    out.push_str("    esac\n}\n\n_stenciltool \"$@\"\n");
+    out
+}
+
+fn fish(commands: &[(&str, Vec<Flag>)], subcommands: &str) -> String {
+    let escape = |help: &str| help.replace('\\', "\\\\").replace('\'', "\\'");
+    let mut out = format!("complete -c stenciltool -n '__fish_use_subcommand' -a '{}'\n", subcommands);
+    for (name, flags) in commands {
+        let condition = match *name {
+            "" => format!("not __fish_seen_subcommand_from {}", subcommands),
+            name => format!("__fish_seen_subcommand_from {}", name),
+        };
+        for flag in flags {
+            let mut line = format!("complete -c stenciltool -n '{}'", condition);
+            if let Some(long) = &flag.long {
+                line.push_str(&format!(" -l {}", long));
+            }
+            if let Some(short) = flag.short {
+                line.push_str(&format!(" -s {}", short));
+            }
+            match &flag.values {
+                None => {}
+                Some(values) if values.is_empty() => line.push_str(" -r -F"),
+                Some(values) => line.push_str(&format!(" -x -a '{}'", values.join(" "))),
+            }
+            if !flag.help.is_empty() {
+                line.push_str(&format!(" -d '{}'", escape(&flag.help)));
+            }
+            out.push_str(&line);
+            out.push('\n');
+        }
+    }
+    out
+}
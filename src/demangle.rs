@@ -0,0 +1,764 @@
+// Demangling for stencils written in languages other than C. The display form follows c++filt
+// and rustc-demangle (without hashes), the identifier is the display form of the name (without
+// parameters) squashed into a C identifier, so overloads on parameter types collide and have to
+// be told apart by the caller.
+
+pub struct Demangled {
+    pub display: String,
+    pub ident: String,
+}
+
+pub fn demangle(symbol: &str) -> Option<Demangled> {
+    if let Some(name) = rust_legacy(symbol) {
+        return Some(Demangled { ident: c_identifier(&name), display: name });
+    }
+    if let Some(mangled) = symbol.strip_prefix("_R") {
+        let mut parser = RustV0 { s: mangled.as_bytes(), pos: 0, depth: 0 };
+        // Encoding version
+        while parser.peek()?.is_ascii_digit() {
+            parser.pos += 1;
+        }
+        let name = parser.path(false)?;
+        return Some(Demangled { ident: c_identifier(&name), display: name });
+    }
+    let mangled = symbol.strip_prefix("_Z")?;
+    let mut parser = Itanium { s: mangled.as_bytes(), pos: 0, subs: Vec::new(), template_args: Vec::new(), qualifiers: Vec::new(), structor: false, depth: 0, named: false };
+    let (name, display) = parser.encoding()?;
+    Some(Demangled { ident: c_identifier(&name), display })
+}
+
+// Collapses everything that can't appear in a C identifier into single underscores.
+fn c_identifier(name: &str) -> String {
+    let mut ident = String::with_capacity(name.len());
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() {
+            ident.push(c);
+        } else if !ident.is_empty() && !ident.ends_with('_') {
+            ident.push('_');
+        }
+    }
+    while ident.ends_with('_') {
+        ident.pop();
+    }
+    if ident.starts_with(|c: char| c.is_ascii_digit()) {
+        ident.insert(0, '_');
+    }
+    ident
+}
+
+// Appends template arguments, keeping them apart from operator< and operator<<.
+fn spaced(name: String, args: &str) -> String {
+    if name.ends_with('<') { format!("{} {}", name, args) } else { name + args }
+}
+
+// The subset of the Itanium C++ ABI mangling that shows up in free functions: nested and
+// templated names, the builtin and standard types, pointers, references, cv-qualifiers, arrays
+// and substitutions. Anything else fails the whole symbol.
+struct Itanium<'s> {
+    s: &'s [u8],
+    pos: usize,
+    // Substitution candidates in order of appearance, as their display form.
+    subs: Vec<String>,
+    // The function's template arguments, which T_ parameters refer to.
+    template_args: Vec<String>,
+    // cv and ref qualifiers of the function itself.
+    qualifiers: Vec<&'static str>,
+    // The last unqualified name outside template arguments was a constructor or destructor,
+    // which have no return type.
+    structor: bool,
+    depth: usize,
+    // Past the function name, so template arguments belong to parameter types.
+    named: bool,
+}
+
+const BUILTINS: &[(u8, &str)] = &[
+    (b'v', "void"), (b'w', "wchar_t"), (b'b', "bool"), (b'c', "char"), (b'a', "signed char"),
+    (b'h', "unsigned char"), (b's', "short"), (b't', "unsigned short"), (b'i', "int"),
+    (b'j', "unsigned int"), (b'l', "long"), (b'm', "unsigned long"), (b'x', "long long"),
+    (b'y', "unsigned long long"), (b'n', "__int128"), (b'o', "unsigned __int128"), (b'f', "float"),
+    (b'd', "double"), (b'e', "long double"), (b'g', "__float128"), (b'z', "..."),
+];
+
+const OPERATORS: &[(&str, &str)] = &[
+    ("nw", "new"), ("na", "new[]"), ("dl", "delete"), ("da", "delete[]"), ("ps", "+"), ("ng", "-"),
+    ("ad", "&"), ("de", "*"), ("co", "~"), ("pl", "+"), ("mi", "-"), ("ml", "*"), ("dv", "/"),
+    ("rm", "%"), ("an", "&"), ("or", "|"), ("eo", "^"), ("aS", "="), ("pL", "+="), ("mI", "-="),
+    ("mL", "*="), ("dV", "/="), ("rM", "%="), ("aN", "&="), ("oR", "|="), ("eO", "^="), ("ls", "<<"),
+    ("rs", ">>"), ("lS", "<<="), ("rS", ">>="), ("eq", "=="), ("ne", "!="), ("lt", "<"), ("gt", ">"),
+    ("le", "<="), ("ge", ">="), ("ss", "<=>"), ("nt", "!"), ("aa", "&&"), ("oo", "||"), ("pp", "++"),
+    ("mm", "--"), ("cm", ","), ("pm", "->*"), ("pt", "->"), ("cl", "()"), ("ix", "[]"),
+];
+
+impl Itanium<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.s.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, c: u8) -> bool {
+        let found = self.peek() == Some(c);
+        self.pos += found as usize;
+        found
+    }
+
+    fn number(&mut self) -> Option<usize> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        std::str::from_utf8(&self.s[start..self.pos]).ok()?.parse().ok()
+    }
+
+    // Returns the name (for the identifier) and the full display form.
+    fn encoding(&mut self) -> Option<(String, String)> {
+        let (name, templated) = self.name()?;
+        self.named = true;
+        if self.pos == self.s.len() {
+            return Some((name.clone(), name));
+        }
+        // Template functions mangle their return type, others don't.
+        let ret = if templated && !self.structor { Some(self.type_()?) } else { None };
+        let mut params = Vec::new();
+        while self.pos < self.s.len() {
+            // Clones of the function made by the compiler, e.g. `.constprop.0`.
+            if self.peek() == Some(b'.') {
+                break;
+            }
+            params.push(self.type_()?);
+        }
+        let params = if params == ["void"] { String::new() } else { params.join(", ") };
+        let qualifiers = self.qualifiers.concat();
+        let display = match ret {
+            Some(ret) => format!("{} {}({}){}", ret, name, params, qualifiers),
+            None => format!("{}({}){}", name, params, qualifiers),
+        };
+        let clone = std::str::from_utf8(&self.s[self.pos..]).ok()?;
+        if clone.is_empty() {
+            Some((name, display))
+        } else {
+            Some((name, format!("{} [clone {}]", display, clone)))
+        }
+    }
+
+    // Returns the name and whether it ends in template arguments.
+    fn name(&mut self) -> Option<(String, bool)> {
+        match self.peek()? {
+            b'N' => {
+                self.pos += 1;
+                self.nested_name()
+            }
+            b'Z' => None,
+            b'S' if self.s.get(self.pos + 1) == Some(&b't') => {
+                self.pos += 2;
+                let name = format!("std::{}", self.unqualified_name(None)?);
+                self.template_suffix(name)
+            }
+            b'S' => {
+                let name = self.substitution()?;
+                if self.peek() != Some(b'I') {
+                    return None;
+                }
+                self.template_suffix(name)
+            }
+            _ => {
+                let name = self.unqualified_name(None)?;
+                self.template_suffix(name)
+            }
+        }
+    }
+
+    fn template_suffix(&mut self, name: String) -> Option<(String, bool)> {
+        if self.peek() != Some(b'I') {
+            return Some((name, false));
+        }
+        self.subs.push(name.clone());
+        let args = self.template_args()?;
+        Some((spaced(name, &args), true))
+    }
+
+    fn nested_name(&mut self) -> Option<(String, bool)> {
+        // Qualifiers of member functions, shown after the parameters.
+        while let Some(qualifier) = match self.peek()? {
+            b'r' => Some(" restrict"),
+            b'V' => Some(" volatile"),
+            b'K' => Some(" const"),
+            b'R' => Some(" &"),
+            b'O' => Some(" &&"),
+            _ => None,
+        } {
+            self.qualifiers.push(qualifier);
+            self.pos += 1;
+        }
+        let mut prefix = String::new();
+        let mut last = String::new();
+        let mut templated = false;
+        while !self.eat(b'E') {
+            templated = false;
+            match self.peek()? {
+                b'S' if self.s.get(self.pos + 1) == Some(&b't') => {
+                    self.pos += 2;
+                    prefix = "std".to_owned();
+                    continue;
+                }
+                b'S' => {
+                    prefix = self.substitution()?;
+                    let base = prefix.split('<').next().unwrap_or_default();
+                    last = base.rsplit("::").next().unwrap_or_default().to_owned();
+                    // Already a candidate.
+                    continue;
+                }
+                b'I' => {
+                    let args = self.template_args()?;
+                    prefix = spaced(prefix, &args);
+                    templated = true;
+                }
+                _ => {
+                    let component = self.unqualified_name(Some(&last))?;
+                    last = component.trim_start_matches('~').split('[').next().unwrap_or_default().to_owned();
+                    prefix = if prefix.is_empty() { component } else { format!("{}::{}", prefix, component) };
+                }
+            }
+            if self.peek() != Some(b'E') {
+                self.subs.push(prefix.clone());
+            }
+        }
+        Some((prefix, templated))
+    }
+
+    // `enclosing` is the class name constructors and destructors are named after.
+    fn unqualified_name(&mut self, enclosing: Option<&str>) -> Option<String> {
+        // Internal linkage.
+        self.eat(b'L');
+        let c = self.peek()?;
+        let outermost = self.depth == 0;
+        if outermost {
+            self.structor = false;
+        }
+        let mut name = if c.is_ascii_digit() {
+            let len = self.number()?;
+            let name = std::str::from_utf8(self.s.get(self.pos..self.pos + len)?).ok()?;
+            self.pos += len;
+            // GCC and clang both name anonymous namespaces `_GLOBAL__N_1`.
+            if name.starts_with("_GLOBAL__N") { "(anonymous namespace)".to_owned() } else { name.to_owned() }
+        } else if matches!(c, b'C' | b'D') && self.s.get(self.pos + 1).is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 2;
+            self.structor = outermost;
+            let class = enclosing?;
+            if c == b'C' { class.to_owned() } else { format!("~{}", class) }
+        } else {
+            let code = std::str::from_utf8(self.s.get(self.pos..self.pos + 2)?).ok()?;
+            let &(_, op) = OPERATORS.iter().find(|(mangled, _)| *mangled == code)?;
+            self.pos += 2;
+            let space = if op.starts_with(|c: char| c.is_ascii_alphabetic()) { " " } else { "" };
+            format!("operator{}{}", space, op)
+        };
+        // ABI tags, e.g. [abi:cxx11]
+        while self.eat(b'B') {
+            let len = self.number()?;
+            let tag = std::str::from_utf8(self.s.get(self.pos..self.pos + len)?).ok()?;
+            self.pos += len;
+            name = format!("{}[abi:{}]", name, tag);
+        }
+        Some(name)
+    }
+
+    fn substitution(&mut self) -> Option<String> {
+        self.pos += 1;
+        // c++filt spells out the typedefs.
+        let abbreviation = match self.peek()? {
+            b'a' => Some("std::allocator"),
+            b'b' => Some("std::basic_string"),
+            b's' => Some("std::basic_string<char, std::char_traits<char>, std::allocator<char> >"),
+            b'i' => Some("std::basic_istream<char, std::char_traits<char> >"),
+            b'o' => Some("std::basic_ostream<char, std::char_traits<char> >"),
+            b'd' => Some("std::basic_iostream<char, std::char_traits<char> >"),
+            _ => None,
+        };
+        if let Some(abbreviation) = abbreviation {
+            self.pos += 1;
+            return Some(abbreviation.to_owned());
+        }
+        let mut index = 0;
+        while !self.eat(b'_') {
+            let digit = (self.peek()? as char).to_digit(36)? as usize;
+            // Sequence ids are base 36 with upper case letters only.
+            if self.peek()?.is_ascii_lowercase() {
+                return None;
+            }
+            index = index * 36 + digit + 1;
+            self.pos += 1;
+        }
+        self.subs.get(index).cloned()
+    }
+
+    fn template_args(&mut self) -> Option<String> {
+        self.pos += 1;
+        self.depth += 1;
+        let mut args = Vec::new();
+        while !self.eat(b'E') {
+            if self.eat(b'L') {
+                let ty = self.type_()?;
+                let start = self.pos;
+                while self.peek()? != b'E' {
+                    self.pos += 1;
+                }
+                let value = std::str::from_utf8(&self.s[start..self.pos]).ok()?.replace('n', "-");
+                self.pos += 1;
+                args.push(match (ty.as_str(), value.as_str()) {
+                    ("bool", "0") => "false".to_owned(),
+                    ("bool", "1") => "true".to_owned(),
+                    ("int", _) => value,
+                    ("unsigned int", _) => value + "u",
+                    ("long", _) => value + "l",
+                    ("unsigned long", _) => value + "ul",
+                    ("long long", _) => value + "ll",
+                    ("unsigned long long", _) => value + "ull",
+                    _ => format!("({}){}", ty, value),
+                });
+            } else {
+                args.push(self.type_()?);
+            }
+        }
+        self.depth -= 1;
+        let mut list = format!("<{}", args.join(", "));
+        if list.ends_with('>') {
+            list.push(' ');
+        }
+        list.push('>');
+        if self.depth == 0 && !self.named {
+            self.template_args = args;
+        }
+        Some(list)
+    }
+
+    // Only as what a pointer or reference points to, which is written around the declarator, so
+    // returns the return type and parameters apart.
+    fn function_type(&mut self) -> Option<(String, String)> {
+        self.pos += 1;
+        // extern "C"
+        self.eat(b'Y');
+        let ret = self.type_()?;
+        let mut params = Vec::new();
+        while !self.eat(b'E') {
+            params.push(self.type_()?);
+        }
+        let params = if params == ["void"] { String::new() } else { params.join(", ") };
+        self.subs.push(format!("{} ({})", ret, params));
+        Some((ret, params))
+    }
+
+    fn type_(&mut self) -> Option<String> {
+        let c = self.peek()?;
+        if let Some(&(_, builtin)) = BUILTINS.iter().find(|(code, _)| *code == c) {
+            self.pos += 1;
+            return Some(builtin.to_owned());
+        }
+        let ty = match c {
+            b'P' | b'R' | b'O' => {
+                self.pos += 1;
+                let declarator = match c { b'P' => "*", b'R' => "&", _ => "&&" };
+                if self.peek() == Some(b'F') {
+                    let (ret, params) = self.function_type()?;
+                    format!("{} ({})({})", ret, declarator, params)
+                } else {
+                    let inner = self.type_()?;
+                    match split_array(&inner) {
+                        // Already a pointer or reference to an array, `int (*) [10]`.
+                        Some((element, dims)) if element.ends_with(')') => {
+                            format!("{}{}) {}", &element[..element.len() - 1], declarator, dims)
+                        }
+                        Some((element, dims)) => format!("{} ({}) {}", element, declarator, dims),
+                        None => inner + declarator,
+                    }
+                }
+            }
+            b'K' | b'V' | b'r' => {
+                self.pos += 1;
+                let inner = self.type_()?;
+                let qualifier = match c { b'K' => "const", b'V' => "volatile", _ => "restrict" };
+                format!("{} {}", inner, qualifier)
+            }
+            b'A' => {
+                self.pos += 1;
+                let len = self.number()?;
+                if !self.eat(b'_') {
+                    return None;
+                }
+                let element = self.type_()?;
+                match split_array(&element) {
+                    Some((element, dims)) => format!("{} [{}]{}", element, len, dims),
+                    None => format!("{} [{}]", element, len),
+                }
+            }
+            b'T' => {
+                self.pos += 1;
+                let mut index = 0;
+                while !self.eat(b'_') {
+                    index = index * 36 + (self.peek()? as char).to_digit(36)? as usize + 1;
+                    self.pos += 1;
+                }
+                self.template_args.get(index)?.clone()
+            }
+            b'D' => {
+                let ty = match self.s.get(self.pos + 1)? {
+                    b'n' => "decltype(nullptr)",
+                    b'i' => "char32_t",
+                    b's' => "char16_t",
+                    b'u' => "char8_t",
+                    _ => return None,
+                };
+                self.pos += 2;
+                // Builtins aren't substitution candidates.
+                return Some(ty.to_owned());
+            }
+            b'S' if self.s.get(self.pos + 1) != Some(&b't') => {
+                let name = self.substitution()?;
+                if self.peek() != Some(b'I') {
+                    // Already a candidate.
+                    return Some(name);
+                }
+                let args = self.template_args()?;
+                spaced(name, &args)
+            }
+            b'N' | b'S' | b'0'..=b'9' => self.name()?.0,
+            _ => return None,
+        };
+        self.subs.push(ty.clone());
+        Some(ty)
+    }
+}
+
+// Splits `int [2][3]` into `int` and `[2][3]`, so pointers and references to arrays and arrays of
+// arrays can be written the way C++ declares them.
+fn split_array(ty: &str) -> Option<(&str, &str)> {
+    let mut element = ty;
+    while let Some(rest) = element.strip_suffix(']') {
+        let open = rest.rfind('[')?;
+        if !rest[open + 1..].bytes().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        element = &rest[..open];
+    }
+    let dims = &ty[element.len()..];
+    let element = element.strip_suffix(' ')?;
+    (!dims.is_empty()).then_some((element, dims))
+}
+
+// rustc's legacy mangling is an Itanium nested name of escaped path components ending in a
+// hash component, `_ZN4core3ptr13drop_in_place17h0123456789abcdefE`.
+fn rust_legacy(symbol: &str) -> Option<String> {
+    let mut rest = symbol.strip_prefix("_ZN")?.strip_suffix('E')?;
+    let mut components = Vec::new();
+    while !rest.is_empty() {
+        let digits = rest.find(|c: char| !c.is_ascii_digit())?;
+        let len = rest[..digits].parse::<usize>().ok()?;
+        components.push(rest.get(digits..digits + len)?);
+        rest = &rest[digits + len..];
+    }
+    let hash = components.pop()?;
+    let is_hash = hash.len() == 17 && hash.starts_with('h') && hash[1..].bytes().all(|c| c.is_ascii_hexdigit());
+    if !is_hash || components.is_empty() {
+        return None;
+    }
+    components.iter().map(|c| unescape_legacy(c)).collect::<Option<Vec<_>>>().map(|c| c.join("::"))
+}
+
+fn unescape_legacy(mut component: &str) -> Option<String> {
+    let mut out = String::new();
+    // Components can't start with `$`, so the escape is prefixed with `_`.
+    if component.starts_with("_$") {
+        component = &component[1..];
+    }
+    while !component.is_empty() {
+        if let Some(rest) = component.strip_prefix("..") {
+            out.push_str("::");
+            component = rest;
+        } else if let Some(rest) = component.strip_prefix('$') {
+            let end = rest.find('$')?;
+            let escape = &rest[..end];
+            out.push(match escape {
+                "SP" => '@',
+                "BP" => '*',
+                "RF" => '&',
+                "LT" => '<',
+                "GT" => '>',
+                "LP" => '(',
+                "RP" => ')',
+                "C" => ',',
+                _ => char::from_u32(u32::from_str_radix(escape.strip_prefix('u')?, 16).ok()?)?,
+            });
+            component = &rest[end + 1..];
+        } else {
+            let end = component.find(['$', '.']).unwrap_or(component.len()).max(1);
+            out.push_str(&component[..end]);
+            component = &component[end..];
+        }
+    }
+    Some(out)
+}
+
+// The v0 mangling, `_RNvCs1234_7mycrate6op_add`. Only paths and the types and constants that
+// show up in their generic arguments are supported.
+struct RustV0<'s> {
+    s: &'s [u8],
+    pos: usize,
+    // Backrefs can only point backwards, but bound the recursion anyway.
+    depth: usize,
+}
+
+impl RustV0<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.s.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<u8> {
+        let c = self.peek()?;
+        self.pos += 1;
+        Some(c)
+    }
+
+    fn eat(&mut self, c: u8) -> bool {
+        let found = self.peek() == Some(c);
+        self.pos += found as usize;
+        found
+    }
+
+    fn base62(&mut self) -> Option<u64> {
+        if self.eat(b'_') {
+            return Some(0);
+        }
+        let mut value = 0u64;
+        loop {
+            let c = self.next()?;
+            let digit = match c {
+                b'0'..=b'9' => c - b'0',
+                b'a'..=b'z' => c - b'a' + 10,
+                b'A'..=b'Z' => c - b'A' + 36,
+                b'_' => return value.checked_add(1),
+                _ => return None,
+            };
+            value = value.checked_mul(62)?.checked_add(digit as u64)?;
+        }
+    }
+
+    fn disambiguator(&mut self) -> Option<u64> {
+        if self.eat(b's') { self.base62().map(|d| d + 1) } else { Some(0) }
+    }
+
+    fn identifier(&mut self) -> Option<(u64, String)> {
+        let disambiguator = self.disambiguator()?;
+        // Punycode
+        if self.peek()? == b'u' {
+            return None;
+        }
+        let start = self.pos;
+        while self.peek()?.is_ascii_digit() {
+            self.pos += 1;
+        }
+        let len = std::str::from_utf8(&self.s[start..self.pos]).ok()?.parse::<usize>().ok()?;
+        self.eat(b'_');
+        let name = std::str::from_utf8(self.s.get(self.pos..self.pos + len)?).ok()?;
+        self.pos += len;
+        Some((disambiguator, name.to_owned()))
+    }
+
+    fn backref<T>(&mut self, parse: impl FnOnce(&mut Self) -> Option<T>) -> Option<T> {
+        let target = usize::try_from(self.base62()?).ok()?;
+        if target >= self.pos || self.depth > 64 {
+            return None;
+        }
+        let resume = std::mem::replace(&mut self.pos, target);
+        self.depth += 1;
+        let result = parse(self);
+        self.depth -= 1;
+        self.pos = resume;
+        result
+    }
+
+    // Generic arguments are written `::<>` in value paths and `<>` in types.
+    fn path(&mut self, in_type: bool) -> Option<String> {
+        Some(match self.next()? {
+            b'C' => self.identifier()?.1,
+            b'N' => {
+                let namespace = self.next()?;
+                let parent = self.path(in_type)?;
+                let (disambiguator, name) = self.identifier()?;
+                match namespace {
+                    b'a'..=b'z' => format!("{}::{}", parent, name),
+                    b'C' if name.is_empty() => format!("{}::{{closure#{}}}", parent, disambiguator),
+                    b'C' => format!("{}::{{closure:{}#{}}}", parent, name, disambiguator),
+                    b'S' if name.is_empty() => format!("{}::{{shim#{}}}", parent, disambiguator),
+                    b'S' => format!("{}::{{shim:{}#{}}}", parent, name, disambiguator),
+                    _ => return None,
+                }
+            }
+            b'M' => {
+                self.disambiguator()?;
+                self.path(false)?;
+                format!("<{}>", self.type_()?)
+            }
+            b'X' => {
+                self.disambiguator()?;
+                self.path(false)?;
+                let ty = self.type_()?;
+                format!("<{} as {}>", ty, self.path(true)?)
+            }
+            b'Y' => {
+                let ty = self.type_()?;
+                format!("<{} as {}>", ty, self.path(true)?)
+            }
+            b'I' => {
+                let path = self.path(in_type)?;
+                let mut args = Vec::new();
+                while !self.eat(b'E') {
+                    args.push(self.generic_arg()?);
+                }
+                let separator = if in_type { "" } else { "::" };
+                format!("{}{}<{}>", path, separator, args.join(", "))
+            }
+            b'B' => self.backref(|parser| parser.path(in_type))?,
+            _ => return None,
+        })
+    }
+
+    fn generic_arg(&mut self) -> Option<String> {
+        if self.eat(b'L') {
+            self.base62()?;
+            return Some("'_".to_owned());
+        }
+        if self.eat(b'K') {
+            return self.const_();
+        }
+        self.type_()
+    }
+
+    fn basic_type(c: u8) -> Option<&'static str> {
+        Some(match c {
+            b'a' => "i8", b'b' => "bool", b'c' => "char", b'd' => "f64", b'e' => "str", b'f' => "f32",
+            b'h' => "u8", b'i' => "isize", b'j' => "usize", b'l' => "i32", b'm' => "u32", b'n' => "i128",
+            b'o' => "u128", b's' => "i16", b't' => "u16", b'u' => "()", b'v' => "...", b'x' => "i64",
+            b'y' => "u64", b'z' => "!", b'p' => "_",
+            _ => return None,
+        })
+    }
+
+    fn type_(&mut self) -> Option<String> {
+        let c = self.peek()?;
+        if let Some(basic) = Self::basic_type(c) {
+            self.pos += 1;
+            return Some(basic.to_owned());
+        }
+        self.pos += 1;
+        Some(match c {
+            b'R' | b'Q' => {
+                if self.eat(b'L') {
+                    self.base62()?;
+                }
+                let mutability = if c == b'Q' { "mut " } else { "" };
+                format!("&{}{}", mutability, self.type_()?)
+            }
+            b'P' => format!("*const {}", self.type_()?),
+            b'O' => format!("*mut {}", self.type_()?),
+            b'S' => format!("[{}]", self.type_()?),
+            b'A' => {
+                let ty = self.type_()?;
+                format!("[{}; {}]", ty, self.const_()?)
+            }
+            b'T' => {
+                let mut types = Vec::new();
+                while !self.eat(b'E') {
+                    types.push(self.type_()?);
+                }
+                if types.len() == 1 { format!("({},)", types[0]) } else { format!("({})", types.join(", ")) }
+            }
+            b'B' => self.backref(Self::type_)?,
+            _ => {
+                self.pos -= 1;
+                self.path(true)?
+            }
+        })
+    }
+
+    fn const_(&mut self) -> Option<String> {
+        match self.next()? {
+            b'p' => return Some("_".to_owned()),
+            b'B' => return self.backref(Self::const_),
+            _ => {}
+        }
+        let ty = self.s[self.pos - 1];
+        let negative = self.eat(b'n');
+        let start = self.pos;
+        while self.peek()? != b'_' {
+            self.pos += 1;
+        }
+        let digits = std::str::from_utf8(&self.s[start..self.pos]).ok()?;
+        self.pos += 1;
+        let value = if digits.is_empty() { 0 } else { u128::from_str_radix(digits, 16).ok()? };
+        Some(match ty {
+            b'b' => (value != 0).to_string(),
+            b'c' => format!("{:?}", char::from_u32(u32::try_from(value).ok()?)?),
+            _ if negative => format!("-{}", value),
+            _ => value.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::demangle;
+
+    fn display(symbol: &str) -> Option<String> {
+        demangle(symbol).map(|d| d.display)
+    }
+
+    fn ident(symbol: &str) -> Option<String> {
+        demangle(symbol).map(|d| d.ident)
+    }
+
+    #[test]
+    fn itanium() {
+        for (symbol, expected) in [
+            ("_Z1fv", "f()"),
+            ("_Z3addll", "add(long, long)"),
+            ("_ZN2ns3addEii", "ns::add(int, int)"),
+            ("_Z1fIiEvT_", "void f<int>(int)"),
+            ("_Z1fPKc", "f(char const*)"),
+            ("_Z1fRA10_i", "f(int (&) [10])"),
+            ("_Z1fPA2_A3_i", "f(int (*) [2][3])"),
+            ("_Z1fRPA10_i", "f(int (*&) [10])"),
+            ("_Z1fA4_i", "f(int [4])"),
+            ("_ZSt4swapIiEvRT_S1_", "void std::swap<int>(int&, int&)"),
+            ("_ZplRK1AS1_", "operator+(A const&, A const&)"),
+            ("_ZlsRSoPKc", "operator<<(std::basic_ostream<char, std::char_traits<char> >&, char const*)"),
+            ("_Z1fPFviE", "f(void (*)(int))"),
+            ("_ZN2ns1fIiEEvT_", "void ns::f<int>(int)"),
+            ("_Z2opILi3EEvv", "void op<3>()"),
+            ("_ZN1A1BIcE1fEv", "A::B<char>::f()"),
+            ("_Z1fPVKi", "f(int const volatile*)"),
+            ("_ZN12_GLOBAL__N_13addEv", "(anonymous namespace)::add()"),
+            ("_ZltI1AEbRKT_S3_", "bool operator< <A>(A const&, A const&)"),
+        ] {
+            assert_eq!(display(symbol).as_deref(), Some(expected), "{}", symbol);
+        }
+    }
+
+    #[test]
+    fn identifiers() {
+        assert_eq!(ident("_ZN2ns3addEii").as_deref(), Some("ns_add"));
+        assert_eq!(ident("_Z2opILi3EEvv").as_deref(), Some("op_3"));
+        assert_eq!(ident("_ZplRK1AS1_").as_deref(), Some("operator"));
+    }
+
+    #[test]
+    fn rust() {
+        assert_eq!(display("_ZN4core3ptr13drop_in_place17h0123456789abcdefE").as_deref(), Some("core::ptr::drop_in_place"));
+        assert_eq!(display("_RNvCs1234_7mycrate6op_add").as_deref(), Some("mycrate::op_add"));
+        assert_eq!(ident("_RNvCs1234_7mycrate6op_add").as_deref(), Some("mycrate_op_add"));
+    }
+
+    #[test]
+    fn not_mangled() {
+        for symbol in ["add", "_Z", "_ZN2ns3add", "_Z1", "_Z3addQ"] {
+            assert!(demangle(symbol).is_none(), "{}", symbol);
+        }
+    }
+}
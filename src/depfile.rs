@@ -0,0 +1,29 @@
+use std::path::Path;
+
+// A make style depfile saying that all of `targets` depend on all of `deps`, which both make
+// and ninja read. Ninja only handles several targets in one rule since 1.10.
+pub fn format(targets: &[&str], deps: &[&Path]) -> String {
+    let mut out = targets.iter().map(|target| escape(target)).collect::<Vec<_>>().join(" ");
+    out.push(':');
+    for dep in deps {
+        out.push_str(" \\\n  ");
+        out.push_str(&escape(&dep.to_string_lossy()));
+    }
+    out.push('\n');
+    out
+}
+
+fn escape(path: &str) -> String {
+    let mut escaped = String::with_capacity(path.len());
+    for c in path.chars() {
+        match c {
+            ' ' | '#' | '\\' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '$' => escaped.push_str("$$"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,204 @@
+use std::error::Error;
+use std::fmt::{self, Display, Write as _};
+use std::io::{self, Write};
+
+use clap::ValueEnum;
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// `warning: <file>: <message>` lines for people
+    Text,
+    /// One JSON object per line with `severity`, `file` (if any) and `message`, for tools
+    Json,
+}
+
+#[derive(Clone, Copy)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+impl Severity {
+    fn as_str(self) -> &'static str {
+        match self {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        }
+    }
+}
+
+// Writes one diagnostic to stderr. Objects are processed on several threads, so each one goes
+// out as a single write to keep lines whole.
+pub fn report(format: Format, severity: Severity, file: Option<&str>, message: &str) {
+    let _ = io::stderr().lock().write_all(line(format, severity, file, message).as_bytes());
+}
+
+// Reports `e`, with the file `in_file` put it in as its own field.
+pub fn report_error(format: Format, e: &(dyn Error + 'static)) {
+    let _ = io::stderr().lock().write_all(error_line(format, e).as_bytes());
+}
+
+fn error_line(format: Format, e: &(dyn Error + 'static)) -> String {
+    match e.downcast_ref::<Failure>() {
+        Some(Failure { file: Some(file), message, .. }) => line(format, Severity::Error, Some(file), message),
+        _ => line(format, Severity::Error, None, &e.to_string()),
+    }
+}
+
+fn line(format: Format, severity: Severity, file: Option<&str>, message: &str) -> String {
+    let mut line = String::new();
+    match format {
+        Format::Text => {
+            line.push_str(severity.as_str());
+            line.push_str(": ");
+            if let Some(file) = file {
+                line.push_str(file);
+                line.push_str(": ");
+            }
+            line.push_str(message);
+        }
+        Format::Json => {
+            let _ = write!(line, "{{\"severity\":\"{}\"", severity.as_str());
+            if let Some(file) = file {
+                line.push_str(",\"file\":");
+                json_string(&mut line, file);
+            }
+            line.push_str(",\"message\":");
+            json_string(&mut line, message);
+            line.push('}');
+        }
+    }
+    line.push('\n');
+    line
+}
+
+// What kind of failure an error is, which picks the exit code so build scripts can tell them apart.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Category {
+    Io,
+    Malformed,
+    Invalid,
+    Mismatch,
+    TestFailure,
+}
+
+pub const EXIT_CODES: &str = "\
+Exit codes:
+  0  Success
+  2  Bad command line
+  3  A file couldn't be read or written
+  4  An object or config file is malformed
+  5  The inputs can't be turned into stencils as asked
+  6  check-abi found breaking changes
+  7  verify's tests or emulation failed a stencil";
+
+impl Category {
+    pub fn exit_code(self) -> u8 {
+        match self {
+            Category::Io => 3,
+            Category::Malformed => 4,
+            Category::Invalid => 5,
+            Category::Mismatch => 6,
+            Category::TestFailure => 7,
+        }
+    }
+
+    pub fn error(self, message: impl Display) -> Box<dyn Error> {
+        Box::new(Failure { category: self, file: None, message: message.to_string() })
+    }
+
+    // Errors that weren't tagged are invalid input, unless they come straight from the file
+    // system or the object parser.
+    pub fn of(e: &(dyn Error + 'static)) -> Category {
+        if let Some(failure) = e.downcast_ref::<Failure>() {
+            failure.category
+        } else if e.is::<io::Error>() {
+            Category::Io
+        } else if e.is::<goblin::error::Error>() {
+            Category::Malformed
+        } else {
+            Category::Invalid
+        }
+    }
+}
+
+// An error with its category, which unlike a boxed error can be sent between threads.
+#[derive(Debug)]
+pub struct Failure {
+    category: Category,
+    // The file it's about, kept apart from the message for --diagnostics-format json.
+    file: Option<String>,
+    message: String,
+}
+
+impl Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}: ", file)?;
+        }
+        f.write_str(&self.message)
+    }
+}
+
+impl Error for Failure {}
+
+// Puts `e` in the file it's about, keeping its category. An error already in a file, such as an
+// archive member, keeps that file in its message.
+pub fn in_file(file: impl Display, e: impl Into<Box<dyn Error>>) -> Failure {
+    let e = e.into();
+    Failure { category: Category::of(&*e), file: Some(file.to_string()), message: e.to_string() }
+}
+
+pub fn json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => { let _ = write!(out, "\\u{:04x}", c as u32); }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn object_error(path: &str) -> Box<dyn Error> {
+        match std::fs::read(path) {
+            Ok(data) => goblin::Object::parse(&data).err().map(|e| in_file(path, e).into()),
+            Err(e) => Some(in_file(path, e).into()),
+        }.expect("the object should be rejected")
+    }
+
+    #[test]
+    fn unreadable_object() {
+        let e = object_error("/nonexistent/stencils.o");
+        assert_eq!(error_line(Format::Json, &*e), "{\"severity\":\"error\",\"file\":\"/nonexistent/stencils.o\",\"message\":\"No such file or directory (os error 2)\"}\n");
+        assert_eq!(error_line(Format::Text, &*e), "error: /nonexistent/stencils.o: No such file or directory (os error 2)\n");
+        assert_eq!(Category::of(&*e), Category::Io);
+    }
+
+    #[test]
+    fn malformed_object() {
+        let path = std::env::temp_dir().join(format!("stenciltool-malformed-{}.o", std::process::id()));
+        std::fs::write(&path, b"\x7fELF").unwrap();
+        let e = object_error(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        let line = error_line(Format::Json, &*e);
+        assert!(line.starts_with(&format!("{{\"severity\":\"error\",\"file\":\"{}\",\"message\":\"", path.display())), "{}", line);
+        assert_eq!(Category::of(&*e), Category::Malformed);
+    }
+
+    #[test]
+    fn errors_in_files_in_files() {
+        let e: Box<dyn Error> = in_file("lib.a", in_file("stencils.o", Category::Malformed.error("no symbol table"))).into();
+        assert_eq!(error_line(Format::Json, &*e), "{\"severity\":\"error\",\"file\":\"lib.a\",\"message\":\"stencils.o: no symbol table\"}\n");
+        assert_eq!(Category::of(&*e), Category::Malformed);
+    }
+}
@@ -0,0 +1,937 @@
+// Just enough of a DWARF 4/5 reader to recover what the compiler knew about the stencil
+// functions. Debug sections of relocatable objects still have their relocations pending, so
+// they are applied to private copies before anything is parsed.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use goblin::elf::{self, Elf};
+
+const DW_TAG_ARRAY_TYPE: u64 = 0x01;
+const DW_TAG_CLASS_TYPE: u64 = 0x02;
+const DW_TAG_ENUMERATION_TYPE: u64 = 0x04;
+const DW_TAG_FORMAL_PARAMETER: u64 = 0x05;
+const DW_TAG_POINTER_TYPE: u64 = 0x0f;
+const DW_TAG_REFERENCE_TYPE: u64 = 0x10;
+const DW_TAG_COMPILE_UNIT: u64 = 0x11;
+const DW_TAG_STRUCTURE_TYPE: u64 = 0x13;
+const DW_TAG_SUBROUTINE_TYPE: u64 = 0x15;
+const DW_TAG_TYPEDEF: u64 = 0x16;
+const DW_TAG_UNION_TYPE: u64 = 0x17;
+const DW_TAG_UNSPECIFIED_PARAMETERS: u64 = 0x18;
+const DW_TAG_BASE_TYPE: u64 = 0x24;
+const DW_TAG_CONST_TYPE: u64 = 0x26;
+const DW_TAG_SUBPROGRAM: u64 = 0x2e;
+const DW_TAG_VOLATILE_TYPE: u64 = 0x35;
+const DW_TAG_RESTRICT_TYPE: u64 = 0x37;
+const DW_TAG_RVALUE_REFERENCE_TYPE: u64 = 0x42;
+const DW_TAG_ATOMIC_TYPE: u64 = 0x47;
+const DW_TAG_SKELETON_UNIT: u64 = 0x4a;
+
+const DW_AT_NAME: u64 = 0x03;
+const DW_AT_STMT_LIST: u64 = 0x10;
+const DW_AT_LOW_PC: u64 = 0x11;
+const DW_AT_COMP_DIR: u64 = 0x1b;
+const DW_AT_ABSTRACT_ORIGIN: u64 = 0x31;
+const DW_AT_DECL_FILE: u64 = 0x3a;
+const DW_AT_DECL_LINE: u64 = 0x3b;
+const DW_AT_SPECIFICATION: u64 = 0x47;
+const DW_AT_TYPE: u64 = 0x49;
+const DW_AT_RANGES: u64 = 0x55;
+const DW_AT_LINKAGE_NAME: u64 = 0x6e;
+const DW_AT_STR_OFFSETS_BASE: u64 = 0x72;
+const DW_AT_DWO_NAME: u64 = 0x76;
+const DW_AT_MIPS_LINKAGE_NAME: u64 = 0x2007;
+const DW_AT_GNU_DWO_NAME: u64 = 0x2130;
+
+#[derive(serde::Serialize, Clone, Debug)]
+pub struct Param {
+    pub name: String,
+    pub datatype: String,
+}
+
+// The C prototype of a stencil function, which is how the previous stencil passes it the
+// runtime's state in registers.
+#[derive(serde::Serialize, Clone, Debug)]
+pub struct Signature {
+    pub returns: String,
+    pub params: Vec<Param>,
+    // The function type, `void (uint64_t*, struct vm*)`.
+    pub c_type: String,
+    // The parameter list with names, `uint64_t* sp, struct vm* vm`.
+    pub c_params: String,
+}
+
+// A row of the line table: the code at `address` onwards came from `file`:`line`.
+#[derive(Clone, Debug)]
+pub struct LineRow {
+    pub address: u64,
+    pub file: String,
+    pub line: u64,
+    // `file` is the unit's main source file rather than a header.
+    pub primary: bool,
+    // The first address after a sequence, which belongs to no line.
+    pub end_sequence: bool,
+    // The section `address` is in, for objects where every code section starts at 0.
+    pub section: Option<usize>,
+}
+
+// The debug sections, relocated.
+pub struct Sections<'d> {
+    info: Cow<'d, [u8]>,
+    abbrev: Cow<'d, [u8]>,
+    str: Cow<'d, [u8]>,
+    line_str: Cow<'d, [u8]>,
+    str_offsets: Cow<'d, [u8]>,
+    line: Cow<'d, [u8]>,
+    // Offsets in .debug_line that were relocated, with the section of the symbol each is against.
+    line_targets: Vec<(usize, usize)>,
+}
+
+impl<'d> Sections<'d> {
+    // None if the object has no .debug_info. Split DWARF objects (.dwo) name their sections
+    // with a `suffix` of ".dwo", and keep the line table in the skeleton object.
+    pub fn load(elf: &Elf<'d>, data: &'d [u8], suffix: &str) -> Result<Option<Sections<'d>>, String> {
+        let Some(info) = section(elf, data, &format!(".debug_info{}", suffix))? else {
+            return Ok(None);
+        };
+        let other = |name| section(elf, data, &format!("{}{}", name, suffix)).map(Option::unwrap_or_default);
+        Ok(Some(Sections {
+            info,
+            abbrev: other(".debug_abbrev")?,
+            str: other(".debug_str")?,
+            line_str: other(".debug_line_str")?,
+            str_offsets: other(".debug_str_offsets")?,
+            line: other(".debug_line")?,
+            line_targets: reloc_targets(elf, &format!(".debug_line{}", suffix)),
+        }))
+    }
+}
+
+// The contents of section `name` with its relocations applied.
+fn section<'d>(elf: &Elf<'d>, data: &'d [u8], name: &str) -> Result<Option<Cow<'d, [u8]>>, String> {
+    let Some((index, shdr)) = elf.section_headers.iter().enumerate()
+        .find(|(_, shdr)| elf.shdr_strtab.get_at(shdr.sh_name) == Some(name)) else {
+        return Ok(None);
+    };
+    if shdr.sh_flags & elf::section_header::SHF_COMPRESSED as u64 != 0 {
+        return Err(format!("{} is compressed, rebuild with -gz=none", name));
+    }
+    let bytes = shdr.sh_offset.checked_add(shdr.sh_size)
+        .and_then(|end| data.get(shdr.sh_offset as usize..end as usize))
+        .ok_or_else(|| format!("{} is out of bounds", name))?;
+    let mut relocs = elf.shdr_relocs.iter()
+        .filter(|(reloc_index, _)| elf.section_headers[*reloc_index].sh_info as usize == index)
+        .peekable();
+    if relocs.peek().is_none() {
+        return Ok(Some(Cow::Borrowed(bytes)));
+    }
+    let mut bytes = bytes.to_vec();
+    for (_, section) in relocs {
+        for reloc in section.iter() {
+            let symbol = elf.syms.get(reloc.r_sym).map_or(0, |s| s.st_value);
+            let relocation = elf::reloc::r_to_str(reloc.r_type, elf.header.e_machine);
+            apply_reloc(&mut bytes, reloc.r_offset as usize, relocation, symbol, reloc.r_addend)
+                .ok_or_else(|| format!("{}: can't apply {} at {:#x}", name, relocation, reloc.r_offset))?;
+        }
+    }
+    Ok(Some(Cow::Owned(bytes)))
+}
+
+fn reloc_targets(elf: &Elf, name: &str) -> Vec<(usize, usize)> {
+    let Some(index) = elf.section_headers.iter().position(|shdr| elf.shdr_strtab.get_at(shdr.sh_name) == Some(name)) else {
+        return Vec::new();
+    };
+    let mut targets = elf.shdr_relocs.iter()
+        .filter(|(reloc_index, _)| elf.section_headers[*reloc_index].sh_info as usize == index)
+        .flat_map(|(_, section)| section.iter())
+        .filter_map(|reloc| Some((reloc.r_offset as usize, elf.syms.get(reloc.r_sym)?.st_shndx)))
+        .collect::<Vec<_>>();
+    targets.sort();
+    targets
+}
+
+// Applies one of the absolute relocations debug sections use. REL relocations (r_addend None)
+// add to the value already there, as do RISC-V's ADD/SUB pairs for code lengths.
+fn apply_reloc(bytes: &mut [u8], offset: usize, relocation: &str, symbol: u64, addend: Option<i64>) -> Option<()> {
+    let (width, op) = match relocation {
+        "X86_64_64" | "AARCH64_ABS64" | "R_RISCV_64" => (8, '='),
+        "X86_64_32" | "X86_64_32S" | "AARCH64_ABS32" | "R_RISCV_32" | "ARM_ABS32" => (4, '='),
+        "R_RISCV_ADD8" => (1, '+'),
+        "R_RISCV_ADD16" => (2, '+'),
+        "R_RISCV_ADD32" => (4, '+'),
+        "R_RISCV_ADD64" => (8, '+'),
+        "R_RISCV_SUB8" => (1, '-'),
+        "R_RISCV_SUB16" => (2, '-'),
+        "R_RISCV_SUB32" => (4, '-'),
+        "R_RISCV_SUB64" => (8, '-'),
+        "R_RISCV_SET8" => (1, '='),
+        "R_RISCV_SET16" => (2, '='),
+        "R_RISCV_SET32" => (4, '='),
+        // Six bit fields in the low bits of a byte, used by DW_CFA_advance_loc.
+        "R_RISCV_SET6" | "R_RISCV_SUB6" => {
+            let byte = bytes.get_mut(offset)?;
+            let value = symbol.wrapping_add(addend.unwrap_or(0) as u64) as u8;
+            let low = if relocation == "R_RISCV_SET6" { value } else { (*byte & 0x3f).wrapping_sub(value) };
+            *byte = (*byte & 0xc0) | (low & 0x3f);
+            return Some(());
+        }
+        "R_RISCV_RELAX" | "R_RISCV_NONE" | "X86_64_NONE" | "AARCH64_NONE" | "ARM_NONE" => return Some(()),
+        _ => return None,
+    };
+    let field = bytes.get_mut(offset..offset + width)?;
+    let mut current = [0; 8];
+    current[..width].copy_from_slice(field);
+    let current = u64::from_le_bytes(current);
+    let value = symbol.wrapping_add(addend.unwrap_or(0) as u64);
+    let value = match (op, addend) {
+        ('=', Some(_)) => value,
+        ('=', None) | ('+', _) => current.wrapping_add(value),
+        _ => current.wrapping_sub(value),
+    };
+    field.copy_from_slice(&value.to_le_bytes()[..width]);
+    Some(())
+}
+
+struct Reader<'d> {
+    data: &'d [u8],
+    pos: usize,
+}
+
+const TRUNCATED: &str = "truncated debug info";
+
+impl<'d> Reader<'d> {
+    fn new(data: &'d [u8], pos: usize) -> Reader<'d> {
+        Reader { data, pos }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'d [u8], String> {
+        let bytes = self.data.get(self.pos..self.pos.checked_add(len).ok_or(TRUNCATED)?).ok_or(TRUNCATED)?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn uint(&mut self, len: usize) -> Result<u64, String> {
+        if len > 8 {
+            return Err(format!("unsupported {}-byte value", len));
+        }
+        let mut value = [0; 8];
+        value[..len].copy_from_slice(self.bytes(len)?);
+        Ok(u64::from_le_bytes(value))
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn uleb(&mut self) -> Result<u64, String> {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= ((byte & 0x7f) as u64) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn sleb(&mut self) -> Result<i64, String> {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= ((byte & 0x7f) as i64) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    value |= -1 << shift;
+                }
+                return Ok(value);
+            }
+        }
+    }
+
+    fn cstr(&mut self) -> Result<&'d str, String> {
+        let rest = self.data.get(self.pos..).ok_or(TRUNCATED)?;
+        let len = rest.iter().position(|&b| b == 0).ok_or(TRUNCATED)?;
+        self.pos += len + 1;
+        std::str::from_utf8(&rest[..len]).map_err(|_| "debug string isn't UTF-8".to_string())
+    }
+}
+
+fn str_at(section: &[u8], offset: u64) -> Result<&str, String> {
+    Reader::new(section, offset as usize).cstr()
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Value<'d> {
+    Udata(u64),
+    Str(&'d str),
+    // Offsets into .debug_str and .debug_line_str.
+    Strp(u64),
+    LineStrp(u64),
+    // Index into the unit's slice of .debug_str_offsets.
+    Strx(u64),
+    // Offset of another entry in .debug_info.
+    Ref(u64),
+    // Things this reader never looks into, like blocks and type unit signatures.
+    Other,
+}
+
+struct Abbrev {
+    tag: u64,
+    children: bool,
+    // (attribute, form, implicit_const value)
+    attrs: Vec<(u64, u64, i64)>,
+}
+
+fn parse_abbrevs(data: &[u8], offset: u64) -> Result<HashMap<u64, Abbrev>, String> {
+    let mut r = Reader::new(data, offset as usize);
+    let mut abbrevs = HashMap::new();
+    loop {
+        let code = r.uleb()?;
+        if code == 0 {
+            return Ok(abbrevs);
+        }
+        let tag = r.uleb()?;
+        let children = r.u8()? != 0;
+        let mut attrs = Vec::new();
+        loop {
+            let (at, form) = (r.uleb()?, r.uleb()?);
+            if at == 0 && form == 0 {
+                break;
+            }
+            // DW_FORM_implicit_const keeps its value in the abbreviation.
+            let value = if form == 0x21 { r.sleb()? } else { 0 };
+            attrs.push((at, form, value));
+        }
+        abbrevs.insert(code, Abbrev { tag, children, attrs });
+    }
+}
+
+struct Die<'d> {
+    tag: u64,
+    unit: usize,
+    attrs: Vec<(u64, Value<'d>)>,
+    children: Vec<usize>,
+}
+
+impl<'d> Die<'d> {
+    fn attr(&self, at: u64) -> Option<Value<'d>> {
+        self.attrs.iter().find(|(a, _)| *a == at).map(|(_, v)| *v)
+    }
+}
+
+struct Unit {
+    offset_size: usize,
+    str_offsets_base: u64,
+    // The unit's own entry in `dies`.
+    root: usize,
+}
+
+// All debugging information entries of an object, indexed by their .debug_info offset.
+pub struct Dwarf<'s, 'd> {
+    sections: &'s Sections<'d>,
+    units: Vec<Unit>,
+    dies: Vec<Die<'s>>,
+    offsets: HashMap<u64, usize>,
+}
+
+impl<'s, 'd> Dwarf<'s, 'd> {
+    pub fn parse(sections: &'s Sections<'d>) -> Result<Dwarf<'s, 'd>, String> {
+        let mut dwarf = Dwarf { sections, units: Vec::new(), dies: Vec::new(), offsets: HashMap::new() };
+        let info = &sections.info[..];
+        let mut pos = 0;
+        while pos < info.len() {
+            let mut r = Reader::new(info, pos);
+            let (length, offset_size) = match r.uint(4)? {
+                0xffff_ffff => (r.uint(8)?, 8),
+                length => (length, 4),
+            };
+            let end = r.pos.checked_add(length as usize).filter(|&end| end <= info.len()).ok_or(TRUNCATED)?;
+            let version = r.uint(2)?;
+            let (abbrev_offset, addr_size) = match version {
+                2..=4 => (r.uint(offset_size)?, r.u8()? as usize),
+                5 => {
+                    let unit_type = r.u8()?;
+                    let addr_size = r.u8()? as usize;
+                    let abbrev_offset = r.uint(offset_size)?;
+                    match unit_type {
+                        // Skeleton and split compile units carry a dwo id.
+                        4 | 5 => { r.uint(8)?; }
+                        // Type units carry a signature and a type offset.
+                        2 | 6 => { r.uint(8)?; r.uint(offset_size)?; }
+                        _ => {}
+                    }
+                    (abbrev_offset, addr_size)
+                }
+                _ => return Err(format!("unsupported DWARF version {}", version)),
+            };
+            let abbrevs = parse_abbrevs(&sections.abbrev, abbrev_offset)?;
+            let unit = dwarf.units.len();
+            // Split units have no DW_AT_str_offsets_base, their offsets start after the DWARF 5
+            // section header.
+            let str_offsets_base = if version >= 5 { 2 * offset_size as u64 } else { 0 };
+            dwarf.units.push(Unit { offset_size, str_offsets_base, root: dwarf.dies.len() });
+            dwarf.parse_unit(&mut r, end, pos as u64, version, addr_size, &abbrevs, unit)?;
+            pos = end;
+        }
+        for die in dwarf.dies.iter() {
+            if let Some(Value::Udata(base)) = die.attr(DW_AT_STR_OFFSETS_BASE) {
+                dwarf.units[die.unit].str_offsets_base = base;
+            }
+        }
+        Ok(dwarf)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn parse_unit(&mut self, r: &mut Reader<'s>, end: usize, unit_offset: u64, version: u64, addr_size: usize,
+                  abbrevs: &HashMap<u64, Abbrev>, unit: usize) -> Result<(), String> {
+        let offset_size = self.units[unit].offset_size;
+        // Entries whose children are still being read.
+        let mut parents: Vec<usize> = Vec::new();
+        while r.pos < end {
+            let offset = r.pos as u64;
+            let code = r.uleb()?;
+            if code == 0 {
+                parents.pop();
+                continue;
+            }
+            let abbrev = abbrevs.get(&code).ok_or_else(|| format!("unknown abbreviation {} at {:#x}", code, offset))?;
+            let mut attrs = Vec::with_capacity(abbrev.attrs.len());
+            for &(at, form, implicit) in &abbrev.attrs {
+                let value = read_value(r, form, implicit, unit_offset, version, addr_size, offset_size)?;
+                attrs.push((at, value));
+            }
+            let index = self.dies.len();
+            self.dies.push(Die { tag: abbrev.tag, unit, attrs, children: Vec::new() });
+            self.offsets.insert(offset, index);
+            if let Some(&parent) = parents.last() {
+                self.dies[parent].children.push(index);
+            }
+            if abbrev.children {
+                parents.push(index);
+            }
+        }
+        Ok(())
+    }
+
+    fn string(&self, die: &Die<'s>, at: u64) -> Option<&'s str> {
+        let sections = self.sections;
+        match die.attr(at)? {
+            Value::Str(s) => Some(s),
+            Value::Strp(offset) => str_at(&sections.str, offset).ok(),
+            Value::LineStrp(offset) => str_at(&sections.line_str, offset).ok(),
+            Value::Strx(index) => {
+                let unit = &self.units[die.unit];
+                let at = unit.str_offsets_base + index * unit.offset_size as u64;
+                let offset = Reader::new(&sections.str_offsets, at as usize).uint(unit.offset_size).ok()?;
+                str_at(&sections.str, offset).ok()
+            }
+            _ => None,
+        }
+    }
+
+    fn reference(&self, die: &Die<'s>, at: u64) -> Option<&Die<'s>> {
+        match die.attr(at)? {
+            Value::Ref(offset) => self.offsets.get(&offset).map(|&i| &self.dies[i]),
+            _ => None,
+        }
+    }
+
+    // An attribute of `die` or of the declaration it completes, which is where out of line and
+    // inlined copies of a function keep their names and types.
+    fn inherited<T>(&self, die: &Die<'s>, f: impl Fn(&Die<'s>) -> Option<T>) -> Option<T> {
+        let mut die = die;
+        for _ in 0..8 {
+            if let Some(value) = f(die) {
+                return Some(value);
+            }
+            die = self.reference(die, DW_AT_ABSTRACT_ORIGIN).or_else(|| self.reference(die, DW_AT_SPECIFICATION))?;
+        }
+        None
+    }
+
+    // The C spelling of the type `die` refers to with DW_AT_type, `void` if it has none.
+    fn type_name(&self, die: &Die<'s>, depth: usize) -> String {
+        let Some(ty) = self.inherited(die, |d| self.reference(d, DW_AT_TYPE)) else {
+            return "void".to_string();
+        };
+        if depth > 16 {
+            return "...".to_string();
+        }
+        let name = self.string(ty, DW_AT_NAME);
+        let inner = || self.type_name(ty, depth + 1);
+        let tagged = |kind: &str| format!("{} {}", kind, name.unwrap_or("<anonymous>"));
+        match ty.tag {
+            DW_TAG_BASE_TYPE | DW_TAG_TYPEDEF => name.unwrap_or("<anonymous>").to_string(),
+            DW_TAG_STRUCTURE_TYPE => tagged("struct"),
+            DW_TAG_UNION_TYPE => tagged("union"),
+            DW_TAG_ENUMERATION_TYPE => tagged("enum"),
+            DW_TAG_CLASS_TYPE => name.unwrap_or("<anonymous>").to_string(),
+            DW_TAG_POINTER_TYPE => match self.reference(ty, DW_AT_TYPE) {
+                Some(f) if f.tag == DW_TAG_SUBROUTINE_TYPE => self.signature(f, depth + 1).c_type.replacen(" (", " (*)(", 1),
+                _ => format!("{}*", inner()),
+            },
+            DW_TAG_REFERENCE_TYPE => format!("{}&", inner()),
+            DW_TAG_RVALUE_REFERENCE_TYPE => format!("{}&&", inner()),
+            DW_TAG_ARRAY_TYPE => format!("{}[]", inner()),
+            // Qualifiers go after pointers and before everything else, `const char* const`.
+            DW_TAG_CONST_TYPE | DW_TAG_VOLATILE_TYPE | DW_TAG_RESTRICT_TYPE | DW_TAG_ATOMIC_TYPE => {
+                let qualifier = match ty.tag {
+                    DW_TAG_CONST_TYPE => "const",
+                    DW_TAG_VOLATILE_TYPE => "volatile",
+                    DW_TAG_RESTRICT_TYPE => "restrict",
+                    _ => "_Atomic",
+                };
+                let inner = inner();
+                if inner.ends_with('*') {
+                    format!("{} {}", inner, qualifier)
+                } else {
+                    format!("{} {}", qualifier, inner)
+                }
+            }
+            DW_TAG_SUBROUTINE_TYPE => self.signature(ty, depth + 1).c_type,
+            _ => name.unwrap_or("void").to_string(),
+        }
+    }
+
+    fn signature(&self, die: &Die<'s>, depth: usize) -> Signature {
+        let mut params = Vec::new();
+        let mut variadic = false;
+        // A concrete out of line copy lists its parameters itself, pointing back at the abstract
+        // ones for their names and types.
+        for &child in &die.children {
+            let child = &self.dies[child];
+            match child.tag {
+                DW_TAG_FORMAL_PARAMETER => params.push(Param {
+                    name: self.inherited(child, |d| self.string(d, DW_AT_NAME))
+                        .map_or_else(|| format!("arg{}", params.len()), str::to_string),
+                    datatype: self.type_name(child, depth),
+                }),
+                DW_TAG_UNSPECIFIED_PARAMETERS => variadic = true,
+                _ => {}
+            }
+        }
+        let returns = self.type_name(die, depth);
+        let list = |names: bool| {
+            let mut list = params.iter()
+                .map(|p: &Param| if names { declaration(&p.datatype, &p.name) } else { p.datatype.clone() })
+                .collect::<Vec<_>>();
+            if variadic {
+                list.push("...".to_string());
+            }
+            if list.is_empty() {
+                list.push("void".to_string());
+            }
+            list.join(", ")
+        };
+        let c_type = format!("{} ({})", returns, list(false));
+        let c_params = list(true);
+        Signature { returns, params, c_type, c_params }
+    }
+
+    // The .dwo files holding the rest of the debug info of split DWARF units, resolved against
+    // their compilation directories.
+    pub fn split_units(&self) -> Vec<PathBuf> {
+        let mut paths = Vec::new();
+        for die in self.dies.iter().filter(|d| matches!(d.tag, DW_TAG_COMPILE_UNIT | DW_TAG_SKELETON_UNIT)) {
+            let Some(name) = self.string(die, DW_AT_DWO_NAME).or_else(|| self.string(die, DW_AT_GNU_DWO_NAME)) else {
+                continue;
+            };
+            let dir = self.string(die, DW_AT_COMP_DIR).unwrap_or("");
+            paths.push(Path::new(dir).join(name));
+        }
+        paths
+    }
+
+    // The concrete subprogram entries of the functions defined in this object, by symbol name.
+    fn functions(&self) -> impl Iterator<Item = (&'s str, &Die<'s>)> + '_ {
+        self.dies.iter().filter(|d| d.tag == DW_TAG_SUBPROGRAM).filter_map(|die| {
+            // Declarations and abstract instances are only reached from the concrete copy.
+            if die.attr(DW_AT_LOW_PC).is_none() && die.attr(DW_AT_RANGES).is_none() {
+                return None;
+            }
+            let symbol = self.inherited(die, |d| self.string(d, DW_AT_LINKAGE_NAME).or_else(|| self.string(d, DW_AT_MIPS_LINKAGE_NAME)))
+                .or_else(|| self.inherited(die, |d| self.string(d, DW_AT_NAME)))?;
+            Some((symbol, die))
+        })
+    }
+
+    // Signatures of the functions defined in this object, by symbol name.
+    pub fn signatures(&self) -> HashMap<&'s str, Signature> {
+        self.functions().map(|(symbol, die)| (symbol, self.signature(die, 0))).collect()
+    }
+
+    // The source file and line each function was declared at, by symbol name.
+    pub fn declarations(&self) -> HashMap<&'s str, (PathBuf, u64)> {
+        let mut declarations = HashMap::new();
+        for (symbol, die) in self.functions() {
+            let udata = |d: &Die<'s>, at| match d.attr(at) {
+                Some(Value::Udata(value)) => Some(value),
+                _ => None,
+            };
+            let (Some(file), Some(line)) = (self.inherited(die, |d| udata(d, DW_AT_DECL_FILE)), self.inherited(die, |d| udata(d, DW_AT_DECL_LINE))) else {
+                continue;
+            };
+            let root = &self.dies[self.units[die.unit].root];
+            let stmt_list = udata(root, DW_AT_STMT_LIST).unwrap_or(0);
+            let comp_dir = self.string(root, DW_AT_COMP_DIR).unwrap_or("");
+            if let Some(path) = self.sections.file_path(stmt_list, file, comp_dir) {
+                declarations.insert(symbol, (path, line));
+            }
+        }
+        declarations
+    }
+}
+
+// `datatype name`, with the name inside function pointer and array declarators.
+fn declaration(datatype: &str, name: &str) -> String {
+    if datatype.contains("(*)") {
+        datatype.replacen("(*)", &format!("(*{})", name), 1)
+    } else if let Some(element) = datatype.strip_suffix("[]") {
+        format!("{} {}[]", element, name)
+    } else {
+        format!("{} {}", datatype, name)
+    }
+}
+
+struct LineHeader<'s> {
+    version: u64,
+    addr_size: usize,
+    // Where the line number program starts.
+    program: usize,
+    min_inst_length: u64,
+    line_base: i64,
+    line_range: u64,
+    opcode_base: u8,
+    opcode_lengths: &'s [u8],
+    dirs: Vec<&'s str>,
+    // (path, directory index)
+    files: Vec<(&'s str, u64)>,
+}
+
+impl LineHeader<'_> {
+    fn file_name(&self, index: u64) -> String {
+        match self.files.get(index as usize) {
+            Some(&(path, dir)) if dir != 0 && !path.starts_with('/') =>
+                format!("{}/{}", self.dirs.get(dir as usize).copied().unwrap_or(""), path),
+            Some(&(path, _)) => path.to_string(),
+            None => format!("<file {}>", index),
+        }
+    }
+}
+
+impl Sections<'_> {
+    // The rows of every line program in .debug_line, in program order.
+    pub fn line_rows(&self) -> Result<Vec<LineRow>, String> {
+        let line = &self.line[..];
+        let mut rows = Vec::new();
+        let mut pos = 0;
+        while pos < line.len() {
+            let mut r = Reader::new(line, pos);
+            let (length, offset_size) = match r.uint(4)? {
+                0xffff_ffff => (r.uint(8)?, 8),
+                length => (length, 4),
+            };
+            let end = r.pos.checked_add(length as usize).filter(|&end| end <= line.len()).ok_or(TRUNCATED)?;
+            self.line_program(&mut r, offset_size, end, &mut rows)?;
+            pos = end;
+        }
+        Ok(rows)
+    }
+
+    fn line_program<'s>(&'s self, r: &mut Reader<'s>, offset_size: usize, end: usize, rows: &mut Vec<LineRow>) -> Result<(), String> {
+        let header = self.line_header(r, offset_size)?;
+        let LineHeader { version, addr_size, min_inst_length, line_base, line_range, opcode_base, opcode_lengths, .. } = header;
+        let primary = header.file_name(if version < 5 { 1 } else { 0 });
+        let file_name = |index| header.file_name(index);
+
+        r.pos = header.program;
+        let (mut address, mut file, mut line) = (0u64, 1u64, 1u64);
+        let mut section = None;
+        while r.pos < end {
+            let mut row = false;
+            let mut end_sequence = false;
+            match r.u8()? {
+                0 => {
+                    let len = r.uleb()? as usize;
+                    let next = r.pos + len;
+                    match r.u8()? {
+                        1 => {
+                            row = true;
+                            end_sequence = true;
+                        }
+                        2 => {
+                            section = self.line_targets.binary_search_by_key(&r.pos, |(offset, _)| *offset).ok()
+                                .map(|i| self.line_targets[i].1);
+                            address = r.uint(addr_size)?;
+                        }
+                        _ => {}
+                    }
+                    r.pos = next;
+                }
+                1 => row = true,
+                2 => address = address.wrapping_add(r.uleb()? * min_inst_length),
+                3 => line = line.wrapping_add(r.sleb()? as u64),
+                4 => file = r.uleb()?,
+                8 => address = address.wrapping_add((255 - opcode_base as u64) / line_range * min_inst_length),
+                9 => address = address.wrapping_add(r.uint(2)?),
+                op if op < opcode_base => {
+                    for _ in 0..opcode_lengths[op as usize - 1] {
+                        r.uleb()?;
+                    }
+                }
+                op => {
+                    let adjusted = (op - opcode_base) as u64;
+                    address = address.wrapping_add(adjusted / line_range * min_inst_length);
+                    line = line.wrapping_add((line_base + (adjusted % line_range) as i64) as u64);
+                    row = true;
+                }
+            }
+            if row {
+                let file = file_name(file);
+                rows.push(LineRow { address, primary: file == primary, file, line, end_sequence, section });
+            }
+            if end_sequence {
+                (address, file, line) = (0, 1, 1);
+                section = None;
+            }
+        }
+        Ok(())
+    }
+
+    fn line_header<'s>(&'s self, r: &mut Reader<'s>, offset_size: usize) -> Result<LineHeader<'s>, String> {
+        let version = r.uint(2)?;
+        if !(2..=5).contains(&version) {
+            return Err(format!("unsupported line table version {}", version));
+        }
+        let mut addr_size = 8;
+        if version == 5 {
+            addr_size = r.u8()? as usize;
+            r.u8()?;
+        }
+        let header_length = r.uint(offset_size)? as usize;
+        let program = r.pos + header_length;
+        let min_inst_length = r.u8()? as u64;
+        if version >= 4 {
+            r.u8()?;
+        }
+        // default_is_stmt, every row counts here.
+        r.u8()?;
+        let line_base = r.u8()? as i8 as i64;
+        let line_range = r.u8()? as u64;
+        let opcode_base = r.u8()?;
+        let opcode_lengths = r.bytes(opcode_base.saturating_sub(1) as usize)?;
+        if line_range == 0 {
+            return Err("line table has a zero line range".to_string());
+        }
+
+        // Before DWARF 5 the compilation directory and primary file are implicit, entry 0 in
+        // both tables.
+        let mut dirs = Vec::new();
+        let mut files = Vec::new();
+        if version < 5 {
+            dirs.push("");
+            files.push(("", 0));
+            loop {
+                let dir = r.cstr()?;
+                if dir.is_empty() {
+                    break;
+                }
+                dirs.push(dir);
+            }
+            loop {
+                let path = r.cstr()?;
+                if path.is_empty() {
+                    break;
+                }
+                let dir = r.uleb()?;
+                r.uleb()?;
+                r.uleb()?;
+                files.push((path, dir));
+            }
+        } else {
+            for (path, _) in self.entry_table(r, offset_size, addr_size)? {
+                dirs.push(path);
+            }
+            files = self.entry_table(r, offset_size, addr_size)?;
+        }
+        Ok(LineHeader { version, addr_size, program, min_inst_length, line_base, line_range, opcode_base, opcode_lengths, dirs, files })
+    }
+
+    // Path of file `index` of the line table at `offset`, relative paths are resolved against
+    // `comp_dir`.
+    fn file_path(&self, offset: u64, index: u64, comp_dir: &str) -> Option<PathBuf> {
+        let mut r = Reader::new(&self.line, offset as usize);
+        let offset_size = match r.uint(4).ok()? {
+            0xffff_ffff => { r.uint(8).ok()?; 8 }
+            _ => 4,
+        };
+        let header = self.line_header(&mut r, offset_size).ok()?;
+        let &(path, dir) = header.files.get(index as usize)?;
+        let dir = header.dirs.get(dir as usize).copied().unwrap_or("");
+        Some(Path::new(comp_dir).join(dir).join(path))
+    }
+
+    // A DWARF 5 directory or file name table, as (path, directory index) pairs.
+    fn entry_table<'s>(&'s self, r: &mut Reader<'s>, offset_size: usize, addr_size: usize) -> Result<Vec<(&'s str, u64)>, String> {
+        let format_count = r.u8()?;
+        let mut format = Vec::new();
+        for _ in 0..format_count {
+            format.push((r.uleb()?, r.uleb()?));
+        }
+        let count = r.uleb()?;
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            let (mut path, mut dir) = ("", 0);
+            for &(content, form) in &format {
+                let value = read_value(r, form, 0, 0, 5, addr_size, offset_size)?;
+                match (content, value) {
+                    // DW_LNCT_path
+                    (1, Value::Str(s)) => path = s,
+                    (1, Value::Strp(offset)) => path = str_at(&self.str, offset)?,
+                    (1, Value::LineStrp(offset)) => path = str_at(&self.line_str, offset)?,
+                    // DW_LNCT_directory_index
+                    (2, Value::Udata(index)) => dir = index,
+                    _ => {}
+                }
+            }
+            entries.push((path, dir));
+        }
+        Ok(entries)
+    }
+}
+
+fn read_value<'d>(r: &mut Reader<'d>, form: u64, implicit: i64, unit_offset: u64, version: u64, addr_size: usize,
+                  offset_size: usize) -> Result<Value<'d>, String> {
+    Ok(match form {
+        0x01 => Value::Udata(r.uint(addr_size)?),
+        0x03 => { let len = r.uint(2)? as usize; r.bytes(len)?; Value::Other }
+        0x04 => { let len = r.uint(4)? as usize; r.bytes(len)?; Value::Other }
+        0x05 => Value::Udata(r.uint(2)?),
+        0x06 => Value::Udata(r.uint(4)?),
+        0x07 => Value::Udata(r.uint(8)?),
+        0x08 => Value::Str(r.cstr()?),
+        0x09 | 0x18 => { let len = r.uleb()? as usize; r.bytes(len)?; Value::Other }
+        0x0a => { let len = r.u8()? as usize; r.bytes(len)?; Value::Other }
+        0x0b => Value::Udata(r.uint(1)?),
+        0x0c => { r.u8()?; Value::Other }
+        0x0d => Value::Udata(r.sleb()? as u64),
+        0x0e => Value::Strp(r.uint(offset_size)?),
+        0x0f => Value::Udata(r.uleb()?),
+        0x10 => Value::Ref(r.uint(if version == 2 { addr_size } else { offset_size })?),
+        0x11 => Value::Ref(unit_offset + r.uint(1)?),
+        0x12 => Value::Ref(unit_offset + r.uint(2)?),
+        0x13 => Value::Ref(unit_offset + r.uint(4)?),
+        0x14 => Value::Ref(unit_offset + r.uint(8)?),
+        0x15 => Value::Ref(unit_offset + r.uleb()?),
+        0x16 => {
+            let form = r.uleb()?;
+            read_value(r, form, implicit, unit_offset, version, addr_size, offset_size)?
+        }
+        0x17 => Value::Udata(r.uint(offset_size)?),
+        0x19 => Value::Other,
+        0x1a | 0x1f02 => Value::Strx(r.uleb()?),
+        0x1b | 0x1f01 => Value::Udata(r.uleb()?),
+        0x1c => { r.uint(4)?; Value::Other }
+        0x1d | 0x1f20 | 0x1f21 => { r.uint(offset_size)?; Value::Other }
+        0x1e => { r.bytes(16)?; Value::Other }
+        0x1f => Value::LineStrp(r.uint(offset_size)?),
+        0x20 => { r.uint(8)?; Value::Other }
+        0x21 => Value::Udata(implicit as u64),
+        0x22 | 0x23 => Value::Udata(r.uleb()?),
+        0x24 => { r.uint(8)?; Value::Other }
+        0x25 => Value::Strx(r.uint(1)?),
+        0x26 => Value::Strx(r.uint(2)?),
+        0x27 => Value::Strx(r.uint(3)?),
+        0x28 => Value::Strx(r.uint(4)?),
+        0x29 => Value::Udata(r.uint(1)?),
+        0x2a => Value::Udata(r.uint(2)?),
+        0x2b => Value::Udata(r.uint(3)?),
+        0x2c => Value::Udata(r.uint(4)?),
+        _ => return Err(format!("unsupported DWARF form {:#x}", form)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{declaration, Dwarf, Reader, Sections};
+    use crate::compile;
+    use goblin::elf::Elf;
+    use std::fs;
+
+    // The examples from the DWARF 5 standard, section 7.6.
+    #[test]
+    fn leb128() {
+        for (bytes, expected) in [(&[2][..], 2), (&[127], 127), (&[0x80, 1], 128), (&[0x81, 1], 129), (&[0x82, 1], 130), (&[0xb9, 0x64], 12857)] {
+            assert_eq!(Reader::new(bytes, 0).uleb(), Ok(expected), "{:x?}", bytes);
+        }
+        for (bytes, expected) in [(&[2][..], 2), (&[0x7e], -2), (&[0xff, 0], 127), (&[0x81, 0x7f], -127), (&[0x80, 1], 128), (&[0x80, 0x7f], -128), (&[0x81, 1], 129), (&[0xff, 0x7e], -129)] {
+            assert_eq!(Reader::new(bytes, 0).sleb(), Ok(expected), "{:x?}", bytes);
+        }
+        assert!(Reader::new(&[0x80], 0).uleb().is_err());
+    }
+
+    #[test]
+    fn declarations() {
+        assert_eq!(declaration("uint64_t*", "sp"), "uint64_t* sp");
+        assert_eq!(declaration("void (*)(int)", "f"), "void (*f)(int)");
+        assert_eq!(declaration("int[]", "xs"), "int xs[]");
+    }
+
+    const SOURCE: &str = "\
+        struct vm;\n\
+        typedef unsigned long uint64_t;\n\
+        void op_add(uint64_t* sp, struct vm* vm, void (*next)(int), const char* const name) {\n\
+            next(sp[0] + sp[1] + (name != 0));\n\
+        }\n";
+
+    fn check(version: &str) {
+        let dir = compile::TempDir::new().unwrap();
+        fs::write(dir.path.join("stencils.c"), SOURCE).unwrap();
+        let mut command = vec!["cc".to_string(), version.to_string()];
+        command.extend(compile::STENCIL_CFLAGS.iter().map(|flag| flag.to_string()));
+        command.push(dir.path.join("stencils.c").to_string_lossy().into_owned());
+        let object = dir.path.join("stencils.o");
+        compile::compile(&command, None, &object).unwrap();
+        let data = fs::read(&object).unwrap();
+        let elf = Elf::parse(&data).unwrap();
+        let sections = Sections::load(&elf, &data, "").unwrap().unwrap();
+        let dwarf = Dwarf::parse(&sections).unwrap();
+
+        let signature = &dwarf.signatures()["op_add"];
+        assert_eq!(signature.returns, "void");
+        assert_eq!(signature.c_type, "void (uint64_t*, struct vm*, void (*)(int), const char* const)");
+        assert_eq!(signature.c_params, "uint64_t* sp, struct vm* vm, void (*next)(int), const char* const name");
+        assert_eq!(dwarf.declarations()["op_add"], (dir.path.join("stencils.c"), 3));
+
+        // -ffunction-sections puts op_add in its own section, which the rows all point into,
+        // starting at the line it's declared on and ending after its last instruction.
+        let symbol = elf.syms.iter().find(|sym| elf.strtab.get_at(sym.st_name) == Some("op_add")).unwrap();
+        let rows = sections.line_rows().unwrap();
+        assert!(rows.iter().all(|row| row.section == Some(symbol.st_shndx) && row.primary));
+        assert!(rows.iter().all(|row| row.file == dir.path.join("stencils.c").to_string_lossy()));
+        assert_eq!((rows[0].address, rows[0].line), (0, 3));
+        let last = rows.last().unwrap();
+        assert!(last.end_sequence);
+        assert_eq!(last.address, symbol.st_size);
+    }
+
+    #[test]
+    fn dwarf4() {
+        check("-gdwarf-4");
+    }
+
+    #[test]
+    fn dwarf5() {
+        check("-gdwarf-5");
+    }
+}
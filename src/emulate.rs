@@ -0,0 +1,397 @@
+use std::collections::HashMap;
+
+use crate::arch::Arch;
+use crate::{Reloc, Stencil};
+
+mod aarch64;
+mod arm;
+mod riscv;
+
+// `verify --execute` runs x86-64 stencils natively in the tests. Stencils for the other
+// architectures can't be run on the host, so they're stepped one instruction at a time through
+// the integer instructions compilers use for stencils, with the same dummy hole values as the
+// tests give them: a page of returns for the functions they call, 8 for small values, and
+// scratch memory whose every word points back into it for everything else. A stencil passes if
+// it reaches its continuation, right after its code, or returns.
+
+// Where everything is in the emulated address space. Low, so 32-bit absolute relocations and
+// ARM's 32-bit addresses work, and within a megabyte of each other for RISC-V's jal.
+const CODE: u64 = 0x10_0000;
+const DATA_SIZE: u64 = 0x1_0000;
+const STACK_SIZE: u64 = 0x1_0000;
+// The return address the stencil is entered with, which is never mapped.
+const RETURN: u64 = 0x1000;
+const MAX_STEPS: usize = 1_000_000;
+
+#[derive(Debug, PartialEq, Eq)]
+enum Exit {
+    Continued,
+    Returned,
+}
+
+struct Region {
+    base: u64,
+    bytes: Vec<u8>,
+    writable: bool,
+}
+
+pub struct Memory {
+    regions: Vec<Region>,
+}
+
+impl Memory {
+    fn region(&mut self, address: u64, len: usize, write: bool) -> Result<&mut [u8], String> {
+        let what = if write { "writes" } else { "reads" };
+        let region = self.regions.iter_mut()
+            .find(|r| address >= r.base && address.wrapping_add(len as u64) <= r.base + r.bytes.len() as u64)
+            .ok_or_else(|| format!("{} {} bytes at {:#x}, which isn't mapped", what, len, address))?;
+        if write && !region.writable {
+            return Err(format!("writes {} bytes at {:#x}, which is read-only", len, address));
+        }
+        let start = (address - region.base) as usize;
+        Ok(&mut region.bytes[start..start + len])
+    }
+
+    pub fn read(&mut self, address: u64, len: usize) -> Result<u64, String> {
+        let bytes = self.region(address, len, false)?;
+        Ok(bytes.iter().rev().fold(0, |value, &b| value << 8 | b as u64))
+    }
+
+    pub fn write(&mut self, address: u64, len: usize, value: u64) -> Result<(), String> {
+        let bytes = self.region(address, len, true)?;
+        bytes.copy_from_slice(&value.to_le_bytes()[..len]);
+        Ok(())
+    }
+}
+
+// An architecture's registers, stepped through the code one instruction at a time.
+pub trait Cpu {
+    fn pc(&self) -> u64;
+    fn step(&mut self, memory: &mut Memory) -> Result<(), String>;
+}
+
+struct Layout {
+    // Where the stencil continues, right after its code as if the next one was emitted there.
+    continuation: u64,
+    returns: u64,
+    // One slot per hole loaded through the GOT.
+    got: u64,
+    // In the middle of the scratch memory.
+    data: u64,
+    stack: u64,
+}
+
+impl Layout {
+    fn new(size: usize) -> Layout {
+        let page = |address: u64| address.div_ceil(0x1000) * 0x1000;
+        let returns = page(CODE + size as u64) + 0x1000;
+        let got = returns + 0x1000;
+        let data = got + 0x1000 + DATA_SIZE / 2;
+        Layout { continuation: CODE + size as u64, returns, got, data, stack: data + DATA_SIZE / 2 + 0x1000 + STACK_SIZE / 2 }
+    }
+
+    fn memory(&self, arch: Arch, code: Vec<u8>) -> Memory {
+        let word = match arch {
+            Arch::Arm => 4,
+            _ => 8,
+        };
+        // The stack is filled like the data, so arguments passed on it are pointers too.
+        let scratch = |size: u64| (0..size / word).flat_map(|_| self.data.to_le_bytes()[..word as usize].to_vec()).collect::<Vec<_>>();
+        let thunk = arch.return_thunk();
+        Memory {
+            regions: vec![
+                Region { base: CODE, bytes: code, writable: false },
+                Region { base: self.returns, bytes: thunk.iter().copied().cycle().take(0x1000).collect(), writable: false },
+                Region { base: self.got, bytes: vec![0; 0x1000], writable: false },
+                Region { base: self.data - DATA_SIZE / 2, bytes: scratch(DATA_SIZE), writable: true },
+                Region { base: self.stack - STACK_SIZE / 2, bytes: scratch(STACK_SIZE), writable: true },
+            ],
+        }
+    }
+
+    // The value the tests patch into a reloc's hole.
+    fn value(&self, stencil: &Stencil, reloc: &Reloc) -> u64 {
+        let hole = &reloc.hole;
+        let value = if hole.name == "cnp_stencil_output" {
+            self.continuation
+        } else if hole.is_argument() && hole.value_datatype == "void*" {
+            self.returns
+        } else if hole.is_argument() && hole.datatype == "uint32_t" {
+            8
+        } else if hole.is_argument() || !is_branch(stencil.arch, reloc.relocation) {
+            self.data
+        } else {
+            self.returns
+        };
+        hole.payload.map_or(value, |payload| payload.insert(value))
+    }
+}
+
+// Relocations of calls and jumps, whose external symbols are functions.
+fn is_branch(arch: Arch, relocation: &str) -> bool {
+    match arch {
+        Arch::X86_64 => false,
+        Arch::AArch64 => matches!(relocation, "AARCH64_CALL26" | "AARCH64_JUMP26" | "AARCH64_CONDBR19" | "AARCH64_TSTBR14"),
+        Arch::RiscV => matches!(relocation, "R_RISCV_CALL" | "R_RISCV_CALL_PLT" | "R_RISCV_JAL" | "R_RISCV_BRANCH" | "R_RISCV_RVC_JUMP" | "R_RISCV_RVC_BRANCH"),
+        Arch::Arm => matches!(relocation, "ARM_CALL" | "ARM_JUMP24" | "ARM_PC24"),
+    }
+}
+
+// Emulates every stencil that isn't x86-64, which the tests run natively, printing a line for
+// each like the tests do.
+pub fn check(stencils: &[&Stencil]) -> Result<(), String> {
+    let emulated = stencils.iter().filter(|s| s.arch != Arch::X86_64).collect::<Vec<_>>();
+    let mut failures = 0;
+    for stencil in &emulated {
+        if let Some(reloc) = stencil.relocs.iter().find(|r| r.hole.transform.is_some()) {
+            println!("skip {}: {} is transformed by an expression only the runtime evaluates", stencil.name, reloc.hole.name);
+            continue;
+        }
+        match run(stencil) {
+            Ok(Exit::Continued) => println!("ok {} (emulated, ran to its continuation)", stencil.name),
+            Ok(Exit::Returned) => println!("ok {} (emulated, ran and returned)", stencil.name),
+            Err(e) => {
+                println!("FAIL {}: {}", stencil.name, e);
+                failures += 1;
+            }
+        }
+    }
+    if failures > 0 {
+        return Err(format!("{} of {} emulated stencils failed", failures, emulated.len()));
+    }
+    Ok(())
+}
+
+// Runs a stencil with dummy values patched into its holes until it finishes, or says why it didn't.
+fn run(stencil: &Stencil) -> Result<Exit, String> {
+    let layout = Layout::new(stencil.code.len());
+    let mut code = stencil.code.to_vec();
+    let mut got = HashMap::new();
+    for reloc in &stencil.relocs {
+        let value = layout.value(stencil, reloc);
+        let slots = got.len() as u64;
+        let slot = *got.entry(reloc.hole.name).or_insert(layout.got + slots * 8);
+        patch(stencil.arch, &mut code, reloc.offset as usize, reloc.relocation, value, reloc.addend, slot)?;
+    }
+    let mut memory = layout.memory(stencil.arch, code);
+    for reloc in &stencil.relocs {
+        let slot = (got[reloc.hole.name] - layout.got) as usize;
+        memory.regions[2].bytes[slot..slot + 8].copy_from_slice(&layout.value(stencil, reloc).to_le_bytes());
+    }
+    execute(cpu(stencil.arch, &layout)?.as_mut(), &mut memory, layout.continuation)
+}
+
+// Entered at the start of the code with every argument register pointing at the scratch memory.
+fn cpu(arch: Arch, layout: &Layout) -> Result<Box<dyn Cpu>, String> {
+    let args = [layout.data; 8];
+    Ok(match arch {
+        Arch::X86_64 => return Err("x86-64 stencils are run natively by the tests".to_string()),
+        Arch::AArch64 => Box::new(aarch64::Cpu::new(CODE, layout.stack, RETURN, &args)),
+        Arch::RiscV => Box::new(riscv::Cpu::new(CODE, layout.stack, RETURN, &args)),
+        Arch::Arm => Box::new(arm::Cpu::new(CODE, layout.stack, RETURN, &args)),
+    })
+}
+
+fn execute(cpu: &mut dyn Cpu, memory: &mut Memory, continuation: u64) -> Result<Exit, String> {
+    for _ in 0..MAX_STEPS {
+        match cpu.pc() {
+            pc if pc == continuation => return Ok(Exit::Continued),
+            RETURN => return Ok(Exit::Returned),
+            pc if (CODE..continuation).contains(&pc) => cpu.step(memory).map_err(|e| format!("{} at offset {:#x}", e, pc - CODE))?,
+            pc => cpu.step(memory).map_err(|e| format!("{} at {:#x}", e, pc))?,
+        }
+    }
+    Err(format!("didn't finish within {} instructions", MAX_STEPS))
+}
+
+fn word(code: &[u8], offset: usize) -> Result<u32, String> {
+    code.get(offset..offset + 4).map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+        .ok_or_else(|| format!("relocation at {:#x} runs past the end of the code", offset))
+}
+
+fn set_word(code: &mut [u8], offset: usize, value: u32) {
+    code[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+// `value` as a signed `bits` wide field, or an error if it doesn't fit.
+fn signed(value: i64, bits: u32, relocation: &str) -> Result<u32, String> {
+    let min = -(1i64 << (bits - 1));
+    if value < min || value >= -min {
+        return Err(format!("{} value {:#x} doesn't fit {} bits", relocation, value, bits));
+    }
+    Ok(value as u32 & (u32::MAX >> (32 - bits)))
+}
+
+// Patches a reloc the way a runtime for its architecture would, with `got` the address of the
+// hole's GOT slot.
+fn patch(arch: Arch, code: &mut [u8], offset: usize, relocation: &str, value: u64, addend: i64, got: u64) -> Result<(), String> {
+    let x = value.wrapping_add(addend as u64);
+    let p = CODE + offset as u64;
+    let pc_relative = x.wrapping_sub(p) as i64;
+    let page = |address: u64| address & !0xfff;
+    match (arch, relocation) {
+        (_, "AARCH64_NONE" | "R_RISCV_NONE" | "R_RISCV_RELAX" | "R_RISCV_ALIGN" | "ARM_NONE" | "ARM_V4BX") => {}
+        (_, "AARCH64_ABS64" | "R_RISCV_64") => code[offset..offset + 8].copy_from_slice(&x.to_le_bytes()),
+        (_, "AARCH64_PREL64") => code[offset..offset + 8].copy_from_slice(&(pc_relative as u64).to_le_bytes()),
+        (_, "AARCH64_ABS32" | "R_RISCV_32") => set_word(code, offset, u32::try_from(x).map_err(|_| format!("{} value {:#x} doesn't fit 32 bits", relocation, x))?),
+        (_, "AARCH64_PREL32") => set_word(code, offset, signed(pc_relative, 32, relocation)?),
+        (Arch::AArch64, _) => {
+            let insn = word(code, offset)?;
+            let adr = |insn: u32, value: i64| -> Result<u32, String> {
+                let imm = signed(value, 21, relocation)?;
+                Ok(insn & 0x9f00_001f | (imm & 3) << 29 | (imm >> 2) << 5)
+            };
+            let patched = match relocation {
+                "AARCH64_CALL26" | "AARCH64_JUMP26" => insn & 0xfc00_0000 | signed(pc_relative, 28, relocation)? >> 2,
+                "AARCH64_CONDBR19" => insn & 0xff00_001f | signed(pc_relative, 21, relocation)? >> 2 << 5,
+                "AARCH64_TSTBR14" => insn & 0xfff8_001f | signed(pc_relative, 16, relocation)? >> 2 << 5,
+                "AARCH64_ADR_PREL_LO21" => adr(insn, pc_relative)?,
+                "AARCH64_ADR_PREL_PG_HI21" | "AARCH64_ADR_PREL_PG_HI21_NC" => adr(insn, (page(x).wrapping_sub(page(p)) as i64) >> 12)?,
+                "AARCH64_ADR_GOT_PAGE" => adr(insn, (page(got).wrapping_sub(page(p)) as i64) >> 12)?,
+                "AARCH64_ADD_ABS_LO12_NC" | "AARCH64_LDST8_ABS_LO12_NC" => insn & 0xffc0_03ff | (x as u32 & 0xfff) << 10,
+                "AARCH64_LDST16_ABS_LO12_NC" => insn & 0xffc0_03ff | (x as u32 & 0xfff) >> 1 << 10,
+                "AARCH64_LDST32_ABS_LO12_NC" => insn & 0xffc0_03ff | (x as u32 & 0xfff) >> 2 << 10,
+                "AARCH64_LDST64_ABS_LO12_NC" => insn & 0xffc0_03ff | (x as u32 & 0xfff) >> 3 << 10,
+                "AARCH64_LDST128_ABS_LO12_NC" => insn & 0xffc0_03ff | (x as u32 & 0xfff) >> 4 << 10,
+                "AARCH64_LD64_GOT_LO12_NC" => insn & 0xffc0_03ff | (got as u32 & 0xfff) >> 3 << 10,
+                "AARCH64_MOVW_UABS_G0" | "AARCH64_MOVW_UABS_G0_NC" => insn & 0xffe0_001f | (x as u32 & 0xffff) << 5,
+                "AARCH64_MOVW_UABS_G1" | "AARCH64_MOVW_UABS_G1_NC" => insn & 0xffe0_001f | ((x >> 16) as u32 & 0xffff) << 5,
+                "AARCH64_MOVW_UABS_G2" | "AARCH64_MOVW_UABS_G2_NC" => insn & 0xffe0_001f | ((x >> 32) as u32 & 0xffff) << 5,
+                "AARCH64_MOVW_UABS_G3" => insn & 0xffe0_001f | ((x >> 48) as u32 & 0xffff) << 5,
+                _ => return Err(format!("can't patch {} relocations", relocation)),
+            };
+            set_word(code, offset, patched);
+        }
+        (Arch::RiscV, _) => {
+            let insn = word(code, offset)?;
+            let i_type = |insn: u32, imm: u32| insn & 0x000f_ffff | imm << 20;
+            let s_type = |insn: u32, imm: u32| insn & 0x01ff_f07f | (imm >> 5) << 25 | (imm & 0x1f) << 7;
+            let hi = |value: i64| -> Result<u32, String> { Ok(signed(value.wrapping_add(0x800) >> 12, 20, relocation)? << 12) };
+            match relocation {
+                "R_RISCV_BRANCH" => {
+                    let imm = signed(pc_relative, 13, relocation)?;
+                    set_word(code, offset, insn & 0x01ff_f07f | (imm >> 12 & 1) << 31 | (imm >> 5 & 0x3f) << 25 | (imm >> 1 & 0xf) << 8 | (imm >> 11 & 1) << 7);
+                }
+                "R_RISCV_JAL" => {
+                    let imm = signed(pc_relative, 21, relocation)?;
+                    set_word(code, offset, insn & 0xfff | (imm >> 20 & 1) << 31 | (imm >> 1 & 0x3ff) << 21 | (imm >> 11 & 1) << 20 | (imm >> 12 & 0xff) << 12);
+                }
+                "R_RISCV_CALL" | "R_RISCV_CALL_PLT" => {
+                    let jalr = word(code, offset + 4)?;
+                    set_word(code, offset, insn & 0xfff | hi(pc_relative)?);
+                    set_word(code, offset + 4, i_type(jalr, pc_relative as u32 & 0xfff));
+                }
+                "R_RISCV_HI20" => set_word(code, offset, insn & 0xfff | hi(x as i64)?),
+                "R_RISCV_LO12_I" => set_word(code, offset, i_type(insn, x as u32 & 0xfff)),
+                "R_RISCV_LO12_S" => set_word(code, offset, s_type(insn, x as u32 & 0xfff)),
+                "R_RISCV_RVC_BRANCH" => {
+                    let imm = signed(pc_relative, 9, relocation)?;
+                    let half = insn as u16 as u32 & 0xe383 | (imm >> 8 & 1) << 12 | (imm >> 3 & 3) << 10 | (imm >> 6 & 3) << 5 | (imm >> 1 & 3) << 3 | (imm >> 5 & 1) << 2;
+                    code[offset..offset + 2].copy_from_slice(&(half as u16).to_le_bytes());
+                }
+                "R_RISCV_RVC_JUMP" => {
+                    let imm = signed(pc_relative, 12, relocation)?;
+                    let half = insn as u16 as u32 & 0xe003 | (imm >> 11 & 1) << 12 | (imm >> 4 & 1) << 11 | (imm >> 8 & 3) << 9 | (imm >> 10 & 1) << 8 |
+                        (imm >> 6 & 1) << 7 | (imm >> 7 & 1) << 6 | (imm >> 1 & 7) << 3 | (imm >> 5 & 1) << 2;
+                    code[offset..offset + 2].copy_from_slice(&(half as u16).to_le_bytes());
+                }
+                _ => return Err(format!("can't patch {} relocations", relocation)),
+            }
+        }
+        // ARM relocations are REL, the addend is what the field holds.
+        (Arch::Arm, _) => {
+            let insn = word(code, offset)?;
+            let patched = match relocation {
+                "ARM_ABS32" => value.wrapping_add(insn as i32 as u64) as u32,
+                "ARM_REL32" => value.wrapping_add(insn as i32 as u64).wrapping_sub(p) as u32,
+                "ARM_CALL" | "ARM_JUMP24" | "ARM_PC24" => {
+                    let addend = ((insn << 8) as i32 >> 6) as i64;
+                    insn & 0xff00_0000 | signed(value.wrapping_add(addend as u64).wrapping_sub(p) as i64, 26, relocation)? >> 2
+                }
+                "ARM_MOVW_ABS_NC" | "ARM_MOVT_ABS" => {
+                    let addend = ((insn >> 4 & 0xf000 | insn & 0xfff) as i16) as i64;
+                    let x = value.wrapping_add(addend as u64) as u32;
+                    let imm = if relocation == "ARM_MOVT_ABS" { x >> 16 } else { x & 0xffff };
+                    insn & 0xfff0_f000 | (imm >> 12) << 16 | imm & 0xfff
+                }
+                _ => return Err(format!("can't patch {} relocations", relocation)),
+            };
+            set_word(code, offset, patched);
+        }
+        _ => return Err(format!("can't patch {} relocations", relocation)),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Patches `relocs`, (offset, relocation, value) with the addends in the code, and runs it.
+    fn emulate(arch: Arch, code: &[u8], relocs: impl Fn(&Layout) -> Vec<(usize, &'static str, u64)>) -> Result<Exit, String> {
+        let mut code = code.to_vec();
+        let layout = Layout::new(code.len());
+        for (offset, relocation, value) in relocs(&layout) {
+            patch(arch, &mut code, offset, relocation, value, 0, layout.got)?;
+        }
+        let mut memory = layout.memory(arch, code);
+        execute(cpu(arch, &layout)?.as_mut(), &mut memory, layout.continuation)
+    }
+
+    fn words(words: &[u32]) -> Vec<u8> {
+        words.iter().flat_map(|w| w.to_le_bytes()).collect()
+    }
+
+    // Each loads the word the data hole points at, traps unless it points back at the data, calls
+    // a function and jumps to its continuation.
+    #[test]
+    fn aarch64() {
+        let code = words(&[0x9000_0001, 0x9100_0021, 0xf940_0022, 0xeb01_005f, 0x5400_0061, 0x9400_0000, 0x1400_0000, 0xd420_0020]);
+        let relocs = |layout: &Layout| vec![
+            (0, "AARCH64_ADR_PREL_PG_HI21", layout.data),
+            (4, "AARCH64_ADD_ABS_LO12_NC", layout.data),
+            (20, "AARCH64_CALL26", layout.returns),
+            (24, "AARCH64_JUMP26", layout.continuation),
+        ];
+        assert_eq!(emulate(Arch::AArch64, &code, relocs), Ok(Exit::Continued));
+        assert_eq!(emulate(Arch::AArch64, &code, |layout| relocs(layout).into_iter().map(|(o, r, v)| (o, r, if o < 8 { v + 8 } else { v })).collect()),
+            Err("traps with d4200020 at offset 0x1c".to_string()));
+    }
+
+    #[test]
+    fn riscv() {
+        // With a compressed ld and ebreak.
+        let code = [
+            0xb7, 0x05, 0x00, 0x00, 0x93, 0x85, 0x05, 0x00, 0x90, 0x61, 0x63, 0x98, 0xc5, 0x00,
+            0x97, 0x00, 0x00, 0x00, 0xe7, 0x80, 0x00, 0x00, 0x6f, 0x00, 0x00, 0x00, 0x02, 0x90,
+        ];
+        let relocs = |layout: &Layout| vec![
+            (0, "R_RISCV_HI20", layout.data),
+            (4, "R_RISCV_LO12_I", layout.data),
+            (0xe, "R_RISCV_CALL", layout.returns),
+            (0x16, "R_RISCV_JAL", layout.continuation),
+        ];
+        assert_eq!(emulate(Arch::RiscV, &code, relocs), Ok(Exit::Continued));
+    }
+
+    #[test]
+    fn arm() {
+        let code = words(&[0xe300_1000, 0xe340_1000, 0xe591_2000, 0xe151_0002, 0x1a00_0001, 0xebff_fffe, 0xeaff_fffe, 0xe7f0_00f0]);
+        let relocs = |layout: &Layout| vec![
+            (0, "ARM_MOVW_ABS_NC", layout.data),
+            (4, "ARM_MOVT_ABS", layout.data),
+            (20, "ARM_CALL", layout.returns),
+            (24, "ARM_JUMP24", layout.continuation),
+        ];
+        assert_eq!(emulate(Arch::Arm, &code, relocs), Ok(Exit::Continued));
+    }
+
+    #[test]
+    fn failures() {
+        assert_eq!(emulate(Arch::AArch64, &words(&[0xd65f_03c0]), |_| Vec::new()), Ok(Exit::Returned));
+        assert_eq!(emulate(Arch::AArch64, &words(&[0xf940_03c1]), |_| Vec::new()), Err("reads 8 bytes at 0x1000, which isn't mapped at offset 0x0".to_string()));
+        assert_eq!(emulate(Arch::AArch64, &words(&[0x1400_0000]), |_| Vec::new()), Err(format!("didn't finish within {} instructions", MAX_STEPS)));
+        assert_eq!(emulate(Arch::AArch64, &words(&[0x9400_0000]), |_| vec![(0, "AARCH64_CALL26", 1 << 40)]),
+            Err("AARCH64_CALL26 value 0xfffff00000 doesn't fit 28 bits".to_string()));
+    }
+}
@@ -0,0 +1,561 @@
+use super::Memory;
+
+// The integer instructions, the loads, stores and moves of SIMD registers compilers use to copy
+// memory, and the hints and barriers, which do nothing here.
+pub struct Cpu {
+    x: [u64; 31],
+    sp: u64,
+    pc: u64,
+    v: [u128; 32],
+    n: bool,
+    z: bool,
+    c: bool,
+    v_flag: bool,
+}
+
+fn bits(insn: u32, low: u32, len: u32) -> u32 {
+    insn >> low & (u32::MAX >> (32 - len))
+}
+
+fn sign_extend(value: u64, bits: u32) -> u64 {
+    ((value << (64 - bits)) as i64 >> (64 - bits)) as u64
+}
+
+fn mask(value: u64, sf: bool) -> u64 {
+    if sf { value } else { value as u32 as u64 }
+}
+
+fn ones(n: u32) -> u64 {
+    if n >= 64 { u64::MAX } else { (1 << n) - 1 }
+}
+
+fn ror(value: u64, amount: u32, width: u32) -> u64 {
+    let amount = amount % width;
+    if amount == 0 {
+        return value;
+    }
+    (value >> amount | value << (width - amount)) & ones(width)
+}
+
+fn replicate(value: u64, esize: u32, width: u32) -> u64 {
+    (0..width / esize).fold(0, |r, i| r | value << (i * esize))
+}
+
+// The masks of logical immediates and bitfield moves, DecodeBitMasks in the architecture manual.
+fn bit_masks(n: u32, imms: u32, immr: u32, width: u32) -> Option<(u64, u64)> {
+    let combined = n << 6 | (!imms & 0x3f);
+    if combined == 0 {
+        return None;
+    }
+    let len = 31 - combined.leading_zeros();
+    if len < 1 {
+        return None;
+    }
+    let esize = 1 << len;
+    let levels = esize - 1;
+    let s = imms & levels;
+    let r = immr & levels;
+    let d = s.wrapping_sub(r) & levels;
+    let wmask = replicate(ror(ones(s + 1), r, esize), esize, width);
+    let tmask = replicate(ones(d + 1), esize, width);
+    Some((wmask, tmask))
+}
+
+fn shift(value: u64, kind: u32, amount: u32, sf: bool) -> u64 {
+    let width = if sf { 64 } else { 32 };
+    let value = mask(value, sf);
+    let amount = amount % width;
+    mask(match kind {
+        0 => value << amount,
+        1 => value >> amount,
+        2 => (sign_extend(value, width) as i64 >> amount) as u64,
+        _ => ror(value, amount, width),
+    }, sf)
+}
+
+fn extend(value: u64, option: u32) -> u64 {
+    match option {
+        0 => value as u8 as u64,
+        1 => value as u16 as u64,
+        2 => value as u32 as u64,
+        4 => value as i8 as u64,
+        5 => value as i16 as u64,
+        6 => value as i32 as u64,
+        _ => value,
+    }
+}
+
+impl Cpu {
+    pub fn new(entry: u64, sp: u64, ret: u64, args: &[u64]) -> Cpu {
+        let mut x = [0; 31];
+        x[..8].copy_from_slice(&args[..8]);
+        x[30] = ret;
+        Cpu { x, sp, pc: entry, v: [0; 32], n: false, z: false, c: false, v_flag: false }
+    }
+
+    // Register 31 is the zero register here, and the stack pointer in `sp_reg`.
+    fn reg(&self, r: u32, sf: bool) -> u64 {
+        if r == 31 { 0 } else { mask(self.x[r as usize], sf) }
+    }
+
+    fn set_reg(&mut self, r: u32, sf: bool, value: u64) {
+        if r != 31 {
+            self.x[r as usize] = mask(value, sf);
+        }
+    }
+
+    fn sp_reg(&self, r: u32, sf: bool) -> u64 {
+        if r == 31 { mask(self.sp, sf) } else { self.reg(r, sf) }
+    }
+
+    fn set_sp_reg(&mut self, r: u32, sf: bool, value: u64) {
+        if r == 31 { self.sp = mask(value, sf) } else { self.set_reg(r, sf, value) }
+    }
+
+    fn add_with_carry(&mut self, x: u64, y: u64, carry: bool, sf: bool, set_flags: bool) -> u64 {
+        let width = if sf { 64 } else { 32 };
+        let (x, y) = (mask(x, sf), mask(y, sf));
+        let unsigned = x as u128 + y as u128 + carry as u128;
+        let result = mask(unsigned as u64, sf);
+        if set_flags {
+            let signed = sign_extend(x, width) as i64 as i128 + sign_extend(y, width) as i64 as i128 + carry as i128;
+            self.n = result >> (width - 1) & 1 == 1;
+            self.z = result == 0;
+            self.c = unsigned >> width != 0;
+            self.v_flag = sign_extend(result, width) as i64 as i128 != signed;
+        }
+        result
+    }
+
+    fn set_logical_flags(&mut self, result: u64, sf: bool) {
+        let width = if sf { 64 } else { 32 };
+        self.n = result >> (width - 1) & 1 == 1;
+        self.z = result == 0;
+        self.c = false;
+        self.v_flag = false;
+    }
+
+    fn condition(&self, cond: u32) -> bool {
+        let holds = match cond >> 1 {
+            0 => self.z,
+            1 => self.c,
+            2 => self.n,
+            3 => self.v_flag,
+            4 => self.c && !self.z,
+            5 => self.n == self.v_flag,
+            6 => self.n == self.v_flag && !self.z,
+            _ => true,
+        };
+        if cond & 1 == 1 && cond != 15 { !holds } else { holds }
+    }
+
+    fn data_immediate(&mut self, insn: u32) -> Result<(), String> {
+        let sf = insn >> 31 == 1;
+        let rd = bits(insn, 0, 5);
+        let rn = bits(insn, 5, 5);
+        match bits(insn, 23, 3) {
+            0 | 1 => {
+                let imm = sign_extend((bits(insn, 5, 19) << 2 | bits(insn, 29, 2)) as u64, 21);
+                let value = if sf { (self.pc & !0xfff).wrapping_add(imm << 12) } else { self.pc.wrapping_add(imm) };
+                self.set_reg(rd, true, value);
+            }
+            2 => {
+                let imm = (bits(insn, 10, 12) as u64) << (12 * bits(insn, 22, 1));
+                let set_flags = bits(insn, 29, 1) == 1;
+                let x = self.sp_reg(rn, sf);
+                let result = if bits(insn, 30, 1) == 1 {
+                    self.add_with_carry(x, !imm, true, sf, set_flags)
+                } else {
+                    self.add_with_carry(x, imm, false, sf, set_flags)
+                };
+                if set_flags { self.set_reg(rd, sf, result) } else { self.set_sp_reg(rd, sf, result) }
+            }
+            4 => {
+                let width = if sf { 64 } else { 32 };
+                let (imm, _) = bit_masks(bits(insn, 22, 1), bits(insn, 10, 6), bits(insn, 16, 6), width)
+                    .ok_or_else(|| format!("can't emulate {:08x}", insn))?;
+                let x = self.reg(rn, sf);
+                match bits(insn, 29, 2) {
+                    0 => self.set_sp_reg(rd, sf, x & imm),
+                    1 => self.set_sp_reg(rd, sf, x | imm),
+                    2 => self.set_sp_reg(rd, sf, x ^ imm),
+                    _ => {
+                        self.set_logical_flags(x & imm, sf);
+                        self.set_reg(rd, sf, x & imm);
+                    }
+                }
+            }
+            5 => {
+                let shift = 16 * bits(insn, 21, 2);
+                let imm = (bits(insn, 5, 16) as u64) << shift;
+                match bits(insn, 29, 2) {
+                    0 => self.set_reg(rd, sf, !imm),
+                    2 => self.set_reg(rd, sf, imm),
+                    3 => {
+                        let old = self.reg(rd, sf);
+                        self.set_reg(rd, sf, old & !(0xffff << shift) | imm);
+                    }
+                    _ => return Err(format!("can't emulate {:08x}", insn)),
+                }
+            }
+            6 => {
+                let width = if sf { 64 } else { 32 };
+                let (immr, imms) = (bits(insn, 16, 6), bits(insn, 10, 6));
+                let (wmask, tmask) = bit_masks(bits(insn, 22, 1), imms, immr, width)
+                    .ok_or_else(|| format!("can't emulate {:08x}", insn))?;
+                let src = self.reg(rn, sf);
+                let opc = bits(insn, 29, 2);
+                let dst = if opc == 1 { self.reg(rd, sf) } else { 0 };
+                let bottom = dst & !wmask | ror(src, immr, width) & wmask;
+                let top = match opc {
+                    0 if src >> imms & 1 == 1 => ones(width),
+                    1 => dst,
+                    _ => 0,
+                };
+                self.set_reg(rd, sf, top & !tmask | bottom & tmask);
+            }
+            7 => {
+                let width = if sf { 64 } else { 32 };
+                let lsb = bits(insn, 10, 6);
+                let (high, low) = (self.reg(rn, sf), self.reg(bits(insn, 16, 5), sf));
+                let value = if lsb == 0 { low } else { low >> lsb | high << (width - lsb) };
+                self.set_reg(rd, sf, value);
+            }
+            _ => return Err(format!("can't emulate {:08x}", insn)),
+        }
+        self.pc += 4;
+        Ok(())
+    }
+
+    fn branch(&mut self, insn: u32) -> Result<(), String> {
+        let next = self.pc + 4;
+        let offset = |imm: u32, len: u32| sign_extend((imm as u64) << 2, len + 2);
+        if insn & 0x7c00_0000 == 0x1400_0000 {
+            if insn >> 31 == 1 {
+                self.x[30] = next;
+            }
+            self.pc = self.pc.wrapping_add(offset(bits(insn, 0, 26), 26));
+        } else if insn & 0xff00_0010 == 0x5400_0000 {
+            self.pc = if self.condition(bits(insn, 0, 4)) { self.pc.wrapping_add(offset(bits(insn, 5, 19), 19)) } else { next };
+        } else if insn & 0x7e00_0000 == 0x3400_0000 {
+            let zero = self.reg(bits(insn, 0, 5), insn >> 31 == 1) == 0;
+            let taken = zero != (bits(insn, 24, 1) == 1);
+            self.pc = if taken { self.pc.wrapping_add(offset(bits(insn, 5, 19), 19)) } else { next };
+        } else if insn & 0x7e00_0000 == 0x3600_0000 {
+            let bit = bits(insn, 31, 1) << 5 | bits(insn, 19, 5);
+            let set = self.reg(bits(insn, 0, 5), true) >> bit & 1 == 1;
+            let taken = set == (bits(insn, 24, 1) == 1);
+            self.pc = if taken { self.pc.wrapping_add(offset(bits(insn, 5, 14), 14)) } else { next };
+        } else if insn & 0xfe1f_0000 == 0xd61f_0000 {
+            // br, blr and ret, and their pointer authenticating forms, with nothing to authenticate.
+            let target = self.reg(bits(insn, 5, 5), true);
+            match bits(insn, 21, 4) {
+                0 | 2 => {}
+                1 => self.x[30] = next,
+                _ => return Err(format!("can't emulate {:08x}", insn)),
+            }
+            self.pc = target;
+        } else if insn & 0xffff_f000 == 0xd503_2000 || insn & 0xffff_f000 == 0xd503_3000 {
+            self.pc = next;
+        } else if insn & 0xff00_0000 == 0xd400_0000 {
+            return Err(format!("traps with {:08x}", insn));
+        } else {
+            return Err(format!("can't emulate {:08x}", insn));
+        }
+        Ok(())
+    }
+
+    fn load(&self, memory: &mut Memory, address: u64, size: usize, signed_to: Option<bool>) -> Result<u64, String> {
+        let value = memory.read(address, size)?;
+        Ok(match signed_to {
+            Some(sf) => mask(sign_extend(value, size as u32 * 8), sf),
+            None => value,
+        })
+    }
+
+    fn load_vector(&mut self, memory: &mut Memory, rt: u32, address: u64, size: usize) -> Result<(), String> {
+        let low = memory.read(address, size.min(8))?;
+        let high = if size == 16 { memory.read(address + 8, 8)? } else { 0 };
+        self.v[rt as usize] = (high as u128) << 64 | low as u128;
+        Ok(())
+    }
+
+    fn store_vector(&self, memory: &mut Memory, rt: u32, address: u64, size: usize) -> Result<(), String> {
+        let value = self.v[rt as usize];
+        memory.write(address, size.min(8), value as u64)?;
+        if size == 16 {
+            memory.write(address + 8, 8, (value >> 64) as u64)?;
+        }
+        Ok(())
+    }
+
+    fn load_store(&mut self, insn: u32, memory: &mut Memory) -> Result<(), String> {
+        let rt = bits(insn, 0, 5);
+        let rn = bits(insn, 5, 5);
+        let vector = bits(insn, 26, 1) == 1;
+        if insn & 0x3b00_0000 == 0x1800_0000 {
+            let address = self.pc.wrapping_add(sign_extend((bits(insn, 5, 19) as u64) << 2, 21));
+            match (vector, bits(insn, 30, 2)) {
+                (false, 0) => { let value = self.load(memory, address, 4, None)?; self.set_reg(rt, true, value) }
+                (false, 1) => { let value = self.load(memory, address, 8, None)?; self.set_reg(rt, true, value) }
+                (false, 2) => { let value = self.load(memory, address, 4, Some(true))?; self.set_reg(rt, true, value) }
+                (false, _) => {}
+                (true, opc) if opc < 3 => self.load_vector(memory, rt, address, 4 << opc)?,
+                _ => return Err(format!("can't emulate {:08x}", insn)),
+            }
+        } else if insn & 0x3f00_0000 == 0x0800_0000 {
+            // Exclusive and acquire/release loads and stores, which always succeed on one core.
+            let size = 1 << bits(insn, 30, 2);
+            let address = self.sp_reg(rn, true);
+            if bits(insn, 21, 1) == 1 {
+                return Err(format!("can't emulate {:08x}", insn));
+            }
+            if bits(insn, 22, 1) == 1 {
+                let value = self.load(memory, address, size, None)?;
+                self.set_reg(rt, true, value);
+            } else {
+                memory.write(address, size, self.reg(rt, true))?;
+                if bits(insn, 23, 1) == 0 {
+                    self.set_reg(bits(insn, 16, 5), false, 0);
+                }
+            }
+        } else if insn & 0x3a00_0000 == 0x2800_0000 {
+            let opc = bits(insn, 30, 2);
+            let load = bits(insn, 22, 1) == 1;
+            let size = match (vector, opc) {
+                (false, 0 | 1) => 4,
+                (false, 2) => 8,
+                (true, opc) if opc < 3 => 4 << opc,
+                _ => return Err(format!("can't emulate {:08x}", insn)),
+            };
+            let offset = sign_extend(bits(insn, 15, 7) as u64, 7).wrapping_mul(size as u64);
+            let base = self.sp_reg(rn, true);
+            let index = bits(insn, 23, 2);
+            let address = if index == 1 { base } else { base.wrapping_add(offset) };
+            let rt2 = bits(insn, 10, 5);
+            for (i, r) in [rt, rt2].into_iter().enumerate() {
+                let address = address.wrapping_add((i * size) as u64);
+                match (vector, load) {
+                    (true, true) => self.load_vector(memory, r, address, size)?,
+                    (true, false) => self.store_vector(memory, r, address, size)?,
+                    (false, true) => {
+                        let value = self.load(memory, address, size, (opc == 1).then_some(true))?;
+                        self.set_reg(r, true, value);
+                    }
+                    (false, false) => memory.write(address, size, self.reg(r, size == 8))?,
+                }
+            }
+            if index == 1 || index == 3 {
+                self.set_sp_reg(rn, true, base.wrapping_add(offset));
+            }
+        } else if insn & 0x3a00_0000 == 0x3800_0000 {
+            let size_bits = bits(insn, 30, 2);
+            let opc = bits(insn, 22, 2);
+            let size: usize = if vector && opc >= 2 { 16 } else { 1 << size_bits };
+            let scale = size.trailing_zeros();
+            let base = self.sp_reg(rn, true);
+            let (address, writeback) = if bits(insn, 24, 1) == 1 {
+                (base.wrapping_add((bits(insn, 10, 12) as u64) << scale), None)
+            } else if bits(insn, 21, 1) == 0 {
+                let offset = sign_extend(bits(insn, 12, 9) as u64, 9);
+                match bits(insn, 10, 2) {
+                    1 => (base, Some(base.wrapping_add(offset))),
+                    3 => (base.wrapping_add(offset), Some(base.wrapping_add(offset))),
+                    _ => (base.wrapping_add(offset), None),
+                }
+            } else if bits(insn, 10, 2) == 2 {
+                let option = bits(insn, 13, 3);
+                let amount = if bits(insn, 12, 1) == 1 { scale } else { 0 };
+                (base.wrapping_add(extend(self.reg(bits(insn, 16, 5), true), option) << amount), None)
+            } else {
+                return Err(format!("can't emulate {:08x}", insn));
+            };
+            match (vector, opc) {
+                (true, 0 | 2) => self.store_vector(memory, rt, address, size)?,
+                (true, _) => self.load_vector(memory, rt, address, size)?,
+                (false, 0) => memory.write(address, size, self.reg(rt, size == 8))?,
+                (false, 1) => { let value = self.load(memory, address, size, None)?; self.set_reg(rt, true, value) }
+                // Prefetches.
+                (false, 2) if size_bits == 3 => {}
+                (false, 2) => { let value = self.load(memory, address, size, Some(true))?; self.set_reg(rt, true, value) }
+                (false, _) if size_bits < 2 => { let value = self.load(memory, address, size, Some(false))?; self.set_reg(rt, false, value) }
+                _ => return Err(format!("can't emulate {:08x}", insn)),
+            }
+            if let Some(address) = writeback {
+                self.set_sp_reg(rn, true, address);
+            }
+        } else {
+            return Err(format!("can't emulate {:08x}", insn));
+        }
+        self.pc += 4;
+        Ok(())
+    }
+
+    fn data_register(&mut self, insn: u32) -> Result<(), String> {
+        let sf = insn >> 31 == 1;
+        let width = if sf { 64 } else { 32 };
+        let rd = bits(insn, 0, 5);
+        let rn = bits(insn, 5, 5);
+        let rm = bits(insn, 16, 5);
+        let set_flags = bits(insn, 29, 1) == 1;
+        let subtract = bits(insn, 30, 1) == 1;
+        if insn & 0x1f00_0000 == 0x0a00_0000 {
+            let mut y = shift(self.reg(rm, sf), bits(insn, 22, 2), bits(insn, 10, 6), sf);
+            if bits(insn, 21, 1) == 1 {
+                y = mask(!y, sf);
+            }
+            let x = self.reg(rn, sf);
+            let result = match bits(insn, 29, 2) {
+                0 | 3 => x & y,
+                1 => x | y,
+                _ => x ^ y,
+            };
+            if bits(insn, 29, 2) == 3 {
+                self.set_logical_flags(result, sf);
+            }
+            self.set_reg(rd, sf, result);
+        } else if insn & 0x1f20_0000 == 0x0b00_0000 || insn & 0x1f20_0000 == 0x0b20_0000 {
+            let extended = bits(insn, 21, 1) == 1;
+            let (x, y) = if extended {
+                (self.sp_reg(rn, sf), extend(self.reg(rm, true), bits(insn, 13, 3)) << bits(insn, 10, 3))
+            } else {
+                (self.reg(rn, sf), shift(self.reg(rm, sf), bits(insn, 22, 2), bits(insn, 10, 6), sf))
+            };
+            let result = if subtract {
+                self.add_with_carry(x, !y, true, sf, set_flags)
+            } else {
+                self.add_with_carry(x, y, false, sf, set_flags)
+            };
+            if extended && !set_flags { self.set_sp_reg(rd, sf, result) } else { self.set_reg(rd, sf, result) }
+        } else if insn & 0x1fe0_fc00 == 0x1a00_0000 {
+            let (x, y) = (self.reg(rn, sf), self.reg(rm, sf));
+            let y = if subtract { !y } else { y };
+            let result = self.add_with_carry(x, y, self.c, sf, set_flags);
+            self.set_reg(rd, sf, result);
+        } else if insn & 0x1fe0_0410 == 0x1a40_0000 {
+            let y = if bits(insn, 11, 1) == 1 { bits(insn, 16, 5) as u64 } else { self.reg(rm, sf) };
+            if self.condition(bits(insn, 12, 4)) {
+                let x = self.reg(rn, sf);
+                if subtract { self.add_with_carry(x, !y, true, sf, true) } else { self.add_with_carry(x, y, false, sf, true) };
+            } else {
+                let nzcv = bits(insn, 0, 4);
+                (self.n, self.z, self.c, self.v_flag) = (nzcv & 8 != 0, nzcv & 4 != 0, nzcv & 2 != 0, nzcv & 1 != 0);
+            }
+        } else if insn & 0x1fe0_0800 == 0x1a80_0000 {
+            let value = if self.condition(bits(insn, 12, 4)) {
+                self.reg(rn, sf)
+            } else {
+                let y = self.reg(rm, sf);
+                match (subtract, bits(insn, 10, 1)) {
+                    (false, 0) => y,
+                    (false, _) => y.wrapping_add(1),
+                    (true, 0) => !y,
+                    (true, _) => y.wrapping_neg(),
+                }
+            };
+            self.set_reg(rd, sf, value);
+        } else if insn & 0x5fe0_0000 == 0x1ac0_0000 {
+            let (x, y) = (self.reg(rn, sf), self.reg(rm, sf));
+            let value = match bits(insn, 10, 6) {
+                2 => x.checked_div(y).unwrap_or(0),
+                3 => {
+                    let (x, y) = (sign_extend(x, width) as i64, sign_extend(y, width) as i64);
+                    if y == 0 { 0 } else { x.wrapping_div(y) as u64 }
+                }
+                op @ 8..=11 => shift(x, op - 8, y as u32, sf),
+                _ => return Err(format!("can't emulate {:08x}", insn)),
+            };
+            self.set_reg(rd, sf, value);
+        } else if insn & 0x5fe0_0000 == 0x5ac0_0000 {
+            let x = self.reg(rn, sf);
+            let value = match (bits(insn, 10, 6), sf) {
+                (0, _) => x.reverse_bits() >> (64 - width),
+                (1, _) => {
+                    let swapped = (x & 0x00ff_00ff_00ff_00ff) << 8 | (x >> 8) & 0x00ff_00ff_00ff_00ff;
+                    mask(swapped, sf)
+                }
+                (2, false) | (3, true) => x.swap_bytes() >> (64 - width),
+                (2, true) => (x as u32).swap_bytes() as u64 | (((x >> 32) as u32).swap_bytes() as u64) << 32,
+                (4, _) => (x.leading_zeros() - (64 - width)) as u64,
+                (5, _) => {
+                    let x = sign_extend(x, width);
+                    let same = if (x as i64) < 0 { (!x).leading_zeros() } else { x.leading_zeros() };
+                    (same - 1) as u64
+                }
+                _ => return Err(format!("can't emulate {:08x}", insn)),
+            };
+            self.set_reg(rd, sf, value);
+        } else if insn & 0x1f00_0000 == 0x1b00_0000 {
+            let ra = self.reg(bits(insn, 10, 5), sf);
+            let negate = bits(insn, 15, 1) == 1;
+            let (x, y) = (self.reg(rn, true), self.reg(rm, true));
+            let product = match bits(insn, 21, 3) {
+                0 => mask(x, sf).wrapping_mul(mask(y, sf)),
+                1 => (x as i32 as i64).wrapping_mul(y as i32 as i64) as u64,
+                5 => (x as u32 as u64).wrapping_mul(y as u32 as u64),
+                2 => ((x as i64 as i128 * y as i64 as i128) >> 64) as u64,
+                6 => ((x as u128 * y as u128) >> 64) as u64,
+                _ => return Err(format!("can't emulate {:08x}", insn)),
+            };
+            let value = match bits(insn, 21, 3) {
+                2 | 6 => product,
+                _ if negate => ra.wrapping_sub(product),
+                _ => ra.wrapping_add(product),
+            };
+            self.set_reg(rd, sf, value);
+        } else {
+            return Err(format!("can't emulate {:08x}", insn));
+        }
+        self.pc += 4;
+        Ok(())
+    }
+
+    // Only moves, movi and fmov between general and SIMD registers, for the copies.
+    fn vector(&mut self, insn: u32) -> Result<(), String> {
+        let rd = bits(insn, 0, 5);
+        if insn & 0x9ff8_0c00 == 0x0f00_0400 && bits(insn, 12, 4) == 0xe {
+            let imm = (bits(insn, 16, 3) << 5 | bits(insn, 5, 5)) as u64;
+            let value = if bits(insn, 29, 1) == 1 {
+                (0..8).fold(0, |r, i| if imm >> i & 1 == 1 { r | 0xff << (i * 8) } else { r })
+            } else {
+                replicate(imm, 8, 64)
+            };
+            let high = if bits(insn, 30, 1) == 1 { value } else { 0 };
+            self.v[rd as usize] = (high as u128) << 64 | value as u128;
+        } else if insn & 0x7f3e_fc00 == 0x1e26_0000 {
+            // fmov between a general register and the low 32 or 64 bits of a SIMD one.
+            let sf = insn >> 31 == 1;
+            let rn = bits(insn, 5, 5);
+            if bits(insn, 16, 1) == 1 {
+                self.v[rd as usize] = mask(self.reg(rn, sf), sf) as u128;
+            } else {
+                self.set_reg(rd, sf, self.v[rn as usize] as u64);
+            }
+        } else if insn & 0xbfe0_fc00 == 0x0ea0_1c00 && bits(insn, 16, 5) == bits(insn, 5, 5) {
+            let value = self.v[bits(insn, 5, 5) as usize];
+            self.v[rd as usize] = if bits(insn, 30, 1) == 1 { value } else { value as u64 as u128 };
+        } else {
+            return Err(format!("can't emulate SIMD or floating point {:08x}", insn));
+        }
+        self.pc += 4;
+        Ok(())
+    }
+}
+
+impl super::Cpu for Cpu {
+    fn pc(&self) -> u64 {
+        self.pc
+    }
+
+    fn step(&mut self, memory: &mut Memory) -> Result<(), String> {
+        let insn = memory.read(self.pc, 4)? as u32;
+        match bits(insn, 25, 4) {
+            0b1000 | 0b1001 => self.data_immediate(insn),
+            0b1010 | 0b1011 => self.branch(insn),
+            0b0100 | 0b0110 | 0b1100 | 0b1110 => self.load_store(insn, memory),
+            0b0101 | 0b1101 => self.data_register(insn),
+            0b0111 | 0b1111 => self.vector(insn),
+            _ => Err(format!("can't emulate {:08x}", insn)),
+        }
+    }
+}
@@ -0,0 +1,396 @@
+use super::Memory;
+
+// The A32 integer instructions, without Thumb, coprocessors or VFP.
+pub struct Cpu {
+    r: [u32; 15],
+    pc: u32,
+    n: bool,
+    z: bool,
+    c: bool,
+    v: bool,
+    // Set by an instruction that wrote the pc.
+    branched: bool,
+}
+
+fn field(insn: u32, low: u32, len: u32) -> u32 {
+    insn >> low & (u32::MAX >> (32 - len))
+}
+
+fn sign_extend(value: u32, bits: u32) -> u32 {
+    ((value << (32 - bits)) as i32 >> (32 - bits)) as u32
+}
+
+// A shift by a register's amount, with its carry out.
+fn shift(value: u32, kind: u32, amount: u32, carry: bool) -> (u32, bool) {
+    if amount == 0 {
+        return (value, carry);
+    }
+    let bit = |n: u32| value >> n & 1 == 1;
+    match kind {
+        0 if amount < 32 => (value << amount, bit(32 - amount)),
+        0 => (0, amount == 32 && bit(0)),
+        1 if amount < 32 => (value >> amount, bit(amount - 1)),
+        1 => (0, amount == 32 && bit(31)),
+        2 if amount < 32 => ((value as i32 >> amount) as u32, bit(amount - 1)),
+        2 => ((value as i32 >> 31) as u32, bit(31)),
+        _ => {
+            let value = value.rotate_right(amount % 32);
+            (value, value >> 31 == 1)
+        }
+    }
+}
+
+impl Cpu {
+    pub fn new(entry: u64, sp: u64, ret: u64, args: &[u64]) -> Cpu {
+        let mut r = [0; 15];
+        for (reg, &arg) in r.iter_mut().zip(&args[..4]) {
+            *reg = arg as u32;
+        }
+        r[13] = sp as u32;
+        r[14] = ret as u32;
+        Cpu { r, pc: entry as u32, n: false, z: false, c: false, v: false, branched: false }
+    }
+
+    // The pc reads as the address two instructions on.
+    fn reg(&self, n: u32) -> u32 {
+        if n == 15 { self.pc.wrapping_add(8) } else { self.r[n as usize] }
+    }
+
+    fn set_reg(&mut self, n: u32, value: u32) -> Result<(), String> {
+        if n != 15 {
+            self.r[n as usize] = value;
+            return Ok(());
+        }
+        if value & 1 == 1 {
+            return Err(format!("branches to Thumb code at {:#x}", value & !1));
+        }
+        self.pc = value & !3;
+        self.branched = true;
+        Ok(())
+    }
+
+    fn condition(&self, cond: u32) -> bool {
+        let holds = match cond >> 1 {
+            0 => self.z,
+            1 => self.c,
+            2 => self.n,
+            3 => self.v,
+            4 => self.c && !self.z,
+            5 => self.n == self.v,
+            6 => self.n == self.v && !self.z,
+            _ => true,
+        };
+        if cond & 1 == 1 && cond != 15 { !holds } else { holds }
+    }
+
+    fn set_nz(&mut self, value: u32) {
+        self.n = value >> 31 == 1;
+        self.z = value == 0;
+    }
+
+    fn add_with_carry(&mut self, x: u32, y: u32, carry: bool, set_flags: bool) -> u32 {
+        let unsigned = x as u64 + y as u64 + carry as u64;
+        let result = unsigned as u32;
+        if set_flags {
+            let signed = x as i32 as i64 + y as i32 as i64 + carry as i64;
+            self.set_nz(result);
+            self.c = unsigned >> 32 != 0;
+            self.v = result as i32 as i64 != signed;
+        }
+        result
+    }
+
+    // The second operand of data processing instructions, with its carry out.
+    fn operand2(&self, insn: u32) -> (u32, bool) {
+        if field(insn, 25, 1) == 1 {
+            let rotate = field(insn, 8, 4) * 2;
+            let value = field(insn, 0, 8).rotate_right(rotate);
+            return (value, if rotate == 0 { self.c } else { value >> 31 == 1 });
+        }
+        let value = self.reg(field(insn, 0, 4));
+        let kind = field(insn, 5, 2);
+        if field(insn, 4, 1) == 1 {
+            return shift(value, kind, self.reg(field(insn, 8, 4)) & 0xff, self.c);
+        }
+        // An immediate shift by 0 is lsr or asr by 32, or for ror a rotate through the carry.
+        match (kind, field(insn, 7, 5)) {
+            (1 | 2, 0) => shift(value, kind, 32, self.c),
+            (3, 0) => ((self.c as u32) << 31 | value >> 1, value & 1 == 1),
+            (_, amount) => shift(value, kind, amount, self.c),
+        }
+    }
+
+    fn data_processing(&mut self, insn: u32) -> Result<(), String> {
+        let set_flags = field(insn, 20, 1) == 1;
+        let rd = field(insn, 12, 4);
+        let x = self.reg(field(insn, 16, 4));
+        let (y, carry) = self.operand2(insn);
+        let opcode = field(insn, 21, 4);
+        if set_flags && rd == 15 && !(8..12).contains(&opcode) {
+            return Err(format!("can't emulate the exception return {:08x}", insn));
+        }
+        let result = match opcode {
+            0 | 8 => x & y,
+            1 | 9 => x ^ y,
+            2 | 10 => return self.arithmetic(opcode, rd, x, !y, true, set_flags),
+            3 => return self.arithmetic(opcode, rd, y, !x, true, set_flags),
+            4 | 11 => return self.arithmetic(opcode, rd, x, y, false, set_flags),
+            5 => return self.arithmetic(opcode, rd, x, y, self.c, set_flags),
+            6 => return self.arithmetic(opcode, rd, x, !y, self.c, set_flags),
+            7 => return self.arithmetic(opcode, rd, y, !x, self.c, set_flags),
+            12 => x | y,
+            13 => y,
+            14 => x & !y,
+            _ => !y,
+        };
+        if set_flags {
+            self.set_nz(result);
+            self.c = carry;
+        }
+        if (8..12).contains(&opcode) { Ok(()) } else { self.set_reg(rd, result) }
+    }
+
+    fn arithmetic(&mut self, opcode: u32, rd: u32, x: u32, y: u32, carry: bool, set_flags: bool) -> Result<(), String> {
+        let result = self.add_with_carry(x, y, carry, set_flags);
+        if (8..12).contains(&opcode) { Ok(()) } else { self.set_reg(rd, result) }
+    }
+
+    fn multiply(&mut self, insn: u32) -> Result<(), String> {
+        let (rm, rn) = (self.reg(field(insn, 8, 4)), self.reg(field(insn, 0, 4)));
+        let (high, low) = (field(insn, 16, 4), field(insn, 12, 4));
+        let accumulate = (self.reg(high) as u64) << 32 | self.reg(low) as u64;
+        let long = match field(insn, 21, 3) {
+            0 => return self.set_multiply(insn, high, rm.wrapping_mul(rn)),
+            1 => return self.set_multiply(insn, high, rm.wrapping_mul(rn).wrapping_add(self.reg(low))),
+            3 => return self.set_multiply(insn, high, self.reg(low).wrapping_sub(rm.wrapping_mul(rn))),
+            4 => rm as u64 * rn as u64,
+            5 => (rm as u64 * rn as u64).wrapping_add(accumulate),
+            6 => (rm as i32 as i64 * rn as i32 as i64) as u64,
+            7 => ((rm as i32 as i64 * rn as i32 as i64) as u64).wrapping_add(accumulate),
+            _ => return Err(format!("can't emulate {:08x}", insn)),
+        };
+        if field(insn, 20, 1) == 1 {
+            self.n = long >> 63 == 1;
+            self.z = long == 0;
+        }
+        self.set_reg(low, long as u32)?;
+        self.set_reg(high, (long >> 32) as u32)
+    }
+
+    fn set_multiply(&mut self, insn: u32, rd: u32, value: u32) -> Result<(), String> {
+        if field(insn, 20, 1) == 1 {
+            self.set_nz(value);
+        }
+        self.set_reg(rd, value)
+    }
+
+    // The address of a load or store and the base register's value after it.
+    fn address(&self, insn: u32, offset: u32) -> (u32, u32) {
+        let base = self.reg(field(insn, 16, 4));
+        let moved = if field(insn, 23, 1) == 1 { base.wrapping_add(offset) } else { base.wrapping_sub(offset) };
+        if field(insn, 24, 1) == 1 { (moved, moved) } else { (base, moved) }
+    }
+
+    fn write_back(&mut self, insn: u32, base: u32) -> Result<(), String> {
+        if field(insn, 24, 1) == 0 || field(insn, 21, 1) == 1 {
+            self.set_reg(field(insn, 16, 4), base)?;
+        }
+        Ok(())
+    }
+
+    fn load_store(&mut self, insn: u32, memory: &mut Memory) -> Result<(), String> {
+        let offset = if field(insn, 25, 1) == 0 {
+            field(insn, 0, 12)
+        } else {
+            let amount = field(insn, 7, 5);
+            let kind = field(insn, 5, 2);
+            let value = self.reg(field(insn, 0, 4));
+            match (kind, amount) {
+                (1 | 2, 0) => shift(value, kind, 32, self.c).0,
+                (3, 0) => (self.c as u32) << 31 | value >> 1,
+                _ => shift(value, kind, amount, self.c).0,
+            }
+        };
+        let (address, base) = self.address(insn, offset);
+        let size = if field(insn, 22, 1) == 1 { 1 } else { 4 };
+        let rt = field(insn, 12, 4);
+        if field(insn, 20, 1) == 1 {
+            let value = memory.read(address as u64, size)? as u32;
+            self.write_back(insn, base)?;
+            self.set_reg(rt, value)
+        } else {
+            memory.write(address as u64, size, self.reg(rt) as u64)?;
+            self.write_back(insn, base)
+        }
+    }
+
+    // Halfwords, signed bytes and doublewords.
+    fn extra_load_store(&mut self, insn: u32, memory: &mut Memory) -> Result<(), String> {
+        let offset = if field(insn, 22, 1) == 1 { field(insn, 8, 4) << 4 | field(insn, 0, 4) } else { self.reg(field(insn, 0, 4)) };
+        let (address, base) = self.address(insn, offset);
+        let rt = field(insn, 12, 4);
+        let address = address as u64;
+        match (field(insn, 20, 1), field(insn, 5, 2)) {
+            (1, 1) => { let value = memory.read(address, 2)? as u32; self.write_back(insn, base)?; self.set_reg(rt, value) }
+            (1, 2) => { let value = memory.read(address, 1)? as i8 as u32; self.write_back(insn, base)?; self.set_reg(rt, value) }
+            (1, _) => { let value = memory.read(address, 2)? as i16 as u32; self.write_back(insn, base)?; self.set_reg(rt, value) }
+            (_, 1) => { memory.write(address, 2, self.reg(rt) as u64)?; self.write_back(insn, base) }
+            (_, 2) => {
+                let (low, high) = (memory.read(address, 4)? as u32, memory.read(address + 4, 4)? as u32);
+                self.write_back(insn, base)?;
+                self.set_reg(rt, low)?;
+                self.set_reg(rt + 1, high)
+            }
+            _ => {
+                memory.write(address, 4, self.reg(rt) as u64)?;
+                memory.write(address + 4, 4, self.reg(rt + 1) as u64)?;
+                self.write_back(insn, base)
+            }
+        }
+    }
+
+    fn load_store_multiple(&mut self, insn: u32, memory: &mut Memory) -> Result<(), String> {
+        if field(insn, 22, 1) == 1 {
+            return Err(format!("can't emulate {:08x}", insn));
+        }
+        let list = field(insn, 0, 16);
+        let rn = field(insn, 16, 4);
+        let base = self.reg(rn);
+        let size = 4 * list.count_ones();
+        let (start, end) = match (field(insn, 24, 1), field(insn, 23, 1)) {
+            (0, 1) => (base, base.wrapping_add(size)),
+            (1, 1) => (base.wrapping_add(4), base.wrapping_add(size)),
+            (0, _) => (base.wrapping_sub(size).wrapping_add(4), base.wrapping_sub(size)),
+            _ => (base.wrapping_sub(size), base.wrapping_sub(size)),
+        };
+        if field(insn, 21, 1) == 1 {
+            self.set_reg(rn, end)?;
+        }
+        let registers = (0..16).filter(|r| list >> r & 1 == 1);
+        for (i, r) in registers.enumerate() {
+            let address = start.wrapping_add(4 * i as u32) as u64;
+            if field(insn, 20, 1) == 1 {
+                let value = memory.read(address, 4)? as u32;
+                self.set_reg(r, value)?;
+            } else {
+                let value = if r == rn { base } else { self.reg(r) };
+                memory.write(address, 4, value as u64)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn media(&mut self, insn: u32) -> Result<(), String> {
+        let rd = field(insn, 12, 4);
+        let rn = field(insn, 16, 4);
+        let rm = self.reg(field(insn, 0, 4));
+        if insn & 0x0f80_03f0 == 0x0680_0070 {
+            let value = rm.rotate_right(8 * field(insn, 10, 2));
+            let extended = match field(insn, 20, 3) {
+                2 => value as i8 as u32,
+                3 => value as i16 as u32,
+                6 => value as u8 as u32,
+                7 => value as u16 as u32,
+                _ => return Err(format!("can't emulate {:08x}", insn)),
+            };
+            let add = if rn == 15 { 0 } else { self.reg(rn) };
+            self.set_reg(rd, extended.wrapping_add(add))
+        } else if insn & 0x0fff_0ff0 == 0x06bf_0f30 {
+            self.set_reg(rd, rm.swap_bytes())
+        } else if insn & 0x0fff_0ff0 == 0x06bf_0fb0 {
+            self.set_reg(rd, (rm & 0x00ff_00ff) << 8 | (rm >> 8) & 0x00ff_00ff)
+        } else if insn & 0x0fff_0ff0 == 0x06ff_0fb0 {
+            self.set_reg(rd, (rm as u16).swap_bytes() as i16 as u32)
+        } else if insn & 0x0fb0_0070 == 0x07a0_0050 {
+            let (lsb, width) = (field(insn, 7, 5), field(insn, 16, 5) + 1);
+            if lsb + width > 32 {
+                return Err(format!("can't emulate {:08x}", insn));
+            }
+            let value = rm << (32 - lsb - width);
+            let extracted = if field(insn, 22, 1) == 1 { value >> (32 - width) } else { (value as i32 >> (32 - width)) as u32 };
+            self.set_reg(rd, extracted)
+        } else if insn & 0x0fe0_0070 == 0x07c0_0010 {
+            let (lsb, msb) = (field(insn, 7, 5), field(insn, 16, 5));
+            if msb < lsb {
+                return Err(format!("can't emulate {:08x}", insn));
+            }
+            let mask = (u32::MAX >> (31 - (msb - lsb))) << lsb;
+            let value = if field(insn, 0, 4) == 15 { 0 } else { rm << lsb };
+            self.set_reg(rd, self.reg(rd) & !mask | value & mask)
+        } else if insn & 0x0fd0_f0f0 == 0x0710_f010 {
+            let (x, y) = (self.reg(field(insn, 0, 4)), self.reg(field(insn, 8, 4)));
+            let quotient = if field(insn, 21, 1) == 1 {
+                x.checked_div(y).unwrap_or(0)
+            } else if y == 0 {
+                0
+            } else {
+                (x as i32).wrapping_div(y as i32) as u32
+            };
+            self.set_reg(rn, quotient)
+        } else {
+            Err(format!("can't emulate {:08x}", insn))
+        }
+    }
+
+    fn execute(&mut self, insn: u32, memory: &mut Memory) -> Result<(), String> {
+        let unknown = || format!("can't emulate {:08x}", insn);
+        if insn >> 28 == 15 {
+            // Barriers and preloads.
+            return if insn & 0xffff_ff00 == 0xf57f_f000 || insn & 0xfd70_f000 == 0xf550_f000 { Ok(()) } else { Err(unknown()) };
+        }
+        if insn & 0x0ff0_00f0 == 0x07f0_00f0 {
+            return Err(format!("traps with {:08x}", insn));
+        }
+        if !self.condition(insn >> 28) {
+            return Ok(());
+        }
+        match field(insn, 25, 3) {
+            0 if insn & 0x0fff_ffd0 == 0x012f_ff10 => {
+                if field(insn, 5, 1) == 1 {
+                    self.r[14] = self.pc + 4;
+                }
+                self.set_reg(15, self.reg(field(insn, 0, 4)))
+            }
+            0 if insn & 0x0fff_0ff0 == 0x016f_0f10 => self.set_reg(field(insn, 12, 4), self.reg(field(insn, 0, 4)).leading_zeros()),
+            0 if insn & 0x0f00_00f0 == 0x0000_0090 => self.multiply(insn),
+            0 if insn & 0x0000_0090 == 0x0000_0090 => self.extra_load_store(insn, memory),
+            0 if insn & 0x0190_0000 == 0x0100_0000 => Err(unknown()),
+            0 => self.data_processing(insn),
+            1 if insn & 0x0ff0_0000 == 0x0300_0000 => self.set_reg(field(insn, 12, 4), field(insn, 16, 4) << 12 | field(insn, 0, 12)),
+            1 if insn & 0x0ff0_0000 == 0x0340_0000 => {
+                let rd = field(insn, 12, 4);
+                self.set_reg(rd, self.reg(rd) & 0xffff | (field(insn, 16, 4) << 12 | field(insn, 0, 12)) << 16)
+            }
+            1 if insn & 0x0fff_ff00 == 0x0320_f000 => Ok(()),
+            1 if insn & 0x0190_0000 == 0x0100_0000 => Err(unknown()),
+            1 => self.data_processing(insn),
+            2 => self.load_store(insn, memory),
+            3 if field(insn, 4, 1) == 0 => self.load_store(insn, memory),
+            3 => self.media(insn),
+            4 => self.load_store_multiple(insn, memory),
+            5 => {
+                if field(insn, 24, 1) == 1 {
+                    self.r[14] = self.pc + 4;
+                }
+                self.set_reg(15, self.reg(15).wrapping_add(sign_extend(field(insn, 0, 24) << 2, 26)))
+            }
+            _ => Err(format!("can't emulate coprocessor or VFP {:08x}", insn)),
+        }
+    }
+}
+
+impl super::Cpu for Cpu {
+    fn pc(&self) -> u64 {
+        self.pc as u64
+    }
+
+    fn step(&mut self, memory: &mut Memory) -> Result<(), String> {
+        let insn = memory.read(self.pc as u64, 4)? as u32;
+        self.branched = false;
+        self.execute(insn, memory)?;
+        if !self.branched {
+            self.pc += 4;
+        }
+        Ok(())
+    }
+}
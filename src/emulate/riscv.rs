@@ -0,0 +1,297 @@
+use super::Memory;
+
+// RV64IMC and the atomics, compressed instructions are expanded to the ones they stand for.
+pub struct Cpu {
+    x: [u64; 32],
+    pc: u64,
+}
+
+fn sign_extend(value: u64, bits: u32) -> u64 {
+    ((value << (64 - bits)) as i64 >> (64 - bits)) as u64
+}
+
+fn field(insn: u32, low: u32, len: u32) -> u32 {
+    insn >> low & (u32::MAX >> (32 - len))
+}
+
+fn i_type(opcode: u32, rd: u32, funct3: u32, rs1: u32, imm: u32) -> u32 {
+    (imm & 0xfff) << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+}
+
+fn s_type(opcode: u32, funct3: u32, rs1: u32, rs2: u32, imm: u32) -> u32 {
+    (imm >> 5 & 0x7f) << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | (imm & 0x1f) << 7 | opcode
+}
+
+fn b_type(funct3: u32, rs1: u32, rs2: u32, imm: u32) -> u32 {
+    (imm >> 12 & 1) << 31 | (imm >> 5 & 0x3f) << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | (imm >> 1 & 0xf) << 8 | (imm >> 11 & 1) << 7 | 0x63
+}
+
+fn j_type(rd: u32, imm: u32) -> u32 {
+    (imm >> 20 & 1) << 31 | (imm >> 1 & 0x3ff) << 21 | (imm >> 11 & 1) << 20 | (imm >> 12 & 0xff) << 12 | rd << 7 | 0x6f
+}
+
+fn r_type(opcode: u32, rd: u32, funct3: u32, rs1: u32, rs2: u32, funct7: u32) -> u32 {
+    funct7 << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+}
+
+// The 32-bit instruction a compressed one stands for.
+fn expand(c: u32) -> Option<u32> {
+    let bit = |n: u32| field(c, n, 1);
+    let rd = field(c, 7, 5);
+    let rs2 = field(c, 2, 5);
+    let rd_ = 8 + field(c, 2, 3);
+    let rs1_ = 8 + field(c, 7, 3);
+    let imm6 = sign_extend((bit(12) << 5 | field(c, 2, 5)) as u64, 6) as u32;
+    let lw_offset = field(c, 10, 3) << 3 | bit(6) << 2 | bit(5) << 6;
+    let ld_offset = field(c, 10, 3) << 3 | field(c, 5, 2) << 6;
+    Some(match (c & 3, field(c, 13, 3)) {
+        (0, 0) => {
+            let imm = field(c, 11, 2) << 4 | field(c, 7, 4) << 6 | bit(6) << 2 | bit(5) << 3;
+            if imm == 0 {
+                return None;
+            }
+            i_type(0x13, rd_, 0, 2, imm)
+        }
+        (0, 2) => i_type(0x03, rd_, 2, rs1_, lw_offset),
+        (0, 3) => i_type(0x03, rd_, 3, rs1_, ld_offset),
+        (0, 6) => s_type(0x23, 2, rs1_, rd_, lw_offset),
+        (0, 7) => s_type(0x23, 3, rs1_, rd_, ld_offset),
+        (1, 0) => i_type(0x13, rd, 0, rd, imm6),
+        (1, 1) => i_type(0x1b, rd, 0, rd, imm6),
+        (1, 2) => i_type(0x13, rd, 0, 0, imm6),
+        (1, 3) if rd == 2 => {
+            let imm = bit(12) << 9 | bit(6) << 4 | bit(5) << 6 | field(c, 3, 2) << 7 | bit(2) << 5;
+            i_type(0x13, 2, 0, 2, sign_extend(imm as u64, 10) as u32)
+        }
+        (1, 3) => imm6 << 12 | rd << 7 | 0x37,
+        (1, 4) => {
+            let shamt = bit(12) << 5 | field(c, 2, 5);
+            match (field(c, 10, 2), bit(12), field(c, 5, 2)) {
+                (0, _, _) => i_type(0x13, rs1_, 5, rs1_, shamt),
+                (1, _, _) => i_type(0x13, rs1_, 5, rs1_, 0x400 | shamt),
+                (2, _, _) => i_type(0x13, rs1_, 7, rs1_, imm6),
+                (3, 0, 0) => r_type(0x33, rs1_, 0, rs1_, rd_, 0x20),
+                (3, 0, 1) => r_type(0x33, rs1_, 4, rs1_, rd_, 0),
+                (3, 0, 2) => r_type(0x33, rs1_, 6, rs1_, rd_, 0),
+                (3, 0, 3) => r_type(0x33, rs1_, 7, rs1_, rd_, 0),
+                (3, 1, 0) => r_type(0x3b, rs1_, 0, rs1_, rd_, 0x20),
+                (3, 1, 1) => r_type(0x3b, rs1_, 0, rs1_, rd_, 0),
+                _ => return None,
+            }
+        }
+        (1, 5) => {
+            let imm = bit(12) << 11 | bit(11) << 4 | field(c, 9, 2) << 8 | bit(8) << 10 | bit(7) << 6 | bit(6) << 7 | field(c, 3, 3) << 1 | bit(2) << 5;
+            j_type(0, sign_extend(imm as u64, 12) as u32)
+        }
+        (1, funct3 @ (6 | 7)) => {
+            let imm = bit(12) << 8 | field(c, 10, 2) << 3 | field(c, 5, 2) << 6 | field(c, 3, 2) << 1 | bit(2) << 5;
+            b_type(funct3 - 6, rs1_, 0, sign_extend(imm as u64, 9) as u32)
+        }
+        (2, 0) => i_type(0x13, rd, 1, rd, bit(12) << 5 | field(c, 2, 5)),
+        (2, 2) => i_type(0x03, rd, 2, 2, bit(12) << 5 | field(c, 4, 3) << 2 | field(c, 2, 2) << 6),
+        (2, 3) => i_type(0x03, rd, 3, 2, bit(12) << 5 | field(c, 5, 2) << 3 | field(c, 2, 3) << 6),
+        (2, 4) => match (bit(12), rd, rs2) {
+            (0, 0, _) => return None,
+            (0, _, 0) => i_type(0x67, 0, 0, rd, 0),
+            (0, _, _) => r_type(0x33, rd, 0, 0, rs2, 0),
+            (1, 0, 0) => 0x0010_0073,
+            (1, _, 0) => i_type(0x67, 1, 0, rd, 0),
+            _ => r_type(0x33, rd, 0, rd, rs2, 0),
+        },
+        (2, 6) => s_type(0x23, 2, 2, rs2, field(c, 9, 4) << 2 | field(c, 7, 2) << 6),
+        (2, 7) => s_type(0x23, 3, 2, rs2, field(c, 10, 3) << 3 | field(c, 7, 3) << 6),
+        _ => return None,
+    })
+}
+
+impl Cpu {
+    pub fn new(entry: u64, sp: u64, ret: u64, args: &[u64]) -> Cpu {
+        let mut x = [0; 32];
+        x[10..18].copy_from_slice(&args[..8]);
+        x[1] = ret;
+        x[2] = sp;
+        Cpu { x, pc: entry }
+    }
+
+    fn set(&mut self, rd: u32, value: u64) {
+        if rd != 0 {
+            self.x[rd as usize] = value;
+        }
+    }
+
+    fn op(insn: u32, x: u64, y: u64, word: bool) -> Option<u64> {
+        let funct3 = field(insn, 12, 3);
+        let funct7 = field(insn, 25, 7);
+        let shamt = if word { y as u32 & 31 } else { y as u32 & 63 };
+        let value = if word {
+            let (x32, y32) = (x as u32, y as u32);
+            (match (funct7, funct3) {
+                (0, 0) => x32.wrapping_add(y32),
+                (0x20, 0) => x32.wrapping_sub(y32),
+                (0, 1) => x32 << shamt,
+                (0, 5) => x32 >> shamt,
+                (0x20, 5) => (x32 as i32 >> shamt) as u32,
+                (1, 0) => x32.wrapping_mul(y32),
+                (1, 4) => if y32 == 0 { u32::MAX } else { (x32 as i32).wrapping_div(y32 as i32) as u32 },
+                (1, 5) => x32.checked_div(y32).unwrap_or(u32::MAX),
+                (1, 6) => if y32 == 0 { x32 } else { (x32 as i32).wrapping_rem(y32 as i32) as u32 },
+                (1, 7) => x32.checked_rem(y32).unwrap_or(x32),
+                _ => return None,
+            }) as i32 as u64
+        } else {
+            match (funct7, funct3) {
+                (0, 0) => x.wrapping_add(y),
+                (0x20, 0) => x.wrapping_sub(y),
+                (0, 1) => x << shamt,
+                (0, 2) => ((x as i64) < (y as i64)) as u64,
+                (0, 3) => (x < y) as u64,
+                (0, 4) => x ^ y,
+                (0, 5) => x >> shamt,
+                (0x20, 5) => (x as i64 >> shamt) as u64,
+                (0, 6) => x | y,
+                (0, 7) => x & y,
+                (1, 0) => x.wrapping_mul(y),
+                (1, 1) => ((x as i64 as i128 * y as i64 as i128) >> 64) as u64,
+                (1, 2) => ((x as i64 as i128 * y as i128) >> 64) as u64,
+                (1, 3) => ((x as u128 * y as u128) >> 64) as u64,
+                (1, 4) => if y == 0 { u64::MAX } else { (x as i64).wrapping_div(y as i64) as u64 },
+                (1, 5) => x.checked_div(y).unwrap_or(u64::MAX),
+                (1, 6) => if y == 0 { x } else { (x as i64).wrapping_rem(y as i64) as u64 },
+                (1, 7) => x.checked_rem(y).unwrap_or(x),
+                _ => return None,
+            }
+        };
+        Some(value)
+    }
+
+    fn execute(&mut self, insn: u32, len: u64, memory: &mut Memory) -> Result<(), String> {
+        let unknown = || format!("can't emulate {:08x}", insn);
+        let rd = field(insn, 7, 5);
+        let rs1 = self.x[field(insn, 15, 5) as usize];
+        let rs2 = self.x[field(insn, 20, 5) as usize];
+        let funct3 = field(insn, 12, 3);
+        let imm_i = sign_extend((insn >> 20) as u64, 12);
+        let imm_s = sign_extend((insn >> 25 << 5 | field(insn, 7, 5)) as u64, 12);
+        let next = self.pc + len;
+        match insn & 0x7f {
+            0x37 => self.set(rd, insn as i32 as i64 as u64 & !0xfff),
+            0x17 => self.set(rd, self.pc.wrapping_add(insn as i32 as i64 as u64 & !0xfff)),
+            0x6f => {
+                let imm = field(insn, 31, 1) << 20 | field(insn, 21, 10) << 1 | field(insn, 20, 1) << 11 | field(insn, 12, 8) << 12;
+                self.set(rd, next);
+                self.pc = self.pc.wrapping_add(sign_extend(imm as u64, 21));
+                return Ok(());
+            }
+            0x67 => {
+                let target = rs1.wrapping_add(imm_i) & !1;
+                self.set(rd, next);
+                self.pc = target;
+                return Ok(());
+            }
+            0x63 => {
+                let taken = match funct3 {
+                    0 => rs1 == rs2,
+                    1 => rs1 != rs2,
+                    4 => (rs1 as i64) < (rs2 as i64),
+                    5 => (rs1 as i64) >= (rs2 as i64),
+                    6 => rs1 < rs2,
+                    7 => rs1 >= rs2,
+                    _ => return Err(unknown()),
+                };
+                let imm = field(insn, 31, 1) << 12 | field(insn, 25, 6) << 5 | field(insn, 8, 4) << 1 | field(insn, 7, 1) << 11;
+                self.pc = if taken { self.pc.wrapping_add(sign_extend(imm as u64, 13)) } else { next };
+                return Ok(());
+            }
+            0x03 => {
+                let size = 1 << (funct3 & 3);
+                let value = memory.read(rs1.wrapping_add(imm_i), size)?;
+                let value = match funct3 {
+                    0..=2 => sign_extend(value, size as u32 * 8),
+                    3..=6 => value,
+                    _ => return Err(unknown()),
+                };
+                self.set(rd, value);
+            }
+            0x23 if funct3 < 4 => memory.write(rs1.wrapping_add(imm_s), 1 << funct3, rs2)?,
+            0x13 => {
+                let shamt = imm_i & 63;
+                let value = match funct3 {
+                    0 => rs1.wrapping_add(imm_i),
+                    1 if imm_i >> 6 == 0 => rs1 << shamt,
+                    2 => ((rs1 as i64) < (imm_i as i64)) as u64,
+                    3 => (rs1 < imm_i) as u64,
+                    4 => rs1 ^ imm_i,
+                    5 if imm_i >> 6 == 0 => rs1 >> shamt,
+                    5 if imm_i >> 6 == 0x10 => (rs1 as i64 >> shamt) as u64,
+                    6 => rs1 | imm_i,
+                    7 => rs1 & imm_i,
+                    _ => return Err(unknown()),
+                };
+                self.set(rd, value);
+            }
+            0x1b => {
+                let shamt = imm_i as u32 & 31;
+                let value = match funct3 {
+                    0 => rs1.wrapping_add(imm_i) as u32,
+                    1 => (rs1 as u32) << shamt,
+                    5 if imm_i >> 5 == 0 => rs1 as u32 >> shamt,
+                    5 if imm_i >> 5 == 0x20 => (rs1 as i32 >> shamt) as u32,
+                    _ => return Err(unknown()),
+                };
+                self.set(rd, value as i32 as u64);
+            }
+            0x33 => self.set(rd, Cpu::op(insn, rs1, rs2, false).ok_or_else(unknown)?),
+            0x3b => self.set(rd, Cpu::op(insn, rs1, rs2, true).ok_or_else(unknown)?),
+            // Fences, and the atomics, which are never interrupted on one hart.
+            0x0f => {}
+            0x2f if funct3 == 2 || funct3 == 3 => {
+                let size = 1 << funct3;
+                let extend = |value: u64| if size == 4 { value as i32 as u64 } else { value };
+                let old = extend(memory.read(rs1, size)?);
+                let new = match field(insn, 27, 5) {
+                    0x02 => None,
+                    0x03 => {
+                        memory.write(rs1, size, rs2)?;
+                        self.set(rd, 0);
+                        self.pc = next;
+                        return Ok(());
+                    }
+                    0x01 => Some(rs2),
+                    0x00 => Some(old.wrapping_add(rs2)),
+                    0x04 => Some(old ^ rs2),
+                    0x0c => Some(old & rs2),
+                    0x08 => Some(old | rs2),
+                    0x10 => Some(if (old as i64) < (extend(rs2) as i64) { old } else { rs2 }),
+                    0x14 => Some(if (old as i64) > (extend(rs2) as i64) { old } else { rs2 }),
+                    0x18 => Some(if old < rs2 { old } else { rs2 }),
+                    0x1c => Some(if old > rs2 { old } else { rs2 }),
+                    _ => return Err(unknown()),
+                };
+                if let Some(new) = new {
+                    memory.write(rs1, size, new)?;
+                }
+                self.set(rd, old);
+            }
+            0x73 => return Err(format!("traps with {:08x}", insn)),
+            _ => return Err(unknown()),
+        }
+        self.pc = next;
+        Ok(())
+    }
+}
+
+impl super::Cpu for Cpu {
+    fn pc(&self) -> u64 {
+        self.pc
+    }
+
+    fn step(&mut self, memory: &mut Memory) -> Result<(), String> {
+        let half = memory.read(self.pc, 2)? as u32;
+        if half & 3 != 3 {
+            let insn = expand(half).ok_or_else(|| format!("can't emulate {:04x}", half))?;
+            return self.execute(insn, 2, memory);
+        }
+        let insn = memory.read(self.pc, 4)? as u32;
+        self.execute(insn, 4, memory)
+    }
+}
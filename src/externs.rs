@@ -0,0 +1,60 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+
+use crate::Stencil;
+
+// Which undefined symbols stencils may reference. Extern configs have one symbol per line, or a
+// prefix ending in `*`, and a leading `!` denies it instead. Denials win over allows.
+pub struct Externs<'c> {
+    allow: Vec<&'c str>,
+    deny: Vec<&'c str>,
+}
+
+pub fn parse_externs(text: &str) -> Result<Externs<'_>, Box<dyn Error>> {
+    let mut externs = Externs { allow: Vec::new(), deny: Vec::new() };
+    for (lineno, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (list, pattern) = match line.strip_prefix('!') {
+            Some(pattern) => (&mut externs.deny, pattern.trim()),
+            None => (&mut externs.allow, line),
+        };
+        if pattern.is_empty() || pattern.contains(char::is_whitespace) || pattern[..pattern.len() - 1].contains('*') {
+            return Err(format!("line {}: expected `[!]symbol` or `[!]prefix*`", lineno + 1).into());
+        }
+        list.push(pattern);
+    }
+    Ok(externs)
+}
+
+fn matches(patterns: &[&str], name: &str) -> bool {
+    patterns.iter().any(|pattern| match pattern.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => *pattern == name,
+    })
+}
+
+// Checks every external hole against the config, listing the stencils that use each one it rejects.
+pub fn check(externs: &Externs, stencils: &[Stencil]) -> Result<(), String> {
+    let mut rejected = BTreeMap::<&str, Vec<&str>>::new();
+    for stencil in stencils {
+        for hole in stencil.holes.iter().filter(|h| !h.internal) {
+            if matches(&externs.deny, hole.name) || !matches(&externs.allow, hole.name) {
+                let users = rejected.entry(hole.name).or_default();
+                if !users.contains(&stencil.name) {
+                    users.push(stencil.name);
+                }
+            }
+        }
+    }
+    if rejected.is_empty() {
+        return Ok(());
+    }
+    let lines = rejected.iter().map(|(name, users)| {
+        let why = if matches(&externs.deny, name) { "denied" } else { "not allowed" };
+        format!("{} is {}, used by {}", name, why, users.join(", "))
+    });
+    Err(lines.collect::<Vec<_>>().join("; "))
+}
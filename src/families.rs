@@ -0,0 +1,93 @@
+use std::collections::HashSet;
+use std::error::Error;
+
+use crate::Stencil;
+
+// Stencils implementing one operation on several types, which the runtime picks between by the
+// opcode and type tag of its IR.
+pub struct Family<'c> {
+    name: &'c str,
+    ops: Vec<&'c str>,
+    types: Vec<&'c str>,
+    // The stencil name for each op and type, with `{op}` and `{type}` in it.
+    pattern: &'c str,
+}
+
+const DEFAULT_PATTERN: &str = "{op}_{type}";
+
+// Family configs have one family per line, `arith = add sub mul * i32 i64 f64`, naming its
+// stencils `<op>_<type>` unless a pattern follows, as in `cmp = eq lt * i32 f64 : cmp_{op}_{type}`.
+pub fn parse_families(text: &str) -> Result<Vec<Family<'_>>, Box<dyn Error>> {
+    let mut families = Vec::<Family>::new();
+    for (lineno, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let parsed = line.split_once('=').and_then(|(name, rest)| {
+            let (product, pattern) = rest.split_once(':').unwrap_or((rest, DEFAULT_PATTERN));
+            let (ops, types) = product.split_once('*')?;
+            Some(Family {
+                name: name.trim(),
+                ops: ops.split_whitespace().collect(),
+                types: types.split_whitespace().collect(),
+                pattern: pattern.trim(),
+            })
+        });
+        let Some(family) = parsed.filter(|f| is_identifier(f.name) && !f.ops.is_empty() && !f.types.is_empty()) else {
+            return Err(format!("line {}: expected `name = op... * type... [: pattern]`", lineno + 1).into());
+        };
+        if let Some(word) = family.ops.iter().chain(&family.types).find(|word| !is_identifier(word)) {
+            return Err(format!("line {}: {} can't be used in an identifier", lineno + 1, word).into());
+        }
+        if !family.pattern.contains("{op}") || !family.pattern.contains("{type}") {
+            return Err(format!("line {}: the pattern needs both {{op}} and {{type}}", lineno + 1).into());
+        }
+        if let Some(word) = duplicate(&family.ops).or_else(|| duplicate(&family.types)) {
+            return Err(format!("line {}: {} is listed twice", lineno + 1, word).into());
+        }
+        if families.iter().any(|f| f.name == family.name) {
+            return Err(format!("line {}: family {} is defined twice", lineno + 1, family.name).into());
+        }
+        families.push(family);
+    }
+    Ok(families)
+}
+
+fn is_identifier(word: &str) -> bool {
+    word.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') && !word.is_empty()
+}
+
+fn duplicate<'c>(words: &[&'c str]) -> Option<&'c str> {
+    let mut seen = HashSet::new();
+    words.iter().copied().find(|word| !seen.insert(*word))
+}
+
+#[derive(serde::Serialize)]
+pub struct FamilyTable<'c> {
+    name: &'c str,
+    ops: &'c [&'c str],
+    types: &'c [&'c str],
+    // Indexed by op then type, None where the family has no stencil for the pair.
+    stencils: Vec<Vec<Option<String>>>,
+}
+
+// Finds each family's stencils. Families don't need a stencil for every pair, but one without any
+// is most likely a typo in its pattern.
+pub fn resolve<'c>(families: &'c [Family<'c>], stencils: &[Stencil]) -> Result<Vec<FamilyTable<'c>>, String> {
+    let names = stencils.iter().map(|s| s.name).collect::<HashSet<_>>();
+    let mut tables = Vec::with_capacity(families.len());
+    for family in families {
+        let table = family.ops.iter().map(|op| {
+            family.types.iter().map(|ty| {
+                let name = family.pattern.replace("{op}", op).replace("{type}", ty);
+                names.contains(name.as_str()).then_some(name)
+            }).collect::<Vec<_>>()
+        }).collect::<Vec<_>>();
+        if table.iter().flatten().all(Option::is_none) {
+            return Err(format!("family {} has no stencils named like {}", family.name, family.pattern));
+        }
+        tables.push(FamilyTable { name: family.name, ops: &family.ops, types: &family.types, stencils: table });
+    }
+    Ok(tables)
+}
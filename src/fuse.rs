@@ -0,0 +1,191 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::error::Error;
+
+use crate::arch::Arch;
+use crate::dwarf::Signature;
+use crate::{Hole, Reloc, SourceLine, Stencil};
+
+// A sequence of stencils to concatenate into one superinstruction.
+pub struct Fusion<'c> {
+    name: Option<&'c str>,
+    parts: Vec<&'c str>,
+}
+
+// Fusion configs have one sequence per line, `load_const;add;store`, optionally named with
+// `name = load_const;add;store`. The default name joins the parts with `__`.
+pub fn parse_fusions(text: &str) -> Result<Vec<Fusion<'_>>, Box<dyn Error>> {
+    let mut fusions = Vec::new();
+    for (lineno, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (name, sequence) = match line.split_once('=') {
+            Some((name, sequence)) => (Some(name.trim()), sequence),
+            None => (None, line),
+        };
+        let parts = sequence.split(';').map(str::trim).collect::<Vec<_>>();
+        if parts.len() < 2 || parts.iter().any(|p| p.is_empty()) || name == Some("") {
+            return Err(format!("line {}: expected `[name =] stencil;stencil...`", lineno + 1).into());
+        }
+        fusions.push(Fusion { name, parts });
+    }
+    Ok(fusions)
+}
+
+struct FusedReloc<'a> {
+    offset: u64,
+    addend: i64,
+    hole: Hole<'a>,
+    // Index into `Fused::arg_names` when the hole was renamed to keep the parts' arguments apart.
+    renamed: Option<usize>,
+    relocation: &'static str,
+    far_call: bool,
+    continuation: bool,
+}
+
+// Owns the data of a fused stencil, `stencil()` borrows it as a regular entry.
+pub struct Fused<'a> {
+    name: String,
+    code: Vec<u8>,
+    relocs: Vec<FusedReloc<'a>>,
+    arg_names: Vec<String>,
+    terminates: bool,
+    // The deepest of the parts, which each leave their frame before the next one runs.
+    stack_size: Option<u64>,
+    literal_pool: u64,
+    fallthrough: bool,
+    // Fused stencils are entered like their first part.
+    signature: Option<Signature>,
+    lines: Vec<SourceLine>,
+    arch: Arch,
+}
+
+pub fn fuse<'a>(stencils: &[Stencil<'a>], fusions: &[Fusion]) -> Result<Vec<Fused<'a>>, Box<dyn Error>> {
+    let by_name = stencils.iter().map(|s| (s.name, s)).collect::<HashMap<_, _>>();
+    let mut fused = Vec::with_capacity(fusions.len());
+    for fusion in fusions {
+        let name = fusion.name.map_or_else(|| fusion.parts.join("__"), str::to_owned);
+        if by_name.contains_key(name.as_str()) {
+            return Err(format!("fused stencil {} clashes with an existing stencil", name).into());
+        }
+        let parts = fusion.parts.iter()
+            .map(|part| by_name.get(part).copied().ok_or_else(|| format!("{}: unknown stencil {}", name, part)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut code = Vec::new();
+        let mut relocs = Vec::new();
+        let mut arg_names = Vec::new();
+        let mut lines = Vec::new();
+        for (i, part) in parts.iter().enumerate() {
+            let last = i + 1 == parts.len();
+            if !last && !part.fallthrough {
+                return Err(format!("{}: {} doesn't end with a removable jump to cnp_stencil_output", name, part.name).into());
+            }
+            let base = code.len() as u64;
+            code.extend_from_slice(&part.code);
+            lines.extend(part.lines.iter().map(|l| SourceLine { offset: base + l.offset, ..l.clone() }));
+            // Each part gets its own copy of its argument holes, the same hole in two parts is
+            // two different values.
+            let mut renames = HashMap::new();
+            for reloc in &part.relocs {
+                let offset = base + reloc.offset;
+                if !last && reloc.hole.name == "cnp_stencil_output" {
+                    // An early exit to the next part, which now starts right after this one.
+                    bake_internal_branch(&mut code, offset, base + part.code.len() as u64 + reloc.addend as u64, reloc.relocation)
+                        .ok_or_else(|| format!("{}: can't resolve {} exit of {} at {:#x}", name, reloc.relocation, part.name, reloc.offset))?;
+                    continue;
+                }
+                let renamed = (reloc.hole.is_argument() && !reloc.hole.stencil_ref).then(|| {
+                    *renames.entry(reloc.hole.name).or_insert_with(|| {
+                        arg_names.push(format!("part{}_{}", i, reloc.hole.name));
+                        arg_names.len() - 1
+                    })
+                });
+                relocs.push(FusedReloc {
+                    offset,
+                    addend: reloc.addend,
+                    hole: reloc.hole,
+                    renamed,
+                    relocation: reloc.relocation,
+                    far_call: reloc.far_call,
+                    continuation: reloc.continuation,
+                });
+            }
+        }
+
+        let last = parts[parts.len() - 1];
+        fused.push(Fused {
+            name,
+            code,
+            relocs,
+            arg_names,
+            terminates: last.terminates,
+            stack_size: parts.iter().map(|p| p.stack_size).try_fold(0, |max, size| Some(max.max(size?))),
+            literal_pool: last.literal_pool,
+            fallthrough: last.fallthrough,
+            signature: parts[0].signature.clone(),
+            lines,
+            arch: last.arch,
+        });
+    }
+    Ok(fused)
+}
+
+// Patches a pc-relative branch whose target is `target` bytes into the code.
+pub fn bake_internal_branch(code: &mut [u8], offset: u64, target: u64, relocation: &str) -> Option<()> {
+    match relocation {
+        "X86_64_PC32" | "X86_64_PLT32" => {
+            let value = i32::try_from(target.wrapping_sub(offset) as i64).ok()?;
+            code.get_mut(offset as usize..offset as usize + 4)?.copy_from_slice(&value.to_le_bytes());
+            Some(())
+        }
+        _ => None,
+    }
+}
+
+impl<'a> Fused<'a> {
+    pub fn stencil(&self) -> Stencil<'_> {
+        let relocs = self.relocs.iter().map(|r| Reloc {
+            offset: r.offset,
+            addend: r.addend,
+            hole: Hole {
+                name: r.renamed.map_or(r.hole.name, |i| self.arg_names[i].as_str()),
+                ..r.hole
+            },
+            arg: None,
+            relocation: r.relocation,
+            far_call: r.far_call,
+            continuation: r.continuation,
+        }).collect();
+        Stencil {
+            name: &self.name,
+            display: None,
+            index: usize::MAX,
+            id: 0,
+            section: 0,
+            address: 0,
+            size: self.code.len() as u64,
+            code: Cow::Borrowed(&self.code),
+            relocs,
+            holes: Vec::new(),
+            terminates: self.terminates,
+            stack_size: self.stack_size,
+            literal_pool: self.literal_pool,
+            fallthrough: self.fallthrough,
+            taken_hole: None,
+            imm32_variant: None,
+            cold: false,
+            alias_of: None,
+            signature: self.signature.clone(),
+            lines: self.lines.clone(),
+            source_range: None,
+            doc: Vec::new(),
+            op: None,
+            types: Vec::new(),
+            unit: None,
+            arch: self.arch,
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use std::error::Error;
+use std::mem;
+
+use goblin::elf::{self, Elf};
+
+use crate::diagnostics::Category;
+use crate::{arch, fuse, Reloc, SourceLine, Stencil};
+
+// GCC splits the unlikely blocks of a function off into `foo.cold` (`foo.cold.0` before GCC 9), a
+// local function in .text.unlikely that the function jumps to and that jumps back into it. Both
+// parts are extracted, and the cold one is appended to the stencil so the branches between them
+// can be resolved like the ones within a function.
+
+// The function a cold part was split off from.
+pub fn cold_part_of(name: &str) -> Option<&str> {
+    match name.rsplit_once(".cold") {
+        Some((parent, suffix)) if !parent.is_empty() && suffix.strip_prefix('.').map_or(suffix.is_empty(), |n| n.parse::<u32>().is_ok()) => Some(parent),
+        _ => None,
+    }
+}
+
+// Where a stencil's code came from: `size` bytes at `address` in `section`, starting `offset`
+// bytes into the stencil.
+struct Part {
+    section: usize,
+    address: u64,
+    size: u64,
+    offset: u64,
+}
+
+// Appends every cold part to its stencil and drops the ones whose function isn't a stencil.
+pub fn merge_cold_parts(elf: &Elf, stencils: &mut Vec<Stencil>) -> Result<(), Box<dyn Error>> {
+    let (cold, rest) = mem::take(stencils).into_iter().partition::<Vec<_>, _>(|s| cold_part_of(s.name).is_some());
+    *stencils = rest;
+    for part in cold {
+        let Some(stencil) = stencils.iter_mut().find(|s| Some(s.name) == cold_part_of(part.name)) else {
+            continue;
+        };
+        let offset = stencil.code.len() as u64;
+        let parts = [
+            Part { section: stencil.section, address: stencil.address, size: stencil.size, offset: 0 },
+            Part { section: part.section, address: part.address, size: part.size, offset },
+        ];
+        stencil.code.to_mut().extend_from_slice(&part.code);
+        stencil.size = stencil.code.len() as u64;
+        stencil.relocs.extend(part.relocs.into_iter().map(|reloc| Reloc { offset: reloc.offset + offset, ..reloc }));
+        stencil.lines.extend(part.lines.into_iter().map(|line| SourceLine { offset: line.offset + offset, ..line }));
+        let mut relocs = Vec::with_capacity(stencil.relocs.len());
+        for reloc in mem::take(&mut stencil.relocs) {
+            let Some(target) = target(elf, &parts, &reloc) else {
+                relocs.push(reloc);
+                continue;
+            };
+            if arch::is_absolute(reloc.relocation) {
+                return Err(Category::Malformed.error(format!("{}: {} relocation at {:#x} takes the address of its own code at {:#x}, which isn't known until it's emitted",
+                    stencil.name, reloc.relocation, reloc.offset, target)));
+            }
+            let relocation = reloc.relocation.strip_prefix("R_").unwrap_or(reloc.relocation);
+            fuse::bake_internal_branch(stencil.code.to_mut(), reloc.offset, target, relocation)
+                .ok_or_else(|| Category::Malformed.error(format!("{}: can't resolve {} relocation at {:#x} between it and {}", stencil.name, reloc.relocation, reloc.offset, part.name)))?;
+        }
+        stencil.relocs = relocs;
+    }
+    Ok(())
+}
+
+// Where a reloc points if it's into one of the parts, relative to the start of the stencil. Like
+// internal_target, the addend is added to section symbols and counts back from the end of the
+// field on x86.
+fn target(elf: &Elf, parts: &[Part], reloc: &Reloc) -> Option<u64> {
+    let sym = elf.syms.get(reloc.hole.index)?;
+    let location = match sym.st_type() {
+        elf::sym::STT_SECTION => sym.st_value.wrapping_add(reloc.addend as u64),
+        elf::sym::STT_NOTYPE | elf::sym::STT_FUNC | elf::sym::STT_OBJECT => sym.st_value,
+        _ => return None,
+    };
+    let width = arch::reloc_width(reloc.relocation) as u64;
+    let part = parts.iter().find(|part| part.section == sym.st_shndx && location.wrapping_add(width).wrapping_sub(part.address) < part.size + width)?;
+    Some(sym.st_value.wrapping_add(reloc.addend as u64).wrapping_sub(part.address).wrapping_add(part.offset))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::cold_part_of;
+
+    #[test]
+    fn cold_parts() {
+        assert_eq!(cold_part_of("add.cold"), Some("add"));
+        assert_eq!(cold_part_of("add.cold.0"), Some("add"));
+        assert_eq!(cold_part_of("add.cold.12"), Some("add"));
+        assert_eq!(cold_part_of("op.cold.cold"), Some("op.cold"));
+    }
+
+    #[test]
+    fn not_cold_parts() {
+        assert_eq!(cold_part_of("add"), None);
+        assert_eq!(cold_part_of(".cold"), None);
+        assert_eq!(cold_part_of("add.cold."), None);
+        assert_eq!(cold_part_of("add.cold.x"), None);
+        assert_eq!(cold_part_of("add.colder"), None);
+        assert_eq!(cold_part_of("add.part.0"), None);
+    }
+}
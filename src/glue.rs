@@ -0,0 +1,178 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::error::Error;
+
+use crate::arch::Arch;
+use crate::Stencil;
+
+// Glue configs describe how the runtime passes values between stencils in registers. One line
+// names the `scratch = reg` register no stencil keeps a value in, the others each name a glue
+// stencil and the moves it makes, `swap = rsi<-rdi rdi<-rsi`. The moves happen all at once, as
+// if every source was read before any destination was written.
+pub struct GlueConfig<'c> {
+    scratch: Option<&'c str>,
+    glues: Vec<(&'c str, Vec<(&'c str, &'c str)>)>,
+}
+
+pub fn parse_glue(text: &str) -> Result<GlueConfig<'_>, Box<dyn Error>> {
+    let mut config = GlueConfig { scratch: None, glues: Vec::new() };
+    for (lineno, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let Some((name, rest)) = line.split_once('=').map(|(name, rest)| (name.trim(), rest.trim())) else {
+            return Err(format!("line {}: expected `scratch = reg` or `name = dst<-src...`", lineno + 1).into());
+        };
+        if name == "scratch" {
+            if config.scratch.is_some() || rest.is_empty() || rest.contains(char::is_whitespace) {
+                return Err(format!("line {}: expected one `scratch = reg`", lineno + 1).into());
+            }
+            config.scratch = Some(rest);
+            continue;
+        }
+        let moves = rest.split_whitespace().map(|m| m.split_once("<-")).collect::<Option<Vec<_>>>();
+        let Some(moves) = moves.filter(|moves| !moves.is_empty() && !name.is_empty()) else {
+            return Err(format!("line {}: expected `name = dst<-src...`", lineno + 1).into());
+        };
+        if let Some((dst, _)) = moves.iter().enumerate().find(|(i, (dst, _))| moves[..*i].iter().any(|(d, _)| d == dst)).map(|(_, m)| m) {
+            return Err(format!("line {}: {} is written twice", lineno + 1, dst).into());
+        }
+        config.glues.push((name, moves));
+    }
+    Ok(config)
+}
+
+// Owns the code of a glue stencil, `stencil()` borrows it as a regular entry.
+pub struct Glue {
+    name: String,
+    code: Vec<u8>,
+    // The moves in the order the code makes them, for the header.
+    doc: String,
+    arch: Arch,
+}
+
+pub fn synthesize(config: &GlueConfig, stencils: &[Stencil], arch: Arch) -> Result<Vec<Glue>, String> {
+    let mut glues = Vec::with_capacity(config.glues.len());
+    for (name, moves) in &config.glues {
+        if stencils.iter().any(|s| s.name == *name) {
+            return Err(format!("glue stencil {} clashes with an existing stencil", name));
+        }
+        let register = |reg: &str| register(arch, reg).ok_or_else(|| format!("{}: {} is not a register on {}", name, reg, arch.name()));
+        let mut pending = HashMap::new();
+        for (dst, src) in moves {
+            let (dst, src) = (register(dst)?, register(src)?);
+            if dst != src {
+                pending.insert(dst, src);
+            }
+        }
+        let scratch = config.scratch.map(register).transpose()?;
+        if let Some(scratch) = scratch && pending.iter().any(|(dst, src)| *dst == scratch || *src == scratch) {
+            return Err(format!("{}: moves the scratch register {}", name, config.scratch.unwrap()));
+        }
+        let ordered = order_moves(pending, scratch).ok_or_else(|| format!("{}: swaps registers, which needs a scratch register", name))?;
+        let mut code = Vec::new();
+        for (dst, src) in &ordered {
+            encode_move(arch, *dst, *src, &mut code);
+        }
+        let doc = ordered.iter().map(|(dst, src)| format!("{} <- {}", register_name(arch, *dst), register_name(arch, *src))).collect::<Vec<_>>().join(", ");
+        glues.push(Glue { name: name.to_string(), code, doc, arch });
+    }
+    Ok(glues)
+}
+
+// Sequences a parallel move. A move whose destination no other move still reads goes first;
+// when only cycles are left, one destination is saved in the scratch register to break one.
+fn order_moves(mut pending: HashMap<u8, u8>, scratch: Option<u8>) -> Option<Vec<(u8, u8)>> {
+    let mut ordered = Vec::new();
+    while !pending.is_empty() {
+        let mut free = pending.keys().copied().filter(|dst| !pending.values().any(|src| src == dst)).collect::<Vec<_>>();
+        // Registers are small integers, sorting keeps the output the same from run to run.
+        free.sort_unstable();
+        if let Some(&dst) = free.first() {
+            ordered.push((dst, pending.remove(&dst).unwrap()));
+            continue;
+        }
+        let scratch = scratch?;
+        let saved = *pending.keys().min().unwrap();
+        ordered.push((scratch, saved));
+        for src in pending.values_mut().filter(|src| **src == saved) {
+            *src = scratch;
+        }
+    }
+    Some(ordered)
+}
+
+// By register number.
+const X86: [&str; 16] = ["rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15"];
+const RISCV: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+    "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+
+fn register(arch: Arch, name: &str) -> Option<u8> {
+    let numbered = |prefix: &str, count: u8| name.strip_prefix(prefix).and_then(|n| n.parse::<u8>().ok()).filter(|n| *n < count);
+    match arch {
+        Arch::X86_64 => X86.iter().position(|r| *r == name).map(|n| n as u8),
+        // x31 is the zero register or sp depending on the instruction, neither of which holds a value.
+        Arch::AArch64 => numbered("x", 31),
+        Arch::RiscV => RISCV.iter().position(|r| *r == name).map(|n| n as u8).or_else(|| numbered("x", 32)).filter(|n| *n != 0),
+        Arch::Arm => numbered("r", 15),
+    }
+}
+
+fn register_name(arch: Arch, n: u8) -> String {
+    match arch {
+        Arch::X86_64 => X86[n as usize].to_string(),
+        Arch::AArch64 => format!("x{}", n),
+        Arch::RiscV => RISCV[n as usize].to_string(),
+        Arch::Arm => format!("r{}", n),
+    }
+}
+
+fn encode_move(arch: Arch, dst: u8, src: u8, code: &mut Vec<u8>) {
+    match arch {
+        // mov r/m64, r64
+        Arch::X86_64 => code.extend_from_slice(&[0x48 | (src >> 3) << 2 | dst >> 3, 0x89, 0xc0 | (src & 7) << 3 | (dst & 7)]),
+        // orr xd, xzr, xn
+        Arch::AArch64 => code.extend_from_slice(&(0xaa0003e0 | (src as u32) << 16 | dst as u32).to_le_bytes()),
+        // addi rd, rs, 0
+        Arch::RiscV => code.extend_from_slice(&((src as u32) << 15 | (dst as u32) << 7 | 0x13).to_le_bytes()),
+        // mov rd, rm
+        Arch::Arm => code.extend_from_slice(&(0xe1a00000 | (dst as u32) << 12 | src as u32).to_le_bytes()),
+    }
+}
+
+impl Glue {
+    pub fn stencil(&self) -> Stencil<'_> {
+        Stencil {
+            name: &self.name,
+            display: None,
+            index: usize::MAX,
+            id: 0,
+            section: 0,
+            address: 0,
+            size: self.code.len() as u64,
+            code: Cow::Borrowed(&self.code),
+            relocs: Vec::new(),
+            holes: Vec::new(),
+            terminates: false,
+            stack_size: Some(0),
+            literal_pool: 0,
+            // Glue never jumps, the next stencil goes right after it.
+            fallthrough: true,
+            taken_hole: None,
+            imm32_variant: None,
+            cold: false,
+            alias_of: None,
+            signature: None,
+            lines: Vec::new(),
+            source_range: None,
+            doc: vec![format!("Glue: {}.", if self.doc.is_empty() { "moves nothing" } else { &self.doc })],
+            op: None,
+            types: Vec::new(),
+            unit: None,
+            arch: self.arch,
+        }
+    }
+}
@@ -0,0 +1,228 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+
+use crate::arch;
+use crate::{Hole, Stencil};
+
+// Only the low `bits` of a hole's value, moved up by `shift`, go into its field. The rest of the
+// field is whatever the addend puts there, typically the tag of a NaN-boxed value.
+#[derive(serde::Serialize, Clone, Copy, PartialEq, Eq)]
+pub struct Payload {
+    bits: u32,
+    shift: u32,
+    // The low `bits` bits, for the templates.
+    mask: u64,
+}
+
+// An expression of `value` the hole's value is replaced with before patching. It is C and Rust
+// alike: integers, `value`, other identifiers for variables the runtime defines, parentheses and
+// the arithmetic and bitwise operators, with `~` for not.
+#[derive(serde::Serialize, Clone, Copy, PartialEq, Eq)]
+pub struct Transform<'c> {
+    // Numbers the distinct expressions of the config from 1, for cnp_reloc.flags.
+    number: u32,
+    expr: &'c str,
+}
+
+impl Payload {
+    pub fn new(bits: u32, shift: u32) -> Payload {
+        Payload { bits, shift, mask: u64::MAX >> (64 - bits) }
+    }
+
+    // What goes into the field for `value`, before the addend.
+    pub fn insert(&self, value: u64) -> u64 {
+        (value & self.mask) << self.shift
+    }
+}
+
+impl<'c> Transform<'c> {
+    pub fn new(number: u32, expr: &'c str) -> Transform<'c> {
+        Transform { number, expr }
+    }
+
+    pub fn number(&self) -> u32 {
+        self.number
+    }
+}
+
+// Bits 8-15, 16-23 and 24-31 of cnp_reloc.flags, next to the other flags.
+pub fn flags(payload: Option<Payload>, transform: Option<Transform>) -> u32 {
+    payload.map_or(0, |p| p.bits << 8 | p.shift << 16) | transform.map_or(0, |t| t.number << 24)
+}
+
+struct HoleSettings<'c> {
+    pattern: &'c str,
+    payload: Option<Payload>,
+    transform: Option<Transform<'c>>,
+}
+
+// Settings for argument holes. Hole configs have one hole per line, by name or by a prefix ending
+// in `*`, followed by `key=value` settings and optionally `: expression`, as in
+// `cnp_large_value_hole_boxed* bits=48 : value - heap_base`. `bits` is the width of the payload,
+// `shift` where it starts in the field, and the expression is applied first. The first line
+// matching a hole applies.
+pub struct HoleConfig<'c> {
+    holes: Vec<HoleSettings<'c>>,
+}
+
+pub fn parse_holes(text: &str) -> Result<HoleConfig<'_>, Box<dyn Error>> {
+    let mut config = HoleConfig { holes: Vec::new() };
+    let mut exprs = Vec::new();
+    for (lineno, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (line, expr) = match line.split_once(':') {
+            Some((line, expr)) => (line, Some(expr.trim())),
+            None => (line, None),
+        };
+        let mut words = line.split_whitespace();
+        let pattern = words.next().filter(|p| !p[..p.len() - 1].contains('*'))
+            .ok_or_else(|| format!("line {}: expected `hole` or `prefix*`", lineno + 1))?;
+        let (mut bits, mut shift) = (None, 0);
+        for setting in words {
+            let parsed = setting.split_once('=').and_then(|(key, value)| Some((key, value.parse::<u32>().ok()?)));
+            match parsed {
+                Some(("bits", value)) if (1..=64).contains(&value) => bits = Some(value),
+                Some(("shift", value)) if value < 64 => shift = value,
+                _ => return Err(format!("line {}: expected `bits=1..64` or `shift=0..63`, not {}", lineno + 1, setting).into()),
+            }
+        }
+        if bits.is_none() && shift != 0 {
+            return Err(format!("line {}: shift needs bits", lineno + 1).into());
+        }
+        let payload = bits.map(|bits| Payload::new(bits, shift));
+        let transform = expr.map(|expr| {
+            check_expr(expr).map_err(|e| format!("line {}: {}", lineno + 1, e))?;
+            let number = match exprs.iter().position(|e| *e == expr) {
+                Some(i) => i,
+                None => {
+                    exprs.push(expr);
+                    exprs.len() - 1
+                }
+            };
+            if number >= u8::MAX as usize {
+                return Err(format!("line {}: more than {} different transforms", lineno + 1, u8::MAX));
+            }
+            Ok(Transform::new(number as u32 + 1, expr))
+        }).transpose()?;
+        config.holes.push(HoleSettings { pattern, payload, transform });
+    }
+    Ok(config)
+}
+
+// Splits an expression into integers, identifiers and operators.
+fn tokens(expr: &str) -> Result<Vec<&str>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = expr.trim_start();
+    while !rest.is_empty() {
+        let len = match rest.as_bytes()[0] {
+            c if c.is_ascii_alphanumeric() || c == b'_' => rest.find(|c: char| !c.is_ascii_alphanumeric() && c != '_').unwrap_or(rest.len()),
+            b'<' | b'>' if rest[1..].starts_with(&rest[..1]) => 2,
+            b'+' | b'-' | b'*' | b'/' | b'%' | b'&' | b'|' | b'^' | b'~' | b'(' | b')' => 1,
+            _ => return Err(format!("unexpected {} in the transform", rest.chars().next().unwrap())),
+        };
+        tokens.push(&rest[..len]);
+        rest = rest[len..].trim_start();
+    }
+    Ok(tokens)
+}
+
+// Checks the expression is one the patch functions can evaluate, operands and operators taking
+// turns with balanced parentheses.
+fn check_expr(expr: &str) -> Result<(), String> {
+    let mut depth = 0;
+    let mut operand = true;
+    for token in tokens(expr)? {
+        let word = token.starts_with(|c: char| c.is_ascii_alphanumeric() || c == '_');
+        match token {
+            _ if word && operand => {
+                if token.starts_with(|c: char| c.is_ascii_digit()) && parse_int(token).is_none() {
+                    return Err(format!("{} is not an integer", token));
+                }
+                operand = false;
+            }
+            "(" | "~" if operand => depth += (token == "(") as i32,
+            ")" if !operand && depth > 0 => depth -= 1,
+            _ if !operand && !word && token != "(" && token != "~" => operand = true,
+            _ => return Err(format!("unexpected {} in the transform", token)),
+        }
+    }
+    if operand || depth != 0 {
+        return Err("the transform is incomplete".to_string());
+    }
+    Ok(())
+}
+
+fn parse_int(token: &str) -> Option<u64> {
+    match token.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => token.parse().ok(),
+    }
+}
+
+// The transforms the stencils' holes use, by number.
+pub fn transforms<'c>(stencils: &[Stencil<'c>]) -> Vec<Transform<'c>> {
+    let transforms = stencils.iter()
+        .flat_map(|s| s.relocs.iter().filter_map(|r| r.hole.transform))
+        .map(|t| (t.number, t))
+        .collect::<BTreeMap<_, _>>();
+    transforms.into_values().collect()
+}
+
+// The runtime variables the transforms use, which the header declares.
+pub fn variables<'c>(transforms: &[Transform<'c>]) -> BTreeSet<&'c str> {
+    transforms.iter()
+        .flat_map(|t| tokens(t.expr).unwrap_or_default())
+        .filter(|token| token.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') && *token != "value")
+        .collect()
+}
+
+// Every hole the stencils patch, once each and in name order, so its position only changes when
+// holes are added or removed. Stencil references are numbered as stencils instead.
+pub fn registry<'s, 'c>(stencils: &'s [Stencil<'c>]) -> Vec<&'s Hole<'c>> {
+    let holes = stencils.iter()
+        .flat_map(|s| s.relocs.iter().map(|r| &r.hole))
+        .filter(|h| !h.stencil_ref)
+        .map(|h| (h.name, h))
+        .collect::<BTreeMap<_, _>>();
+    holes.into_values().collect()
+}
+
+impl<'c> HoleConfig<'c> {
+    fn settings(&self, name: &str) -> Option<&HoleSettings<'c>> {
+        self.holes.iter().find(|h| match h.pattern.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix),
+            None => h.pattern == name,
+        })
+    }
+}
+
+// Applies the config to every argument hole of the stencils, checking each payload fits the
+// field of every reloc patching it.
+pub fn apply<'c>(config: &HoleConfig<'c>, stencils: &mut [Stencil<'c>]) -> Result<(), String> {
+    for stencil in stencils {
+        for reloc in stencil.relocs.iter_mut().filter(|r| r.hole.is_argument() && !r.hole.stencil_ref) {
+            let Some(settings) = config.settings(reloc.hole.name) else {
+                continue;
+            };
+            if let Some(payload) = settings.payload {
+                let width = arch::reloc_width(reloc.relocation) as u32 * 8;
+                if !arch::is_absolute(reloc.relocation) || payload.bits + payload.shift > width {
+                    return Err(format!("{}: {} is patched by {} at {:#x}, which has no room for a {}-bit payload at bit {}",
+                        stencil.name, reloc.hole.name, reloc.relocation, reloc.offset, payload.bits, payload.shift));
+                }
+            }
+            reloc.hole.payload = settings.payload;
+            reloc.hole.transform = settings.transform;
+        }
+        for hole in stencil.holes.iter_mut() {
+            if let Some(reloc) = stencil.relocs.iter().find(|r| r.hole.name == hole.name) {
+                hole.payload = reloc.hole.payload;
+                hole.transform = reloc.hole.transform;
+            }
+        }
+    }
+    Ok(())
+}
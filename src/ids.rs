@@ -0,0 +1,49 @@
+use std::collections::{BTreeMap, HashSet};
+use std::error::Error;
+use std::fmt::Write;
+
+// Stencil IDs from an ID file, which has one `name id` per line. Names that are no longer
+// stencils keep their line so their IDs are never handed out again.
+pub fn parse_ids(text: &str) -> Result<BTreeMap<String, usize>, Box<dyn Error>> {
+    let mut ids = BTreeMap::new();
+    let mut used = HashSet::new();
+    for (lineno, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let parsed = line.split_once(char::is_whitespace)
+            .and_then(|(name, id)| Some((name, id.trim().parse::<usize>().ok()?)));
+        let Some((name, id)) = parsed else {
+            return Err(format!("line {}: expected `name id`", lineno + 1).into());
+        };
+        if !used.insert(id) {
+            return Err(format!("line {}: id {} is used twice", lineno + 1, id).into());
+        }
+        if ids.insert(name.to_string(), id).is_some() {
+            return Err(format!("line {}: {} is listed twice", lineno + 1, name).into());
+        }
+    }
+    Ok(ids)
+}
+
+// Gives each of `names` without an ID the next one after every ID ever assigned, in order.
+pub fn assign_ids<'n>(ids: &mut BTreeMap<String, usize>, names: impl IntoIterator<Item = &'n str>) {
+    let mut next = ids.values().max().map_or(0, |max| max + 1);
+    for name in names {
+        if !ids.contains_key(name) {
+            ids.insert(name.to_string(), next);
+            next += 1;
+        }
+    }
+}
+
+pub fn format_ids(ids: &BTreeMap<String, usize>) -> String {
+    let mut by_id = ids.iter().collect::<Vec<_>>();
+    by_id.sort_by_key(|(_, id)| **id);
+    let mut out = String::from("# Stencil IDs, maintained by stenciltool.\n");
+    for (name, id) in by_id {
+        let _ = writeln!(out, "{} {}", name, id);
+    }
+    out
+}
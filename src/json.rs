@@ -0,0 +1,146 @@
+// A JSON reader for the few JSON files stenciltool reads, which only need strings, arrays and
+// objects out of them.
+
+// Parses a whole JSON document.
+pub fn parse(text: &str) -> Result<Json, String> {
+    let mut parser = Parser { text: text.as_bytes(), pos: 0 };
+    let value = parser.value()?;
+    parser.skip_space();
+    if parser.pos != text.len() {
+        return Err(parser.error("trailing characters"));
+    }
+    Ok(value)
+}
+
+#[derive(Clone)]
+pub enum Json {
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+    // Numbers, booleans and null, which nothing needs yet.
+    Other,
+}
+
+impl Json {
+    pub fn field(&self, name: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields.iter().find(|(key, _)| key == name).map(|(_, value)| value),
+            _ => None,
+        }
+    }
+}
+
+struct Parser<'t> {
+    text: &'t [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, message: &str) -> String {
+        let line = self.text[..self.pos.min(self.text.len())].iter().filter(|&&b| b == b'\n').count() + 1;
+        format!("line {}: {}", line, message)
+    }
+
+    fn skip_space(&mut self) {
+        while self.text.get(self.pos).is_some_and(|b| b.is_ascii_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    fn eat(&mut self, byte: u8) -> bool {
+        self.skip_space();
+        let found = self.text.get(self.pos) == Some(&byte);
+        self.pos += found as usize;
+        found
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), String> {
+        match self.eat(byte) {
+            true => Ok(()),
+            false => Err(self.error(&format!("expected `{}`", byte as char))),
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.skip_space();
+        match self.text.get(self.pos) {
+            Some(b'"') => Ok(Json::String(self.string()?)),
+            Some(b'[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                if !self.eat(b']') {
+                    loop {
+                        items.push(self.value()?);
+                        if self.eat(b']') {
+                            break;
+                        }
+                        self.expect(b',')?;
+                    }
+                }
+                Ok(Json::Array(items))
+            }
+            Some(b'{') => {
+                self.pos += 1;
+                let mut fields = Vec::new();
+                if !self.eat(b'}') {
+                    loop {
+                        self.skip_space();
+                        let key = self.string()?;
+                        self.expect(b':')?;
+                        fields.push((key, self.value()?));
+                        if self.eat(b'}') {
+                            break;
+                        }
+                        self.expect(b',')?;
+                    }
+                }
+                Ok(Json::Object(fields))
+            }
+            Some(b) if b.is_ascii_alphanumeric() || *b == b'-' => {
+                while self.text.get(self.pos).is_some_and(|b| b.is_ascii_alphanumeric() || b"+-.".contains(b)) {
+                    self.pos += 1;
+                }
+                Ok(Json::Other)
+            }
+            _ => Err(self.error("expected a value")),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        if self.text.get(self.pos) != Some(&b'"') {
+            return Err(self.error("expected a string"));
+        }
+        self.pos += 1;
+        let mut bytes = Vec::new();
+        loop {
+            let Some(&b) = self.text.get(self.pos) else {
+                return Err(self.error("unterminated string"));
+            };
+            self.pos += 1;
+            match b {
+                b'"' => break,
+                b'\\' => {
+                    let escape = self.text.get(self.pos).copied();
+                    self.pos += 1;
+                    match escape {
+                        Some(b'n') => bytes.push(b'\n'),
+                        Some(b't') => bytes.push(b'\t'),
+                        Some(b'r') => bytes.push(b'\r'),
+                        Some(b'u') => {
+                            let code = self.text.get(self.pos..self.pos + 4)
+                                .and_then(|hex| u32::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok())
+                                .and_then(char::from_u32)
+                                .ok_or_else(|| self.error("bad \\u escape"))?;
+                            self.pos += 4;
+                            bytes.extend_from_slice(code.encode_utf8(&mut [0; 4]).as_bytes());
+                        }
+                        Some(b @ (b'"' | b'\\' | b'/')) => bytes.push(b),
+                        _ => return Err(self.error("bad escape")),
+                    }
+                }
+                _ => bytes.push(b),
+            }
+        }
+        String::from_utf8(bytes).map_err(|_| self.error("string isn't UTF-8"))
+    }
+}
@@ -0,0 +1,139 @@
+// Minimal LZ4 block compressor (greedy, one hash probe per position), enough to shrink the code
+// blob. The generated source carries its own decompressor, so only the block format matters.
+
+const MIN_MATCH: usize = 4;
+// The format wants the last 5 bytes as literals and no match starting in the last 12.
+const LAST_LITERALS: usize = 5;
+const MATCH_LIMIT: usize = 12;
+const HASH_BITS: u32 = 12;
+
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut table = vec![usize::MAX; 1 << HASH_BITS];
+    let mut anchor = 0;
+    let mut pos = 0;
+    while pos + MATCH_LIMIT < input.len() {
+        let word = u32::from_le_bytes(input[pos..pos + 4].try_into().unwrap());
+        let hash = (word.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize;
+        let candidate = std::mem::replace(&mut table[hash], pos);
+        if candidate == usize::MAX || pos - candidate > u16::MAX as usize || input[candidate..candidate + 4] != input[pos..pos + 4] {
+            pos += 1;
+            continue;
+        }
+        let mut len = MIN_MATCH;
+        while pos + len < input.len() - LAST_LITERALS && input[candidate + len] == input[pos + len] {
+            len += 1;
+        }
+        push_sequence(&mut out, &input[anchor..pos], Some((pos - candidate, len)));
+        pos += len;
+        anchor = pos;
+    }
+    push_sequence(&mut out, &input[anchor..], None);
+    out
+}
+
+fn push_sequence(out: &mut Vec<u8>, literals: &[u8], matched: Option<(usize, usize)>) {
+    let match_len = matched.map_or(0, |(_, len)| len - MIN_MATCH);
+    out.push((literals.len().min(15) as u8) << 4 | match_len.min(15) as u8);
+    push_length(out, literals.len());
+    out.extend_from_slice(literals);
+    if let Some((offset, _)) = matched {
+        out.extend_from_slice(&(offset as u16).to_le_bytes());
+        push_length(out, match_len);
+    }
+}
+
+// Lengths of 15 and up continue in bytes after the token, 255 meaning another byte follows.
+fn push_length(out: &mut Vec<u8>, len: usize) {
+    if len < 15 {
+        return;
+    }
+    let mut rest = len - 15;
+    while rest >= 255 {
+        out.push(255);
+        rest -= 255;
+    }
+    out.push(rest as u8);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compress, LAST_LITERALS};
+
+    // The generated source's cnp_decompress_code_blob, checking the format's end conditions.
+    fn decompress(mut src: &[u8]) -> Vec<u8> {
+        let mut dst = Vec::new();
+        let length = |src: &mut &[u8], mut len: usize| {
+            if len == 15 {
+                while let Some((&more, rest)) = src.split_first() {
+                    *src = rest;
+                    len += more as usize;
+                    if more != 255 {
+                        break;
+                    }
+                }
+            }
+            len
+        };
+        loop {
+            let token = src[0];
+            src = &src[1..];
+            let len = length(&mut src, (token >> 4) as usize);
+            dst.extend_from_slice(&src[..len]);
+            src = &src[len..];
+            if src.is_empty() {
+                assert_eq!(token & 15, 0, "the last sequence has no match");
+                assert!(len >= LAST_LITERALS || len == dst.len(), "the block ends in {} literals", LAST_LITERALS);
+                return dst;
+            }
+            let offset = u16::from_le_bytes([src[0], src[1]]) as usize;
+            src = &src[2..];
+            assert!(offset > 0 && offset <= dst.len(), "offset {} out of {}", offset, dst.len());
+            let len = length(&mut src, (token & 15) as usize) + 4;
+            for _ in 0..len {
+                dst.push(dst[dst.len() - offset]);
+            }
+        }
+    }
+
+    fn round_trip(input: &[u8]) -> Vec<u8> {
+        let compressed = compress(input);
+        assert_eq!(decompress(&compressed), input);
+        compressed
+    }
+
+    #[test]
+    fn overlapping_matches() {
+        // One literal, then a match 1 back longer than its offset, and the last 5 as literals.
+        assert_eq!(round_trip(&[b'a'; 20]), [0x1a, b'a', 1, 0, 0x50, b'a', b'a', b'a', b'a', b'a']);
+        assert!(round_trip(&[b'a'; 10_000]).len() < 100);
+        assert!(round_trip(&b"abc".repeat(1000)).len() < 100);
+    }
+
+    #[test]
+    fn literals_only() {
+        for len in [0, 1, 12, 13, 14, 15, 16, 269, 270, 271, 600] {
+            let input = (0..len).map(|i| (i * 7) as u8).collect::<Vec<_>>();
+            round_trip(&input);
+        }
+    }
+
+    #[test]
+    fn code_like() {
+        // Pseudo-random bytes that don't compress, with repeated instruction sequences among them.
+        let mut state = 0x2545f491u32;
+        let mut input = Vec::new();
+        for i in 0..2000 {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            input.extend_from_slice(&state.to_le_bytes());
+            if i % 7 == 0 {
+                input.extend_from_slice(&[0x48, 0x8b, 0x05, 0, 0, 0, 0, 0x48, 0x89, 0x07, 0xc3]);
+            }
+        }
+        let compressed = round_trip(&input);
+        assert!(compressed.len() < input.len());
+        round_trip(&input[..input.len() / 3]);
+    }
+}
@@ -1,202 +1,2501 @@
-use std::{env, fs};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::io::IsTerminal;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitCode};
 use std::error::Error;
-use goblin::{elf, Object};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use goblin::{elf, elf::Elf, Object};
 use minijinja::{Environment, context};
-use serde;
-use clap::Parser;
+use clap::{CommandFactory, Parser, ValueEnum};
 
-#[derive(serde::Serialize)]
+mod abi;
+mod archive;
+mod arch;
+mod cache;
+mod coff;
+mod compile;
+mod completions;
+mod demangle;
+mod depfile;
+mod diagnostics;
+mod dwarf;
+mod emulate;
+mod externs;
+mod families;
+mod fuse;
+mod gcc;
+mod glue;
+mod holes;
+mod ids;
+mod json;
+mod lz4;
+mod manifest;
+mod merge;
+mod naming;
+mod object;
+mod output;
+mod profile;
+mod progress;
+mod provenance;
+mod rename;
+mod report;
+mod sha256;
+mod split;
+mod symver;
+mod variants;
+mod x86;
+
+use arch::Arch;
+use cache::{Cache, KeyBuilder};
+use diagnostics::{Category, Severity};
+use output::{write_output, STDOUT};
+use progress::Progress;
+
+#[derive(serde::Serialize, Clone, Copy)]
 struct Hole<'a> {
     name: &'a str,
     index: usize,
     datatype: &'static str,
+    // Argument type of the hole in the emit functions, which take addresses rather than raw patch bytes.
+    value_datatype: &'static str,
     internal: bool,
+    // Refers to another stencil from the same object, `name` is the callee and the runtime
+    // supplies the address it emitted the callee at.
+    stencil_ref: bool,
+    // Set by --holes when only part of the value goes into the field.
+    payload: Option<holes::Payload>,
+    // Set by --holes when the value is patched in as an expression of it.
+    transform: Option<holes::Transform<'a>>,
+    // The GNU symbol version an external hole is bound to, GLIBC_2.14 for `memcpy@GLIBC_2.14`.
+    version: Option<&'a str>,
+}
+
+impl Hole<'_> {
+    fn is_argument(&self) -> bool {
+        self.internal && self.name != "cnp_stencil_output"
+    }
 }
 
 #[derive(serde::Serialize)]
 struct Reloc<'a> {
     offset: u64,
     addend: i64,
-    hole: &'a Hole<'a>,
+    hole: Hole<'a>,
+    // Position of the hole in the stencil's patch arguments, if the caller supplies its value.
+    arg: Option<usize>,
     relocation: &'static str,
+    // A rel32 call or jump to a function hole, which can be routed through a trampoline when
+    // the target ends up out of range.
+    far_call: bool,
+    // A jump to the next stencil or to one the runtime emitted elsewhere, rather than a call or
+    // a data reference.
+    continuation: bool,
+}
+
+impl Reloc<'_> {
+    // Number of bytes patched at `offset`.
+    fn width(&self) -> usize {
+        arch::reloc_width(self.relocation)
+    }
 }
 
 #[derive(serde::Serialize)]
 struct Stencil<'a> {
     name: &'a str,
+    // The demangled symbol when `name` was derived from a mangled one.
+    display: Option<&'a str>,
+    // Symbol index in its object.
+    index: usize,
+    // Value of the stencil's cnp_stencil_id, its position in --sort order unless --ids keeps
+    // them stable.
+    id: usize,
+    // Index of the section holding its code, which `address` is relative to.
+    #[serde(skip)]
+    section: usize,
     address: u64,
     size: u64,
-    code: &'a [u8],
+    code: Cow<'a, [u8]>,
     relocs: Vec<Reloc<'a>>,
-    holes: Vec<&'a Hole<'a>>,
+    holes: Vec<Hole<'a>>,
+    // Ends by returning rather than continuing to the next stencil.
+    terminates: bool,
+    // Deepest the stencil takes the stack below its entry stack pointer, or None if that depends
+    // on runtime values. Chained stencils leave their frame before jumping on, so this is per stencil.
+    stack_size: Option<u64>,
+    // Bytes of literal pool after the function's own code, which the trimming passes must keep.
+    literal_pool: u64,
+    // Its trailing jump to cnp_stencil_output was removed, so it runs into whatever is emitted next.
+    fallthrough: bool,
+    // For a conditional branch, the argument hole it continues at when taken. It continues at
+    // cnp_stencil_output, or falls through, otherwise.
+    taken_hole: Option<&'a str>,
+    // A variant taking 32-bit immediates for some of this stencil's 64-bit value holes.
+    imm32_variant: Option<Imm32Variant<'a>>,
+    // The --profile saw it copied fewer than --cold-below times, so it goes in the cold sections.
+    cold: bool,
+    // An earlier stencil with identical code and relocations whose data this one shares.
+    alias_of: Option<&'a str>,
+    // The function's C prototype from its debug info, which documents what the runtime passes in
+    // registers.
+    signature: Option<dwarf::Signature>,
+    // Where the code came from according to the line table, for the dump.
+    #[serde(skip)]
+    lines: Vec<SourceLine>,
+    // The primary source file and the range of its lines the stencil covers, `ops.c:12-18`.
+    source_range: Option<String>,
+    // The comment above the function in its source, without comment markers.
+    doc: Vec<String>,
+    // The op and types --naming reads out of its name, `add` and `[i64, i32]` for `add_i64_i32`.
+    op: Option<&'a str>,
+    types: Vec<&'a str>,
+    // The --split header it's declared in, None for --header.
+    unit: Option<&'a str>,
+    #[serde(skip)]
+    arch: Arch,
 }
 
-fn read_elf1<'a>(data: &'a Vec<u8>, stencils: &mut Vec<Stencil<'a>>, holes: &mut Vec<Hole<'a>>) -> Result<(), Box<dyn Error>> {
-    let object = Object::parse(&*data)?;
-    let elf = match object {
-        Object::Elf(x) => x,
-        _ => unreachable!("object file is not elf"),
-    };
-    let (_, text) = elf.section_headers.iter().enumerate().find(|(_, shdr)| {
-        let name = elf.shdr_strtab.get_at(shdr.sh_name);
-        name == Some(".text")
-    })
-    .expect("No .text segment");
-    
-    for (index, symbol) in elf.syms.iter().enumerate() {
-        if symbol.st_bind() != elf::sym::STB_GLOBAL ||
-           symbol.st_type() != elf::sym::STT_FUNC {
-            let name = elf.strtab.get_at(symbol.st_name).ok_or("symbol missing in strtab")?;
-            let datatype_opt = match name {
-                name if name.starts_with("cnp_large_value_hole") => Some("uint64_t"),
-                name if name.starts_with("cnp_small_value_hole") => Some("uint32_t"),
-                name if name.starts_with("cnp_near_func_hole") => Some("uint32_t"),
-                name if name.starts_with("cnp_far_fun_hole") => Some("void*"),
-                name if name == "cnp_stencil_output" => Some("uint32_t"),
-                _ => None,
+#[derive(Clone)]
+struct SourceLine {
+    offset: u64,
+    file: String,
+    line: u64,
+}
+
+impl Stencil<'_> {
+    fn truncate_code(&mut self, end: usize) {
+        match &mut self.code {
+            Cow::Borrowed(code) => *code = &code[..end],
+            Cow::Owned(code) => code.truncate(end),
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+struct Imm32Variant<'a> {
+    name: &'a str,
+    // For each patch argument, the range check ("imm32" or "imm32s") its value must pass for the
+    // variant to be usable, or None if both stencils take it unchanged.
+    checks: Vec<Option<&'static str>>,
+}
+
+// Whether `symbol` is a function to extract as a stencil. Local (static) functions are only
+// stencils if --include-local-functions was given and they start with its prefix, --visibility
+// only filters the others. Skipped definitions are left to the runtime's linker like any other
+// external symbol.
+fn is_stencil_symbol(symbol: &elf::Sym, name: &str, args: &ReadArgs, only: Option<&HashSet<&str>>) -> bool {
+    if only.is_some_and(|only| !only.contains(name)) {
+        return false;
+    }
+    let visible = match args.visibility {
+        Visibility::All => true,
+        Visibility::Default => matches!(symbol.st_visibility(), elf::sym::STV_DEFAULT | elf::sym::STV_PROTECTED),
+        Visibility::Hidden => matches!(symbol.st_visibility(), elf::sym::STV_HIDDEN | elf::sym::STV_INTERNAL),
+    };
+    symbol.st_type() == elf::sym::STT_FUNC && match symbol.st_bind() {
+        elf::sym::STB_GLOBAL => visible,
+        elf::sym::STB_LOCAL => args.include_local_functions.as_deref().is_some_and(|prefix| name.starts_with(prefix)),
+        elf::sym::STB_WEAK => symbol.st_shndx != elf::section_header::SHN_UNDEF as usize && args.weak_functions == WeakPolicy::Stencil && visible,
+        _ => false,
+    }
+}
+
+// Rejects symbols that can't be handled either way before anything is extracted.
+fn check_symbols(elf: &Elf, path: &str, args: &ReadArgs) -> Result<(), Box<dyn Error>> {
+    for symbol in elf.syms.iter().filter(|s| s.st_shndx != elf::section_header::SHN_UNDEF as usize) {
+        let name = elf.strtab.get_at(symbol.st_name).unwrap_or("");
+        if symbol.st_type() == elf::sym::STT_GNU_IFUNC {
+            return Err(format!("{} is a GNU ifunc, which is resolved by the dynamic loader and can't be a stencil", name).into());
+        }
+        if symbol.st_type() == elf::sym::STT_FUNC && symbol.st_bind() == elf::sym::STB_WEAK && args.weak_functions == WeakPolicy::Skip {
+            diagnostics::report(args.diagnostics_format, Severity::Warning, Some(path),
+                &format!("skipping weak function {}, references to it are left external", name));
+        }
+    }
+    // Stencils are found by their function symbols in whatever code section they're in, so code
+    // that has none, hand written assembly without `.type`, is silently left out.
+    for (index, shdr) in elf.section_headers.iter().enumerate() {
+        let code = shdr.sh_type == elf::section_header::SHT_PROGBITS && shdr.sh_flags & elf::section_header::SHF_EXECINSTR as u64 != 0;
+        if code && shdr.sh_size > 0 && !elf.syms.iter().any(|s| s.st_shndx == index && s.st_type() == elf::sym::STT_FUNC) {
+            let name = elf.shdr_strtab.get_at(shdr.sh_name).unwrap_or("?");
+            diagnostics::report(args.diagnostics_format, Severity::Warning, Some(path),
+                &format!("{} has {} bytes of code but no functions, mark them with `.type name, @function`", name, shdr.sh_size));
+        }
+    }
+    Ok(())
+}
+
+// Static constructors and destructors are run by the loader or libc, which never sees stencil
+// code, so anything that depends on them would silently use uninitialized state.
+fn check_static_init(elf: &Elf) -> Result<(), Box<dyn Error>> {
+    for (index, shdr) in elf.section_headers.iter().enumerate() {
+        let name = elf.shdr_strtab.get_at(shdr.sh_name).unwrap_or("?");
+        let when = match shdr.sh_type {
+            elf::section_header::SHT_INIT_ARRAY | elf::section_header::SHT_PREINIT_ARRAY => "at load time",
+            elf::section_header::SHT_FINI_ARRAY => "at exit",
+            _ if name.starts_with(".ctors") => "at load time",
+            _ if name.starts_with(".dtors") => "at exit",
+            _ => continue,
+        };
+        // The entries are relocated against the functions they run, or their section plus an offset.
+        let relocs = elf.shdr_relocs.iter().filter(|(reloc_index, _)| elf.section_headers[*reloc_index].sh_info as usize == index);
+        let culprit = relocs.flat_map(|(_, relocs)| relocs.iter()).find_map(|reloc| {
+            let sym = elf.syms.get(reloc.r_sym)?;
+            let sym = match sym.st_type() {
+                elf::sym::STT_SECTION => elf.syms.iter().find(|s| s.st_type() == elf::sym::STT_FUNC && s.st_shndx == sym.st_shndx &&
+                    s.st_value == reloc.r_addend.unwrap_or(0) as u64)?,
+                _ => sym,
             };
-            if let Some(datatype) = datatype_opt {
+            elf.strtab.get_at(sym.st_name).filter(|name| !name.is_empty())
+        });
+        return Err(match culprit {
+            Some(function) => format!("{} runs {} {}, stencils can't have static constructors or destructors", name, function, when),
+            None => format!("{} runs code {}, stencils can't have static constructors or destructors", name, when),
+        }.into());
+    }
+    // Function-local statics with dynamic initializers are guarded by a _ZGV variable, and
+    // initialized through __cxa_guard_acquire the first time control passes through them.
+    for symbol in elf.syms.iter() {
+        let name = elf.strtab.get_at(symbol.st_name).unwrap_or("");
+        if name.starts_with("_ZGV") || name == "__cxa_guard_acquire" {
+            return Err(format!("{}: stencils can't have function-local statics with dynamic initializers", name).into());
+        }
+    }
+    Ok(())
+}
+
+// The contents of a section, which a truncated file may not have.
+fn section_data<'a>(data: &'a [u8], shdr: &elf::SectionHeader, name: &str) -> Result<&'a [u8], Box<dyn Error>> {
+    shdr.sh_offset.checked_add(shdr.sh_size)
+        .and_then(|end| data.get(shdr.sh_offset as usize..end as usize))
+        .ok_or_else(|| Category::Malformed.error(format!("{} ({:#x} bytes at {:#x}) is past the end of the file", name, shdr.sh_size, shdr.sh_offset)))
+}
+
+// The patch and emit function argument types of a hole the runtime supplies the value of, by the
+// naming convention of the hole symbols, or None for a symbol it links against.
+fn hole_datatypes(name: &str) -> Option<(&'static str, &'static str)> {
+    match name {
+        name if name.starts_with("cnp_large_value_hole") => Some(("uint64_t", "uint64_t")),
+        name if name.starts_with("cnp_small_value_hole") => Some(("uint32_t", "uint32_t")),
+        name if name.starts_with("cnp_near_func_hole") => Some(("uint32_t", "void*")),
+        name if name.starts_with("cnp_far_fun_hole") => Some(("void*", "void*")),
+        "cnp_stencil_output" => Some(("uint32_t", "void*")),
+        _ => None,
+    }
+}
+
+fn read_elf1<'a>(elf: &Elf<'a>, data: &'a [u8], arch: Arch, args: &ReadArgs, only: Option<&HashSet<&str>>, stencils: &mut Vec<Stencil<'a>>, holes: &mut Vec<Hole<'a>>) -> Result<(), Box<dyn Error>> {
+    // Nearly every symbol becomes either a stencil or a hole.
+    holes.reserve(elf.syms.len());
+    for (index, symbol) in elf.syms.iter().enumerate() {
+        let name = elf.strtab.get_at(symbol.st_name).ok_or_else(|| Category::Malformed.error(format!("symbol {} has no name in the string table", index)))?;
+        let (name, version) = symver::split(name);
+        // A versioned definition is the `.symver` alias of a function extracted under its own name.
+        let cold_part = symbol.st_type() == elf::sym::STT_FUNC && gcc::cold_part_of(name).is_some();
+        if version.is_some() || !(cold_part || is_stencil_symbol(&symbol, name, args, only)) {
+            if let Some((datatype, value_datatype)) = hole_datatypes(name) {
                 holes.push(Hole {
-                    name: name,
-                    index: index,
-                    datatype: datatype,
+                    name,
+                    index,
+                    datatype,
+                    value_datatype,
                     internal: true,
+                    stencil_ref: false,
+                    payload: None,
+                    transform: None,
+                    version,
                 });
             } else {
                 holes.push(Hole {
-                    name: name,
-                    index: index,
+                    name,
+                    index,
                     datatype: "void*",
+                    value_datatype: "void*",
                     internal: false,
+                    stencil_ref: false,
+                    payload: None,
+                    transform: None,
+                    version,
                 });
             }
             continue
         }
-        let name = elf.strtab.get_at(symbol.st_name).ok_or("symbol missing in strtab")?;
-        let start = (text.sh_offset + symbol.st_value) as usize;
-        let size = symbol.st_size as usize;
+        // With -ffunction-sections every function has its own .text.<name>.
+        let shdr = elf.section_headers.get(symbol.st_shndx)
+            .ok_or_else(|| Category::Malformed.error(format!("{}: no section {}", name, symbol.st_shndx)))?;
+        let section_name = elf.shdr_strtab.get_at(shdr.sh_name).unwrap_or("?");
+        if shdr.sh_type == elf::section_header::SHT_NOBITS {
+            return Err(format!("{}: {} has no contents", name, section_name).into());
+        }
+        // Relocations are only read for code sections, so the stencil would be left unpatched.
+        if shdr.sh_flags & elf::section_header::SHF_EXECINSTR as u64 == 0 {
+            return Err(Category::Malformed.error(format!("{}: {} isn't an executable section", name, section_name)));
+        }
+        let text_data = section_data(data, shdr, section_name)?;
+        let start = symbol.st_value as usize;
+        let end = symbol.st_value.checked_add(symbol.st_size)
+            .filter(|&end| end <= text_data.len() as u64)
+            .ok_or_else(|| Category::Malformed.error(format!("{}: {:#x} bytes at {:#x} are outside {} ({:#x} bytes)", name, symbol.st_size, symbol.st_value, section_name, text_data.len())))?
+            as usize;
+        let pool_end = arch.literal_pool_end(text_data, start, end);
         stencils.push( Stencil {
-            name: name,
+            name,
+            display: None,
+            index,
+            id: 0,
+            section: symbol.st_shndx,
             address: symbol.st_value,
-            size: symbol.st_size,
-            code: &data[start .. start + size],
+            size: (pool_end - start) as u64,
+            code: Cow::Borrowed(&text_data[start .. pool_end]),
             relocs: Vec::new(),
             holes: Vec::new(),
+            terminates: false,
+            stack_size: arch.stack_usage(&text_data[start..end]),
+            literal_pool: (pool_end - end) as u64,
+            fallthrough: false,
+            taken_hole: None,
+            imm32_variant: None,
+            cold: false,
+            alias_of: None,
+            signature: None,
+            lines: Vec::new(),
+            source_range: None,
+            doc: Vec::new(),
+            op: None,
+            types: Vec::new(),
+            unit: None,
+            arch,
         });
     }
 
     Ok(())
 }
 
-fn read_elf2<'a>(data: &'a Vec<u8>, stencils: &mut Vec<Stencil<'a>>, holes: &'a Vec<Hole<'a>>) -> Result<(), Box<dyn Error>> {
-    let object = Object::parse(&*data)?;
-    let elf = match object {
-        Object::Elf(x) => x,
-        _ => unreachable!("object file is not elf"),
-    };
-    let (text_index, _) = elf.section_headers.iter().enumerate().find(|(_, shdr)| {
-        let name = elf.shdr_strtab.get_at(shdr.sh_name);
-        name == Some(".text")
-    })
-    .expect("No .text segment");
-
-    let (_, reloc_section) = elf.shdr_relocs.iter()
-        .find(|(idx, _)| *idx==text_index+1)
-        .expect("no relocations in .text");
-    for reloc in reloc_section.iter() {
-        if let Some(stencil) = stencils.iter_mut().find(|s| (s.address..s.address+s.size).contains(&reloc.r_offset)) {
-            stencil.relocs.push( Reloc {
-                offset: reloc.r_offset - stencil.address,
-                addend: reloc.r_addend.unwrap_or(0),
-                hole: &holes.iter().find(|h| h.index == reloc.r_sym).unwrap(),
-                relocation: elf::reloc::r_to_str(reloc.r_type, elf::header::EM_X86_64),
-            });
+fn read_elf2<'a>(elf: &Elf<'a>, stencils: &mut [Stencil<'a>], holes: &[Hole<'a>]) -> Result<(), Box<dyn Error>> {
+    let callees = stencils.iter().map(|s| (s.index, s.name)).collect::<HashMap<_, _>>();
+    // A code section whose stencils need no patching has no relocation section.
+    for (reloc_index, relocs) in &elf.shdr_relocs {
+        let section = elf.section_headers[*reloc_index].sh_info as usize;
+        // Debug info and unwind tables have relocations too, only code sections hold stencils.
+        let code = elf.section_headers.get(section).is_some_and(|shdr| shdr.sh_flags & elf::section_header::SHF_EXECINSTR as u64 != 0);
+        if !code || !stencils.iter().any(|s| s.section == section) {
+            continue;
+        }
+        let symbols = symbol_ranges(elf, section);
+        // Stencils are sorted by section and address and holes by symbol index, so both lookups can bisect.
+        for reloc in relocs.iter() {
+            let relocation = elf::reloc::r_to_str(reloc.r_type, elf.header.e_machine);
+            let next = stencils.partition_point(|s| (s.section, s.address) <= (section, reloc.r_offset));
+            // Aliases are several symbols at the same address, each gets its own copy of the relocs
+            // so that dedup_stencils finds them identical.
+            let start = stencils[..next].last().map_or(next, |last| stencils.partition_point(|s| (s.section, s.address) < (last.section, last.address)));
+            let owned = stencils[start..next].iter().any(|s| s.section == section && reloc.r_offset < s.address + s.size);
+            // A patch between functions means a symbol's size is wrong, usually a missing .size in
+            // assembly, and the stencil would be cut off before it. Alignment hints are allowed there.
+            if !owned && reloc.r_type != 0 && relocation != "R_RISCV_ALIGN" &&
+               let Some(before) = stencils[..next].last().filter(|s| s.section == section) &&
+               !symbols.iter().any(|&(start, end, _)| start <= reloc.r_offset && reloc.r_offset < end) {
+                return Err(Category::Malformed.error(format!("{}: {} relocation at {:#x} is in the padding after its {:#x} bytes",
+                    before.name, relocation, reloc.r_offset - before.address, before.size)));
+            }
+            for stencil in stencils[start..next].iter_mut().filter(|s| s.section == section && reloc.r_offset < s.address+s.size) {
+                let hole = match holes.binary_search_by_key(&reloc.r_sym, |h| h.index) {
+                    Ok(i) if !holes[i].internal && let Some(target) = internal_target(elf, stencil, &reloc) => {
+                        let offset = reloc.r_offset - stencil.address;
+                        let relocation = relocation.strip_prefix("R_").unwrap_or(relocation);
+                        if arch::is_absolute(relocation) {
+                            return Err(Category::Malformed.error(format!("{}: {} relocation at {:#x} takes the address of its own code at {:#x}, which isn't known until it's emitted",
+                                stencil.name, relocation, offset, target)));
+                        }
+                        fuse::bake_internal_branch(stencil.code.to_mut(), offset, target, relocation)
+                            .ok_or_else(|| Category::Malformed.error(format!("{}: can't resolve {} relocation at {:#x} to its own code", stencil.name, relocation, offset)))?;
+                        continue;
+                    }
+                    Ok(i) => holes[i],
+                    Err(_) => Hole {
+                        name: callees.get(&reloc.r_sym).ok_or_else(|| Category::Malformed.error("relocation against unknown symbol"))?,
+                        index: reloc.r_sym,
+                        datatype: "uint32_t",
+                        value_datatype: "void*",
+                        internal: true,
+                        stencil_ref: true,
+                        payload: None,
+                        transform: None,
+                        version: None,
+                    },
+                };
+                let offset = reloc.r_offset - stencil.address;
+                let end = reloc.r_offset + arch::reloc_width(relocation) as u64;
+                if end > stencil.address + stencil.size {
+                    let into = symbols.iter().find(|&&(start, _, _)| start >= stencil.address + stencil.size && start < end)
+                        .map_or_else(|| "the padding after it".to_string(), |(_, _, name)| format!("{}'s code", name));
+                    return Err(Category::Malformed.error(format!("{}: {} relocation at {:#x} runs past its end into {}", stencil.name, relocation, offset, into)));
+                }
+                stencil.relocs.push( Reloc {
+                    offset,
+                    addend: reloc.r_addend.unwrap_or(0),
+                    hole,
+                    arg: None,
+                    relocation,
+                    far_call: false,
+                    continuation: false,
+                });
+            }
         }
     }
 
     Ok(())
 }
 
+// Where a relocation against a local label or the section points inside the stencil it patches,
+// as in a loop the assembler left for the linker, relative to the stencil's start. The addend is
+// added to section symbols, and on x86 counts back from the end of the field.
+fn internal_target(elf: &Elf, stencil: &Stencil, reloc: &elf::Reloc) -> Option<u64> {
+    let sym = elf.syms.get(reloc.r_sym)?;
+    if sym.st_shndx != stencil.section {
+        return None;
+    }
+    let addend = reloc.r_addend.unwrap_or(0);
+    let location = match sym.st_type() {
+        elf::sym::STT_SECTION => sym.st_value.wrapping_add(addend as u64),
+        elf::sym::STT_NOTYPE | elf::sym::STT_FUNC | elf::sym::STT_OBJECT => sym.st_value,
+        _ => return None,
+    };
+    let width = arch::reloc_width(elf::reloc::r_to_str(reloc.r_type, elf.header.e_machine)) as u64;
+    let inside = location.wrapping_add(width).wrapping_sub(stencil.address) < stencil.size + width;
+    inside.then(|| sym.st_value.wrapping_add(addend as u64).wrapping_sub(stencil.address))
+}
+
+// The start, end and name of every symbol with a size in `section`.
+fn symbol_ranges<'a>(elf: &Elf<'a>, section: usize) -> Vec<(u64, u64, &'a str)> {
+    elf.syms.iter()
+        .filter(|sym| sym.st_shndx == section && sym.st_size > 0 && !matches!(sym.st_type(), elf::sym::STT_SECTION | elf::sym::STT_FILE))
+        .map(|sym| (sym.st_value, sym.st_value.saturating_add(sym.st_size), elf.strtab.get_at(sym.st_name).unwrap_or("?")))
+        .collect()
+}
+
+#[derive(Default)]
+struct SplitUnits {
+    signatures: HashMap<String, dwarf::Signature>,
+    declarations: HashMap<String, (PathBuf, u64)>,
+    // Where each .dwo was found.
+    files: Vec<PathBuf>,
+}
+
+// Split DWARF leaves a skeleton unit in the object and describes the functions in .dwo files,
+// found through the compilation directory or else next to the object.
+fn read_split_units(path: &str, dwos: &[PathBuf]) -> Result<SplitUnits, Box<dyn Error>> {
+    let mut split = SplitUnits::default();
+    for dwo in dwos {
+        let beside = Path::new(path).with_file_name(dwo.file_name().unwrap_or_default());
+        let (file, data) = fs::read(dwo).map(|data| (dwo.clone(), data))
+            .or_else(|_| fs::read(&beside).map(|data| (beside, data)))
+            .map_err(|e| diagnostics::in_file(dwo.display(), e))?;
+        let Object::Elf(elf) = Object::parse(&data).map_err(|e| diagnostics::in_file(dwo.display(), e))? else {
+            return Err(Category::Malformed.error(format!("{}: not an ELF object", dwo.display())));
+        };
+        let sections = dwarf::Sections::load(&elf, &data, ".dwo").map_err(|e| diagnostics::in_file(dwo.display(), Category::Malformed.error(e)))?;
+        if let Some(sections) = sections {
+            let dwarf = dwarf::Dwarf::parse(&sections).map_err(|e| diagnostics::in_file(dwo.display(), Category::Malformed.error(e)))?;
+            split.signatures.extend(dwarf.signatures().into_iter().map(|(name, s)| (name.to_string(), s)));
+            split.declarations.extend(dwarf.declarations().into_iter().map(|(name, d)| (name.to_string(), d)));
+        }
+        split.files.push(file);
+    }
+    Ok(split)
+}
+
+// Returns the source files that were read.
+fn add_doc_comments(stencils : &mut [Stencil], declarations: &HashMap<&str, (PathBuf, u64)>) -> Vec<PathBuf> {
+    // The sources are only there for documentation, so a missing or moved file means no doc.
+    let mut sources = HashMap::new();
+    for stencil in stencils.iter_mut() {
+        let Some((path, line)) = declarations.get(stencil.name) else {
+            continue;
+        };
+        let source = sources.entry(path).or_insert_with(|| fs::read_to_string(path).ok());
+        if let Some(source) = source {
+            stencil.doc = doc_comment(&source.lines().collect::<Vec<_>>(), *line as usize);
+        }
+    }
+    sources.into_iter().filter(|(_, source)| source.is_some()).map(|(path, _)| path.clone()).collect()
+}
+
+// The `//` lines or `/* */` block right above line `decl` (1-based) of `lines`, skipping the
+// lines of a declaration that starts above its name, like a return type on its own line.
+fn doc_comment(lines: &[&str], decl: usize) -> Vec<String> {
+    let mut end = decl.saturating_sub(1).min(lines.len());
+    for _ in 0..3 {
+        let Some(line) = end.checked_sub(1).map(|i| lines[i].trim()) else { break };
+        let code = !line.is_empty() && !line.starts_with("//") && !line.starts_with('#') &&
+            !line.ends_with(';') && !line.ends_with('}') && !line.ends_with('{') && !line.ends_with("*/");
+        if !code {
+            break;
+        }
+        end -= 1;
+    }
+    let mut start = end;
+    let mut doc = Vec::new();
+    if end > 0 && lines[end - 1].trim().ends_with("*/") {
+        while start > 0 {
+            start -= 1;
+            if lines[start].contains("/*") {
+                break;
+            }
+        }
+        for line in &lines[start..end] {
+            let line = line.trim();
+            let line = line.split_once("/*").map_or(line, |(_, rest)| rest.trim_start_matches('*'));
+            let line = line.strip_suffix("*/").unwrap_or(line).trim_end_matches('*');
+            let line = line.trim_start();
+            let line = line.strip_prefix("* ").or(line.strip_prefix('*')).unwrap_or(line);
+            doc.push(line.trim_end().to_string());
+        }
+    } else {
+        while start > 0 && lines[start - 1].trim().starts_with("//") {
+            start -= 1;
+        }
+        for line in &lines[start..end] {
+            let line = line.trim().trim_start_matches('/').trim_start_matches('!');
+            doc.push(line.strip_prefix(' ').unwrap_or(line).trim_end().to_string());
+        }
+    }
+    // Drop the blank lines left by `/**` and `*/` on their own lines.
+    while doc.first().is_some_and(|l| l.trim().is_empty()) {
+        doc.remove(0);
+    }
+    while doc.last().is_some_and(|l| l.trim().is_empty()) {
+        doc.pop();
+    }
+    doc
+}
+
+fn annotate_lines(stencils : &mut [Stencil], rows: &[dwarf::LineRow]) {
+    for stencil in stencils.iter_mut() {
+        let range = stencil.address..stencil.address + stencil.size;
+        let mut rows = rows.iter()
+            .filter(|row| !row.end_sequence && row.line != 0 && range.contains(&row.address))
+            .filter(|row| row.section.is_none_or(|section| section == stencil.section))
+            .collect::<Vec<_>>();
+        rows.sort_by_key(|row| row.address);
+        // Of several rows for one address the last applies, and a row repeating the line before
+        // it adds nothing.
+        let mut lines = Vec::<SourceLine>::new();
+        for row in rows.iter() {
+            let offset = row.address - stencil.address;
+            if lines.last().is_some_and(|l| l.offset == offset) {
+                lines.pop();
+            }
+            if !lines.last().is_some_and(|l| l.file == row.file && l.line == row.line) {
+                lines.push(SourceLine { offset, file: row.file.clone(), line: row.line });
+            }
+        }
+        // Lines from other files are inlined headers, the range is for the stencil's own file.
+        let main_file = rows.iter().find(|row| row.primary).or(rows.first()).map(|row| &row.file);
+        if let Some(file) = main_file {
+            let own = lines.iter().filter(|l| &l.file == file).map(|l| l.line);
+            let (min, max) = (own.clone().min().unwrap_or(0), own.max().unwrap_or(0));
+            stencil.source_range = Some(if min == max {
+                format!("{}:{}", file, min)
+            } else {
+                format!("{}:{}-{}", file, min, max)
+            });
+        }
+        stencil.lines = lines;
+    }
+}
+
+fn strip_trailing_padding(stencils : &mut [Stencil], arch: Arch) {
+    // Padding is only stripped when what's left ends in an unconditional jump or return, so the
+    // removed bytes can't have been reached by falling through, and nothing branches into it.
+    for stencil in stencils.iter_mut().filter(|s| s.literal_pool == 0) {
+        let end = arch.padding_start(&stencil.code);
+        if end == stencil.code.len() {
+            continue;
+        }
+        let relocs_before_end = stencil.relocs.iter().all(|r| r.offset as usize + r.width() <= end);
+        let reloc_at_end = stencil.relocs.iter().any(|r| r.offset as usize + r.width() == end);
+        let reloc_offsets = stencil.relocs.iter().map(|r| r.offset as usize).collect::<Vec<_>>();
+        // A branch to the end of the padding is a branch to the next stencil, which moves too.
+        let targets_padding = arch.branch_targets(&stencil.code, &reloc_offsets)
+            .is_none_or(|targets| targets.iter().any(|&target| target >= end as i64 && target <= stencil.code.len() as i64));
+        if relocs_before_end && arch.ends_in_terminator(&stencil.code[..end], reloc_at_end) && !targets_padding {
+            stencil.truncate_code(end);
+        }
+    }
+}
 
-fn trim_trailing_jmp(stencils : &mut Vec<Stencil>) -> () {
-    // If the last reloc is a jump to cnp_stencil_output, then remove it.
+// Stencils are copied on their own, so data they reference in the rest of the object isn't there
+// at runtime. Short string literals can come along after the code, like a literal pool, with the
+// pc-relative reference to them resolved. Anything else is an error.
+fn inline_strings(elf: &Elf, data: &[u8], stencils: &mut [Stencil], max_len: Option<usize>) -> Result<(), Box<dyn Error>> {
     for stencil in stencils.iter_mut() {
-        if let Some(lastreloc) = stencil.relocs.last() {
-            let codelen = stencil.size as usize;
-            if lastreloc.offset == stencil.size - 4 &&
+        // Where each string is in the stencil, by symbol, so it's only copied once.
+        let mut copied = HashMap::new();
+        let mut i = 0;
+        while i < stencil.relocs.len() {
+            let reloc = &stencil.relocs[i];
+            let symbol = elf.syms.get(reloc.hole.index).filter(|sym| sym.st_bind() == elf::sym::STB_LOCAL && sym.st_type() != elf::sym::STT_FUNC);
+            let shdr = symbol.and_then(|sym| elf.section_headers.get(sym.st_shndx)).filter(|shdr| shdr.sh_type != elf::section_header::SHT_NULL);
+            let (Some(symbol), Some(shdr)) = (symbol, shdr) else {
+                i += 1;
+                continue;
+            };
+            if shdr.sh_flags & elf::section_header::SHF_EXECINSTR as u64 != 0 {
+                i += 1;
+                continue;
+            }
+            let section_name = elf.shdr_strtab.get_at(shdr.sh_name).unwrap_or("?");
+            let name = match reloc.hole.name {
+                "" => format!("{}{:+}", section_name, reloc.addend),
+                name => name.to_string(),
+            };
+            let strings = shdr.sh_flags & elf::section_header::SHF_STRINGS as u64 != 0 && symbol.st_type() != elf::sym::STT_SECTION;
+            let string = match strings {
+                true => section_data(data, shdr, section_name)?.get(symbol.st_value as usize..)
+                    .and_then(|rest| rest.iter().position(|&b| b == 0).map(|nul| &rest[..=nul])),
+                false => None,
+            };
+            let Some(string) = string.filter(|string| max_len.is_some_and(|max| string.len() - 1 <= max)) else {
+                let hint = match (string, max_len) {
+                    (Some(_), None) => ", --inline-strings can copy it in".to_string(),
+                    (Some(string), Some(max)) => format!(", it's {} bytes and --inline-strings copies up to {}", string.len() - 1, max),
+                    (None, _) => String::new(),
+                };
+                return Err(format!("{}: references {} in {}, which isn't copied with the stencil{}", stencil.name, name, section_name, hint).into());
+            };
+            if reloc.relocation != "X86_64_PC32" {
+                return Err(format!("{}: can't inline string {} referenced by a {} relocation", stencil.name, name, reloc.relocation).into());
+            }
+            let code = stencil.code.to_mut();
+            let pool = &mut stencil.literal_pool;
+            let at = *copied.entry(reloc.hole.index).or_insert_with(|| {
+                code.extend_from_slice(string);
+                *pool += string.len() as u64;
+                code.len() - string.len()
+            });
+            let value = i32::try_from(at as i64 + reloc.addend - reloc.offset as i64)?;
+            let offset = reloc.offset as usize;
+            code[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+            stencil.size = code.len() as u64;
+            stencil.relocs.remove(i);
+        }
+    }
+    Ok(())
+}
+
+fn trim_trailing_jmp(stencils : &mut [Stencil], arch: Arch) {
+    // If the last reloc is a jump to cnp_stencil_output, then remove it. A literal pool after
+    // the jump means it isn't last, and the pool has to stay where the loads expect it.
+    for stencil in stencils.iter_mut().filter(|s| s.literal_pool == 0) {
+        loop {
+            // Linker relaxation hints share the offset of the reloc they annotate.
+            let last = stencil.relocs.iter().rposition(|r| r.relocation != "R_RISCV_RELAX");
+            if let Some(lastreloc) = last.map(|i| &stencil.relocs[i]) &&
                lastreloc.hole.name == "cnp_stencil_output" &&
-               stencil.code[codelen-5..codelen] == [0xe9,0,0,0,0] {
-                stencil.code = &stencil.code[0..codelen-5];
-                stencil.relocs.pop();
+               let Some(len) = arch.trailing_branch_len(&stencil.code, lastreloc.offset as usize, lastreloc.relocation) {
+                let end = stencil.code.len() - len;
+                stencil.truncate_code(end);
+                stencil.relocs.retain(|r| (r.offset as usize) < end);
+                stencil.fallthrough = true;
+            } else if !(arch == Arch::X86_64 && trim_x86_tail(stencil)) {
+                break;
+            }
+        }
+    }
+}
+
+// Handles x86-64 stencils that reach cnp_stencil_output through something other than a trailing
+// jmp: `jcc cnp_stencil_output; jmp target` becomes `jncc target`, and a trailing jmp to an
+// instruction that jumps to cnp_stencil_output is dropped. Returns whether the code changed.
+fn trim_x86_tail(stencil: &mut Stencil) -> bool {
+    let Some(insns) = x86::decode_all(&stencil.code) else {
+        return false;
+    };
+    let starts = x86::starts(&insns);
+    let Some((&jmp, &jmp_start)) = insns.last().zip(starts.last()) else {
+        return false;
+    };
+    let Some((rel, _)) = jmp.rel.filter(|_| jmp.branch == Some(x86::BranchKind::Jmp) && jmp.prefixes == 0) else {
+        return false;
+    };
+    let reloc_at = |offset: usize| stencil.relocs.iter().position(|r| r.offset as usize == offset);
+    // Where the internal branches go, as instruction indexes. Nothing can jump past the end,
+    // that's where the next stencil will be.
+    let mut targets = Vec::with_capacity(insns.len());
+    for (insn, &start) in insns.iter().zip(&starts) {
+        let target = match insn.rel {
+            Some((offset, _)) if insn.branch.is_some() && reloc_at(start + offset).is_none() => {
+                let target = x86::rel_target(&stencil.code, start, insn).and_then(|t| usize::try_from(t).ok());
+                match target.map(|t| starts.binary_search(&t)) {
+                    Some(Ok(i)) => Some(i),
+                    _ => return false,
+                }
+            }
+            _ => None,
+        };
+        targets.push(target);
+    }
+    // The rel32 jmp or jcc at instruction `i` to cnp_stencil_output, as an index into relocs.
+    let exit = |i: usize| {
+        let (offset, width) = insns[i].rel.filter(|_| insns[i].prefixes == 0)?;
+        let reloc = reloc_at(starts[i] + offset).filter(|&r| width == 4 && stencil.relocs[r].hole.name == "cnp_stencil_output")?;
+        matches!(insns[i].branch, Some(x86::BranchKind::Jmp | x86::BranchKind::Jcc(_))).then_some(reloc)
+    };
+
+    let last = insns.len() - 1;
+    let jmp_reloc = reloc_at(jmp_start + rel);
+    if jmp_reloc.is_none() && targets[last].is_some_and(|t| t != last && exit(t).is_some() && insns[t].branch == Some(x86::BranchKind::Jmp)) {
+        // Jumping to a jump to the next stencil is the same as running into it.
+        stencil.truncate_code(jmp_start);
+        stencil.fallthrough = true;
+        return true;
+    }
+
+    let Some(jcc) = last.checked_sub(1) else {
+        return false;
+    };
+    let (Some(x86::BranchKind::Jcc(_)), Some(output)) = (insns[jcc].branch, exit(jcc)) else {
+        return false;
+    };
+    // Other branches to the jmp would run into the next stencil instead of its target.
+    if targets.contains(&Some(last)) || targets[last].is_some_and(|t| t >= jcc) {
+        return false;
+    }
+    let jcc_start = starts[jcc];
+    let disp = jcc_start + insns[jcc].rel.map_or(0, |(offset, _)| offset);
+    let code = stencil.code.to_mut();
+    // Condition codes come in pairs that differ in the low bit, `jcc rel32` is 0f 8x.
+    code[jcc_start + 1] ^= 1;
+    match (jmp_reloc, targets[last]) {
+        (Some(r), _) => stencil.relocs[r].offset = disp as u64,
+        (None, Some(t)) => {
+            let value = starts[t] as i64 - (jcc_start + insns[jcc].len) as i64;
+            code[disp..disp + 4].copy_from_slice(&(value as i32).to_le_bytes());
+        }
+        (None, None) => unreachable!("an internal branch always has a target"),
+    }
+    stencil.relocs.remove(output);
+    stencil.truncate_code(jmp_start);
+    stencil.relocs.retain(|r| (r.offset as usize) < jmp_start);
+    stencil.fallthrough = true;
+    true
+}
+
+fn trim_trailing_ret(stencils : &mut [Stencil], trim: bool) {
+    // Terminator stencils end in `ret` (or `repz ret`) instead of jumping to cnp_stencil_output.
+    for stencil in stencils.iter_mut().filter(|s| s.literal_pool == 0) {
+        let ret_len = match stencil.code[..] {
+            [.., 0xf3, 0xc3] => 2,
+            [.., 0xc3] => 1,
+            _ => continue,
+        };
+        // A reloc patching the last bytes means they're an operand rather than an opcode.
+        let codelen = stencil.code.len();
+        if stencil.relocs.iter().any(|r| r.offset as usize + r.width() > codelen - ret_len) {
+            continue;
+        }
+        stencil.terminates = true;
+        if trim {
+            stencil.truncate_code(codelen - ret_len);
+        }
+    }
+}
+
+fn shorten_branches(stencils : &mut [Stencil]) {
+    // Compilers pick rel32 for branches whose targets only end up close once stencils are
+    // trimmed, or when optimizing for alignment over size. Data after the code doesn't decode.
+    for stencil in stencils.iter_mut().filter(|s| s.literal_pool == 0) {
+        let relocs = stencil.relocs.iter().map(|r| (r.offset as usize, r.width())).collect::<Vec<_>>();
+        let Some(shortened) = x86::shorten_branches(&stencil.code, &relocs) else {
+            continue;
+        };
+        // Relocs are never in a shortened branch, so they move with the start of their instruction.
+        let moves = &shortened.moves;
+        let remap = |offset: &mut u64| {
+            let (old, new) = moves[moves.partition_point(|&(old, _)| old <= *offset as usize) - 1];
+            *offset = (new + *offset as usize - old) as u64;
+        };
+        for reloc in stencil.relocs.iter_mut() {
+            remap(&mut reloc.offset);
+        }
+        for line in stencil.lines.iter_mut() {
+            remap(&mut line.offset);
+        }
+        stencil.code = Cow::Owned(shortened.code);
+    }
+}
+
+// Objects compiled as PIC or PIE load the addresses of external symbols out of the GOT, which
+// stencils don't have. Like the linker, turn the loads of code and external symbols into lea and
+// the calls and jumps through it into direct ones, so the address is patched in as a rel32 that
+// has to reach. A value hole can be any 64-bit value, and other instructions can't be rewritten,
+// so those get a GOT entry of their own after the code that the value is patched into.
+fn relax_got_loads(stencils : &mut [Stencil]) -> Result<(), Box<dyn Error>> {
+    for stencil in stencils.iter_mut() {
+        let mut entries = HashMap::new();
+        let mut relocs = Vec::with_capacity(stencil.relocs.len());
+        for reloc in mem::take(&mut stencil.relocs) {
+            if !matches!(reloc.relocation, "X86_64_GOTPCREL" | "R_X86_64_GOTPCRELX" | "R_X86_64_REX_GOTPCRELX") {
+                relocs.push(reloc);
+                continue;
             }
+            let offset = reloc.offset as usize;
+            let is_value = reloc.hole.internal && reloc.hole.value_datatype != "void*";
+            if !is_value && x86::relax_got_load(stencil.code.to_mut(), offset).is_some() {
+                relocs.push(Reloc { relocation: "X86_64_PC32", ..reloc });
+                continue;
+            }
+            let code = stencil.code.to_mut();
+            let entry = *entries.entry(reloc.hole.name).or_insert_with(|| {
+                code.extend_from_slice(&[0; 8]);
+                stencil.literal_pool += 8;
+                relocs.push(Reloc { offset: code.len() as u64 - 8, addend: 0, relocation: "X86_64_64", ..reloc });
+                code.len() - 8
+            });
+            let value = i32::try_from(entry as i64 + reloc.addend - offset as i64)?;
+            code[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        }
+        stencil.size = stencil.code.len() as u64;
+        relocs.sort_by_key(|r| r.offset);
+        stencil.relocs = relocs;
+    }
+    Ok(())
+}
+
+fn mark_far_calls(stencils : &mut [Stencil]) {
+    // Only direct call/jmp rel32 can be redirected, a rel32 data reference has to reach on its own.
+    for stencil in stencils.iter_mut() {
+        for reloc in stencil.relocs.iter_mut() {
+            let is_function_hole = !reloc.hole.internal || reloc.hole.stencil_ref ||
+                reloc.hole.name.starts_with("cnp_near_func_hole");
+            let is_rel32 = matches!(reloc.relocation, "X86_64_PC32" | "X86_64_PLT32") && reloc.addend == -4;
+            let opcode = (reloc.offset as usize).checked_sub(1).and_then(|i| stencil.code.get(i));
+            reloc.far_call = is_function_hole && is_rel32 && matches!(opcode, Some(0xe8 | 0xe9));
         }
     }
 }
 
-fn populate_stencil_holes<'a>(stencils : &mut Vec<Stencil<'a>>) -> () {
+fn mark_continuations(stencils : &mut [Stencil]) {
+    // Whatever the code after a stencil is, it's entered by jumping to cnp_stencil_output. Other
+    // stencils are only continuations where they're jumped to rather than called.
+    for stencil in stencils.iter_mut() {
+        for reloc in stencil.relocs.iter_mut() {
+            let is_stencil = reloc.hole.stencil_ref || reloc.hole.name.starts_with("cnp_near_func_hole");
+            reloc.continuation = reloc.hole.name == "cnp_stencil_output" ||
+                is_stencil && stencil.arch.is_jump(&stencil.code, reloc.offset as usize, reloc.relocation);
+        }
+    }
+}
+
+// A stencil whose only exits are to cnp_stencil_output (or off its end) and to one argument is a
+// conditional branch. Others are straight line code, or a multiway branch the runtime has to
+// look at the relocs of.
+fn mark_branches(stencils : &mut [Stencil]) {
+    for stencil in stencils.iter_mut() {
+        let mut exits = stencil.relocs.iter().filter(|r| r.continuation && r.hole.is_argument()).map(|r| r.hole.name).collect::<Vec<_>>();
+        exits.sort_unstable();
+        exits.dedup();
+        let falls_through = stencil.fallthrough || stencil.relocs.iter().any(|r| r.continuation && r.hole.name == "cnp_stencil_output");
+        stencil.taken_hole = match exits[..] {
+            [hole] if falls_through => Some(hole),
+            _ => None,
+        };
+    }
+}
+
+fn populate_stencil_holes(stencils : &mut [Stencil]) {
     // Populate the list of holes used to make codegen eaiser.
     for stencil in stencils.iter_mut() {
         for reloc in stencil.relocs.iter() {
-            let missing_hole = stencil.holes.iter().find(|h| h.index == reloc.hole.index).is_none();
+            let missing_hole = !stencil.holes.iter().any(|h| h.name == reloc.hole.name);
             if missing_hole {
-                stencil.holes.push(&reloc.hole);
+                stencil.holes.push(reloc.hole);
             }
         }
+        let args = stencil.holes.iter().filter(|h| h.is_argument()).map(|h| h.name).collect::<Vec<_>>();
+        for reloc in stencil.relocs.iter_mut() {
+            reloc.arg = args.iter().position(|&name| name == reloc.hole.name);
+        }
     }
 }
 
-fn hex_filter(value: minijinja::Value) -> String {
-    let hex_strings: Vec<String> = value.try_iter().expect("no code")
-        .map(|b| format!("0x{:02x}", b.as_usize().expect("number")))
-        .collect();
-    hex_strings.join(", ")
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum WeakPolicy {
+    /// Extract weak functions as stencils like global ones
+    Stencil,
+    /// Leave weak functions to be resolved at runtime, with a warning
+    Skip,
 }
 
-fn emit_code(stencils : &Vec<Stencil>, holes : &Vec<Hole>, header: &str, source: &str) -> Result<(), Box<dyn Error>> {
-    for stencil in stencils.iter() {
-        println!("{}: {}", stencil.name, hex::encode(stencil.code));
-        for reloc in stencil.relocs.iter() {
-            println!(" {}: {} {}", reloc.offset, reloc.hole.name, reloc.relocation);
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Visibility {
+    /// Only functions with default (or protected) visibility are stencils
+    Default,
+    /// Only hidden (or internal) functions are stencils
+    Hidden,
+    /// Visibility doesn't matter
+    All,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Report {
+    /// Code size, reloc count and hole count of every stencil, largest first
+    Sizes,
+    /// How often each relocation kind is used and by which stencils
+    Relocs,
+    /// Instruction counts of every stencil, with calls, branches and vector instructions
+    Insns,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Dump {
+    /// Print nothing
+    None,
+    /// One line per stencil with its size, reloc count and argument count
+    Summary,
+    /// The code in hex followed by each reloc and source line
+    Hex,
+    /// Every stencil one instruction per line, with the bytes its holes patch marked
+    Disasm,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum SortOrder {
+    /// Sort stencils by symbol name
+    Name,
+    /// Keep stencils in object and address order
+    Address,
+}
+
+fn rename_demangled<'a>(stencils : &mut [Stencil<'a>], demangled: &'a [Option<demangle::Demangled>]) -> Result<(), Box<dyn Error>> {
+    // Mangled names are valid C identifiers, just unreadable ones.
+    for (stencil, demangled) in stencils.iter_mut().zip(demangled) {
+        if let Some(demangled) = demangled {
+            stencil.display = Some(&demangled.display);
         }
     }
+    let names = demangled.iter().map(|d| d.as_ref().map(|d| d.ident.as_str())).collect::<Vec<_>>();
+    rename_stencils(stencils, &names)
+}
 
-    let mut env = Environment::new();
-    env.add_filter("hex", hex_filter);
-    minijinja_embed::load_templates!(&mut env);
-
-    let source_tmpl = env.get_template("source.jinja").unwrap();
-    let source_rendered = source_tmpl.render(context!(stencils => stencils, holes => holes, header => header)).unwrap();
-    fs::write(source, source_rendered)?;
+// Gives each stencil its new name, if it has one, and the stencils calling it the new name too.
+fn rename_stencils<'a>(stencils : &mut [Stencil<'a>], names: &[Option<&'a str>]) -> Result<(), Box<dyn Error>> {
+    let mut renames = HashMap::new();
+    let mut seen = HashMap::new();
+    for (stencil, name) in stencils.iter_mut().zip(names) {
+        let display = stencil.display.unwrap_or(stencil.name);
+        if let Some(name) = *name {
+            renames.insert(stencil.name, name);
+            stencil.name = name;
+        }
+        if let Some(other) = seen.insert(stencil.name, display) {
+            return Err(format!("{} and {} both become stencil {}", other, display, stencil.name).into());
+        }
+    }
+    for stencil in stencils.iter_mut() {
+        let holes = stencil.holes.iter_mut().chain(stencil.relocs.iter_mut().map(|r| &mut r.hole));
+        for hole in holes.filter(|h| h.stencil_ref) {
+            if let Some(name) = renames.get(hole.name) {
+                hole.name = name;
+            }
+        }
+    }
+    Ok(())
+}
 
-    let header_tmpl = env.get_template("header.jinja").unwrap();
-    let header_rendered = header_tmpl.render(context!(stencils => stencils)).unwrap();
-    fs::write(header, header_rendered)?;
+fn sort_stencils(stencils : &mut [Stencil], order: SortOrder) {
+    // Symbol table order differs between compiler versions, so don't let it leak into the output.
+    for stencil in stencils.iter_mut() {
+        stencil.relocs.sort_by_key(|r| r.offset);
+    }
+    match order {
+        SortOrder::Name => {
+            stencils.sort_by_key(|s| s.name);
+        }
+        SortOrder::Address => {
+            // Each object is already sorted by address and objects are merged in command line order.
+        }
+    }
+}
 
+fn pair_imm32_variants(stencils : &mut [Stencil]) -> Result<(), Box<dyn Error>> {
+    // `<name>_imm32` is `<name>` compiled with cnp_small_value_holes in place of (some of) its
+    // cnp_large_value_holes, so it can take values that fit in a 32-bit immediate.
+    let by_name = stencils.iter().enumerate().map(|(i, s)| (s.name, i)).collect::<HashMap<_, _>>();
+    let mut pairs = Vec::new();
+    for (i, stencil) in stencils.iter().enumerate() {
+        let Some(&j) = by_name.get(format!("{}_imm32", stencil.name).as_str()) else {
+            continue;
+        };
+        let variant = &stencils[j];
+        let args = stencil.holes.iter().filter(|h| h.is_argument()).collect::<Vec<_>>();
+        let variant_args = variant.holes.iter().filter(|h| h.is_argument()).collect::<Vec<_>>();
+        let mismatch = || format!("{} doesn't take the same arguments as {}", variant.name, stencil.name);
+        if args.len() != variant_args.len() {
+            return Err(mismatch().into());
+        }
+        let mut checks = Vec::with_capacity(args.len());
+        for (arg, variant_arg) in args.iter().zip(&variant_args) {
+            if arg.name == variant_arg.name {
+                checks.push(None);
+                continue;
+            }
+            let small = arg.name.strip_prefix("cnp_large_value_hole").map(|n| format!("cnp_small_value_hole{}", n));
+            if small.as_deref() != Some(variant_arg.name) {
+                return Err(mismatch().into());
+            }
+            // Sign extended immediates take the signed 32-bit range.
+            let signed = variant.relocs.iter().any(|r| r.hole.name == variant_arg.name && r.relocation.ends_with("_32S"));
+            checks.push(Some(if signed { "imm32s" } else { "imm32" }));
+        }
+        pairs.push((i, Imm32Variant { name: variant.name, checks }));
+    }
+    for (i, variant) in pairs {
+        stencils[i].imm32_variant = Some(variant);
+    }
     Ok(())
 }
 
-#[derive(Parser, Debug)]
-struct Args {
-    object: String,
-    #[arg(long)]
-    header: String,
-    #[arg(long)]
-    source: String,
+fn dedup_stencils(stencils : &mut [Stencil]) {
+    // Different opcodes can compile to the same code, only emit the bytes and reloc table once.
+    let mut seen = HashMap::new();
+    let canonicals = stencils.iter().map(|stencil| {
+        let relocs = stencil.relocs.iter()
+            .map(|r| (r.offset, r.addend, r.relocation, r.hole.name))
+            .collect::<Vec<_>>();
+        *seen.entry((&stencil.code[..], relocs)).or_insert(stencil.name)
+    }).collect::<Vec<_>>();
+    for (stencil, canonical) in stencils.iter_mut().zip(canonicals) {
+        if canonical != stencil.name {
+            stencil.alias_of = Some(canonical);
+        }
+    }
+}
+
+fn assign_stencil_ids(stencils : &mut [Stencil], ids: Option<&mut BTreeMap<String, usize>>) {
+    match ids {
+        Some(ids) => {
+            ids::assign_ids(ids, stencils.iter().map(|s| s.name));
+            for stencil in stencils.iter_mut() {
+                stencil.id = ids[stencil.name];
+            }
+        }
+        None => {
+            for (id, stencil) in stencils.iter_mut().enumerate() {
+                stencil.id = id;
+            }
+        }
+    }
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let args = Args::parse();
+// The stencils of objects, and the other files that were read for them: .dwo files and the
+// sources of doc comments.
+type Extracted<'a> = (Vec<Stencil<'a>>, Vec<PathBuf>);
+// The stencils of one architecture, which is only named for `arch=object` inputs.
+type ArchStencils<'a> = (Option<Arch>, Vec<Stencil<'a>>);
+type ObjectGroup = (Option<Arch>, Vec<String>);
+
+fn process_object<'a>(path: &str, data: &'a [u8], args: &ReadArgs, only: Option<&HashSet<&str>>) -> Result<Extracted<'a>, Box<dyn Error>> {
+    let elf = match Object::parse(data)? {
+        Object::Elf(x) => x,
+        // There's no debug info to read out of COFF objects, and no GCC cold parts or string
+        // sections in them, so only the passes after reading are shared. Functions run on into
+        // the padding before the next one, which has to go before GOT entries are added after it.
+        Object::COFF(coff) => {
+            let mut stencils = coff::read(&coff, data, args, only)?;
+            strip_trailing_padding(&mut stencils, Arch::X86_64);
+            relax_got_loads(&mut stencils)?;
+            finish_stencils(&mut stencils, Arch::X86_64, args);
+            return Ok((stencils, Vec::new()));
+        }
+        Object::PE(_) => return Err(Category::Invalid.error("a linked PE image, stencils are read out of object files")),
+        _ => return Err(Category::Malformed.error("not an ELF or COFF object")),
+    };
 
     let mut holes = Vec::<Hole>::new();
     let mut stencils = Vec::<Stencil>::new();
-    let data = fs::read(args.object)?;
-    read_elf1(&data, &mut stencils, &mut holes)?;
-    read_elf2(&data, &mut stencils, &holes)?;
-    
-    trim_trailing_jmp(&mut stencils);
-    populate_stencil_holes(&mut stencils);
+    let mut inputs = Vec::new();
+    let arch = Arch::from_machine(elf.header.e_machine)?;
+    check_symbols(&elf, path, args)?;
+    check_static_init(&elf)?;
+    read_elf1(&elf, data, arch, args, only, &mut stencils, &mut holes)?;
+    stencils.sort_by_key(|s| (s.section, s.address));
+    read_elf2(&elf, &mut stencils, &holes)?;
+    if let Some(sections) = dwarf::Sections::load(&elf, data, "").map_err(|e| Category::Malformed.error(e))? {
+        let dwarf = dwarf::Dwarf::parse(&sections).map_err(|e| Category::Malformed.error(e))?;
+        let signatures = dwarf.signatures();
+        let mut declarations = dwarf.declarations();
+        let split = read_split_units(path, &dwarf.split_units())?;
+        for stencil in stencils.iter_mut() {
+            stencil.signature = signatures.get(stencil.name).or_else(|| split.signatures.get(stencil.name)).cloned();
+        }
+        declarations.extend(split.declarations.iter().map(|(name, decl)| (name.as_str(), decl.clone())));
+        inputs.extend(split.files.iter().cloned());
+        inputs.extend(add_doc_comments(&mut stencils, &declarations));
+        annotate_lines(&mut stencils, &sections.line_rows().map_err(|e| Category::Malformed.error(e))?);
+    }
+    gcc::merge_cold_parts(&elf, &mut stencils)?;
+    if arch == Arch::X86_64 {
+        relax_got_loads(&mut stencils)?;
+    }
 
-    emit_code(&stencils, &holes, &args.header, &args.source)?;
+    strip_trailing_padding(&mut stencils, arch);
+    inline_strings(&elf, data, &mut stencils, args.inline_strings)?;
+    finish_stencils(&mut stencils, arch, args);
 
-    Ok(())
+    Ok((stencils, inputs))
+}
+
+// The ELF machine of an object, AMD64 COFF objects being read into the same relocations as
+// x86-64 ELF ones.
+fn object_machine(data: &[u8]) -> Result<u16, Box<dyn Error>> {
+    match Object::parse(data)? {
+        Object::COFF(coff) if coff.header.machine == goblin::pe::header::COFF_MACHINE_X86_64 => Ok(elf::header::EM_X86_64),
+        Object::COFF(coff) => Err(format!("unsupported COFF machine {:#x}, only AMD64 objects can be read", coff.header.machine).into()),
+        _ => Ok(Elf::parse_header(data)?.e_machine),
+    }
+}
+
+fn finish_stencils(stencils: &mut [Stencil], arch: Arch, args: &ReadArgs) {
+    trim_trailing_jmp(stencils, arch);
+    trim_trailing_ret(stencils, args.trim_ret);
+    if args.shorten_branches && arch == Arch::X86_64 {
+        shorten_branches(stencils);
+    }
+    mark_far_calls(stencils);
+    mark_continuations(stencils);
+    populate_stencil_holes(stencils);
+}
+
+fn parallel_map<'a, T: Sync, R: Send>(items: &'a [T], f: impl Fn(&'a T) -> R + Sync) -> Vec<R> {
+    // Workers pull the next item off a shared counter, results are put back in input order.
+    let workers = thread::available_parallelism().map_or(1, |n| n.get()).min(items.len());
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new((0..items.len()).map(|_| None).collect::<Vec<Option<R>>>());
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                if i >= items.len() {
+                    break;
+                }
+                let result = f(&items[i]);
+                results.lock().unwrap()[i] = Some(result);
+            });
+        }
+    });
+    results.into_inner().unwrap().into_iter().map(|r| r.unwrap()).collect()
+}
+
+fn hex_filter(value: minijinja::Value) -> String {
+    let hex_strings: Vec<String> = value.try_iter().expect("no code")
+        .map(|b| format!("0x{:02x}", b.as_usize().expect("number")))
+        .collect();
+    hex_strings.join(", ")
+}
+
+// Escapes a path for a ninja build statement or variable.
+fn ninja_filter(value: &str) -> String {
+    value.replace('$', "$$").replace(' ', "$ ").replace(':', "$:")
+}
+
+fn template_env() -> Environment<'static> {
+    let mut env = Environment::new();
+    env.add_filter("hex", hex_filter);
+    env.add_filter("ninja", ninja_filter);
+    minijinja_embed::load_templates!(&mut env);
+    env
+}
+
+fn dump_stencils(stencils : &[Stencil], dump: Dump) {
+    for stencil in stencils.iter() {
+        if let Some(alias_of) = stencil.alias_of {
+            if dump != Dump::None {
+                println!("{}: alias of {}", stencil.name, alias_of);
+            }
+            continue;
+        }
+        match dump {
+            Dump::None => {}
+            Dump::Summary => println!("{}: {} bytes, {} relocs, {} arguments", stencil.name, stencil.code.len(),
+                stencil.relocs.len(), stencil.holes.iter().filter(|h| h.is_argument()).count()),
+            Dump::Hex => {
+                match stencil.display {
+                    Some(display) => println!("{} ({}): {}", stencil.name, display, hex::encode(&stencil.code)),
+                    None => println!("{}: {}", stencil.name, hex::encode(&stencil.code)),
+                }
+                // Line comments go before the relocs at the same offset, they describe the instruction.
+                let mut lines = stencil.lines.iter().filter(|l| (l.offset as usize) < stencil.code.len()).peekable();
+                for reloc in stencil.relocs.iter() {
+                    while let Some(line) = lines.next_if(|l| l.offset <= reloc.offset) {
+                        println!(" {}: // {}:{}", line.offset, line.file, line.line);
+                    }
+                    println!(" {}: {} {}", reloc.offset, reloc.hole.name, reloc.relocation);
+                }
+                for line in lines {
+                    println!(" {}: // {}:{}", line.offset, line.file, line.line);
+                }
+            }
+            Dump::Disasm => println!("{}", report::explain(stencil, std::io::stdout().is_terminal())),
+        }
+    }
+}
+
+fn emit_code(env: &Environment, args: &Args, stencils : &[Stencil], families: &[families::Family], provenance: Option<&provenance::Provenance>, outputs: &[(&str, String)], units: &[split::Unit]) -> Result<(), Box<dyn Error>> {
+    // Rendering dominates generation time for large stencil sets, so render each output on its own thread.
+    let ctx = template_context(args, stencils, families, provenance)?;
+    // A unit's header and source render the same templates with `unit` naming the stencils they take.
+    let mut renders = outputs.iter().map(|(template, path)| (*template, path.as_str(), ctx.clone())).collect::<Vec<_>>();
+    for unit in units {
+        let unit_ctx = context!(unit => unit.header, unit_header => include_name(args, "--split", unit.header)?, ..ctx.clone());
+        renders.push(("split_header.jinja", unit.header, unit_ctx.clone()));
+        renders.push(("source.jinja", unit.source, unit_ctx));
+    }
+    let results = parallel_map(&renders, |(template, path, ctx)| {
+        let tmpl = env.get_template(template).unwrap();
+        write_output(path, |w| {
+            tmpl.render_to_write(ctx, w)?;
+            Ok(())
+        })
+        .map_err(|e| diagnostics::in_file(path, e))
+    });
+    for result in results {
+        result?;
+    }
+
+    Ok(())
+}
+
+// Every stencil's code back to back at multiples of `align`, with aliases sharing the code of the
+// stencil they alias. Returns where each stencil starts, by name, and the blob.
+fn code_blob<'a>(stencils: &[Stencil<'a>], align: u64) -> (BTreeMap<&'a str, usize>, Vec<u8>) {
+    let mut offsets = BTreeMap::new();
+    let mut blob = Vec::new();
+    for stencil in stencils.iter().filter(|s| s.alias_of.is_none()) {
+        blob.resize(blob.len().next_multiple_of(align as usize), 0);
+        offsets.insert(stencil.name, blob.len());
+        blob.extend_from_slice(&stencil.code);
+    }
+    for stencil in stencils {
+        if let Some(alias_of) = stencil.alias_of {
+            offsets.insert(stencil.name, offsets[alias_of]);
+        }
+    }
+    (offsets, blob)
+}
+
+// How the other outputs include a header. --deterministic names an absolute header relative to
+// the source, which includes it, so the outputs don't depend on where they were generated.
+fn include_name<'p>(args: &Args, option: &str, path: &'p str) -> Result<&'p str, String> {
+    let header = Path::new(path);
+    if !args.deterministic || !header.is_absolute() {
+        return Ok(path);
+    }
+    let dir = args.source.as_deref().and_then(|source| Path::new(source).parent());
+    dir.and_then(|dir| header.strip_prefix(dir).ok()).and_then(Path::to_str)
+        .ok_or_else(|| format!("--deterministic needs {} {} to be relative or in the directory of --source", option, path))
+}
+
+fn template_context(args: &Args, stencils: &[Stencil], families: &[families::Family], provenance: Option<&provenance::Provenance>) -> Result<minijinja::Value, String> {
+    let reloc_kinds = reloc_kinds(stencils);
+    let externs = stencils.iter()
+        .flat_map(|s| s.holes.iter().filter(|h| !h.internal).map(|h| h.name))
+        .collect::<BTreeSet<_>>();
+    let listings = match args.html_report {
+        Some(_) => stencils.iter().map(report::listing).collect(),
+        None => Vec::new(),
+    };
+    let (variant_groups, cpu_features) = variants::group(stencils)?;
+    let families = families::resolve(families, stencils)?;
+    let transforms = holes::transforms(stencils);
+    let transform_variables = holes::variables(&transforms);
+    if args.rust_crate.is_some() && let Some(name) = transform_variables.first() {
+        return Err(format!("--rust-crate can't patch holes transformed with the runtime variable {}", name));
+    }
+    // Only x86-64 calls are marked as far calls to route through a trampoline.
+    if args.trampolines && let Some(stencil) = stencils.iter().find(|s| s.arch != Arch::X86_64) {
+        return Err(format!("--trampolines only routes x86-64 calls, {} is {}", stencil.name, stencil.arch.name()));
+    }
+    let units = stencils.iter().filter_map(|s| s.unit).collect::<BTreeSet<_>>().into_iter()
+        .map(|header| include_name(args, "--split", header)).collect::<Result<Vec<_>, _>>()?;
+    let (blob_offsets, blob) = match args.blob || args.blob_bin.is_some() {
+        true => {
+            let (offsets, blob) = code_blob(stencils, args.code_align);
+            (Some(offsets), Some(blob))
+        }
+        false => (None, None),
+    };
+    Ok(context!(
+        stencils => stencils,
+        stencil_count => stencil_count(stencils),
+        listings => listings,
+        holes => holes::registry(stencils),
+        header => include_name(args, "--header", &args.header)?,
+        internal_header => args.internal_header.as_deref().map(|path| include_name(args, "--internal-header", path)).transpose()?,
+        units => units,
+        // Which --split unit a header or source is for, None for --header and --source.
+        unit => None::<&str>,
+        reloc_kinds => reloc_kinds,
+        externs => externs,
+        symbol_versions => symver::externs(stencils)?,
+        crate_name => crate_name(args),
+        alloc_helpers => args.alloc_helpers,
+        gdb_jit => args.gdb_jit,
+        perf_map => args.perf_map,
+        trampolines => args.trampolines,
+        callable => args.callable,
+        // Bundles render each architecture on its own, so the stencils share one.
+        return_thunk => stencils.first().map_or(Arch::X86_64, |s| s.arch).return_thunk(),
+        // The other architectures' stencils are emulated instead.
+        execute => args.execute && stencils.first().is_none_or(|s| s.arch == Arch::X86_64),
+        assert_ranges => args.assert_ranges,
+        code_align => args.code_align,
+        object_data => args.object.is_some() || args.format == OutputFormat::Staticlib,
+        hot_cold => args.cold_below.is_some(),
+        blob_offsets => blob_offsets,
+        blob_size => blob.as_ref().map_or(0, Vec::len),
+        blob_lz4 => blob.as_deref().filter(|_| args.compress_blob.is_some()).map(lz4::compress),
+        blob => blob.filter(|_| args.blob_bin.is_none()),
+        variant_groups => variant_groups,
+        cpu_features => cpu_features,
+        families => families,
+        transforms => transforms,
+        transform_variables => transform_variables,
+        provenance => provenance,
+    ))
+}
+
+// Renders each output once per architecture, every copy compiled only when targeting its own.
+// Stencil IDs agree between them, so the runtime uses them the same way on every target.
+fn emit_bundle(env: &Environment, args: &Args, bundle: &[ArchStencils], families: &[families::Family], provenance: Option<&provenance::Provenance>, outputs: &[(&str, String)]) -> Result<(), Box<dyn Error>> {
+    for (template, path) in outputs {
+        let tmpl = env.get_template(template)?;
+        write_output(path, |w| {
+            for (i, (arch, stencils)) in bundle.iter().enumerate() {
+                let arch = arch.expect("bundles name every architecture");
+                writeln!(w, "#{} {}", if i == 0 { "if" } else { "elif" }, arch.c_condition())?;
+                if *template == "header.jinja" {
+                    writeln!(w, "#define CNP_ARCH \"{}\"", arch.name())?;
+                }
+                tmpl.render_to_write(template_context(args, stencils, families, provenance)?, &mut *w)?;
+                writeln!(w)?;
+            }
+            writeln!(w, "#else")?;
+            writeln!(w, "#error \"no stencils for this architecture\"")?;
+            writeln!(w, "#endif")?;
+            Ok(())
+        })
+        .map_err(|e| diagnostics::in_file(path, e))?;
+    }
+    Ok(())
+}
+
+// Per-architecture objects only make a header and source, and must agree on the stencils.
+// An output written to stdout can't be included by the others, and is the only thing that can
+// go there.
+fn check_stdout(args: &Args, output_paths: &[&str]) -> Result<(), String> {
+    if args.source.is_none() && args.format == OutputFormat::Source && args.header != STDOUT {
+        return Err("--format source needs --source".to_string());
+    }
+    let piped = output_paths.iter().copied().chain(args.depfile.iter().chain(&args.manifest).chain(&args.ids).map(String::as_str)).filter(|path| *path == STDOUT).count();
+    if piped > 1 {
+        return Err("only one output can be written to stdout".to_string());
+    }
+    if args.header == STDOUT {
+        let includers = [
+            ("--source", args.source.is_some()),
+            ("--internal-header", args.internal_header.is_some()),
+            ("--offsets", args.offsets.is_some()),
+            ("--emit-tests", args.emit_tests.is_some()),
+            ("--split", args.split.is_some()),
+            ("--format staticlib", args.format == OutputFormat::Staticlib),
+        ];
+        if let Some((option, _)) = includers.iter().find(|(_, given)| *given) {
+            return Err(format!("{} can't include a header written to stdout", option));
+        }
+    }
+    let conflicting = [
+        ("--dump", args.dump != Dump::None),
+        ("--report", !args.report.is_empty()),
+        // There's nothing to read the previous stencils back from.
+        ("--merge", args.merge),
+    ];
+    if piped > 0 && let Some((option, _)) = conflicting.iter().find(|(_, given)| *given) {
+        return Err(format!("{} can't be used with an output written to stdout", option));
+    }
+    Ok(())
+}
+
+fn check_bundle(args: &Args, bundle: &[ArchStencils]) -> Result<(), String> {
+    if bundle[0].0.is_none() {
+        return Ok(());
+    }
+    let unsupported = [
+        ("--object", args.object.is_some()),
+        ("--format staticlib", args.format == OutputFormat::Staticlib),
+        ("--blob-bin", args.blob_bin.is_some()),
+        ("--rust-crate", args.rust_crate.is_some()),
+        ("--html-report", args.html_report.is_some()),
+        ("--abi", args.abi.is_some()),
+        ("--split", args.split.is_some()),
+        ("--merge", args.merge),
+        // Register names differ between architectures.
+        ("--glue", args.glue.is_some()),
+    ];
+    if let Some((option, _)) = unsupported.iter().find(|(_, given)| *given) {
+        return Err(format!("{} can't be used with per-architecture objects", option));
+    }
+    let ids = |stencils: &[Stencil]| stencils.iter().map(|s| (s.name.to_string(), s.id)).collect::<BTreeMap<_, _>>();
+    let (first_arch, first) = &bundle[0];
+    let expected = ids(first);
+    for (arch, stencils) in &bundle[1..] {
+        let found = ids(stencils);
+        if let Some(name) = expected.keys().find(|name| !found.contains_key(*name)).or_else(|| found.keys().find(|name| !expected.contains_key(*name))) {
+            let (has, lacks) = if expected.contains_key(name) { (first_arch, arch) } else { (arch, first_arch) };
+            return Err(format!("stencil {} is in the {} objects but not the {} ones", name, has.unwrap().name(), lacks.unwrap().name()));
+        }
+        if let Some(name) = expected.keys().find(|name| expected[*name] != found[*name]) {
+            return Err(format!("stencil {} gets different IDs on {} and {}, use --sort name or --ids", name, first_arch.unwrap().name(), arch.unwrap().name()));
+        }
+    }
+    Ok(())
+}
+
+// Objects given as `arch=path` are grouped by architecture in command line order. Without
+// architectures all objects are one group.
+fn group_objects(objects: &[String]) -> Result<Vec<ObjectGroup>, String> {
+    let mut groups = Vec::<ObjectGroup>::new();
+    for object in objects {
+        let (arch, path) = split_arch(object);
+        if groups.first().is_some_and(|(first, _)| first.is_some() != arch.is_some()) {
+            return Err(format!("{}: either every object or none must be given as arch=path", object));
+        }
+        match groups.iter_mut().find(|(group, _)| *group == arch) {
+            Some((_, paths)) => paths.push(path.to_string()),
+            None => groups.push((arch, vec![path.to_string()])),
+        }
+    }
+    Ok(groups)
+}
+
+fn split_arch(object: &str) -> (Option<Arch>, &str) {
+    object.split_once('=')
+        .and_then(|(name, path)| Some((Some(Arch::from_name(name)?), path)))
+        .unwrap_or((None, object))
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Compression {
+    /// The LZ4 block format, with a decompressor small enough to generate
+    Lz4,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum OutputFormat {
+    /// A C source to compile along with the header
+    Source,
+    /// A static library holding the compiled source and the stencil data, built with $CC
+    /// (default cc) and $CFLAGS
+    Staticlib,
+}
+
+// The CNP_RELOC_* kinds in enum order.
+fn reloc_kinds<'a>(stencils: &[Stencil<'a>]) -> BTreeSet<&'static str> {
+    stencils.iter().flat_map(|s| s.relocs.iter().map(|r| r.relocation)).collect()
+}
+
+fn stencil_count(stencils: &[Stencil]) -> usize {
+    stencils.iter().map(|s| s.id + 1).max().unwrap_or(0)
+}
+
+#[derive(Parser, Debug)]
+#[command(after_help = diagnostics::EXIT_CODES)]
+struct Args {
+    #[arg(required = true)]
+    objects: Vec<String>,
+    /// Where to write the header, - for stdout
+    #[arg(long)]
+    header: String,
+    /// Only declare the stencil IDs and sizes and the emit functions in --header, and the code,
+    /// reloc tables and cnp_stencils the source needs in this header, which includes it
+    #[arg(long)]
+    internal_header: Option<String>,
+    /// Where to write the source, - for stdout. --format source needs it unless the header goes
+    /// to stdout, since the source includes the header
+    #[arg(long)]
+    source: Option<String>,
+    /// What to generate besides the header
+    #[arg(long, value_enum, default_value_t = OutputFormat::Source)]
+    format: OutputFormat,
+    /// Where to write the library for --format staticlib
+    #[arg(long, required_if_eq("format", "staticlib"))]
+    lib: Option<String>,
+    /// Skip regeneration when the inputs, templates and options match the previous run and the
+    /// outputs are as it left them. Runs with --dump, --report or --execute always regenerate
+    #[arg(long)]
+    cache_dir: Option<String>,
+    #[arg(long, value_enum, default_value_t = SortOrder::Name)]
+    sort: SortOrder,
+    /// Also write a no_std Rust crate with the stencil data and copy-and-patch functions, and
+    /// tests for `cargo test` that check what they patch
+    #[arg(long)]
+    rust_crate: Option<String>,
+    /// Also emit cnp_code_alloc/cnp_code_finalize helpers for W^X executable memory
+    #[arg(long)]
+    alloc_helpers: bool,
+    /// Also emit helpers that register emitted code with the GDB JIT interface
+    #[arg(long)]
+    gdb_jit: bool,
+    /// Also emit helpers that record emitted code in /tmp/perf-<pid>.map for perf
+    #[arg(long)]
+    perf_map: bool,
+    /// Also emit trampoline pool helpers for calls that can't reach their target with rel32, for
+    /// x86-64 stencils
+    #[arg(long)]
+    trampolines: bool,
+    /// Also emit cnp_emit_callable, which makes a stencil return instead of continuing
+    #[arg(long)]
+    callable: bool,
+    /// Assert in the patching code that every patched value fits its field, such as a rel32
+    /// target being within 2GB. The C asserts go away with NDEBUG, the Rust ones in release builds.
+    /// Building the C source with -DCNP_RANGE_FAILED=f calls `void f(const char*)` instead
+    #[arg(long)]
+    assert_ranges: bool,
+    /// Alignment of the generated code arrays in bytes
+    #[arg(long, default_value_t = 16, value_parser = parse_alignment)]
+    code_align: u64,
+    /// Also emit superinstructions for the stencil sequences listed in this file, one
+    /// `[name =] a;b;c` per line
+    #[arg(long)]
+    fuse: Option<String>,
+    /// What to print about each stencil while generating
+    #[arg(long, value_enum, default_value_t = Dump::None)]
+    dump: Dump,
+    /// Print a report on the generated stencils after the dump, can be repeated
+    #[arg(long, value_enum)]
+    report: Vec<Report>,
+    /// Also write an HTML page describing every stencil, with its code and patched bytes
+    #[arg(long)]
+    html_report: Option<String>,
+    /// Also write a header of plain #defines with each stencil's ID, code size and patch offsets,
+    /// for assembly and linker scripts
+    #[arg(long)]
+    offsets: Option<String>,
+    /// Also write a C program that emits every stencil with test values and checks the patched
+    /// bytes, running the ones that take and reference nothing
+    #[arg(long)]
+    emit_tests: Option<String>,
+    // Set by `verify --execute`, to have the tests run every x86-64 stencil and the others emulated.
+    #[arg(skip)]
+    execute: bool,
+    /// Also write a make/ninja depfile listing the files the outputs were generated from
+    #[arg(long)]
+    depfile: Option<String>,
+    /// Also write a JSON manifest of the generated files with their hashes and the inputs they
+    /// were generated from
+    #[arg(long)]
+    manifest: Option<String>,
+    /// Only let stencils reference the undefined symbols this file allows, one symbol or `prefix*`
+    /// per line, with `!` in front to deny it
+    #[arg(long)]
+    externs: Option<String>,
+    /// Emit the stencils most often copied according to this profile first, one `name count`
+    /// per line, so they share pages and cache lines. IDs don't change
+    #[arg(long)]
+    profile: Option<String>,
+    /// Also emit a cnp_select_<family> function mapping an opcode and type to a stencil for each
+    /// family in this file, one `family = op... * type... [: pattern]` per line, where the
+    /// pattern names the stencils and defaults to `{op}_{type}`
+    #[arg(long)]
+    families: Option<String>,
+    /// Read each stencil's op and types out of its name with this scheme for the templates and
+    /// --abi, as in `{op}_{type}_{type}`, or `{op}_{type}...` for any number of types. Each part
+    /// is the text up to the separator after it
+    #[arg(long)]
+    naming: Option<String>,
+    /// Also emit glue stencils that move values between registers, for between stencils that
+    /// expect them in different ones. Each line of this file is a `name = dst<-src...`, and a
+    /// `scratch = reg` line names the register that breaks cycles
+    #[arg(long)]
+    glue: Option<String>,
+    /// Settings for argument holes, one `hole` or `prefix*` per line followed by `bits=N` and
+    /// optionally `shift=N` to patch only an N-bit payload into the hole's field
+    #[arg(long)]
+    holes: Option<String>,
+    /// Rename stencils in the generated code, one `old = new` per line or `prefix* = new*` to
+    /// replace a prefix, `prefix* = *` to strip it
+    #[arg(long)]
+    rename_map: Option<String>,
+    /// Declare and define the stencils matching each line of this file in a header and source of
+    /// their own, one `header source = name-or-prefix*...` per line. The rest, cnp_stencils and
+    /// cnp_emit stay in --header and --source, which every unit's source is compiled with
+    #[arg(long, requires = "source", conflicts_with = "lib")]
+    split: Option<String>,
+    /// Keep the stencils already in --header and --source that the objects don't define, after
+    /// the new ones and with the IDs they had, so regenerating from some objects only replaces
+    /// their stencils. The source must have the code, not --blob, --object or --split
+    #[arg(long, requires = "source")]
+    merge: bool,
+    /// Stamp the outputs with the tool version and the sha256 of every input, and only name
+    /// source files by file name, so they depend on nothing but what the inputs contain. The
+    /// --manifest and --depfile name files relative to the current directory
+    #[arg(long)]
+    deterministic: bool,
+    /// Put the stencils the --profile counts fewer than this many copies of in cold sections and
+    /// the rest in hot ones, for the linker to place apart
+    #[arg(long, requires = "profile", conflicts_with_all = ["object", "lib", "blob", "blob_bin"])]
+    cold_below: Option<u64>,
+    /// Keep each stencil's cnp_stencil_id in this file across runs, new stencils get new IDs
+    /// and the file is updated
+    #[arg(long)]
+    ids: Option<String>,
+    /// Also write the stencils and their patch arguments as JSON, the baseline for `check-abi`
+    #[arg(long)]
+    abi: Option<String>,
+    /// Put every stencil's code in one cnp_code_blob array, with a cnp_code_ranges table of where
+    /// each one is, instead of an array per stencil
+    #[arg(long, conflicts_with_all = ["object", "lib"])]
+    blob: bool,
+    /// Write the --blob code to this file instead of the source, for the build to embed as
+    /// cnp_code_blob
+    #[arg(long, conflicts_with_all = ["object", "lib"])]
+    blob_bin: Option<String>,
+    /// Compress the --blob code in the source, cnp_decompress_code_blob() unpacks it into
+    /// cnp_code_blob at startup. Saves flash at the cost of the copy in RAM
+    #[arg(long, requires = "blob", conflicts_with = "blob_bin")]
+    compress_blob: Option<Compression>,
+    /// Write the code arrays, reloc tables and cnp_stencils into this relocatable object
+    /// instead of the source, which then only holds the functions and compiles much faster
+    #[arg(long)]
+    object: Option<String>,
+    #[command(flatten)]
+    read: ReadArgs,
+}
+
+// How objects are turned into stencils, shared by every command that reads them.
+#[derive(clap::Args, Debug)]
+struct ReadArgs {
+    /// Remove the trailing `ret` of terminator stencils
+    #[arg(long)]
+    trim_ret: bool,
+    /// Re-encode x86-64 rel32 jumps within a stencil as rel8 where they reach
+    #[arg(long)]
+    shorten_branches: bool,
+    /// Also extract static functions as stencils, only those starting with PREFIX if given
+    #[arg(long, value_name = "PREFIX", num_args = 0..=1, default_missing_value = "")]
+    include_local_functions: Option<String>,
+    /// What to do with weak function definitions
+    #[arg(long, value_enum, default_value_t = WeakPolicy::Stencil)]
+    weak_functions: WeakPolicy,
+    /// Only take global and weak functions with this ELF visibility as stencils, the rest are
+    /// left external
+    #[arg(long, value_enum, default_value_t = Visibility::All)]
+    visibility: Visibility,
+    /// Only take the functions named in this file as stencils, one symbol per line, and leave
+    /// the rest external
+    #[arg(long)]
+    symbols_from: Option<String>,
+    /// Copy string literals of up to MAX_LEN bytes (64 if not given) that a stencil references
+    /// to the end of its code, instead of rejecting it
+    #[arg(long, value_name = "MAX_LEN", num_args = 0..=1, require_equals = true, default_missing_value = "64")]
+    inline_strings: Option<usize>,
+    /// How warnings and errors are written to stderr
+    #[arg(long, value_enum, default_value_t = diagnostics::Format::Text)]
+    diagnostics_format: diagnostics::Format,
+}
+
+// Compiles C sources with the recommended flags, or with their compile_commands.json entries,
+// and generates from the objects. The sources take the place of the objects on the command line.
+#[derive(Parser, Debug)]
+#[command(bin_name = "stenciltool build", after_help = diagnostics::EXIT_CODES)]
+struct BuildArgs {
+    /// The C compiler
+    #[arg(long, default_value = "cc")]
+    cc: String,
+    /// Extra compiler flags, split on whitespace, after the recommended ones
+    #[arg(long, allow_hyphen_values = true)]
+    cflags: Vec<String>,
+    /// Compile each source with its command from this compile_commands.json instead, so the
+    /// objects are built exactly like the rest of the project
+    #[arg(long, conflicts_with_all = ["cc", "cflags"])]
+    compile_commands: Option<String>,
+    #[command(flatten)]
+    generate: Args,
+}
+
+// Generates, then builds the --emit-tests program with the source, and the --object or --blob-bin
+// code, and runs it, failing if any stencil does.
+#[derive(Parser, Debug)]
+#[command(bin_name = "stenciltool verify", after_help = diagnostics::EXIT_CODES)]
+struct VerifyArgs {
+    /// The C compiler to build the tests with
+    #[arg(long, default_value = "cc")]
+    cc: String,
+    /// Also run every stencil with dummy values patched into its holes, and fail the ones that
+    /// crash or don't reach their continuation. x86-64 stencils run natively, each in a process
+    /// of its own, AArch64, RISC-V and ARM ones are emulated
+    #[arg(long)]
+    execute: bool,
+    #[command(flatten)]
+    generate: Args,
+}
+
+// Writes a CMake file defining stenciltool_add_stencils(), to include or use as a package config.
+#[derive(Parser, Debug)]
+#[command(bin_name = "stenciltool gen-cmake", after_help = diagnostics::EXIT_CODES)]
+struct GenCmakeArgs {
+    /// Where to write it, stenciltool-config.cmake for find_package, stdout if not given
+    #[arg(short, long)]
+    output: Option<String>,
+}
+
+// Writes ninja rules for compiling stencil sources and generating from them, and build
+// statements for `sources` if given.
+#[derive(Parser, Debug)]
+#[command(bin_name = "stenciltool gen-ninja", after_help = diagnostics::EXIT_CODES)]
+struct GenNinjaArgs {
+    sources: Vec<String>,
+    #[arg(long, requires = "sources", default_value = "stencils.h")]
+    header: String,
+    #[arg(long, requires = "sources", default_value = "stencils.c")]
+    source: String,
+    /// The C compiler, which must understand -MD -MF
+    #[arg(long, default_value = "cc")]
+    cc: String,
+    /// Where to write the rules, stdout if not given
+    #[arg(short, long)]
+    output: Option<String>,
+}
+
+// Prints one stencil's code with the bytes its holes patch marked.
+#[derive(Parser, Debug)]
+#[command(bin_name = "stenciltool explain", after_help = diagnostics::EXIT_CODES)]
+struct ExplainArgs {
+    object: String,
+    /// The stencil's name, or its demangled symbol
+    stencil: String,
+    #[command(flatten)]
+    read: ReadArgs,
+}
+
+// Compares the stencils in the objects against a baseline written with --abi.
+#[derive(Parser, Debug)]
+#[command(bin_name = "stenciltool check-abi", after_help = diagnostics::EXIT_CODES)]
+struct CheckAbiArgs {
+    baseline: String,
+    #[arg(required = true)]
+    objects: Vec<String>,
+    /// The fusion config the baseline was generated with
+    #[arg(long)]
+    fuse: Option<String>,
+    #[command(flatten)]
+    read: ReadArgs,
+}
+
+// Prints a completion script for the shell. It is set up once, so it stays undocumented.
+#[derive(Parser, Debug)]
+#[command(bin_name = "stenciltool completions", after_help = diagnostics::EXIT_CODES)]
+struct CompletionsArgs {
+    #[arg(value_enum)]
+    shell: completions::Shell,
+}
+
+fn parse_alignment(value: &str) -> Result<u64, String> {
+    match value.parse::<u64>() {
+        Ok(align) if align.is_power_of_two() => Ok(align),
+        _ => Err(format!("{} is not a power of two", value)),
+    }
+}
+
+fn output_files(args: &Args) -> Vec<(&'static str, String)> {
+    let mut outputs = vec![("header.jinja", args.header.clone())];
+    if let Some(path) = &args.internal_header {
+        outputs.push(("internal_header.jinja", path.clone()));
+    }
+    if let Some(path) = &args.source {
+        outputs.insert(0, ("source.jinja", path.clone()));
+    }
+    if let Some(path) = &args.offsets {
+        outputs.push(("offsets.jinja", path.clone()));
+    }
+    if let Some(path) = &args.emit_tests {
+        outputs.push(("tests.jinja", path.clone()));
+    }
+    if let Some(path) = &args.html_report {
+        outputs.push(("report.jinja", path.clone()));
+    }
+    if let Some(path) = &args.abi {
+        outputs.push(("abi.jinja", path.clone()));
+    }
+    if let Some(dir) = &args.rust_crate {
+        let dir = Path::new(dir);
+        outputs.push(("rust_cargo.jinja", dir.join("Cargo.toml").to_string_lossy().into_owned()));
+        outputs.push(("rust_lib.jinja", dir.join("src").join("lib.rs").to_string_lossy().into_owned()));
+        outputs.push(("rust_tests.jinja", dir.join("tests").join("stencils.rs").to_string_lossy().into_owned()));
+    }
+    outputs
+}
+
+fn crate_name(args: &Args) -> String {
+    let name = args.rust_crate.as_ref()
+        .and_then(|dir| Path::new(dir).file_name().map(|name| name.to_string_lossy().into_owned()))
+        .unwrap_or_else(|| "stencils".to_string());
+    name.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect()
+}
+
+fn cache_key(env: &Environment, args: &Args, configs: &[&str], datas: &[Vec<Vec<u8>>]) -> String {
+    let mut key = KeyBuilder::new();
+    key.add(format!("{:?}", args).as_bytes());
+    for config in configs {
+        key.add(config.as_bytes());
+    }
+    let mut templates = env.templates().collect::<Vec<_>>();
+    templates.sort_by_key(|(name, _)| *name);
+    for (name, template) in templates {
+        key.add(name.as_bytes());
+        key.add(template.source().as_bytes());
+    }
+    for data in datas.iter().flatten() {
+        key.add(data);
+    }
+    key.finish()
+}
+
+fn main() -> ExitCode {
+    // Subcommands are picked off by hand so that generating keeps its `stenciltool <objects>...` form.
+    let (result, format) = match std::env::args().nth(1).as_deref() {
+        Some("check-abi") => {
+            let args = CheckAbiArgs::parse_from(std::env::args_os().skip(1));
+            (check_abi(&args), args.read.diagnostics_format)
+        }
+        Some("build") => {
+            let args = BuildArgs::parse_from(std::env::args_os().skip(1));
+            (build(&args), args.generate.read.diagnostics_format)
+        }
+        Some("verify") => {
+            let args = VerifyArgs::parse_from(std::env::args_os().skip(1));
+            let format = args.generate.read.diagnostics_format;
+            (verify(args), format)
+        }
+        Some("gen-cmake") => {
+            let args = GenCmakeArgs::parse_from(std::env::args_os().skip(1));
+            (gen_cmake(&args), diagnostics::Format::Text)
+        }
+        Some("gen-ninja") => {
+            let args = GenNinjaArgs::parse_from(std::env::args_os().skip(1));
+            (gen_ninja(&args), diagnostics::Format::Text)
+        }
+        Some("completions") => {
+            let args = CompletionsArgs::parse_from(std::env::args_os().skip(1));
+            (completions(&args), diagnostics::Format::Text)
+        }
+        Some("explain") => {
+            let args = ExplainArgs::parse_from(std::env::args_os().skip(1));
+            (explain(&args), args.read.diagnostics_format)
+        }
+        _ => {
+            let args = Args::parse();
+            (run(&args), args.read.diagnostics_format)
+        }
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            diagnostics::report_error(format, &*e);
+            ExitCode::from(Category::of(&*e).exit_code())
+        }
+    }
+}
+
+fn read_files(paths: &[String]) -> Result<Vec<Vec<u8>>, Box<dyn Error>> {
+    let datas = paths.iter()
+        .map(|path| fs::read(path).map_err(|e| diagnostics::in_file(path, e)))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(datas)
+}
+
+// `only` is the --symbols-from list, if given.
+fn read_objects<'a>(paths: &[String], datas: &'a [Vec<u8>], args: &ReadArgs, only: Option<&HashSet<&str>>) -> Result<Extracted<'a>, Box<dyn Error>> {
+    // Objects are independent until emission, so parse and transform them in parallel
+    // and merge in command line order to keep the output deterministic.
+    let inputs = paths.iter().zip(datas.iter()).collect::<Vec<_>>();
+    let progress = Progress::new(inputs.len());
+    let results = parallel_map(&inputs, |(path, data)| {
+        progress.time(path, || process_object(path, data, args, only).map_err(|e| diagnostics::in_file(path, e)))
+    });
+    progress.summary();
+
+    let mut stencils = Vec::<Stencil>::new();
+    let mut inputs = Vec::new();
+    for result in results {
+        let (object_stencils, object_inputs) = result?;
+        stencils.extend(object_stencils);
+        inputs.extend(object_inputs);
+    }
+    if let Some(only) = only {
+        let extracted = stencils.iter().map(|s| s.name).collect::<HashSet<_>>();
+        if let Some(name) = only.iter().filter(|name| !extracted.contains(*name)).min() {
+            let path = args.symbols_from.as_deref().unwrap_or_default();
+            return Err(diagnostics::in_file(path, format!("{} isn't a function the objects define", name)).into());
+        }
+    }
+    Ok((stencils, inputs))
+}
+
+// The --symbols-from list, one symbol per line.
+fn parse_symbol_list<'t>(path: Option<&String>, text: Option<&'t String>) -> Result<Option<HashSet<&'t str>>, Box<dyn Error>> {
+    let (Some(path), Some(text)) = (path, text) else {
+        return Ok(None);
+    };
+    let mut symbols = HashSet::new();
+    for (lineno, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.contains(char::is_whitespace) {
+            return Err(diagnostics::in_file(path, Category::Malformed.error(format!("line {}: expected one symbol", lineno + 1))).into());
+        }
+        if !line.is_empty() {
+            symbols.insert(line);
+        }
+    }
+    Ok(Some(symbols))
+}
+
+fn read_config(path: Option<&String>) -> Result<Option<String>, Box<dyn Error>> {
+    let config = path
+        .map(|path| fs::read_to_string(path).map_err(|e| diagnostics::in_file(path, e)))
+        .transpose()?;
+    Ok(config)
+}
+
+fn completions(args: &CompletionsArgs) -> Result<(), Box<dyn Error>> {
+    let commands = vec![
+        ("", Args::command()),
+        ("build", BuildArgs::command()),
+        ("check-abi", CheckAbiArgs::command()),
+        ("explain", ExplainArgs::command()),
+        ("gen-cmake", GenCmakeArgs::command()),
+        ("gen-ninja", GenNinjaArgs::command()),
+        ("verify", VerifyArgs::command()),
+    ];
+    print!("{}", completions::generate(args.shell, commands));
+    Ok(())
+}
+
+fn gen_cmake(args: &GenCmakeArgs) -> Result<(), Box<dyn Error>> {
+    let env = template_env();
+    let ctx = context!(executable => std::env::current_exe()?.to_string_lossy());
+    let tmpl = env.get_template("cmake.jinja")?;
+    match &args.output {
+        Some(path) => write_output(path, |w| Ok(tmpl.render_to_write(&ctx, w).map(|_| ())?)).map_err(|e| diagnostics::in_file(path, e))?,
+        None => println!("{}", tmpl.render(&ctx)?),
+    }
+    Ok(())
+}
+
+#[derive(serde::Serialize)]
+struct NinjaObject<'a> {
+    source: &'a str,
+    object: String,
+}
+
+fn gen_ninja(args: &GenNinjaArgs) -> Result<(), Box<dyn Error>> {
+    let objects = args.sources.iter().map(|source| NinjaObject {
+        source,
+        object: Path::new(source).with_extension("stencil.o").to_string_lossy().into_owned(),
+    }).collect::<Vec<_>>();
+    let env = template_env();
+    let ctx = context!(
+        executable => std::env::current_exe()?.to_string_lossy(),
+        cc => args.cc,
+        cflags => compile::STENCIL_CFLAGS.join(" "),
+        objects => objects,
+        header => args.header,
+        source => args.source,
+    );
+    let tmpl = env.get_template("ninja.jinja")?;
+    match &args.output {
+        Some(path) => write_output(path, |w| Ok(tmpl.render_to_write(&ctx, w).map(|_| ())?)).map_err(|e| diagnostics::in_file(path, e))?,
+        None => println!("{}", tmpl.render(&ctx)?),
+    }
+    Ok(())
+}
+
+fn explain(args: &ExplainArgs) -> Result<(), Box<dyn Error>> {
+    let datas = read_files(std::slice::from_ref(&args.object))?;
+    let symbols_config = read_config(args.read.symbols_from.as_ref())?;
+    let only = parse_symbol_list(args.read.symbols_from.as_ref(), symbols_config.as_ref())?;
+    let (mut stencils, _) = read_objects(std::slice::from_ref(&args.object), &datas, &args.read, only.as_ref())?;
+    let demangled = stencils.iter().map(|s| demangle::demangle(s.name)).collect::<Vec<_>>();
+    rename_demangled(&mut stencils, &demangled)?;
+    let stencil = stencils.iter()
+        .find(|s| s.name == args.stencil || s.display == Some(args.stencil.as_str()))
+        .ok_or_else(|| format!("{}: no stencil {}", args.object, args.stencil))?;
+    print!("{}", report::explain(stencil, std::io::stdout().is_terminal()));
+    Ok(())
+}
+
+fn check_abi(args: &CheckAbiArgs) -> Result<(), Box<dyn Error>> {
+    let baseline = fs::read_to_string(&args.baseline).map_err(|e| diagnostics::in_file(&args.baseline, e))?;
+    let baseline = abi::parse(&baseline).map_err(|e| diagnostics::in_file(&args.baseline, Category::Malformed.error(e)))?;
+    let fuse_config = read_config(args.fuse.as_ref())?;
+    let datas = read_files(&args.objects)?;
+    let symbols_config = read_config(args.read.symbols_from.as_ref())?;
+    let only = parse_symbol_list(args.read.symbols_from.as_ref(), symbols_config.as_ref())?;
+
+    let (mut stencils, _) = read_objects(&args.objects, &datas, &args.read, only.as_ref())?;
+    let demangled = stencils.iter().map(|s| demangle::demangle(s.name)).collect::<Vec<_>>();
+    rename_demangled(&mut stencils, &demangled)?;
+    let fusions = match (&args.fuse, &fuse_config) {
+        (Some(path), Some(text)) => fuse::parse_fusions(text).map_err(|e| diagnostics::in_file(path, Category::Malformed.error(e)))?,
+        _ => Vec::new(),
+    };
+    let fused = fuse::fuse(&stencils, &fusions)?;
+    let count = stencils.len();
+    stencils.extend(fused.iter().map(|f| f.stencil()));
+    populate_stencil_holes(&mut stencils[count..]);
+
+    let changes = abi::breaking_changes(&baseline, &abi::from_stencils(&stencils));
+    for change in &changes {
+        diagnostics::report(args.read.diagnostics_format, Severity::Error, Some(&args.baseline), change);
+    }
+    if !changes.is_empty() {
+        return Err(Category::Mismatch.error(format!("{} breaking changes against {}", changes.len(), args.baseline)));
+    }
+    Ok(())
+}
+
+fn build(args: &BuildArgs) -> Result<(), Box<dyn Error>> {
+    let database = args.compile_commands.as_ref()
+        .map(|path| -> Result<_, Box<dyn Error>> {
+            let text = fs::read_to_string(path).map_err(|e| diagnostics::in_file(path, e))?;
+            Ok(json::parse(&text).map_err(|e| diagnostics::in_file(path, Category::Malformed.error(e)))?)
+        })
+        .transpose()?;
+    let dir = compile::TempDir::new()?;
+    let mut objects = Vec::new();
+    for (i, source) in args.generate.objects.iter().enumerate() {
+        let object = dir.object(i, source);
+        let (command, cwd) = match &database {
+            Some(database) => {
+                let (command, cwd) = compile::database_command(database, Path::new(source)).map_err(|e| diagnostics::in_file(source, e))?;
+                (command, Some(cwd))
+            }
+            None => {
+                let mut command = vec![args.cc.clone()];
+                command.extend(compile::STENCIL_CFLAGS.iter().map(|flag| flag.to_string()));
+                command.extend(args.cflags.iter().flat_map(|flags| flags.split_whitespace()).map(str::to_string));
+                command.push(source.clone());
+                (command, None)
+            }
+        };
+        compile::compile(&command, cwd.as_deref(), &object).map_err(|e| diagnostics::in_file(source, e))?;
+        objects.push(object.to_string_lossy().into_owned());
+    }
+    generate(&args.generate, &objects)
+}
+
+fn verify(mut args: VerifyArgs) -> Result<(), Box<dyn Error>> {
+    let source = match &args.generate.source {
+        Some(source) if args.generate.format == OutputFormat::Source && *source != STDOUT && args.generate.header != STDOUT => source.clone(),
+        _ => return Err("verify needs --format source with --header and --source written to files".into()),
+    };
+    let dir = compile::TempDir::new()?;
+    let tests = match &args.generate.emit_tests {
+        Some(tests) if tests == STDOUT => return Err("verify needs --emit-tests written to a file".into()),
+        Some(tests) => tests.clone(),
+        None => dir.path.join("tests.c").to_string_lossy().into_owned(),
+    };
+    if [&args.generate.object, &args.generate.blob_bin].into_iter().flatten().any(|path| path == STDOUT) {
+        return Err("verify needs --object and --blob-bin written to files".into());
+    }
+    args.generate.emit_tests = Some(tests.clone());
+    args.generate.execute = args.execute;
+    generate(&args.generate, &args.generate.objects)?;
+    // The tests' runtime only patches x86-64 code, --execute has emulated the others' stencils.
+    if args.execute && !native_objects(&args.generate.objects)? {
+        return Ok(());
+    }
+    let program = dir.path.join("tests");
+    // A value out of range fails the stencil being tested rather than aborting them all.
+    let mut command = vec![args.cc.clone(), "-std=gnu11".to_string(), "-Wno-builtin-declaration-mismatch".to_string(), "-DCNP_RANGE_FAILED=cnp_test_range_failed".to_string(), "-I.".to_string(), source, tests];
+    // The code the source leaves out, linked in like the build would.
+    command.extend(args.generate.object.iter().cloned());
+    if let Some(path) = &args.generate.blob_bin {
+        let blob = fs::read(path).map_err(|e| diagnostics::in_file(path, e))?;
+        let embedded = dir.path.join("blob.c");
+        let bytes = blob.iter().map(|byte| format!("{:#04x},", byte)).collect::<String>();
+        fs::write(&embedded, format!("#include <stdint.h>\n\nuint8_t cnp_code_blob[] __attribute__((aligned({}))) = {{{}}};\n", args.generate.code_align, bytes))?;
+        command.push(embedded.to_string_lossy().into_owned());
+    }
+    let command = command.iter().map(String::as_str).collect::<Vec<_>>();
+    compile::link(&command, &program).map_err(|e| format!("can't build the tests: {}", e))?;
+    let status = Command::new(&program).status().map_err(|e| format!("can't run the tests: {}", e))?;
+    if !status.success() {
+        return Err(Category::TestFailure.error(format!("the tests failed with {}", status)));
+    }
+    Ok(())
+}
+
+// Whether any of the objects is x86-64.
+fn native_objects(objects: &[String]) -> Result<bool, Box<dyn Error>> {
+    for (arch, paths) in group_objects(objects)? {
+        let arch = match arch {
+            Some(arch) => arch,
+            None => Arch::from_machine(object_machine(&read_files(&paths[..1])?[0])?)?,
+        };
+        if arch == Arch::X86_64 {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+// Compiles the rendered source and archives it with the stencil data object.
+fn write_staticlib(path: &str, dir: &compile::TempDir, source: &str, data: &[u8]) -> Result<(), Box<dyn Error>> {
+    // The source includes the header by the path it was given, relative to the current directory.
+    let mut command = vec![std::env::var("CC").unwrap_or_else(|_| "cc".to_string()), "-O2".to_string(), "-fPIC".to_string(), "-I.".to_string()];
+    command.extend(compile::split_command(&std::env::var("CFLAGS").unwrap_or_default())?);
+    command.push(source.to_string());
+    let object = dir.path.join("stencils.o");
+    compile::compile(&command, None, &object)?;
+    let functions = fs::read(&object)?;
+    let archive = archive::write(&[("stencils.o", &functions), ("stencil_data.o", data)])?;
+    write_output(path, |w| Ok(w.write_all(&archive)?))
+}
+
+fn run(args: &Args) -> Result<(), Box<dyn Error>> {
+    generate(args, &args.objects)
+}
+
+// `objects` are what gets read, `args.objects` is what the outputs depend on. They differ when
+// `build` compiled the objects from sources.
+fn generate(args: &Args, objects: &[String]) -> Result<(), Box<dyn Error>> {
+    let fuse_config = read_config(args.fuse.as_ref())?;
+    let externs_config = read_config(args.externs.as_ref())?;
+    let profile_config = read_config(args.profile.as_ref())?;
+    let families_config = read_config(args.families.as_ref())?;
+    let glue_config = read_config(args.glue.as_ref())?;
+    let holes_config = read_config(args.holes.as_ref())?;
+    let rename_config = read_config(args.rename_map.as_ref())?;
+    let split_config = read_config(args.split.as_ref())?;
+    let symbols_config = read_config(args.read.symbols_from.as_ref())?;
+    // Read now, the outputs are overwritten later.
+    let previous = match &args.source {
+        Some(source) if args.merge && Path::new(source).exists() => {
+            let header = fs::read_to_string(&args.header).map_err(|e| diagnostics::in_file(&args.header, e))?;
+            Some((header, source, fs::read_to_string(source).map_err(|e| diagnostics::in_file(source, e))?))
+        }
+        _ => None,
+    };
+    let groups = group_objects(objects)?;
+    let datas = groups.iter().map(|(_, paths)| read_files(paths)).collect::<Result<Vec<_>, _>>()?;
+    // A missing ID file is how a project starts using one.
+    let ids_config = match &args.ids {
+        Some(path) if Path::new(path).exists() => Some(fs::read_to_string(path).map_err(|e| diagnostics::in_file(path, e))?),
+        _ => None,
+    };
+
+    // Parsed up front, the units' files are outputs too.
+    let units = match (&args.split, &split_config) {
+        (Some(path), Some(text)) => split::parse_units(text).map_err(|e| diagnostics::in_file(path, Category::Malformed.error(e)))?,
+        _ => Vec::new(),
+    };
+
+    let env = template_env();
+    let outputs = output_files(args);
+    let output_paths = outputs.iter().map(|(_, path)| path.as_str()).chain(units.iter().flat_map(|unit| [unit.header, unit.source]))
+        .chain(args.object.as_deref()).chain(args.lib.as_deref()).chain(args.blob_bin.as_deref()).collect::<Vec<_>>();
+    let configs = fuse_config.iter().chain(&externs_config).chain(&profile_config).chain(&families_config).chain(&glue_config).chain(&holes_config).chain(&rename_config).chain(&split_config).chain(&symbols_config).chain(&ids_config).map(String::as_str).collect::<Vec<_>>();
+    check_stdout(args, &output_paths)?;
+    // Whatever went to stdout last time is gone, so there's nothing to skip regenerating. Neither
+    // is what --dump, --report and --execute printed, which the run is for.
+    let piped = output_paths.iter().copied().chain(args.manifest.as_deref()).chain(args.depfile.as_deref()).any(|path| path == STDOUT)
+        || args.dump != Dump::None || !args.report.is_empty() || args.execute;
+    let output_paths = output_paths.into_iter().filter(|path| *path != STDOUT).collect::<Vec<_>>();
+    let cache = args.cache_dir.as_ref().filter(|_| !piped)
+        .map(|dir| Cache::new(dir, &output_paths, cache_key(&env, args, &configs, &datas)));
+    if let Some(cache) = &cache && cache.is_fresh() {
+        return Ok(());
+    }
+
+    let only = parse_symbol_list(args.read.symbols_from.as_ref(), symbols_config.as_ref())?;
+    let mut extracted = Vec::with_capacity(groups.len());
+    let mut inputs = Vec::new();
+    for ((arch, paths), datas) in groups.iter().zip(&datas) {
+        if let Some(arch) = arch {
+            for (path, data) in paths.iter().zip(datas) {
+                let machine = Arch::from_machine(object_machine(data)?)?;
+                if machine != *arch {
+                    return Err(format!("{}: is an {} object, not {}", path, machine.name(), arch.name()).into());
+                }
+            }
+        }
+        let (stencils, group_inputs) = read_objects(paths, datas, &args.read, only.as_ref())?;
+        inputs.extend(group_inputs);
+        extracted.push((*arch, stencils));
+    }
+    let demangled = extracted.iter()
+        .map(|(_, stencils)| stencils.iter().map(|s| demangle::demangle(s.name)).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    for ((_, stencils), demangled) in extracted.iter_mut().zip(&demangled) {
+        rename_demangled(stencils, demangled)?;
+    }
+    let renamed = match (&args.rename_map, &rename_config) {
+        (Some(path), Some(text)) => {
+            let map = rename::parse_renames(text).map_err(|e| diagnostics::in_file(path, Category::Malformed.error(e)))?;
+            rename::resolve(&map, &extracted).map_err(|e| diagnostics::in_file(path, e))?
+        }
+        _ => Vec::new(),
+    };
+    for ((_, stencils), renamed) in extracted.iter_mut().zip(&renamed) {
+        let names = renamed.iter().map(Option::as_deref).collect::<Vec<_>>();
+        rename_stencils(stencils, &names)?;
+    }
+    for (_, stencils) in extracted.iter_mut() {
+        sort_stencils(stencils, args.sort);
+    }
+    // Before fusing, which keeps the settings of the parts' holes.
+    if let (Some(path), Some(text)) = (&args.holes, &holes_config) {
+        let config = holes::parse_holes(text).map_err(|e| diagnostics::in_file(path, Category::Malformed.error(e)))?;
+        for (_, stencils) in extracted.iter_mut() {
+            holes::apply(&config, stencils).map_err(|e| diagnostics::in_file(path, e))?;
+        }
+    }
+
+    // Fused stencils go after the ones they are made of, in config order.
+    let fusions = match (&args.fuse, &fuse_config) {
+        (Some(path), Some(text)) => fuse::parse_fusions(text).map_err(|e| diagnostics::in_file(path, Category::Malformed.error(e)))?,
+        _ => Vec::new(),
+    };
+    let externs = match (&args.externs, &externs_config) {
+        (Some(path), Some(text)) => Some((path, externs::parse_externs(text).map_err(|e| diagnostics::in_file(path, Category::Malformed.error(e)))?)),
+        _ => None,
+    };
+    let profile = match (&args.profile, &profile_config) {
+        (Some(path), Some(text)) => Some(profile::parse_profile(text).map_err(|e| diagnostics::in_file(path, Category::Malformed.error(e)))?),
+        _ => None,
+    };
+    let naming = args.naming.as_deref().map(naming::parse_naming).transpose()?;
+    let families = match (&args.families, &families_config) {
+        (Some(path), Some(text)) => families::parse_families(text).map_err(|e| diagnostics::in_file(path, Category::Malformed.error(e)))?,
+        _ => Vec::new(),
+    };
+    let fused = extracted.iter().map(|(_, stencils)| fuse::fuse(stencils, &fusions)).collect::<Result<Vec<_>, _>>()?;
+    let glue = match (&args.glue, &glue_config) {
+        (Some(path), Some(text)) => {
+            let config = glue::parse_glue(text).map_err(|e| diagnostics::in_file(path, Category::Malformed.error(e)))?;
+            let arch = Arch::from_machine(object_machine(&datas[0][0])?)?;
+            glue::synthesize(&config, &extracted[0].1, arch).map_err(|e| diagnostics::in_file(path, e))?
+        }
+        _ => Vec::new(),
+    };
+    let mut ids = match (&args.ids, &ids_config) {
+        (Some(path), Some(text)) => Some(ids::parse_ids(text).map_err(|e| diagnostics::in_file(path, Category::Malformed.error(e)))?),
+        (Some(_), None) => Some(BTreeMap::new()),
+        _ => None,
+    };
+    let mut kept = match &previous {
+        Some((header, path, source)) => {
+            let machine = object_machine(&datas[0][0])?;
+            merge::parse_stencils(header, source, machine).map_err(|e| diagnostics::in_file(path, Category::Malformed.error(e)))?
+        }
+        None => Vec::new(),
+    };
+    for ((_, stencils), fused) in extracted.iter_mut().zip(&fused) {
+        let count = stencils.len();
+        stencils.extend(fused.iter().map(|f| f.stencil()));
+        stencils.extend(glue.iter().map(|g| g.stencil()));
+        populate_stencil_holes(&mut stencils[count..]);
+        if !kept.is_empty() {
+            // The earlier stencils --merge keeps are sorted in with the extracted ones.
+            let mut kept = mem::take(&mut kept).into_iter().filter(|old| !stencils.iter().any(|s| s.name == old.name)).collect::<Vec<_>>();
+            populate_stencil_holes(&mut kept);
+            let added = stencils.split_off(count);
+            stencils.extend(kept);
+            sort_stencils(stencils, args.sort);
+            stencils.extend(added);
+        }
+        mark_branches(stencils);
+        if let Some(naming) = &naming {
+            naming::apply(naming, stencils);
+        }
+        pair_imm32_variants(stencils)?;
+        if let Some((header, path, _)) = &previous {
+            merge::check_transforms(stencils).map_err(|e| diagnostics::in_file(path, e))?;
+            // --ids keeps IDs itself.
+            if ids.is_none() {
+                ids = Some(merge::previous_ids(header, stencils));
+            }
+        }
+        if let Some(path) = &args.split {
+            split::assign(&units, stencils).map_err(|e| diagnostics::in_file(path, e))?;
+        }
+        assign_stencil_ids(stencils, ids.as_mut());
+        // After the IDs so they don't change with the profile, and before dedup so the hottest
+        // of identical stencils keeps the data.
+        if let Some(profile) = &profile {
+            profile::order(stencils, profile);
+            if let Some(below) = args.cold_below {
+                profile::mark_cold(stencils, profile, below);
+            }
+        }
+        dedup_stencils(stencils);
+        if let Some((path, externs)) = &externs {
+            externs::check(externs, stencils).map_err(|e| diagnostics::in_file(path, e))?;
+        }
+    }
+    check_bundle(args, &extracted)?;
+    let provenance = args.deterministic.then(|| {
+        let objects = groups.iter().zip(&datas)
+            .flat_map(|((arch, paths), datas)| paths.iter().zip(datas).map(move |(path, data)| (arch.map(|a| a.name()), path.as_str(), data.as_slice())))
+            .collect::<Vec<_>>();
+        provenance::stamp(&objects, &configs)
+    });
+    if args.deterministic {
+        for (_, stencils) in extracted.iter_mut() {
+            stencils.iter_mut().for_each(provenance::strip_dirs);
+        }
+    }
+
+    if let Some(dir) = &args.rust_crate {
+        fs::create_dir_all(Path::new(dir).join("src"))?;
+        fs::create_dir_all(Path::new(dir).join("tests"))?;
+    }
+    for (arch, stencils) in &extracted {
+        if let Some(arch) = arch && args.dump != Dump::None {
+            println!("{}:", arch.name());
+        }
+        dump_stencils(stencils, args.dump);
+    }
+    if extracted[0].0.is_some() {
+        emit_bundle(&env, args, &extracted, &families, provenance.as_ref(), &outputs)?;
+    }
+    let (_, stencils) = &extracted[0];
+    // The library's source is only compiled, never written next to the other outputs.
+    let lib_dir = args.lib.as_ref().map(|_| compile::TempDir::new()).transpose()?;
+    let lib_source = lib_dir.as_ref().map(|dir| dir.path.join("stencils.c").to_string_lossy().into_owned());
+    let rendered = outputs.iter().cloned().chain(lib_source.iter().map(|path| ("source.jinja", path.clone()))).collect::<Vec<_>>();
+    if extracted[0].0.is_none() {
+        emit_code(&env, args, stencils, &families, provenance.as_ref(), &rendered, &units)?;
+    }
+    let object_path = args.object.as_ref().or(args.lib.as_ref());
+    let object = match object_path {
+        Some(path) => {
+            let arch = Arch::from_machine(object_machine(&datas[0][0])?)?;
+            object::write(arch, stencils, &reloc_kinds(stencils), stencil_count(stencils), args.code_align)
+                .map_err(|e| diagnostics::in_file(path, e))?
+        }
+        None => Vec::new(),
+    };
+    if let Some(path) = &args.object {
+        write_output(path, |w| Ok(w.write_all(&object)?)).map_err(|e| diagnostics::in_file(path, e))?;
+    }
+    if let Some(path) = &args.blob_bin {
+        let (_, blob) = code_blob(stencils, args.code_align);
+        write_output(path, |w| Ok(w.write_all(&blob)?)).map_err(|e| diagnostics::in_file(path, e))?;
+    }
+    if let (Some(path), Some(dir), Some(source)) = (&args.lib, &lib_dir, &lib_source) {
+        write_staticlib(path, dir, source, &object).map_err(|e| diagnostics::in_file(path, e))?;
+    }
+    if let (Some(path), Some(ids)) = (&args.ids, &ids) {
+        write_output(path, |w| Ok(w.write_all(ids::format_ids(ids).as_bytes())?)).map_err(|e| diagnostics::in_file(path, e))?;
+    }
+    let mut input_paths = args.objects.iter().map(|object| split_arch(object).1).chain(args.fuse.iter().chain(&args.externs).chain(&args.profile).chain(&args.families).chain(&args.glue).chain(&args.holes).chain(&args.rename_map).chain(&args.split).chain(&args.read.symbols_from).chain(&args.ids).map(String::as_str))
+        .map(Path::new).collect::<Vec<_>>();
+    for input in &inputs {
+        if !input_paths.contains(&input.as_path()) {
+            input_paths.push(input);
+        }
+    }
+    let cwd = std::env::current_dir()?;
+    let listed = |path: &Path| if args.deterministic { provenance::relative_to(path, &cwd) } else { path.to_path_buf() };
+    let listed_inputs = input_paths.iter().map(|path| listed(path)).collect::<Vec<_>>();
+    if let Some(path) = &args.depfile {
+        // The templates are compiled into the tool, so it stands in for them.
+        let exe = listed(&std::env::current_exe()?);
+        let targets = output_paths.iter().map(|path| listed(Path::new(path)).to_string_lossy().into_owned()).collect::<Vec<_>>();
+        let targets = targets.iter().map(String::as_str).collect::<Vec<_>>();
+        let deps = listed_inputs.iter().map(PathBuf::as_path).chain([exe.as_path()]).collect::<Vec<_>>();
+        write_output(path, |w| Ok(w.write_all(depfile::format(&targets, &deps).as_bytes())?)).map_err(|e| diagnostics::in_file(path, e))?;
+    }
+    if let Some(path) = &args.manifest {
+        let mut generated = outputs.iter().filter(|(_, path)| path != STDOUT).map(|(template, path)| (path.as_str(), Some(*template))).collect::<Vec<_>>();
+        generated.extend(units.iter().flat_map(|unit| [(unit.header, Some("split_header.jinja")), (unit.source, Some("source.jinja"))]));
+        generated.extend(args.object.iter().chain(&args.lib).chain(&args.blob_bin).chain(&args.ids).chain(&args.depfile).map(|path| (path.as_str(), None)));
+        let generated_paths = generated.iter().map(|(path, _)| listed(Path::new(path)).to_string_lossy().into_owned()).collect::<Vec<_>>();
+        let generated = generated_paths.iter().zip(&generated).map(|(path, (_, template))| (path.as_str(), *template)).collect::<Vec<_>>();
+        let inputs = listed_inputs.iter().map(PathBuf::as_path).collect::<Vec<_>>();
+        let manifest = manifest::format(&generated, &inputs).map_err(|e| diagnostics::in_file(path, e))?;
+        write_output(path, |w| Ok(w.write_all(manifest.as_bytes())?)).map_err(|e| diagnostics::in_file(path, e))?;
+    }
+    if args.execute {
+        let stencils = extracted.iter().flat_map(|(_, stencils)| stencils).collect::<Vec<_>>();
+        emulate::check(&stencils).map_err(|e| Category::TestFailure.error(e))?;
+    }
+    for (arch, stencils) in &extracted {
+        if let Some(arch) = arch && !args.report.is_empty() {
+            println!("{}:", arch.name());
+        }
+        for report in &args.report {
+            match report {
+                Report::Sizes => print!("{}", report::sizes(stencils)),
+                Report::Relocs => print!("{}", report::relocs(stencils)),
+                Report::Insns => print!("{}", report::insns(stencils)),
+            }
+        }
+    }
+
+    if let Some(cache) = &cache {
+        let written = output_paths.iter().copied().chain(args.depfile.as_deref()).chain(args.manifest.as_deref()).filter(|path| *path != STDOUT).collect::<Vec<_>>();
+        cache.store(&inputs, &written)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Compiles `source` with `cflags` and runs `verify` on the object, with the outputs named in
+    // `outputs` written next to it.
+    fn verify_source(source: &str, cflags: &[&str], outputs: &[(&str, &str)]) -> Result<(), Box<dyn Error>> {
+        let dir = compile::TempDir::new()?;
+        let path = |name: &str| dir.path.join(name).to_string_lossy().into_owned();
+        fs::write(path("stencils.c"), source)?;
+        let mut command = vec!["cc".to_string(), "-O2".to_string()];
+        command.extend(cflags.iter().map(|flag| flag.to_string()));
+        command.push(path("stencils.c"));
+        compile::compile(&command, None, Path::new(&path("stencils.o")))?;
+        let mut args = vec!["verify".to_string(), path("stencils.o"), "--header".to_string(), path("stencils.h"), "--source".to_string(), path("source.c"), "--assert-ranges".to_string()];
+        args.extend(outputs.iter().flat_map(|&(option, name)| [option.to_string(), path(name)]));
+        verify(VerifyArgs::parse_from(args))
+    }
+
+    const OP_CONST: &str = "\
+        extern char cnp_small_value_hole_0[] __attribute__((visibility(\"hidden\")));\n\
+        extern void cnp_stencil_output(long*, long) __attribute__((visibility(\"hidden\")));\n\
+        void op_const(long* sp, long x) { cnp_stencil_output(sp, x + (long)(unsigned)(unsigned long)cnp_small_value_hole_0); }\n";
+
+    // A uint32_t argument truncates whatever test value it's given to 32 bits.
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn uint32_holes() {
+        // lea rdx, [rip + hole] with a PC32 reloc, then movabs rax, hole with an ABS64 one.
+        verify_source(OP_CONST, &["-fPIE"], &[]).unwrap();
+        verify_source(OP_CONST, &["-fno-pic", "-mcmodel=large"], &[]).unwrap();
+    }
+
+    // The code isn't in the source, so the tests are linked with it.
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn code_outside_the_source() {
+        verify_source(OP_CONST, &["-fPIE"], &[("--object", "code.o")]).unwrap();
+        verify_source(OP_CONST, &["-fPIE"], &[("--blob-bin", "code.bin")]).unwrap();
+    }
 }
@@ -0,0 +1,33 @@
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+use crate::diagnostics::json_string;
+use crate::sha256;
+
+// Lists the files a run generated, with the template each was rendered from, and the inputs
+// they were generated from, all with their sha256 as they are on disk now.
+pub fn format(generated: &[(&str, Option<&str>)], inputs: &[&Path]) -> Result<String, String> {
+    let hash = |path: &Path| fs::read(path).map(|data| sha256::digest(&data)).map_err(|e| format!("{}: {}", path.display(), e));
+    let mut out = String::from("{\n  \"tool\": ");
+    json_string(&mut out, concat!("stenciltool ", env!("CARGO_PKG_VERSION")));
+    out.push_str(",\n  \"outputs\": [");
+    for (i, (path, template)) in generated.iter().enumerate() {
+        out.push_str(if i == 0 { "\n    { \"path\": " } else { ",\n    { \"path\": " });
+        json_string(&mut out, path);
+        let _ = write!(out, ", \"sha256\": \"{}\"", hash(Path::new(path))?);
+        if let Some(template) = template {
+            out.push_str(", \"template\": ");
+            json_string(&mut out, template);
+        }
+        out.push_str(" }");
+    }
+    out.push_str("\n  ],\n  \"inputs\": [");
+    for (i, path) in inputs.iter().enumerate() {
+        out.push_str(if i == 0 { "\n    { \"path\": " } else { ",\n    { \"path\": " });
+        json_string(&mut out, &path.to_string_lossy());
+        let _ = write!(out, ", \"sha256\": \"{}\" }}", hash(path)?);
+    }
+    out.push_str("\n  ]\n}\n");
+    Ok(out)
+}
@@ -0,0 +1,251 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+
+use goblin::elf;
+
+use crate::arch::Arch;
+use crate::dwarf::{Param, Signature};
+use crate::holes::{Payload, Transform};
+use crate::{hole_datatypes, Hole, Reloc, Stencil};
+
+// --merge reads the stencils back out of the header and source an earlier run generated, so that
+// regenerating from some of the objects only replaces their stencils. The templates write out
+// everything a stencil is made of as long as its code is in the source, which isn't the case with
+// --blob, --object or --split. What later passes work out again, aliases, imm32 variants, branch
+// targets and --naming, isn't read back.
+
+// The cnp_stencil_id of every stencil the header declares, by its name in the enum, `ADD_I32`.
+fn parse_ids(header: &str) -> HashMap<&str, usize> {
+    header.lines()
+        .filter_map(|line| line.trim().strip_prefix("CNP_STENCIL_")?.strip_suffix(',')?.split_once(" = "))
+        .filter_map(|(name, id)| Some((name, id.parse().ok()?)))
+        .collect()
+}
+
+// Keeps the IDs the header gave the stencils it has, the others get new ones.
+pub fn previous_ids(header: &str, stencils: &[Stencil]) -> BTreeMap<String, usize> {
+    let ids = parse_ids(header);
+    stencils.iter()
+        .filter_map(|s| Some((s.name.to_string(), *ids.get(s.name.to_uppercase().as_str())?)))
+        .collect()
+}
+
+// The stencils the source defines, in its order.
+pub fn parse_stencils<'t>(header: &'t str, source: &'t str, machine: u16) -> Result<Vec<Stencil<'t>>, String> {
+    let arch = Arch::from_machine(machine)?;
+    let lines = source.lines().map(without_section).collect::<Vec<_>>();
+    let mut names = Vec::new();
+    let mut codes = HashMap::new();
+    let mut reloc_tables = HashMap::new();
+    let mut params = HashMap::new();
+    let mut table = HashMap::new();
+    let mut transforms = HashMap::new();
+    let mut versions = HashMap::new();
+    for (i, line) in lines.iter().enumerate() {
+        if let Some(name) = line.strip_prefix("uint8_t* cnp_copy_").and_then(|rest| rest.strip_suffix("(uint8_t* stencil_start) {")) {
+            names.push(name);
+        } else if let Some((name, _)) = line.strip_prefix("uint8_t cnp_stencil_").and_then(|rest| rest.split_once("_code[] ")) {
+            let source_range = lines[..i].last().and_then(|line| line.strip_prefix("// "));
+            let code = body(&lines, i).iter()
+                .flat_map(|line| line.split(','))
+                .map(str::trim)
+                .filter(|byte| !byte.is_empty())
+                .map(|byte| byte.strip_prefix("0x").and_then(|hex| u8::from_str_radix(hex, 16).ok()))
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| format!("line {}: cnp_stencil_{}_code isn't a list of bytes", i + 1, name))?;
+            codes.insert(name, (code, source_range));
+        } else if let Some(name) = line.strip_prefix("const struct cnp_reloc cnp_relocs_").and_then(|rest| rest.strip_suffix("[] = {")) {
+            reloc_tables.insert(name, (i, body(&lines, i)));
+        } else if let Some(rest) = line.strip_prefix("void cnp_patch_") && let Some((name, rest)) = rest.split_once("(uint8_t* stencil_start") {
+            let list = rest.strip_suffix(") {").unwrap_or(rest);
+            let names = split_top(list, ',').into_iter().skip(1).map(|param| param.rsplit(' ').next().unwrap_or(param)).collect::<Vec<_>>();
+            params.insert(name, names);
+        } else if let Some(name) = line.trim().strip_prefix("[CNP_STENCIL_").and_then(|rest| rest.strip_suffix("] = {")) {
+            let fields = lines[i + 1..].iter().take(12).map(|field| field.trim().strip_suffix(',').unwrap_or(field.trim())).collect::<Vec<_>>();
+            table.insert(name, fields);
+        } else if let Some(number) = line.trim().strip_prefix("case ").and_then(|rest| rest.strip_suffix(':')).and_then(|n| n.parse::<u32>().ok()) &&
+                  let Some(expr) = lines.get(i + 1).and_then(|next| next.trim().strip_prefix("return (uint64_t)(")?.strip_suffix(");")) {
+            transforms.insert(number, expr);
+        } else if let Some((name, versioned)) = line.strip_prefix("__asm__(\".symver ").and_then(|rest| rest.strip_suffix("\");")?.split_once(", ")) {
+            versions.extend(versioned.strip_prefix(name).and_then(|version| version.strip_prefix('@')).map(|version| (name, version)));
+        }
+    }
+
+    let mut stencils = Vec::with_capacity(names.len());
+    for name in names {
+        let fields = table.get(name.to_uppercase().as_str()).filter(|fields| fields.len() == 12)
+            .ok_or_else(|| format!("{} has no entry in cnp_stencils", name))?;
+        let data = fields[1].strip_prefix("cnp_stencil_").and_then(|rest| rest.strip_suffix("_code"));
+        let Some((code, source_range)) = data.and_then(|data| codes.get(data)) else {
+            return Err(format!("{}'s code isn't in the source, --merge needs it generated without --blob, --object or --split", name));
+        };
+        let (at, entries) = reloc_tables.get(data.unwrap()).ok_or_else(|| format!("{} has no cnp_relocs_{}", name, data.unwrap()))?;
+        let params = params.get(name).ok_or_else(|| format!("{} has no cnp_patch_{}", name, name))?;
+        let mut relocs = Vec::with_capacity(entries.len());
+        for (j, entry) in entries.iter().enumerate() {
+            let reloc = parse_reloc(entry, params, &transforms, &versions, machine).ok_or_else(|| format!("line {}: can't read the reloc of {}", at + j + 2, name))?;
+            relocs.extend(reloc);
+        }
+        let display = fields[0].trim_matches('"');
+        stencils.push(Stencil {
+            name,
+            display: (display != name).then_some(display),
+            index: usize::MAX,
+            id: 0,
+            section: 0,
+            address: 0,
+            size: code.len() as u64,
+            code: Cow::Owned(code.clone()),
+            relocs,
+            holes: Vec::new(),
+            terminates: fields[6] == "1",
+            stack_size: fields[9].parse().ok(),
+            literal_pool: 0,
+            fallthrough: fields[10] == "1",
+            taken_hole: None,
+            imm32_variant: None,
+            cold: false,
+            alias_of: None,
+            signature: fields[8].strip_prefix('"').and_then(|c_type| signature(header, name, c_type.strip_suffix('"')?)),
+            lines: Vec::new(),
+            source_range: source_range.filter(|_| data == Some(name)).map(str::to_string),
+            doc: doc(header, name, (display != name).then_some(display)),
+            op: None,
+            types: Vec::new(),
+            unit: None,
+            arch,
+        });
+    }
+    Ok(stencils)
+}
+
+// A line with the CNP_SECTION() that --cold-below puts in front of definitions taken off.
+fn without_section(line: &str) -> &str {
+    line.strip_prefix("CNP_SECTION(\"").and_then(|rest| rest.split_once("\") ")).map_or(line, |(_, rest)| rest)
+}
+
+// The lines of the initializer starting on line `start`, up to its closing `};`.
+fn body<'l, 't>(lines: &'l [&'t str], start: usize) -> &'l [&'t str] {
+    let rest = &lines[start + 1..];
+    &rest[..rest.iter().position(|line| *line == "};").unwrap_or(rest.len())]
+}
+
+// Splits on `separator` outside of parentheses.
+fn split_top(text: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let (mut depth, mut start) = (0, 0);
+    for (i, c) in text.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            c if c == separator && depth == 0 => {
+                parts.push(text[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(text[start..].trim());
+    parts
+}
+
+// One `{ offset, kind, arg, flags, callee, addend, symbol },` of a reloc table, None for the
+// placeholder of an empty one.
+fn parse_reloc<'t>(entry: &'t str, params: &[&'t str], transforms: &HashMap<u32, &'t str>, versions: &HashMap<&str, &'t str>, machine: u16) -> Option<Option<Reloc<'t>>> {
+    let fields = split_top(entry.trim().strip_prefix("{ ")?.strip_suffix(" },")?, ',');
+    let &[offset, kind, arg, flags, callee, addend, symbol] = &fields[..] else {
+        return None;
+    };
+    if kind == "CNP_RELOC_COUNT" {
+        return Some(None);
+    }
+    let kind = kind.strip_prefix("CNP_RELOC_")?;
+    let relocation = (0..=u8::MAX as u32).map(|r_type| elf::reloc::r_to_str(r_type, machine)).find(|name| *name == kind)?;
+    let (mut far_call, mut continuation, mut payload, mut transform) = (false, false, None, None);
+    for flag in split_top(flags, '|').into_iter().filter(|flag| *flag != "0") {
+        let call = |prefix| flag.strip_prefix(prefix)?.strip_suffix(')').map(|args| split_top(args, ','));
+        match flag {
+            "CNP_RELOC_FLAG_FAR_CALL" => far_call = true,
+            "CNP_RELOC_FLAG_CONTINUATION" => continuation = true,
+            _ => match (call("CNP_RELOC_PAYLOAD(").as_deref(), call("CNP_RELOC_TRANSFORM(").as_deref()) {
+                (Some(&[bits, shift]), _) => payload = Some(Payload::new(bits.parse().ok()?, shift.parse().ok()?)),
+                (_, Some(&[number])) => {
+                    let number = number.parse().ok()?;
+                    transform = Some(Transform::new(number, transforms.get(&number)?));
+                }
+                _ => return None,
+            },
+        }
+    }
+    let stencil_ref = callee != "CNP_STENCIL_COUNT";
+    let (name, internal) = match arg {
+        "CNP_ARG_OUTPUT" => ("cnp_stencil_output", true),
+        "CNP_ARG_SYMBOL" => (symbol.strip_prefix("(const void*)&")?, false),
+        arg => (*params.get(arg.parse::<usize>().ok()?)?, true),
+    };
+    let (datatype, value_datatype) = match (internal, stencil_ref) {
+        (true, true) => ("uint32_t", "void*"),
+        (true, false) => hole_datatypes(name)?,
+        (false, _) => ("void*", "void*"),
+    };
+    Some(Some(Reloc {
+        offset: offset.parse().ok()?,
+        addend: addend.parse().ok()?,
+        hole: Hole { name, index: usize::MAX, datatype, value_datatype, internal, stencil_ref, payload, transform, version: versions.get(name).copied() },
+        arg: None,
+        relocation,
+        far_call,
+        continuation,
+    }))
+}
+
+// The comment lines the header puts above the stencil's functions.
+fn comments<'t>(header: &'t str, name: &str) -> Vec<&'t str> {
+    let lines = header.lines().collect::<Vec<_>>();
+    let copy = format!("uint8_t* cnp_copy_{}(uint8_t* stencil_start);", name);
+    let Some(at) = lines.iter().position(|line| *line == copy) else {
+        return Vec::new();
+    };
+    let start = lines[..at].iter().rposition(|line| !line.starts_with("//")).map_or(0, |i| i + 1);
+    lines[start..at].to_vec()
+}
+
+// The doc comment is what's left of them once the lines the header adds itself are taken out.
+fn doc(header: &str, name: &str, display: Option<&str>) -> Vec<String> {
+    comments(header, name).into_iter()
+        .filter(|line| !line.starts_with("// Entered as ") && !line.starts_with("// Branches to ") && (display.is_none() || line.strip_prefix("// ") != display))
+        .map(|line| line.strip_prefix("// ").or_else(|| line.strip_prefix("//")).unwrap_or(line).to_string())
+        .collect()
+}
+
+// Put back together from the `Entered as` comment and the function type cnp_stencils has.
+fn signature(header: &str, name: &str, c_type: &str) -> Option<Signature> {
+    let entered = comments(header, name).into_iter().find_map(|line| line.strip_prefix("// Entered as "))?;
+    let (returns, c_params) = entered.split_once(&format!(" {}(", name))?;
+    let c_params = c_params.strip_suffix(')')?;
+    let types = c_type.strip_prefix(returns)?.trim_start().strip_prefix('(')?.strip_suffix(')')?;
+    let params = split_top(types, ',').into_iter().zip(split_top(c_params, ','))
+        .filter(|(datatype, _)| *datatype != "void" && *datatype != "...")
+        .map(|(datatype, declaration)| {
+            let name = match (datatype.strip_suffix("[]"), datatype.find("(*)")) {
+                (Some(element), _) => declaration.strip_prefix(element)?.trim().strip_suffix("[]")?,
+                (None, Some(_)) => declaration.split_once("(*")?.1.split_once(')')?.0,
+                (None, None) => declaration.strip_prefix(datatype)?.trim(),
+            };
+            Some(Param { name: name.to_string(), datatype: datatype.to_string() })
+        })
+        .collect::<Option<Vec<_>>>()?;
+    Some(Signature { returns: returns.to_string(), params, c_type: c_type.to_string(), c_params: c_params.to_string() })
+}
+
+// Transforms are numbered by the --holes config, the stencils read back must number them the same
+// way as the ones extracted now.
+pub fn check_transforms(stencils: &[Stencil]) -> Result<(), String> {
+    let mut by_number = HashMap::new();
+    for transform in stencils.iter().flat_map(|s| s.relocs.iter().filter_map(|r| r.hole.transform)) {
+        if *by_number.entry(transform.number()).or_insert(transform) != transform {
+            return Err(format!("--holes transform {} isn't the one the source was generated with, regenerate it without --merge", transform.number()));
+        }
+    }
+    Ok(())
+}
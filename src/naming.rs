@@ -0,0 +1,113 @@
+use crate::variants;
+use crate::Stencil;
+
+// How stencil names are made up of an op and types, `{op}_{type}_{type}` for `add_i64_i32`.
+// Ending in `...` repeats the last type with the separator before it, so `{op}_{type}...` also
+// takes `neg_i64` and `select_i64_i1_i64`.
+pub struct Naming<'c> {
+    parts: Vec<Part<'c>>,
+    // The separator repeated types follow, if the scheme ends in `...`.
+    repeat: Option<&'c str>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Part<'c> {
+    Literal(&'c str),
+    Op,
+    Type,
+}
+
+pub fn parse_naming(scheme: &str) -> Result<Naming<'_>, String> {
+    let (body, repeats) = match scheme.strip_suffix("...") {
+        Some(body) => (body, true),
+        None => (scheme, false),
+    };
+    let mut parts = Vec::new();
+    let mut rest = body;
+    while !rest.is_empty() {
+        let (part, len) = if rest.starts_with("{op}") {
+            (Part::Op, 4)
+        } else if rest.starts_with("{type}") {
+            (Part::Type, 6)
+        } else {
+            let len = rest.find('{').filter(|&i| i > 0).unwrap_or(rest.len());
+            if rest[..len].contains(['{', '}']) {
+                return Err(format!("--naming {}: only {{op}} and {{type}} can be used", scheme));
+            }
+            (Part::Literal(&rest[..len]), len)
+        };
+        if !matches!(part, Part::Literal(_)) && matches!(parts.last(), Some(Part::Op | Part::Type)) {
+            return Err(format!("--naming {}: placeholders need a separator between them", scheme));
+        }
+        parts.push(part);
+        rest = &rest[len..];
+    }
+    if parts.iter().filter(|p| **p == Part::Op).count() != 1 {
+        return Err(format!("--naming {}: needs {{op}} once", scheme));
+    }
+    let repeat = match (repeats, &parts[..]) {
+        (false, _) => None,
+        (true, [.., Part::Literal(separator), Part::Type]) => Some(*separator),
+        (true, _) => return Err(format!("--naming {}: only a `<separator>{{type}}` can repeat", scheme)),
+    };
+    Ok(Naming { parts, repeat })
+}
+
+impl<'c> Naming<'c> {
+    // Splits a name into its op and types, or None if it doesn't follow the scheme. Each
+    // placeholder takes the text up to the first occurrence of the separator after it.
+    fn split<'n>(&self, name: &'n str) -> Option<(&'n str, Vec<&'n str>)> {
+        let mut rest = name;
+        let mut op = None;
+        let mut types = Vec::new();
+        for (i, part) in self.parts.iter().enumerate() {
+            let next = match self.parts.get(i + 1) {
+                Some(Part::Literal(literal)) => Some(*literal),
+                _ => self.repeat,
+            };
+            let word = match part {
+                Part::Literal(literal) => {
+                    rest = rest.strip_prefix(literal)?;
+                    continue;
+                }
+                _ => {
+                    let len = next.and_then(|next| rest.find(next)).unwrap_or(rest.len());
+                    let word = &rest[..len];
+                    rest = &rest[len..];
+                    word
+                }
+            };
+            if word.is_empty() {
+                return None;
+            }
+            match part {
+                Part::Op => op = Some(word),
+                _ => types.push(word),
+            }
+        }
+        if let Some(separator) = self.repeat {
+            while let Some(after) = rest.strip_prefix(separator) {
+                let len = after.find(separator).unwrap_or(after.len());
+                if len == 0 {
+                    return None;
+                }
+                types.push(&after[..len]);
+                rest = &after[len..];
+            }
+        }
+        Some((op?, types)).filter(|_| rest.is_empty())
+    }
+}
+
+// Sets the op and types of every stencil named by the scheme. The `__<feature>` and `_imm32`
+// variants of a stencil get the same ones as it.
+pub fn apply<'a>(naming: &Naming, stencils: &mut [Stencil<'a>]) {
+    for stencil in stencils {
+        let base = variants::base_name(stencil.name);
+        let base = base.strip_suffix("_imm32").unwrap_or(base);
+        if let Some((op, types)) = naming.split(base) {
+            stencil.op = Some(op);
+            stencil.types = types;
+        }
+    }
+}
@@ -0,0 +1,266 @@
+use std::collections::{BTreeSet, HashMap};
+
+use goblin::elf::{header, reloc, section_header as sh, sym};
+
+use crate::arch::Arch;
+use crate::holes;
+use crate::Stencil;
+
+// Layout of the structs in header.jinja on a 64-bit target.
+const RELOC_SIZE: usize = 32;
+const STENCIL_SIZE: usize = 80;
+const ARG_OUTPUT: u16 = 0xffff;
+const ARG_SYMBOL: u16 = 0xfffe;
+const ARG_NONE: u32 = 0xfffd;
+const RELOC_FLAG_FAR_CALL: u32 = 1;
+const RELOC_FLAG_CONTINUATION: u32 = 2;
+
+// Sections with contents, in section header order after the null one.
+const DATA: usize = 0;
+const RODATA: usize = 1;
+const DATA_REL_RO: usize = 2;
+const SECTIONS: [(&str, u32); 3] = [
+    (".data", sh::SHF_ALLOC | sh::SHF_WRITE),
+    (".rodata", sh::SHF_ALLOC),
+    (".data.rel.ro", sh::SHF_ALLOC | sh::SHF_WRITE),
+];
+// The symbol table starts with the null symbol and the .rodata section symbol the strings are
+// relocated against, everything after is global.
+const RODATA_SYMBOL: u32 = 1;
+const FIRST_GLOBAL: u32 = 2;
+
+struct Symbol {
+    name: String,
+    // Index into SECTIONS, None for an undefined weak symbol.
+    section: Option<usize>,
+    value: u64,
+    size: u64,
+}
+
+struct Rela {
+    offset: u64,
+    symbol: u32,
+    addend: i64,
+}
+
+#[derive(Default)]
+struct Section {
+    data: Vec<u8>,
+    align: u64,
+}
+
+impl Section {
+    fn alloc(&mut self, size: usize, align: u64) -> usize {
+        self.align = self.align.max(align);
+        self.data.resize(self.data.len().next_multiple_of(align as usize), 0);
+        let offset = self.data.len();
+        self.data.resize(offset + size, 0);
+        offset
+    }
+
+    fn put(&mut self, offset: usize, bytes: &[u8]) {
+        self.data[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+}
+
+#[derive(Default)]
+struct Builder {
+    sections: [Section; 3],
+    symbols: Vec<Symbol>,
+    by_name: HashMap<String, u32>,
+    // Relocations of .data.rel.ro, the only section holding pointers.
+    relas: Vec<Rela>,
+}
+
+impl Builder {
+    fn define(&mut self, name: String, section: usize, value: usize, size: usize) {
+        self.symbol(name, Some(section), value as u64, size as u64);
+    }
+
+    fn symbol(&mut self, name: String, section: Option<usize>, value: u64, size: u64) -> u32 {
+        if let Some(&index) = self.by_name.get(&name) {
+            return index;
+        }
+        let index = FIRST_GLOBAL + self.symbols.len() as u32;
+        self.by_name.insert(name.clone(), index);
+        self.symbols.push(Symbol { name, section, value, size });
+        index
+    }
+
+    // Points the word at `offset` in .data.rel.ro at `symbol`, declaring it weak if it isn't defined.
+    fn pointer(&mut self, offset: usize, symbol: &str) {
+        let symbol = self.symbol(symbol.to_owned(), None, 0, 0);
+        self.relas.push(Rela { offset: offset as u64, symbol, addend: 0 });
+    }
+
+    fn string(&mut self, offset: usize, s: &str) {
+        let rodata = &mut self.sections[RODATA];
+        let at = rodata.alloc(s.len() + 1, 1);
+        rodata.put(at, s.as_bytes());
+        self.relas.push(Rela { offset: offset as u64, symbol: RODATA_SYMBOL, addend: at as i64 });
+    }
+}
+
+// Writes the code arrays, reloc tables and cnp_stencils that source.jinja otherwise defines as
+// a relocatable ELF object, with the same symbol names so the rest of the source links against it.
+pub fn write(arch: Arch, stencils: &[Stencil], reloc_kinds: &BTreeSet<&str>, stencil_count: usize, code_align: u64) -> Result<Vec<u8>, String> {
+    let (machine, abs64) = match arch {
+        Arch::X86_64 => (header::EM_X86_64, reloc::R_X86_64_64),
+        Arch::AArch64 => (header::EM_AARCH64, reloc::R_AARCH64_ABS64),
+        Arch::RiscV => (header::EM_RISCV, reloc::R_RISCV_64),
+        Arch::Arm => return Err("only 64-bit targets are supported".into()),
+    };
+    let ids = stencils.iter().map(|s| (s.name, s.id as u32)).collect::<HashMap<_, _>>();
+    let kinds = reloc_kinds.iter().enumerate().map(|(i, &kind)| (kind, i as u16)).collect::<HashMap<_, _>>();
+    let mut b = Builder::default();
+
+    for stencil in stencils.iter().filter(|s| s.alias_of.is_none()) {
+        let at = b.sections[DATA].alloc(stencil.code.len(), code_align);
+        b.sections[DATA].put(at, &stencil.code);
+        b.define(format!("cnp_stencil_{}_code", stencil.name), DATA, at, stencil.code.len());
+
+        // An empty table still gets its placeholder entry, like the C array.
+        let table = b.sections[DATA_REL_RO].alloc(RELOC_SIZE * stencil.relocs.len().max(1), 8);
+        if stencil.relocs.is_empty() {
+            b.sections[DATA_REL_RO].put(table + 4, &(reloc_kinds.len() as u16).to_le_bytes());
+            b.sections[DATA_REL_RO].put(table + 12, &(stencil_count as u32).to_le_bytes());
+        }
+        for (i, r) in stencil.relocs.iter().enumerate() {
+            let at = table + i * RELOC_SIZE;
+            let arg = match r.arg {
+                _ if r.hole.name == "cnp_stencil_output" => ARG_OUTPUT,
+                Some(arg) => arg as u16,
+                None => ARG_SYMBOL,
+            };
+            let callee = if r.hole.stencil_ref { ids[r.hole.name] } else { stencil_count as u32 };
+            let section = &mut b.sections[DATA_REL_RO];
+            section.put(at, &(r.offset as u32).to_le_bytes());
+            section.put(at + 4, &kinds[r.relocation].to_le_bytes());
+            section.put(at + 6, &arg.to_le_bytes());
+            let flags = if r.far_call { RELOC_FLAG_FAR_CALL } else { 0 } | if r.continuation { RELOC_FLAG_CONTINUATION } else { 0 }
+                | holes::flags(r.hole.payload, r.hole.transform);
+            section.put(at + 8, &flags.to_le_bytes());
+            section.put(at + 12, &callee.to_le_bytes());
+            section.put(at + 16, &r.addend.to_le_bytes());
+            if arg == ARG_SYMBOL {
+                b.pointer(at + 24, r.hole.name);
+            }
+        }
+        b.define(format!("cnp_relocs_{}", stencil.name), DATA_REL_RO, table, RELOC_SIZE * stencil.relocs.len().max(1));
+
+        let count = b.sections[RODATA].alloc(8, 8);
+        b.sections[RODATA].put(count, &(stencil.relocs.len() as u64).to_le_bytes());
+        b.define(format!("cnp_relocs_{}_count", stencil.name), RODATA, count, 8);
+    }
+
+    // Gaps left by --ids stay zeroed, so their name is NULL.
+    let table = b.sections[DATA_REL_RO].alloc(STENCIL_SIZE * stencil_count, 8);
+    for stencil in stencils {
+        let at = table + stencil.id * STENCIL_SIZE;
+        let data = stencil.alias_of.unwrap_or(stencil.name);
+        let arg_count = stencil.holes.iter().filter(|h| h.is_argument()).count();
+        let imm32_variant = stencil.imm32_variant.as_ref().map_or(stencil_count as u32, |v| ids[v.name]);
+        b.string(at, stencil.display.unwrap_or(stencil.name));
+        b.pointer(at + 8, &format!("cnp_stencil_{}_code", data));
+        b.pointer(at + 24, &format!("cnp_relocs_{}", data));
+        if let Some(signature) = &stencil.signature {
+            b.string(at + 56, &signature.c_type);
+        }
+        let section = &mut b.sections[DATA_REL_RO];
+        section.put(at + 16, &(stencil.code.len() as u64).to_le_bytes());
+        section.put(at + 32, &(stencil.relocs.len() as u64).to_le_bytes());
+        section.put(at + 40, &(arg_count as u64).to_le_bytes());
+        section.put(at + 48, &(stencil.terminates as u32).to_le_bytes());
+        section.put(at + 52, &imm32_variant.to_le_bytes());
+        section.put(at + 64, &stencil.stack_size.unwrap_or(u64::MAX).to_le_bytes());
+        section.put(at + 72, &(stencil.fallthrough as u32).to_le_bytes());
+        let taken_arg = stencil.relocs.iter().find(|r| Some(r.hole.name) == stencil.taken_hole).and_then(|r| r.arg);
+        section.put(at + 76, &taken_arg.map_or(ARG_NONE, |arg| arg as u32).to_le_bytes());
+    }
+    b.define("cnp_stencils".to_owned(), DATA_REL_RO, table, STENCIL_SIZE * stencil_count);
+
+    Ok(link(b, machine, abs64))
+}
+
+// Lays out the ELF file: header, section contents, then the section header table.
+fn link(b: Builder, machine: u16, abs64: u32) -> Vec<u8> {
+    let mut strtab = vec![0];
+    let mut symtab = vec![0; 2 * 24];
+    symtab[24 + 4] = sym::STT_SECTION;
+    symtab[24 + 6..24 + 8].copy_from_slice(&(RODATA as u16 + 1).to_le_bytes());
+    for symbol in &b.symbols {
+        let name = strtab.len() as u32;
+        strtab.extend_from_slice(symbol.name.as_bytes());
+        strtab.push(0);
+        let (info, shndx) = match symbol.section {
+            Some(section) => (sym::STB_GLOBAL << 4 | sym::STT_OBJECT, section as u16 + 1),
+            None => (sym::STB_WEAK << 4 | sym::STT_NOTYPE, 0),
+        };
+        symtab.extend_from_slice(&name.to_le_bytes());
+        symtab.extend_from_slice(&[info, 0]);
+        symtab.extend_from_slice(&shndx.to_le_bytes());
+        symtab.extend_from_slice(&symbol.value.to_le_bytes());
+        symtab.extend_from_slice(&symbol.size.to_le_bytes());
+    }
+    let mut rela = Vec::with_capacity(b.relas.len() * 24);
+    for r in &b.relas {
+        rela.extend_from_slice(&r.offset.to_le_bytes());
+        rela.extend_from_slice(&(u64::from(r.symbol) << 32 | u64::from(abs64)).to_le_bytes());
+        rela.extend_from_slice(&r.addend.to_le_bytes());
+    }
+
+    // name, type, flags, contents, align, link, info, entry size
+    let symtab_index = 1 + SECTIONS.len() as u32 + 1;
+    let mut sections = vec![("", sh::SHT_NULL, 0, Vec::new(), 0, 0, 0, 0)];
+    for ((name, flags), section) in SECTIONS.iter().zip(b.sections) {
+        sections.push((name, sh::SHT_PROGBITS, *flags, section.data, section.align.max(1), 0, 0, 0));
+    }
+    sections.push((".rela.data.rel.ro", sh::SHT_RELA, sh::SHF_INFO_LINK, rela, 8, symtab_index, DATA_REL_RO as u32 + 1, 24));
+    sections.push((".symtab", sh::SHT_SYMTAB, 0, symtab, 8, symtab_index + 1, FIRST_GLOBAL, 24));
+    sections.push((".strtab", sh::SHT_STRTAB, 0, strtab, 1, 0, 0, 0));
+    // Without it linkers assume the object needs an executable stack.
+    sections.push((".note.GNU-stack", sh::SHT_PROGBITS, 0, Vec::new(), 1, 0, 0, 0));
+    let mut shstrtab = vec![0];
+    let mut names = Vec::new();
+    for (name, ..) in &sections {
+        names.push(if name.is_empty() { 0 } else { shstrtab.len() as u32 });
+        shstrtab.extend_from_slice(name.as_bytes());
+        shstrtab.push(0);
+    }
+    names.push(shstrtab.len() as u32);
+    shstrtab.extend_from_slice(b".shstrtab\0");
+    sections.push((".shstrtab", sh::SHT_STRTAB, 0, shstrtab, 1, 0, 0, 0));
+
+    let mut out = vec![0; header::header64::SIZEOF_EHDR];
+    let mut headers = vec![0; sh::section_header64::SIZEOF_SHDR];
+    for (i, (_, sh_type, flags, data, align, link, info, entsize)) in sections.iter().enumerate().skip(1) {
+        out.resize(out.len().next_multiple_of(*align as usize), 0);
+        let offset = out.len() as u64;
+        out.extend_from_slice(data);
+        headers.extend_from_slice(&names[i].to_le_bytes());
+        headers.extend_from_slice(&sh_type.to_le_bytes());
+        headers.extend_from_slice(&u64::from(*flags).to_le_bytes());
+        headers.extend_from_slice(&0u64.to_le_bytes());
+        headers.extend_from_slice(&offset.to_le_bytes());
+        headers.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        headers.extend_from_slice(&link.to_le_bytes());
+        headers.extend_from_slice(&info.to_le_bytes());
+        headers.extend_from_slice(&align.to_le_bytes());
+        headers.extend_from_slice(&(*entsize as u64).to_le_bytes());
+    }
+    out.resize(out.len().next_multiple_of(8), 0);
+    let shoff = out.len() as u64;
+    out.extend_from_slice(&headers);
+
+    let ehdr = &mut out[..header::header64::SIZEOF_EHDR];
+    ehdr[..7].copy_from_slice(&[0x7f, b'E', b'L', b'F', header::ELFCLASS64, header::ELFDATA2LSB, header::EV_CURRENT]);
+    ehdr[16..18].copy_from_slice(&header::ET_REL.to_le_bytes());
+    ehdr[18..20].copy_from_slice(&machine.to_le_bytes());
+    ehdr[20..24].copy_from_slice(&u32::from(header::EV_CURRENT).to_le_bytes());
+    ehdr[40..48].copy_from_slice(&shoff.to_le_bytes());
+    ehdr[52..54].copy_from_slice(&(header::header64::SIZEOF_EHDR as u16).to_le_bytes());
+    ehdr[58..60].copy_from_slice(&(sh::section_header64::SIZEOF_SHDR as u16).to_le_bytes());
+    ehdr[60..62].copy_from_slice(&(sections.len() as u16).to_le_bytes());
+    ehdr[62..64].copy_from_slice(&(sections.len() as u16 - 1).to_le_bytes());
+    out
+}
@@ -0,0 +1,68 @@
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+pub const STDOUT: &str = "-";
+
+// Renders into a sibling file first and only replaces `path` when the contents differ, so
+// timestamps of unchanged outputs are left alone and make/ninja don't rebuild dependents.
+// The replacement is a rename within the same directory, so an interrupted run leaves either
+// the old or the new file in place, never a truncated one. A path of `-` is stdout.
+pub fn write_output(path: &str, render: impl FnOnce(&mut dyn Write) -> Result<(), Box<dyn Error>>) -> Result<(), Box<dyn Error>> {
+    if path == STDOUT {
+        let mut stdout = BufWriter::new(io::stdout().lock());
+        render(&mut stdout)?;
+        stdout.flush()?;
+        return Ok(());
+    }
+    let staging = Staging::new(path);
+    let mut file = BufWriter::new(File::create(&staging.path)?);
+    render(&mut file)?;
+    file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+
+    if !(Path::new(path).exists() && files_equal(&staging.path, path)?) {
+        fs::rename(&staging.path, path)?;
+    }
+    Ok(())
+}
+
+// Removes the staging file unless it has been renamed into place.
+struct Staging {
+    path: String,
+}
+
+impl Staging {
+    fn new(path: &str) -> Staging {
+        let path = Path::new(path);
+        let name = path.file_name().map_or("output".into(), |name| name.to_string_lossy());
+        let staging = path.with_file_name(format!(".{}.{}.tmp", name, std::process::id()));
+        Staging { path: staging.to_string_lossy().into_owned() }
+    }
+}
+
+impl Drop for Staging {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn files_equal(a: &str, b: &str) -> io::Result<bool> {
+    if fs::metadata(a)?.len() != fs::metadata(b)?.len() {
+        return Ok(false);
+    }
+    let mut a = BufReader::new(File::open(a)?);
+    let mut b = BufReader::new(File::open(b)?);
+    let mut a_chunk = [0; 8192];
+    let mut b_chunk = [0; 8192];
+    loop {
+        let n = a.read(&mut a_chunk)?;
+        if n == 0 {
+            return Ok(true);
+        }
+        b.read_exact(&mut b_chunk[..n])?;
+        if a_chunk[..n] != b_chunk[..n] {
+            return Ok(false);
+        }
+    }
+}
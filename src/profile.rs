@@ -0,0 +1,42 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::error::Error;
+
+use crate::Stencil;
+
+// How often each stencil was copied, one `name count` per line. Names are the ones in
+// cnp_stencils, so demangled names with spaces in them work too.
+pub fn parse_profile(text: &str) -> Result<HashMap<&str, u64>, Box<dyn Error>> {
+    let mut counts = HashMap::new();
+    for (lineno, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let parsed = line.rsplit_once(char::is_whitespace)
+            .and_then(|(name, count)| Some((name.trim_end(), count.parse::<u64>().ok()?)));
+        let Some((name, count)) = parsed else {
+            return Err(format!("line {}: expected `name count`", lineno + 1).into());
+        };
+        // Profiles of several runs can simply be concatenated.
+        *counts.entry(name).or_insert(0) += count;
+    }
+    Ok(counts)
+}
+
+// Puts the most copied stencils first, so they end up next to each other in the output and share
+// pages and cache lines. Stencils the profile doesn't mention count as never copied, and names
+// it has that are no longer stencils are ignored, so a stale profile only costs locality.
+pub fn order(stencils: &mut [Stencil], counts: &HashMap<&str, u64>) {
+    stencils.sort_by_cached_key(|s| Reverse(count(counts, s)));
+}
+
+pub fn mark_cold(stencils: &mut [Stencil], counts: &HashMap<&str, u64>, below: u64) {
+    for stencil in stencils {
+        stencil.cold = count(counts, stencil) < below;
+    }
+}
+
+fn count(counts: &HashMap<&str, u64>, stencil: &Stencil) -> u64 {
+    counts.get(stencil.display.unwrap_or(stencil.name)).or_else(|| counts.get(stencil.name)).copied().unwrap_or(0)
+}
@@ -0,0 +1,56 @@
+use std::io::{self, IsTerminal, Write};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Reports per-object progress on stderr while a batch runs, followed by a timing summary.
+// Everything is suppressed when stderr is not a terminal so build logs stay clean.
+pub struct Progress {
+    enabled: bool,
+    total: usize,
+    start: Instant,
+    done: Mutex<Vec<(String, Duration)>>,
+}
+
+impl Progress {
+    pub fn new(total: usize) -> Progress {
+        Progress {
+            enabled: io::stderr().is_terminal(),
+            total,
+            start: Instant::now(),
+            done: Mutex::new(Vec::with_capacity(total)),
+        }
+    }
+
+    pub fn time<R>(&self, name: &str, f: impl FnOnce() -> R) -> R {
+        let start = Instant::now();
+        let result = f();
+        self.finished(name, start.elapsed());
+        result
+    }
+
+    fn finished(&self, name: &str, elapsed: Duration) {
+        if !self.enabled {
+            return;
+        }
+        let mut done = self.done.lock().unwrap();
+        done.push((name.to_string(), elapsed));
+        let mut stderr = io::stderr().lock();
+        let _ = write!(stderr, "\r\x1b[K[{}/{}] {} ({:.1?})", done.len(), self.total, name, elapsed);
+        let _ = stderr.flush();
+    }
+
+    pub fn summary(&self) {
+        if !self.enabled || self.total == 0 {
+            return;
+        }
+        let mut done = self.done.lock().unwrap();
+        done.sort_by_key(|&(_, elapsed)| std::cmp::Reverse(elapsed));
+        let mut stderr = io::stderr().lock();
+        let _ = writeln!(stderr, "\r\x1b[Kprocessed {} objects in {:.1?}", done.len(), self.start.elapsed());
+        if self.total > 1 {
+            for (name, elapsed) in done.iter() {
+                let _ = writeln!(stderr, "  {:>10.1?}  {}", elapsed, name);
+            }
+        }
+    }
+}
@@ -0,0 +1,102 @@
+use std::fmt::Write;
+use std::path::{Component, Path, PathBuf};
+
+use crate::sha256;
+use crate::Stencil;
+
+// What --deterministic stamps the outputs with. Only what the inputs contain goes in, never
+// where they were or when, so the same inputs always give the same stamp.
+#[derive(serde::Serialize)]
+pub struct Provenance {
+    tool: &'static str,
+    // File name (with its `arch=` if it had one) and sha256 of each object.
+    objects: Vec<(String, String)>,
+    // Of the --fuse, --externs, --profile, --families, --glue, --holes, --rename-map, --split,
+    // --symbols-from and --ids files together, if any were given.
+    config_sha256: Option<String>,
+    // Of everything above, for the runtime to compare against.
+    sha256: String,
+}
+
+pub fn stamp(objects: &[(Option<&str>, &str, &[u8])], configs: &[&str]) -> Provenance {
+    let objects = objects.iter().map(|(arch, path, data)| {
+        let name = Path::new(path).file_name().map_or_else(|| path.to_string(), |name| name.to_string_lossy().into_owned());
+        let name = match arch {
+            Some(arch) => format!("{}={}", arch, name),
+            None => name,
+        };
+        (name, sha256::digest(data))
+    }).collect::<Vec<_>>();
+    // Each config is prefixed with its length so moving text between them changes the hash.
+    let config_sha256 = (!configs.is_empty()).then(|| {
+        let mut all = Vec::new();
+        for config in configs {
+            all.extend_from_slice(&(config.len() as u64).to_le_bytes());
+            all.extend_from_slice(config.as_bytes());
+        }
+        sha256::digest(&all)
+    });
+    let tool = concat!("stenciltool ", env!("CARGO_PKG_VERSION"));
+    let mut summary = format!("{}\n", tool);
+    for (name, sha256) in &objects {
+        let _ = writeln!(summary, "{} {}", name, sha256);
+    }
+    if let Some(sha256) = &config_sha256 {
+        let _ = writeln!(summary, "config {}", sha256);
+    }
+    Provenance { tool, objects, config_sha256, sha256: sha256::digest(summary.as_bytes()) }
+}
+
+// Line tables name files the way the compiler was given them, often absolute, which would tie
+// the outputs to the machine that built the objects. Keeps only the file name of those.
+pub fn strip_dirs(stencil: &mut Stencil) {
+    for line in stencil.lines.iter_mut() {
+        line.file = strip_dir(&line.file).to_string();
+    }
+    if let Some((file, lines)) = stencil.source_range.as_deref().and_then(|range| range.rsplit_once(':')) {
+        stencil.source_range = Some(format!("{}:{}", strip_dir(file), lines));
+    }
+}
+
+fn strip_dir(file: &str) -> &str {
+    match Path::new(file).is_absolute() {
+        true => Path::new(file).file_name().and_then(|name| name.to_str()).unwrap_or(file),
+        false => file,
+    }
+}
+
+// The manifest and depfile name every file they list, which --deterministic makes relative to
+// `base`, the directory make and ninja run in, rather than wherever the checkout happens to be.
+// Paths that are already relative, or on another drive, are left alone.
+pub fn relative_to(path: &Path, base: &Path) -> PathBuf {
+    if !path.is_absolute() {
+        return path.to_path_buf();
+    }
+    let path_components = path.components().collect::<Vec<_>>();
+    let base_components = base.components().collect::<Vec<_>>();
+    let common = path_components.iter().zip(&base_components).take_while(|(a, b)| a == b).count();
+    if !path_components[..common].iter().any(|component| matches!(component, Component::RootDir)) {
+        return path.to_path_buf();
+    }
+    let mut relative = base_components[common..].iter().map(|_| Component::ParentDir).chain(path_components[common..].iter().copied()).collect::<PathBuf>();
+    if relative.as_os_str().is_empty() {
+        relative.push(Component::CurDir);
+    }
+    relative
+}
+
+#[cfg(test)]
+mod tests {
+    use super::relative_to;
+    use std::path::Path;
+
+    #[test]
+    fn relative_paths() {
+        let base = Path::new("/tmp/t1/build");
+        assert_eq!(relative_to(Path::new("/tmp/t1/build/stencils.h"), base), Path::new("stencils.h"));
+        assert_eq!(relative_to(Path::new("/tmp/t1/st.c"), base), Path::new("../st.c"));
+        assert_eq!(relative_to(Path::new("/usr/include/stdint.h"), base), Path::new("../../../usr/include/stdint.h"));
+        assert_eq!(relative_to(Path::new("/tmp/t1/build"), base), Path::new("."));
+        assert_eq!(relative_to(Path::new("st.c"), base), Path::new("st.c"));
+    }
+}
@@ -0,0 +1,77 @@
+use std::error::Error;
+
+use crate::ArchStencils;
+
+// Rename maps have one rule per line, `old = new` for one stencil or `prefix* = new*` for every
+// stencil starting with `prefix`, so `__op_* = *` strips it. Names are the ones the stencils
+// would otherwise get, demangled for C++, and the first rule matching a stencil applies.
+pub struct RenameMap<'c> {
+    rules: Vec<Rule<'c>>,
+}
+
+struct Rule<'c> {
+    from: &'c str,
+    to: &'c str,
+    prefix: bool,
+    lineno: usize,
+}
+
+pub fn parse_renames(text: &str) -> Result<RenameMap<'_>, Box<dyn Error>> {
+    let mut map = RenameMap { rules: Vec::new() };
+    for (lineno, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let Some((from, to)) = line.split_once('=').map(|(from, to)| (from.trim(), to.trim())) else {
+            return Err(format!("line {}: expected `old = new` or `prefix* = new*`", lineno + 1).into());
+        };
+        let rule = match (from.strip_suffix('*'), to.strip_suffix('*')) {
+            (Some(from), Some(to)) if !from.is_empty() => Rule { from, to, prefix: true, lineno: lineno + 1 },
+            (None, None) if !from.is_empty() && is_identifier(to) => Rule { from, to, prefix: false, lineno: lineno + 1 },
+            _ => return Err(format!("line {}: expected `old = new` or `prefix* = new*`", lineno + 1).into()),
+        };
+        if rule.from.contains('*') || rule.to.contains('*') {
+            return Err(format!("line {}: only a trailing * matches a prefix", lineno + 1).into());
+        }
+        map.rules.push(rule);
+    }
+    Ok(map)
+}
+
+fn is_identifier(name: &str) -> bool {
+    name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') && name.starts_with(|c: char| !c.is_ascii_digit()) && !name.is_empty()
+}
+
+// The new name of each stencil of each group, None where no rule applies. A rule for one stencil
+// that no group has is most likely a stale entry.
+pub fn resolve(map: &RenameMap, groups: &[ArchStencils]) -> Result<Vec<Vec<Option<String>>>, String> {
+    let mut used = vec![false; map.rules.len()];
+    let mut renamed = Vec::with_capacity(groups.len());
+    for (_, stencils) in groups {
+        let mut names = Vec::with_capacity(stencils.len());
+        for stencil in stencils {
+            let rule = map.rules.iter().position(|rule| match rule.prefix {
+                true => stencil.name.starts_with(rule.from),
+                false => stencil.name == rule.from,
+            });
+            let name = rule.map(|i| {
+                used[i] = true;
+                let rule = &map.rules[i];
+                match rule.prefix {
+                    true => format!("{}{}", rule.to, &stencil.name[rule.from.len()..]),
+                    false => rule.to.to_string(),
+                }
+            });
+            if let Some(name) = &name && !is_identifier(name) {
+                return Err(format!("{} would be renamed to `{}`, which isn't a C identifier", stencil.name, name));
+            }
+            names.push(name);
+        }
+        renamed.push(names);
+    }
+    if let Some(rule) = map.rules.iter().zip(&used).find(|(rule, used)| !rule.prefix && !**used).map(|(rule, _)| rule) {
+        return Err(format!("line {}: there is no stencil {}", rule.lineno, rule.from));
+    }
+    Ok(renamed)
+}
@@ -0,0 +1,178 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::Stencil;
+
+// Stencils by code size, largest first, with totals. Aliases share their canonical stencil's
+// bytes and are left out.
+pub fn sizes(stencils: &[Stencil]) -> String {
+    let mut rows = stencils.iter()
+        .filter(|s| s.alias_of.is_none())
+        .map(|s| (s.name, s.code.len(), s.relocs.len(), s.holes.len()))
+        .collect::<Vec<_>>();
+    rows.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    let total = format!("total ({})", rows.len());
+    let width = rows.iter().map(|r| r.0.len()).chain([total.len(), "stencil".len()]).max().unwrap_or(0);
+
+    let mut out = String::new();
+    let _ = writeln!(out, "{:<width$}  {:>8}  {:>6}  {:>5}", "stencil", "size", "relocs", "holes");
+    for (name, size, relocs, holes) in &rows {
+        let _ = writeln!(out, "{:<width$}  {:>8}  {:>6}  {:>5}", name, size, relocs, holes);
+    }
+    let _ = writeln!(out, "{:<width$}  {:>8}  {:>6}  {:>5}", total,
+        rows.iter().map(|r| r.1).sum::<usize>(),
+        rows.iter().map(|r| r.2).sum::<usize>(),
+        rows.iter().map(|r| r.3).sum::<usize>());
+    out
+}
+
+// Relocation kinds by how often they occur, with the stencils using each most.
+pub fn relocs(stencils: &[Stencil]) -> String {
+    let mut kinds = BTreeMap::<&str, BTreeMap<&str, usize>>::new();
+    for stencil in stencils.iter().filter(|s| s.alias_of.is_none()) {
+        for reloc in &stencil.relocs {
+            *kinds.entry(reloc.relocation).or_default().entry(stencil.name).or_default() += 1;
+        }
+    }
+    let mut rows = kinds.into_iter().map(|(kind, users)| {
+        let mut users = users.into_iter().collect::<Vec<_>>();
+        users.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        (kind, users.iter().map(|u| u.1).sum::<usize>(), users)
+    }).collect::<Vec<_>>();
+    rows.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    let width = rows.iter().map(|r| r.0.len()).chain(["relocation".len()]).max().unwrap_or(0);
+
+    let mut out = String::new();
+    let _ = writeln!(out, "{:<width$}  {:>6}  {:>8}  most used by", "relocation", "count", "stencils");
+    for (kind, count, users) in &rows {
+        let top = users.iter().take(5).map(|(name, n)| format!("{} ({})", name, n)).collect::<Vec<_>>();
+        let more = if users.len() > 5 { format!(", {} more", users.len() - 5) } else { String::new() };
+        let _ = writeln!(out, "{:<width$}  {:>6}  {:>8}  {}{}", kind, count, users.len(), top.join(", "), more);
+    }
+    out
+}
+
+#[derive(serde::Serialize)]
+pub struct ListingRow<'a> {
+    offset: usize,
+    parts: Vec<ListingPart<'a>>,
+}
+
+// A run of instruction bytes, patched by a reloc if `hole` is set.
+#[derive(serde::Serialize)]
+pub struct ListingPart<'a> {
+    hex: String,
+    hole: Option<&'a str>,
+    relocation: Option<&'static str>,
+}
+
+// The stencil's code one instruction per row, with the bytes relocs patch split out.
+pub fn listing<'a>(stencil: &Stencil<'a>) -> Vec<ListingRow<'a>> {
+    let code = &stencil.code[..];
+    let mut rows = Vec::new();
+    for insn in stencil.arch.instructions(code) {
+        let mut parts = Vec::new();
+        let mut at = insn.start;
+        let relocs = stencil.relocs.iter()
+            .filter(|r| r.relocation != "R_RISCV_RELAX")
+            .filter(|r| insn.contains(&(r.offset as usize)));
+        for reloc in relocs {
+            let start = reloc.offset as usize;
+            let end = (start + reloc.width()).min(insn.end);
+            if start < at {
+                continue;
+            }
+            if at < start {
+                parts.push(ListingPart { hex: hex::encode(&code[at..start]), hole: None, relocation: None });
+            }
+            parts.push(ListingPart { hex: hex::encode(&code[start..end]), hole: Some(reloc.hole.name), relocation: Some(reloc.relocation) });
+            at = end;
+        }
+        if at < insn.end {
+            parts.push(ListingPart { hex: hex::encode(&code[at..insn.end]), hole: None, relocation: None });
+        }
+        rows.push(ListingRow { offset: insn.start, parts });
+    }
+    rows
+}
+
+// Instruction counts by kind for every stencil, a rough proxy for what it costs to run.
+pub fn insns(stencils: &[Stencil]) -> String {
+    let rows = stencils.iter()
+        .filter(|s| s.alias_of.is_none())
+        .map(|s| (s.name, s.arch.insn_stats(&s.code)))
+        .collect::<Vec<_>>();
+    let width = rows.iter().map(|r| r.0.len()).chain(["stencil".len()]).max().unwrap_or(0);
+
+    let mut out = String::new();
+    let _ = writeln!(out, "{:<width$}  {:>6}  {:>5}  {:>8}  {:>8}  {:>7}  {:>6}", "stencil", "insns", "calls", "branches", "indirect", "returns", "vector");
+    for (name, stats) in &rows {
+        let _ = writeln!(out, "{:<width$}  {:>6}  {:>5}  {:>8}  {:>8}  {:>7}  {:>6}", name,
+            stats.insns, stats.calls, stats.branches, stats.indirect, stats.returns, stats.vector);
+    }
+    out
+}
+
+// A stencil's code one instruction per row with the bytes each reloc patches underlined and
+// labelled, and the source lines they came from in between. Colored if `color`.
+pub fn explain(stencil: &Stencil, color: bool) -> String {
+    let style = |code: &str, text: &str| match color {
+        true => format!("\x1b[{}m{}\x1b[0m", code, text),
+        false => text.to_string(),
+    };
+    let mut out = String::new();
+    let _ = writeln!(out, "{}", style("1", stencil.display.unwrap_or(stencil.name)));
+    for line in &stencil.doc {
+        let _ = writeln!(out, "  {}", style("2", line));
+    }
+    if let Some(signature) = &stencil.signature {
+        let _ = writeln!(out, "  entered as {} {}({})", signature.returns, stencil.name, signature.c_params);
+    }
+    if let Some(range) = &stencil.source_range {
+        let _ = writeln!(out, "  from {}", range);
+    }
+    let stack = stencil.stack_size.map_or("unknown".to_string(), |size| format!("{} bytes", size));
+    let exit = match (stencil.terminates, stencil.fallthrough) {
+        (true, _) => "returns",
+        (_, true) => "falls through to the next stencil",
+        _ => "jumps to the next stencil",
+    };
+    let _ = writeln!(out, "  {} bytes, {} of stack, {}", stencil.code.len(), stack, exit);
+    for hole in stencil.holes.iter().filter(|h| h.is_argument()) {
+        let _ = writeln!(out, "  argument {} {}", hole.value_datatype, style("33", hole.name));
+    }
+    let _ = writeln!(out);
+
+    let mut lines = stencil.lines.iter().peekable();
+    for row in listing(stencil) {
+        while let Some(line) = lines.next_if(|l| l.offset as usize <= row.offset) {
+            let _ = writeln!(out, "{}", style("2", &format!("  // {}:{}", line.file, line.line)));
+        }
+        let mut bytes = String::new();
+        let mut labels = Vec::new();
+        for part in &row.parts {
+            let hex = part.hex.as_bytes().chunks(2).map(|b| std::str::from_utf8(b).unwrap()).collect::<Vec<_>>().join(" ");
+            if !bytes.is_empty() {
+                bytes.push(' ');
+            }
+            match (part.hole, part.relocation) {
+                (Some(hole), Some(relocation)) => {
+                    bytes.push_str(&style("4;33", &hex));
+                    labels.push(format!("{} {}", hole, relocation));
+                }
+                _ => bytes.push_str(&hex),
+            }
+        }
+        let _ = write!(out, "  {:>5}:  {}", row.offset, bytes);
+        if !labels.is_empty() {
+            // Escape codes take no columns, so pad by the visible width.
+            let width = row.parts.iter().map(|p| p.hex.len() / 2 * 3).sum::<usize>().saturating_sub(1);
+            let _ = write!(out, "{}  {}", " ".repeat(30usize.saturating_sub(width)), style("33", &format!("<- {}", labels.join(", "))));
+        }
+        out.push('\n');
+    }
+    for line in lines {
+        let _ = writeln!(out, "{}", style("2", &format!("  // {}:{}", line.file, line.line)));
+    }
+    out
+}
@@ -0,0 +1,69 @@
+use std::error::Error;
+
+use crate::Stencil;
+
+// Split configs route stencils to their own header and source, one `header source = pattern...`
+// per line where a pattern is a stencil name or `prefix*`. The first line matching a stencil
+// takes it, the rest stay in --header and --source along with cnp_stencils and cnp_emit.
+pub struct Unit<'c> {
+    pub header: &'c str,
+    pub source: &'c str,
+    patterns: Vec<&'c str>,
+    lineno: usize,
+}
+
+pub fn parse_units(text: &str) -> Result<Vec<Unit<'_>>, Box<dyn Error>> {
+    let mut units: Vec<Unit> = Vec::new();
+    for (lineno, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (files, patterns) = line.split_once('=').unwrap_or((line, ""));
+        let files = files.split_whitespace().collect::<Vec<_>>();
+        let patterns = patterns.split_whitespace().collect::<Vec<_>>();
+        let (&[header, source], false) = (&files[..], patterns.is_empty()) else {
+            return Err(format!("line {}: expected `header source = pattern...`", lineno + 1).into());
+        };
+        if let Some(pattern) = patterns.iter().find(|p| p.trim_end_matches('*').contains('*') || **p == "*") {
+            return Err(format!("line {}: {}: only a trailing * after a prefix matches several stencils", lineno + 1, pattern).into());
+        }
+        for unit in &units {
+            if let Some(file) = [header, source].into_iter().find(|file| [unit.header, unit.source].contains(file)) {
+                return Err(format!("line {}: line {} already writes to {}", lineno + 1, unit.lineno, file).into());
+            }
+        }
+        units.push(Unit { header, source, patterns, lineno: lineno + 1 });
+    }
+    Ok(units)
+}
+
+impl Unit<'_> {
+    fn matches(&self, name: &str) -> bool {
+        self.patterns.iter().any(|pattern| match pattern.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix),
+            None => name == *pattern,
+        })
+    }
+}
+
+// Sets the unit of every stencil a line matches. The _imm32 variant of a stencil goes where the
+// stencil does, since its emit function calls the variant's. A line matching nothing is most
+// likely stale.
+pub fn assign<'a>(units: &[Unit<'a>], stencils: &mut [Stencil<'a>]) -> Result<(), String> {
+    for stencil in stencils.iter_mut() {
+        stencil.unit = units.iter().find(|unit| unit.matches(stencil.name)).map(|unit| unit.header);
+    }
+    for i in 0..stencils.len() {
+        if let Some(variant) = stencils[i].imm32_variant.as_ref().map(|v| v.name) {
+            let unit = stencils[i].unit;
+            if let Some(j) = stencils.iter().position(|s| s.name == variant) {
+                stencils[j].unit = unit;
+            }
+        }
+    }
+    if let Some(unit) = units.iter().find(|unit| !stencils.iter().any(|s| s.unit == Some(unit.header))) {
+        return Err(format!("line {}: no stencil goes in {}", unit.lineno, unit.header));
+    }
+    Ok(())
+}
@@ -0,0 +1,31 @@
+use std::collections::BTreeMap;
+
+use crate::Stencil;
+
+// GNU symbol versions. Objects assembled with `.symver` name versioned symbols `memcpy@GLIBC_2.14`,
+// or `foo@@VERS_2` for the version a definition gets by default. Only linked objects have the
+// .gnu.version tables, and stencils can't be extracted from those.
+
+// The name of a symbol without its version, and the version.
+pub fn split(name: &str) -> (&str, Option<&str>) {
+    match name.split_once('@') {
+        Some((base, version)) if !base.is_empty() && !version.is_empty() => (base, Some(version.trim_start_matches('@'))),
+        _ => (name, None),
+    }
+}
+
+// The version each external hole is bound to, leaving out unversioned ones. The source declares
+// every external symbol once, so stencils can't reference it in different versions.
+pub fn externs<'a>(stencils: &[Stencil<'a>]) -> Result<BTreeMap<&'a str, &'a str>, String> {
+    let mut versions = BTreeMap::new();
+    for hole in stencils.iter().flat_map(|s| &s.holes).filter(|h| !h.internal) {
+        match versions.insert(hole.name, hole.version) {
+            Some(other) if other != hole.version => {
+                let describe = |version: Option<&str>| version.map_or(hole.name.to_string(), |version| format!("{}@{}", hole.name, version));
+                return Err(format!("{} is referenced both as {} and {}", hole.name, describe(other), describe(hole.version)));
+            }
+            _ => {}
+        }
+    }
+    Ok(versions.into_iter().filter_map(|(name, version)| Some((name, version?))).collect())
+}
@@ -0,0 +1,94 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::Stencil;
+
+// An ISA extension a stencil can be specialised for with a `__<name>` suffix. `check` is the C
+// expression that is non-zero when the running CPU has it, compiled only where `guard` holds.
+#[derive(serde::Serialize)]
+pub struct CpuFeature {
+    name: &'static str,
+    guard: &'static str,
+    check: &'static str,
+}
+
+const X86: &str = "defined(__x86_64__) || defined(__i386__)";
+const LINUX_AARCH64: &str = "defined(__aarch64__) && defined(__linux__)";
+
+// Most preferred first, a group picks its first variant the CPU can run.
+const CPU_FEATURES: &[CpuFeature] = &[
+    CpuFeature { name: "avx512f", guard: X86, check: "__builtin_cpu_supports(\"avx512f\")" },
+    CpuFeature { name: "avx2", guard: X86, check: "__builtin_cpu_supports(\"avx2\")" },
+    CpuFeature { name: "fma", guard: X86, check: "__builtin_cpu_supports(\"fma\")" },
+    CpuFeature { name: "bmi2", guard: X86, check: "__builtin_cpu_supports(\"bmi2\")" },
+    CpuFeature { name: "avx", guard: X86, check: "__builtin_cpu_supports(\"avx\")" },
+    CpuFeature { name: "sse4_2", guard: X86, check: "__builtin_cpu_supports(\"sse4.2\")" },
+    CpuFeature { name: "sse4_1", guard: X86, check: "__builtin_cpu_supports(\"sse4.1\")" },
+    CpuFeature { name: "ssse3", guard: X86, check: "__builtin_cpu_supports(\"ssse3\")" },
+    CpuFeature { name: "sse3", guard: X86, check: "__builtin_cpu_supports(\"sse3\")" },
+    CpuFeature { name: "sse2", guard: X86, check: "__builtin_cpu_supports(\"sse2\")" },
+    CpuFeature { name: "sve2", guard: LINUX_AARCH64, check: "getauxval(AT_HWCAP2) & (1 << 1)" },
+    CpuFeature { name: "sve", guard: LINUX_AARCH64, check: "getauxval(AT_HWCAP) & (1 << 22)" },
+    CpuFeature { name: "neon", guard: "defined(__aarch64__)", check: "1" },
+    CpuFeature { name: "v", guard: "defined(__riscv) && defined(__linux__)", check: "getauxval(AT_HWCAP) & (1 << ('V' - 'A'))" },
+];
+
+#[derive(serde::Serialize)]
+pub struct VariantGroup<'s> {
+    name: &'s str,
+    // Most preferred first, ending with the unsuffixed stencil if there is one.
+    variants: Vec<Variant<'s>>,
+}
+
+#[derive(serde::Serialize)]
+struct Variant<'s> {
+    stencil: &'s str,
+    feature: Option<&'static str>,
+}
+
+// Groups `<name>__<feature>` stencils by name, along with the features any of them need.
+pub fn group<'s>(stencils: &'s [Stencil]) -> Result<(Vec<VariantGroup<'s>>, Vec<&'static CpuFeature>), String> {
+    let by_name = stencils.iter().map(|s| (s.name, s)).collect::<HashMap<_, _>>();
+    let mut groups = BTreeMap::<&str, Vec<(usize, &Stencil)>>::new();
+    for stencil in stencils {
+        let Some((name, suffix)) = stencil.name.rsplit_once("__") else {
+            continue;
+        };
+        if let Some(rank) = CPU_FEATURES.iter().position(|f| f.name == suffix) {
+            groups.entry(name).or_default().push((rank, stencil));
+        }
+    }
+
+    let mut used = vec![false; CPU_FEATURES.len()];
+    let mut variant_groups = Vec::with_capacity(groups.len());
+    for (name, mut members) in groups {
+        members.sort_by_key(|(rank, _)| *rank);
+        let mut variants = members.iter().map(|(rank, s)| {
+            used[*rank] = true;
+            Variant { stencil: s.name, feature: Some(CPU_FEATURES[*rank].name) }
+        }).collect::<Vec<_>>();
+        let fallback = by_name.get(name).copied();
+        variants.extend(fallback.map(|s| Variant { stencil: s.name, feature: None }));
+        // The runtime passes the same hole values whichever variant was picked.
+        let first = members[0].1;
+        for other in members[1..].iter().map(|(_, s)| *s).chain(fallback) {
+            if arguments(other) != arguments(first) {
+                return Err(format!("{} doesn't take the same arguments as {}", other.name, first.name));
+            }
+        }
+        variant_groups.push(VariantGroup { name, variants });
+    }
+    let features = CPU_FEATURES.iter().zip(used).filter(|(_, used)| *used).map(|(f, _)| f).collect();
+    Ok((variant_groups, features))
+}
+
+// The name of the stencil a `<name>__<feature>` stencil is a variant of, or the name itself.
+pub fn base_name(name: &str) -> &str {
+    match name.rsplit_once("__") {
+        Some((base, suffix)) if CPU_FEATURES.iter().any(|f| f.name == suffix) => base,
+        _ => name,
+    }
+}
+
+fn arguments<'a>(stencil: &Stencil<'a>) -> Vec<&'a str> {
+    stencil.holes.iter().filter(|h| h.is_argument()).map(|h| h.name).collect()
+}
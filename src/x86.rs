@@ -0,0 +1,489 @@
+// x86-64 instruction length decoding. This only works out where instructions start and where
+// their pc-relative operands are, which is all the passes that rewrite code need.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BranchKind {
+    Jmp,
+    // Condition code, the low nibble of the opcode.
+    Jcc(u8),
+    Call,
+    // loop/loopcc/jrcxz, which only have a rel8 form.
+    Loop,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Insn {
+    pub len: usize,
+    // Bytes of legacy and REX prefixes before the opcode.
+    pub prefixes: usize,
+    pub branch: Option<BranchKind>,
+    // Offset and width of the pc-relative operand, either a branch displacement or a RIP
+    // relative memory operand, relative to the start of the instruction.
+    pub rel: Option<(usize, usize)>,
+    // Call or Jmp through a register or memory operand.
+    pub indirect: Option<BranchKind>,
+    pub ret: bool,
+    // MMX, SSE or AVX, anything operating on vector registers.
+    pub vector: bool,
+}
+
+// One byte opcodes followed by a ModRM byte.
+fn has_modrm_1(op: u8) -> bool {
+    matches!(op,
+        0x00..=0x03 | 0x08..=0x0b | 0x10..=0x13 | 0x18..=0x1b |
+        0x20..=0x23 | 0x28..=0x2b | 0x30..=0x33 | 0x38..=0x3b |
+        0x63 | 0x69 | 0x6b | 0x80..=0x8f | 0xc0 | 0xc1 | 0xc6 | 0xc7 |
+        0xd0..=0xd3 | 0xd8..=0xdf | 0xf6 | 0xf7 | 0xfe | 0xff)
+}
+
+// Two byte (0f xx) opcodes without a ModRM byte.
+fn no_modrm_2(op: u8) -> bool {
+    matches!(op, 0x05..=0x09 | 0x0b | 0x0e | 0x30..=0x35 | 0x37 | 0x77 | 0x80..=0x8f |
+        0xa0..=0xa2 | 0xa8..=0xaa | 0xc8..=0xcf)
+}
+
+// Two byte opcodes with an imm8 after the ModRM operand.
+fn imm8_2(op: u8) -> bool {
+    matches!(op, 0x70..=0x73 | 0xa4 | 0xac | 0xba | 0xc2 | 0xc4..=0xc6)
+}
+
+// Length of the ModRM/SIB/displacement bytes at `code[0]`, and the offset of a RIP relative
+// displacement among them.
+fn modrm_len(code: &[u8]) -> Option<(usize, Option<usize>)> {
+    let modrm = *code.first()?;
+    let (mode, rm) = (modrm >> 6, modrm & 7);
+    if mode == 3 {
+        return Some((1, None));
+    }
+    let mut len = 1;
+    let mut base = rm;
+    if rm == 4 {
+        base = code.get(1)? & 7;
+        len += 1;
+    }
+    Some(match mode {
+        0 if rm == 5 => (len + 4, Some(len)),
+        0 if base == 5 => (len + 4, None),
+        0 => (len, None),
+        1 => (len + 1, None),
+        _ => (len + 4, None),
+    })
+}
+
+// Decodes the instruction at the start of `code`, or None if it's truncated or not something
+// 64-bit code would contain.
+pub fn decode(code: &[u8]) -> Option<Insn> {
+    let mut i = 0;
+    let mut opsize16 = false;
+    let mut addrsize32 = false;
+    while let Some(&b) = code.get(i) {
+        match b {
+            0x66 => opsize16 = true,
+            0x67 => addrsize32 = true,
+            0xf0 | 0xf2 | 0xf3 | 0x2e | 0x36 | 0x3e | 0x26 | 0x64 | 0x65 => {}
+            _ => break,
+        }
+        i += 1;
+    }
+    let mut rex_w = false;
+    if let Some(&b) = code.get(i) && b & 0xf0 == 0x40 {
+        rex_w = b & 8 != 0;
+        i += 1;
+    }
+    let prefixes = i;
+    let imm_z = if opsize16 { 2 } else { 4 };
+
+    let op = *code.get(i)?;
+    i += 1;
+    let mut insn = Insn { len: 0, prefixes, branch: None, rel: None, indirect: None, ret: false, vector: false };
+    let mut modrm = false;
+    let mut imm = 0;
+    match op {
+        // VEX and EVEX, which carry the opcode map in their payload.
+        0xc4 | 0xc5 | 0x62 => {
+            let (map, payload) = match op {
+                0xc4 => (code.get(i)? & 0x1f, 2),
+                0xc5 => (1, 1),
+                _ => (code.get(i)? & 7, 3),
+            };
+            i += payload;
+            let op = *code.get(i)?;
+            i += 1;
+            // vzeroupper/vzeroall are the only VEX instructions without a ModRM byte.
+            modrm = !(map == 1 && op == 0x77);
+            // BMI and friends are VEX encoded general purpose instructions.
+            insn.vector = !(map == 2 && (0xf0..=0xf7).contains(&op) || map == 3 && op == 0xf0);
+            imm = match map {
+                3 => 1,
+                1 if imm8_2(op) => 1,
+                1..=6 => 0,
+                _ => return None,
+            };
+        }
+        0x0f => {
+            let op = *code.get(i)?;
+            i += 1;
+            match op {
+                0x38 => {
+                    // movbe, crc32 and adcx/adox are at the top of the map.
+                    insn.vector = code.get(i).is_some_and(|&op| op < 0xf0);
+                    i += 1;
+                    modrm = true;
+                }
+                0x3a => {
+                    insn.vector = true;
+                    i += 1;
+                    modrm = true;
+                    imm = 1;
+                }
+                // 3DNow! puts its opcode in an imm8 suffix.
+                0x0f => {
+                    insn.vector = true;
+                    modrm = true;
+                    imm = 1;
+                }
+                0x80..=0x8f => {
+                    insn.branch = Some(BranchKind::Jcc(op & 0xf));
+                    imm = 4;
+                }
+                _ => {
+                    modrm = !no_modrm_2(op);
+                    imm = if imm8_2(op) { 1 } else { 0 };
+                    insn.vector = matches!(op, 0x10..=0x17 | 0x28..=0x2f | 0x50..=0x7f | 0xc2 | 0xc4..=0xc6 | 0xd0..=0xfe);
+                }
+            }
+        }
+        0x06 | 0x07 | 0x0e | 0x16 | 0x17 | 0x1e | 0x1f | 0x27 | 0x2f | 0x37 | 0x3f |
+        0x60 | 0x61 | 0x9a | 0xce | 0xd4 | 0xd5 | 0xd6 | 0xea => return None,
+        0x70..=0x7f => {
+            insn.branch = Some(BranchKind::Jcc(op & 0xf));
+            imm = 1;
+        }
+        0xe0..=0xe3 => {
+            insn.branch = Some(BranchKind::Loop);
+            imm = 1;
+        }
+        0xeb => {
+            insn.branch = Some(BranchKind::Jmp);
+            imm = 1;
+        }
+        0xe9 => {
+            insn.branch = Some(BranchKind::Jmp);
+            imm = 4;
+        }
+        0xe8 => {
+            insn.branch = Some(BranchKind::Call);
+            imm = 4;
+        }
+        _ => {
+            modrm = has_modrm_1(op);
+            imm = match op {
+                0x04 | 0x0c | 0x14 | 0x1c | 0x24 | 0x2c | 0x34 | 0x3c => 1,
+                0x05 | 0x0d | 0x15 | 0x1d | 0x25 | 0x2d | 0x35 | 0x3d => imm_z,
+                0x68 | 0x69 | 0x81 | 0xa9 | 0xc7 => imm_z,
+                0x6a | 0x6b | 0x80 | 0x83 | 0xa8 | 0xb0..=0xb7 | 0xc0 | 0xc1 | 0xc6 | 0xcd |
+                0xe4..=0xe7 => 1,
+                0xa0..=0xa3 => if addrsize32 { 4 } else { 8 },
+                0xb8..=0xbf => if rex_w { 8 } else { imm_z },
+                0xc2 | 0xca => 2,
+                0xc8 => 3,
+                // test has an immediate, the other group 3 instructions don't.
+                0xf6 if code.get(i).is_some_and(|m| m >> 3 & 7 < 2) => 1,
+                0xf7 if code.get(i).is_some_and(|m| m >> 3 & 7 < 2) => imm_z,
+                _ => 0,
+            };
+        }
+    }
+    match op {
+        0xc2 | 0xc3 | 0xca | 0xcb => insn.ret = true,
+        0xff => insn.indirect = match code.get(i).map(|m| m >> 3 & 7) {
+            Some(2 | 3) => Some(BranchKind::Call),
+            Some(4 | 5) => Some(BranchKind::Jmp),
+            _ => None,
+        },
+        _ => {}
+    }
+    if modrm {
+        let (len, rip) = modrm_len(code.get(i..)?)?;
+        insn.rel = rip.map(|offset| (i + offset, 4));
+        i += len;
+    }
+    if insn.branch.is_some() {
+        insn.rel = Some((i, imm));
+    }
+    i += imm;
+    if i > code.len() || i > 15 {
+        return None;
+    }
+    insn.len = i;
+    Some(insn)
+}
+
+// Decodes all of `code` into instructions, or None if any part of it doesn't decode.
+pub fn decode_all(code: &[u8]) -> Option<Vec<Insn>> {
+    let mut insns = Vec::new();
+    let mut offset = 0;
+    while offset < code.len() {
+        let insn = decode(&code[offset..])?;
+        offset += insn.len;
+        insns.push(insn);
+    }
+    Some(insns)
+}
+
+// Offset of each of `insns` in the code they were decoded from.
+pub fn starts(insns: &[Insn]) -> Vec<usize> {
+    insns.iter().scan(0, |offset, insn| {
+        let start = *offset;
+        *offset += insn.len;
+        Some(start)
+    }).collect()
+}
+
+// Offset the pc-relative operand of `insn`, starting at `start`, points at within `code`.
+pub fn rel_target(code: &[u8], start: usize, insn: &Insn) -> Option<i64> {
+    let (offset, width) = insn.rel?;
+    let bytes = code.get(start + offset..start + offset + width)?;
+    let disp = match width {
+        1 => bytes[0] as i8 as i64,
+        4 => i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as i64,
+        _ => return None,
+    };
+    Some((start + insn.len) as i64 + disp)
+}
+
+pub struct Shortened {
+    pub code: Vec<u8>,
+    // (old, new) offset of every instruction start.
+    pub moves: Vec<(usize, usize)>,
+}
+
+// Re-encodes rel32 jmp/jcc branches within `code` as rel8 where the target is close enough.
+// `relocs` are the (offset, width) ranges patched later, which are left alone. Returns None if
+// nothing was shortened, the code doesn't decode or it has pc-relative operands this can't follow.
+pub fn shorten_branches(code: &[u8], relocs: &[(usize, usize)]) -> Option<Shortened> {
+    let insns = decode_all(code)?;
+    let starts = starts(&insns);
+    let patched = |start: usize, len: usize| relocs.iter().any(|&(offset, width)| offset < start + len && start < offset + width);
+
+    // The instruction (or the end of the code) each internal pc-relative operand points at.
+    let mut targets = vec![None; insns.len()];
+    for (i, insn) in insns.iter().enumerate() {
+        let Some((offset, width)) = insn.rel else { continue };
+        let start = starts[i];
+        if patched(start + offset, width) {
+            continue;
+        }
+        let target = rel_target(code, start, insn)?;
+        let index = if target == code.len() as i64 {
+            insns.len()
+        } else {
+            starts.binary_search(&usize::try_from(target).ok()?).ok()?
+        };
+        targets[i] = Some(index);
+    }
+
+    // Shortening a branch only ever brings others closer to their targets, so keep going until
+    // nothing else fits.
+    let mut short = vec![false; insns.len()];
+    let mut new_starts = starts.clone();
+    new_starts.push(code.len());
+    loop {
+        let mut changed = false;
+        for (i, insn) in insns.iter().enumerate() {
+            let candidate = matches!(insn.branch, Some(BranchKind::Jmp | BranchKind::Jcc(_))) &&
+                insn.rel.is_some_and(|(_, width)| width == 4) && insn.prefixes == 0;
+            if short[i] || !candidate {
+                continue;
+            }
+            let Some(target) = targets[i] else { continue };
+            let disp = new_starts[target] as i64 - (new_starts[i] + 2) as i64;
+            if i8::try_from(disp).is_err() {
+                continue;
+            }
+            short[i] = true;
+            changed = true;
+            let mut offset = 0;
+            for (j, insn) in insns.iter().enumerate() {
+                new_starts[j] = offset;
+                offset += if short[j] { 2 } else { insn.len };
+            }
+            new_starts[insns.len()] = offset;
+        }
+        if !changed {
+            break;
+        }
+    }
+    if !short.contains(&true) {
+        return None;
+    }
+
+    let mut out = Vec::with_capacity(new_starts[insns.len()]);
+    for (i, insn) in insns.iter().enumerate() {
+        let old = &code[starts[i]..starts[i] + insn.len];
+        let end = new_starts[i + 1] as i64;
+        match (short[i], insn.branch, targets[i]) {
+            (true, Some(kind), Some(target)) => {
+                let opcode = match kind {
+                    BranchKind::Jcc(cc) => 0x70 | cc,
+                    _ => 0xeb,
+                };
+                out.extend_from_slice(&[opcode, (new_starts[target] as i64 - end) as i8 as u8]);
+            }
+            (_, _, Some(target)) => {
+                let (offset, width) = insn.rel?;
+                let disp = new_starts[target] as i64 - end;
+                let at = out.len() + offset;
+                out.extend_from_slice(old);
+                match width {
+                    1 => out[at] = i8::try_from(disp).ok()? as u8,
+                    _ => out[at..at + 4].copy_from_slice(&i32::try_from(disp).ok()?.to_le_bytes()),
+                }
+            }
+            _ => out.extend_from_slice(old),
+        }
+    }
+    Some(Shortened { code: out, moves: starts.into_iter().zip(new_starts).collect() })
+}
+
+// Rewrites the instruction whose rel32 at `offset` is the address of a GOT entry to use the address
+// in it directly: mov becomes lea, and an indirect call or jmp a direct one. The rel32 stays
+// where it is. Returns None for other instructions.
+pub fn relax_got_load(code: &mut [u8], offset: usize) -> Option<()> {
+    let op = offset.checked_sub(2)?;
+    code.get(offset..offset + 4)?;
+    match *code.get(op..offset)? {
+        // mov reg, [rip+rel32]
+        [0x8b, modrm] if modrm & 0xc7 == 0x05 => {
+            code[op] = 0x8d;
+            Some(())
+        }
+        // call [rip+rel32] becomes addr32 call rel32, the same length.
+        [0xff, 0x15] => {
+            code[op..offset].copy_from_slice(&[0x67, 0xe8]);
+            Some(())
+        }
+        // jmp [rip+rel32] becomes nop; jmp rel32.
+        [0xff, 0x25] => {
+            code[op..offset].copy_from_slice(&[0x90, 0xe9]);
+            Some(())
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, decode_all, rel_target, relax_got_load, shorten_branches, BranchKind};
+
+    // Length, pc-relative operand and whether it's a vector instruction, for one of each shape.
+    #[test]
+    fn lengths() {
+        for (code, len, rel, vector) in [
+            // ret, push rbp
+            (&[0xc3][..], 1, None, false),
+            (&[0x55], 1, None, false),
+            // mov rax, [rip+0x10]
+            (&[0x48, 0x8b, 0x05, 0x10, 0, 0, 0], 7, Some((3, 4)), false),
+            // mov eax, 1, movabs rax, imm64 and mov ax, 1
+            (&[0xb8, 1, 0, 0, 0], 5, None, false),
+            (&[0x48, 0xb8, 1, 2, 3, 4, 5, 6, 7, 8], 10, None, false),
+            (&[0x66, 0xb8, 1, 0], 4, None, false),
+            // lea rax, [rsp+8], mov eax, [rbp+0x100] and mov eax, [0x1234] through a SIB byte
+            (&[0x48, 0x8d, 0x44, 0x24, 0x08], 5, None, false),
+            (&[0x8b, 0x85, 0, 1, 0, 0], 6, None, false),
+            (&[0x8b, 0x04, 0x25, 0x34, 0x12, 0, 0], 7, None, false),
+            // test byte [rax], 1 has an immediate, neg rax doesn't.
+            (&[0xf6, 0x00, 0x01], 3, None, false),
+            (&[0x48, 0xf7, 0xd8], 3, None, false),
+            // lock xadd [rdi], eax
+            (&[0xf0, 0x0f, 0xc1, 0x07], 4, None, false),
+            // addps xmm0, xmm1, pshufd xmm0, xmm1, 0x1b and vaddps ymm0, ymm1, ymm2
+            (&[0x0f, 0x58, 0xc1], 3, None, true),
+            (&[0x66, 0x0f, 0x70, 0xc1, 0x1b], 5, None, true),
+            (&[0xc5, 0xf4, 0x58, 0xc2], 4, None, true),
+            // vpermq ymm0, ymm1, 0x4e and vzeroupper
+            (&[0xc4, 0xe3, 0xfd, 0x00, 0xc1, 0x4e], 6, None, true),
+            (&[0xc5, 0xf8, 0x77], 3, None, true),
+            // andn eax, ebx, ecx is VEX encoded but not a vector instruction.
+            (&[0xc4, 0xe2, 0x60, 0xf2, 0xc1], 5, None, false),
+        ] {
+            let insn = decode(code).unwrap_or_else(|| panic!("{:02x?}", code));
+            assert_eq!((insn.len, insn.rel, insn.vector), (len, rel, vector), "{:02x?}", code);
+        }
+    }
+
+    #[test]
+    fn branches() {
+        // jmp rel8, je rel32 and call rel32
+        let jmp = decode(&[0xeb, 0xfe]).unwrap();
+        assert_eq!((jmp.len, jmp.branch, jmp.rel), (2, Some(BranchKind::Jmp), Some((1, 1))));
+        let je = decode(&[0x0f, 0x84, 0, 0, 0, 0]).unwrap();
+        assert_eq!((je.len, je.branch, je.rel), (6, Some(BranchKind::Jcc(4)), Some((2, 4))));
+        let call = decode(&[0xe8, 0, 0, 0, 0]).unwrap();
+        assert_eq!((call.len, call.branch, call.rel), (5, Some(BranchKind::Call), Some((1, 4))));
+        // call [rip+0] and jmp rax
+        let call = decode(&[0xff, 0x15, 0, 0, 0, 0]).unwrap();
+        assert_eq!((call.len, call.branch, call.indirect, call.rel), (6, None, Some(BranchKind::Call), Some((2, 4))));
+        let jmp = decode(&[0xff, 0xe0]).unwrap();
+        assert_eq!((jmp.len, jmp.indirect, jmp.rel), (2, Some(BranchKind::Jmp), None));
+        assert!(decode(&[0xc3]).unwrap().ret);
+        // jmp $ points back at itself, jmp +2 past the code after it.
+        assert_eq!(rel_target(&[0x90, 0xeb, 0xfe], 1, &decode(&[0xeb, 0xfe]).unwrap()), Some(1));
+        assert_eq!(rel_target(&[0xeb, 0x02], 0, &decode(&[0xeb, 0x02]).unwrap()), Some(4));
+    }
+
+    #[test]
+    fn invalid() {
+        // push es doesn't exist in 64-bit code.
+        assert!(decode(&[0x06]).is_none());
+        // Truncated call rel32, ModRM and prefixes with nothing after them.
+        assert!(decode(&[0xe8, 0, 0]).is_none());
+        assert!(decode(&[0x48, 0x8b]).is_none());
+        assert!(decode(&[0x66, 0x48]).is_none());
+        assert!(decode(&[]).is_none());
+        assert!(decode_all(&[0x90, 0xe8, 0]).is_none());
+        assert_eq!(decode_all(&[0x55, 0x48, 0xf7, 0xd8, 0xc3]).unwrap().iter().map(|insn| insn.len).collect::<Vec<_>>(), [1, 3, 1]);
+    }
+
+    #[test]
+    fn short_branches() {
+        // jmp rel32 over two nops to the ret.
+        let code = [0xe9, 2, 0, 0, 0, 0x90, 0x90, 0xc3];
+        let shortened = shorten_branches(&code, &[]).unwrap();
+        assert_eq!(shortened.code, [0xeb, 2, 0x90, 0x90, 0xc3]);
+        assert_eq!(shortened.moves, [(0, 0), (5, 2), (6, 3), (7, 4)]);
+        // Not when the displacement is patched later.
+        assert!(shorten_branches(&code, &[(1, 4)]).is_none());
+    }
+
+    fn relaxed(mut code: Vec<u8>, offset: usize) -> Option<Vec<u8>> {
+        relax_got_load(&mut code, offset)?;
+        Some(code)
+    }
+
+    #[test]
+    fn mov_becomes_lea() {
+        // mov rax, [rip+0x11223344]
+        assert_eq!(relaxed(vec![0x48, 0x8b, 0x05, 0x44, 0x33, 0x22, 0x11], 3), Some(vec![0x48, 0x8d, 0x05, 0x44, 0x33, 0x22, 0x11]));
+        // mov r9d, [rip+0]
+        assert_eq!(relaxed(vec![0x44, 0x8b, 0x0d, 0, 0, 0, 0], 3), Some(vec![0x44, 0x8d, 0x0d, 0, 0, 0, 0]));
+    }
+
+    #[test]
+    fn indirect_branches_become_direct() {
+        assert_eq!(relaxed(vec![0xff, 0x15, 1, 2, 3, 4], 2), Some(vec![0x67, 0xe8, 1, 2, 3, 4]));
+        assert_eq!(relaxed(vec![0xff, 0x25, 1, 2, 3, 4], 2), Some(vec![0x90, 0xe9, 1, 2, 3, 4]));
+    }
+
+    #[test]
+    fn other_instructions_are_left_alone() {
+        // add rax, [rip+0], cmp rax, [rip+0] and mov rax, [rbx]
+        assert_eq!(relaxed(vec![0x48, 0x03, 0x05, 0, 0, 0, 0], 3), None);
+        assert_eq!(relaxed(vec![0x48, 0x3b, 0x05, 0, 0, 0, 0], 3), None);
+        assert_eq!(relaxed(vec![0x48, 0x8b, 0x03, 0, 0, 0, 0], 3), None);
+        // The rel32 runs past the end of the code.
+        assert_eq!(relaxed(vec![0x48, 0x8b, 0x05, 0, 0], 3), None);
+        assert_eq!(relaxed(vec![0, 0, 0, 0], 0), None);
+    }
+}